@@ -3,10 +3,26 @@
 //! This module defines the consensus mechanism for block generation and validation.
 //! Currently implements Proof-of-Authority (PoA) consensus, but designed to be
 //! pluggable for other consensus algorithms.
+//!
+//! - [`instant_seal`]: Instant-seal / manual-seal engine for development and tests
+//! - [`raft`]: Raft crash-fault-tolerant replication for small clusters
+//! - [`finality`]: BFT finality gadget producing commit certificates
+//! - [`evidence`]: Equivocation detection for authorities
+
+pub mod evidence;
+pub mod finality;
+pub mod instant_seal;
+pub mod raft;
+
+pub use evidence::EquivocationDetector;
+pub use finality::{FinalityError, FinalityGadget, FinalityOutput};
+pub use instant_seal::{Clock, InstantSealConsensus, ManualClock, SealMode, SystemClock};
+pub use raft::{RaftConfig, RaftConsensus};
 
-use ed25519_dalek::{Signer, SigningKey};
+use ed25519_dalek::SigningKey;
 use thiserror::Error;
 
+use crate::storage::StorageError;
 use crate::types::{Block, ChainState, CryptoError, PublicKey, Transaction};
 
 #[derive(Debug, Error)]
@@ -25,6 +41,12 @@ pub enum ConsensusError {
     InvalidNonce,
     #[error("No pending transactions available to generate a block")]
     NoPendingTransactions,
+    #[error("This node is not the leader")]
+    NotLeader,
+    #[error("Timed out waiting for a quorum to commit the block")]
+    QuorumTimeout,
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
 }
 
 pub trait ConsensusEngine: Send + Sync {
@@ -36,10 +58,54 @@ pub trait ConsensusEngine: Send + Sync {
         prev_block: &Block,
         chain_state: &ChainState,
     ) -> Result<Block, ConsensusError>;
+
+    /// Whether the runtime should seal a block as soon as a transaction is
+    /// accepted, instead of waiting for an explicit `produce_block`.
+    fn seal_on_submit(&self) -> bool {
+        false
+    }
+
+    /// Reach agreement on a generated, validated block before it is applied.
+    ///
+    /// Single-node engines accept immediately. Replicated engines block here
+    /// until enough peers have acknowledged the block, and return an error if
+    /// that does not happen.
+    fn agree_on_block(&self, _block: &Block) -> Result<(), ConsensusError> {
+        Ok(())
+    }
+
+    /// Fork choice: whether the branch ending in `candidate` should replace
+    /// the canonical branch ending in `current`.
+    ///
+    /// The default is the longest chain, keeping the current branch on ties so
+    /// that nodes do not flip between equally long forks.
+    fn prefers(&self, candidate: &Block, current: &Block) -> bool {
+        candidate.index > current.index
+    }
+
+    /// Hash a generated block once the runtime has filled in execution
+    /// results such as `state_root`, and seal it if the engine seals blocks.
+    ///
+    /// `generate_block` must not seal: an authority signs each height exactly
+    /// once, here, so that it never puts its key to two headers at one height.
+    fn seal_block(&self, block: &mut Block) -> Result<(), ConsensusError> {
+        block.hash = block.calculate_hash()?;
+        block.seal = None;
+        Ok(())
+    }
+
+    /// Keys entitled to seal blocks and cast finality votes.
+    ///
+    /// Equivocation evidence can only name one of these. Engines whose
+    /// blocks are not signed have none.
+    fn authorities(&self) -> Vec<PublicKey> {
+        Vec::new()
+    }
 }
 
 pub struct PoAConsensus {
     authorized_signer_key: PublicKey,
+    signing_key: Option<SigningKey>,
     _block_time_interval_ms: u64,
 }
 
@@ -47,15 +113,35 @@ impl PoAConsensus {
     pub fn new(authorized_signer_key: PublicKey, block_time_interval_ms: u64) -> Self {
         Self {
             authorized_signer_key,
+            signing_key: None,
             _block_time_interval_ms: block_time_interval_ms,
         }
     }
 
-    pub fn validate_block(&self, _block: &Block) -> Result<(), ConsensusError> {
-        // For PoA, we just check if the block is signed by an authorized signer
-        // In a real implementation, you'd check the signature against the authorized key
+    /// Create an engine for the authority itself; generated blocks are sealed
+    /// with `signing_key`.
+    pub fn with_signing_key(signing_key: SigningKey, block_time_interval_ms: u64) -> Self {
+        Self {
+            authorized_signer_key: PublicKey::from(signing_key.verifying_key()),
+            signing_key: Some(signing_key),
+            _block_time_interval_ms: block_time_interval_ms,
+        }
+    }
 
-        // For now, just return Ok() - implement actual signature verification later
+    pub fn validate_block(&self, block: &Block) -> Result<(), ConsensusError> {
+        // Every block but genesis must be sealed by the authorized signer.
+        if block.index == 0 {
+            return Ok(());
+        }
+        let header = block
+            .signed_header()
+            .ok_or_else(|| ConsensusError::ValidationFailed("Block is not sealed".to_string()))?;
+        if header.signer != self.authorized_signer_key {
+            return Err(ConsensusError::UnauthorizedSigner);
+        }
+        if !header.verify_signature() {
+            return Err(CryptoError::SignatureVerificationFailed.into());
+        }
         Ok(())
     }
 
@@ -69,10 +155,7 @@ impl PoAConsensus {
             return Err(ConsensusError::UnauthorizedSigner);
         }
 
-        // Sign the block
-        let _signature = private_key.sign(&block.hash);
-        // TODO: Add signature to block metadata or create a signed block type
-
+        block.seal(private_key);
         Ok(())
     }
 }
@@ -81,9 +164,16 @@ impl crate::consensus::ConsensusEngine for PoAConsensus {
     fn validate_block(
         &self,
         block: &Block,
-        _chain_state: &ChainState,
+        chain_state: &ChainState,
     ) -> Result<(), ConsensusError> {
-        self.validate_block(block)
+        self.validate_block(block)?;
+        // Authorities jailed for equivocation may no longer produce blocks.
+        if let Some(seal) = &block.seal {
+            if chain_state.jailed_authorities.contains(&seal.signer) {
+                return Err(ConsensusError::UnauthorizedSigner);
+            }
+        }
+        Ok(())
     }
 
     fn generate_block(
@@ -105,12 +195,32 @@ impl crate::consensus::ConsensusEngine for PoAConsensus {
             prev_hash,
             hash: [0u8; 32],
             nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
             transactions,
             metadata: None,
+            seal: None,
         };
+        block.transactions_root = block.compute_transactions_root();
         block.hash = block
             .calculate_hash()
             .map_err(|e| ConsensusError::ValidationFailed(format!("Hash error: {:?}", e)))?;
         Ok(block)
     }
+
+    fn seal_block(&self, block: &mut Block) -> Result<(), ConsensusError> {
+        // Only the authority can produce blocks other nodes accept.
+        let signing_key = self
+            .signing_key
+            .as_ref()
+            .ok_or(ConsensusError::UnauthorizedSigner)?;
+        block.hash = block.calculate_hash()?;
+        block.seal(signing_key);
+        Ok(())
+    }
+
+    fn authorities(&self) -> Vec<PublicKey> {
+        vec![self.authorized_signer_key]
+    }
 }
@@ -0,0 +1,137 @@
+//! Equivocation detection for block authorities.
+//!
+//! An honest authority signs at most one block, and casts at most one vote of
+//! each kind, per height. [`EquivocationDetector`] remembers the first signed
+//! statement it sees for each (height, signer) pair. When a conflicting one
+//! arrives, it returns [`EquivocationEvidence`] that any node can verify,
+//! gossip, and submit on-chain as `TransactionPayload::SubmitEvidence`.
+
+use std::collections::BTreeMap;
+
+use crate::types::{EquivocationEvidence, PublicKey, SignedHeader, Vote, VoteKind};
+
+/// Remembers signed headers and votes and reports conflicting pairs.
+#[derive(Debug, Default)]
+pub struct EquivocationDetector {
+    headers: BTreeMap<(u64, PublicKey), SignedHeader>,
+    votes: BTreeMap<(u64, PublicKey, VoteKind), Vote>,
+}
+
+impl EquivocationDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a signed block header.
+    ///
+    /// Returns evidence if the signer already signed a different block at the
+    /// same height. Headers with invalid signatures are ignored.
+    pub fn observe_header(&mut self, header: SignedHeader) -> Option<EquivocationEvidence> {
+        if !header.verify_signature() {
+            return None;
+        }
+        let first = self
+            .headers
+            .entry((header.height, header.signer))
+            .or_insert_with(|| header.clone());
+        (first.block_hash != header.block_hash).then(|| EquivocationEvidence::DoubleSign {
+            first: first.clone(),
+            second: header,
+        })
+    }
+
+    /// Record a finality vote.
+    ///
+    /// Returns evidence if the voter already cast a vote of the same kind for
+    /// a different block at the same height. Invalid votes are ignored.
+    pub fn observe_vote(&mut self, vote: Vote) -> Option<EquivocationEvidence> {
+        if !vote.verify_signature() {
+            return None;
+        }
+        let first = self
+            .votes
+            .entry((vote.height, vote.voter, vote.kind))
+            .or_insert_with(|| vote.clone());
+        (first.block_hash != vote.block_hash).then(|| EquivocationEvidence::DoubleVote {
+            first: first.clone(),
+            second: vote,
+        })
+    }
+
+    /// Forget everything recorded below `height`.
+    pub fn prune_below(&mut self, height: u64) {
+        self.headers
+            .retain(|(header_height, _), _| *header_height >= height);
+        self.votes
+            .retain(|(vote_height, _, _), _| *vote_height >= height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Block;
+    use ed25519_dalek::SigningKey;
+
+    fn sealed_block(index: u64, tag: u8, key: &SigningKey) -> Block {
+        let mut block = Block {
+            index,
+            timestamp: index,
+            prev_hash: [0; 32],
+            hash: [tag; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: Vec::new(),
+            metadata: None,
+            seal: None,
+        };
+        block.seal(key);
+        block
+    }
+
+    #[test]
+    fn test_detects_double_sign() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let mut detector = EquivocationDetector::new();
+
+        let a = sealed_block(5, 1, &key).signed_header().unwrap();
+        let b = sealed_block(5, 2, &key).signed_header().unwrap();
+        assert!(detector.observe_header(a.clone()).is_none());
+        // Seeing the same header again is not equivocation.
+        assert!(detector.observe_header(a.clone()).is_none());
+
+        let evidence = detector.observe_header(b).unwrap();
+        assert!(evidence.verify());
+        assert_eq!(evidence.offender(), a.signer);
+        assert_eq!(evidence.height(), 5);
+
+        // Seals from different heights cannot be passed off as evidence.
+        let c = sealed_block(6, 2, &key).signed_header().unwrap();
+        let forged = EquivocationEvidence::DoubleSign {
+            first: a,
+            second: SignedHeader { height: 5, ..c },
+        };
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn test_detects_double_vote() {
+        let key = SigningKey::from_bytes(&[4; 32]);
+        let mut detector = EquivocationDetector::new();
+        let first = Vote::new_signed(VoteKind::Precommit, 2, [1; 32], &key);
+        let second = Vote::new_signed(VoteKind::Precommit, 2, [2; 32], &key);
+        // A prevote and a precommit for different blocks are separate statements.
+        let prevote = Vote::new_signed(VoteKind::Prevote, 2, [2; 32], &key);
+
+        assert!(detector.observe_vote(first).is_none());
+        assert!(detector.observe_vote(prevote).is_none());
+        let evidence = detector.observe_vote(second).unwrap();
+        assert!(evidence.verify());
+
+        detector.prune_below(3);
+        let third = Vote::new_signed(VoteKind::Precommit, 2, [3; 32], &key);
+        assert!(detector.observe_vote(third).is_none());
+    }
+}
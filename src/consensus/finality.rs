@@ -0,0 +1,406 @@
+//! BFT finality gadget layered on top of block production.
+//!
+//! PoA (or any other engine) keeps producing blocks; the gadget then runs a
+//! two-phase, Tendermint-style vote among the authorities for each height:
+//!
+//! 1. Every authority prevotes for the block it sees at that height.
+//! 2. Once more than two thirds prevote the same block, each authority
+//!    precommits it.
+//! 3. More than two thirds of precommits form a [`CommitCertificate`]. The
+//!    host passes it to `Runtime::apply_commit_certificate` to store it and
+//!    advance `ChainState::finalized_height`.
+//!
+//! An authority prevotes at most once per height, so two conflicting blocks
+//! can never both collect a supermajority unless more than a third of the
+//! authorities equivocate.
+//!
+//! The gadget holds no locks and does no I/O. Votes it returns must be gossiped
+//! to the other authorities (e.g. as [`crate::sync::NetworkMessage::FinalityVote`]),
+//! and votes received from them are fed back into [`FinalityGadget::on_vote`].
+//! Conflicting votes from the same authority are reported as
+//! [`EquivocationEvidence`]. `Runtime::with_finality` does all of this for a
+//! node. Jailed authorities (see [`FinalityGadget::set_jailed`]) neither vote
+//! nor count towards the supermajority.
+
+use ed25519_dalek::SigningKey;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::consensus::evidence::EquivocationDetector;
+use crate::types::{
+    is_supermajority, Block, CommitCertificate, EquivocationEvidence, PublicKey, Vote, VoteKind,
+};
+
+/// How far past the local tip votes are still accepted.
+///
+/// Votes for heights the node has not seen yet are kept so that a slightly
+/// lagging node still finalizes, but anything further ahead is rejected so a
+/// peer cannot fill memory with votes for arbitrary future heights.
+pub const MAX_VOTE_LOOKAHEAD: u64 = 16;
+
+#[derive(Debug, Error)]
+pub enum FinalityError {
+    #[error("Vote signature is invalid")]
+    InvalidSignature,
+    #[error("Voter is not an authority")]
+    UnknownVoter,
+    #[error("Voter is a jailed authority")]
+    JailedVoter,
+    #[error("Height {0} is already finalized")]
+    AlreadyFinalized(u64),
+    #[error("Height {0} is too far ahead of the local tip")]
+    TooFarAhead(u64),
+}
+
+/// What the host must act on after feeding the gadget.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FinalityOutput {
+    /// Votes to gossip to the other authorities.
+    pub broadcast: Vec<Vote>,
+    /// Set when a block gathered a supermajority of precommits.
+    pub finalized: Option<CommitCertificate>,
+    /// Proof of any authority that voted for two blocks at one height.
+    pub evidence: Vec<EquivocationEvidence>,
+}
+
+#[derive(Default)]
+struct HeightVotes {
+    prevotes: BTreeMap<PublicKey, Vote>,
+    precommits: BTreeMap<PublicKey, Vote>,
+    /// Block this node prevoted for, if any.
+    prevoted: Option<[u8; 32]>,
+    /// Block this node precommitted, if any.
+    precommitted: Option<[u8; 32]>,
+}
+
+/// Collects finality votes and produces commit certificates.
+pub struct FinalityGadget {
+    authorities: Vec<PublicKey>,
+    /// Authorities jailed for equivocation; they neither vote nor count
+    /// towards the supermajority.
+    jailed: Vec<PublicKey>,
+    signing_key: Option<SigningKey>,
+    finalized_height: u64,
+    /// Highest block passed to [`FinalityGadget::on_block`].
+    tip_height: u64,
+    heights: BTreeMap<u64, HeightVotes>,
+    detector: EquivocationDetector,
+}
+
+impl FinalityGadget {
+    /// Create a gadget for `authorities`. Pass the node's own key if it is one
+    /// of them; observers that only assemble certificates pass `None`.
+    pub fn new(
+        authorities: Vec<PublicKey>,
+        signing_key: Option<SigningKey>,
+        finalized_height: u64,
+    ) -> Self {
+        Self {
+            authorities,
+            jailed: Vec::new(),
+            signing_key,
+            finalized_height,
+            tip_height: finalized_height,
+            heights: BTreeMap::new(),
+            detector: EquivocationDetector::new(),
+        }
+    }
+
+    pub fn authorities(&self) -> &[PublicKey] {
+        &self.authorities
+    }
+
+    pub fn finalized_height(&self) -> u64 {
+        self.finalized_height
+    }
+
+    /// Replace the set of jailed authorities, typically with
+    /// `ChainState::jailed_authorities` after each block.
+    ///
+    /// Votes already recorded from them stop counting.
+    pub fn set_jailed(&mut self, jailed: &[PublicKey]) {
+        self.jailed = jailed.to_vec();
+    }
+
+    /// Authorities that may vote: all of them except the jailed ones.
+    pub fn voters(&self) -> Vec<PublicKey> {
+        self.authorities
+            .iter()
+            .filter(|authority| !self.jailed.contains(authority))
+            .copied()
+            .collect()
+    }
+
+    /// Start voting on a newly produced or imported block.
+    pub fn on_block(&mut self, block: &Block) -> FinalityOutput {
+        if block.index <= self.finalized_height {
+            return FinalityOutput::default();
+        }
+        self.tip_height = self.tip_height.max(block.index);
+        let Some(key) = self.signing_key.clone() else {
+            return FinalityOutput::default();
+        };
+        if self.jailed.contains(&PublicKey::from(key.verifying_key())) {
+            return FinalityOutput::default();
+        }
+        let votes = self.heights.entry(block.index).or_default();
+        if votes.prevoted.is_some() {
+            return FinalityOutput::default();
+        }
+        votes.prevoted = Some(block.hash);
+        let vote = Vote::new_signed(VoteKind::Prevote, block.index, block.hash, &key);
+        // Count our own vote like any other so a single authority still finalizes.
+        let mut output = self.on_vote(vote.clone()).unwrap_or_default();
+        output.broadcast.insert(0, vote);
+        output
+    }
+
+    /// Record a vote from any authority (including ourselves).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vote is unsigned, from a non-authority or a
+    /// jailed one, for a height that is already final, or more than
+    /// [`MAX_VOTE_LOOKAHEAD`] blocks past the local tip. Duplicate votes are
+    /// ignored.
+    pub fn on_vote(&mut self, vote: Vote) -> Result<FinalityOutput, FinalityError> {
+        if vote.height <= self.finalized_height {
+            return Err(FinalityError::AlreadyFinalized(vote.height));
+        }
+        if vote.height > self.tip_height.saturating_add(MAX_VOTE_LOOKAHEAD) {
+            return Err(FinalityError::TooFarAhead(vote.height));
+        }
+        if !self.authorities.contains(&vote.voter) {
+            return Err(FinalityError::UnknownVoter);
+        }
+        if self.jailed.contains(&vote.voter) {
+            return Err(FinalityError::JailedVoter);
+        }
+        if !vote.verify_signature() {
+            return Err(FinalityError::InvalidSignature);
+        }
+
+        let total = self.voters().len();
+        let height = vote.height;
+        let block_hash = vote.block_hash;
+        let mut output = FinalityOutput::default();
+        output
+            .evidence
+            .extend(self.detector.observe_vote(vote.clone()));
+        let jailed = &self.jailed;
+        let votes = self.heights.entry(height).or_default();
+
+        match vote.kind {
+            VoteKind::Prevote => {
+                votes.prevotes.entry(vote.voter).or_insert(vote);
+                let support = votes
+                    .prevotes
+                    .values()
+                    .filter(|v| v.block_hash == block_hash && !jailed.contains(&v.voter))
+                    .count();
+                if is_supermajority(support, total) && votes.precommitted.is_none() {
+                    if let Some(key) = self
+                        .signing_key
+                        .as_ref()
+                        .filter(|key| !jailed.contains(&PublicKey::from(key.verifying_key())))
+                    {
+                        votes.precommitted = Some(block_hash);
+                        let precommit =
+                            Vote::new_signed(VoteKind::Precommit, height, block_hash, key);
+                        output.broadcast.push(precommit.clone());
+                        votes.precommits.entry(precommit.voter).or_insert(precommit);
+                    }
+                }
+            }
+            VoteKind::Precommit => {
+                votes.precommits.entry(vote.voter).or_insert(vote);
+            }
+        }
+
+        let precommits: Vec<Vote> = votes
+            .precommits
+            .values()
+            .filter(|v| v.block_hash == block_hash && !jailed.contains(&v.voter))
+            .cloned()
+            .collect();
+        if is_supermajority(precommits.len(), total) {
+            self.finalized_height = height;
+            // Votes for this height and below are no longer needed.
+            self.heights = self.heights.split_off(&(height + 1));
+            self.detector.prune_below(height + 1);
+            output.finalized = Some(CommitCertificate {
+                height,
+                block_hash,
+                precommits,
+            });
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authority_keys(n: u8) -> Vec<SigningKey> {
+        (1..=n).map(|i| SigningKey::from_bytes(&[i; 32])).collect()
+    }
+
+    fn block(index: u64, tag: u8) -> Block {
+        Block {
+            index,
+            timestamp: index,
+            prev_hash: [0; 32],
+            hash: [tag; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: Vec::new(),
+            metadata: None,
+            seal: None,
+        }
+    }
+
+    /// Deliver every broadcast to every gadget until no more votes flow.
+    fn run(gadgets: &mut [FinalityGadget], block: &Block) -> Vec<CommitCertificate> {
+        let mut queue: Vec<Vote> = Vec::new();
+        let mut certificates = Vec::new();
+        for gadget in gadgets.iter_mut() {
+            queue.extend(gadget.on_block(block).broadcast);
+        }
+        while let Some(vote) = queue.pop() {
+            for gadget in gadgets.iter_mut() {
+                if vote.voter
+                    == PublicKey::from(gadget.signing_key.as_ref().unwrap().verifying_key())
+                {
+                    continue;
+                }
+                let output = gadget.on_vote(vote.clone()).unwrap_or_default();
+                queue.extend(output.broadcast);
+                certificates.extend(output.finalized);
+            }
+        }
+        certificates
+    }
+
+    #[test]
+    fn test_supermajority_produces_verifiable_certificate() {
+        let keys = authority_keys(4);
+        let authorities: Vec<PublicKey> = keys
+            .iter()
+            .map(|k| PublicKey::from(k.verifying_key()))
+            .collect();
+        // One of four authorities is offline: 3/4 > 2/3 still finalizes.
+        let mut gadgets: Vec<FinalityGadget> = keys[..3]
+            .iter()
+            .map(|k| FinalityGadget::new(authorities.clone(), Some(k.clone()), 0))
+            .collect();
+
+        let certificates = run(&mut gadgets, &block(1, 7));
+        assert!(!certificates.is_empty());
+        let certificate = &certificates[0];
+        assert_eq!(certificate.height, 1);
+        assert!(certificate.verify(&authorities));
+        assert!(gadgets.iter().all(|g| g.finalized_height() == 1));
+
+        // The same votes are not enough against a larger authority set.
+        let mut larger = authorities.clone();
+        larger.extend(
+            authority_keys(6)[4..]
+                .iter()
+                .map(|k| PublicKey::from(k.verifying_key())),
+        );
+        assert!(!certificate.verify(&larger));
+    }
+
+    #[test]
+    fn test_no_certificate_without_supermajority() {
+        let keys = authority_keys(4);
+        let authorities: Vec<PublicKey> = keys
+            .iter()
+            .map(|k| PublicKey::from(k.verifying_key()))
+            .collect();
+        let mut gadgets: Vec<FinalityGadget> = keys[..2]
+            .iter()
+            .map(|k| FinalityGadget::new(authorities.clone(), Some(k.clone()), 0))
+            .collect();
+        assert!(run(&mut gadgets, &block(1, 7)).is_empty());
+        assert!(gadgets.iter().all(|g| g.finalized_height() == 0));
+    }
+
+    #[test]
+    fn test_rejects_forged_and_foreign_votes() {
+        let keys = authority_keys(4);
+        let authorities: Vec<PublicKey> = keys[..3]
+            .iter()
+            .map(|k| PublicKey::from(k.verifying_key()))
+            .collect();
+        let mut gadget = FinalityGadget::new(authorities, None, 0);
+
+        let outsider = Vote::new_signed(VoteKind::Prevote, 1, [7; 32], &keys[3]);
+        assert!(matches!(
+            gadget.on_vote(outsider),
+            Err(FinalityError::UnknownVoter)
+        ));
+
+        let mut forged = Vote::new_signed(VoteKind::Prevote, 1, [7; 32], &keys[0]);
+        forged.block_hash = [8; 32];
+        assert!(matches!(
+            gadget.on_vote(forged),
+            Err(FinalityError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_jailed_authorities_neither_vote_nor_count() {
+        let keys = authority_keys(4);
+        let authorities: Vec<PublicKey> = keys
+            .iter()
+            .map(|k| PublicKey::from(k.verifying_key()))
+            .collect();
+        let mut gadget = FinalityGadget::new(authorities.clone(), None, 0);
+        gadget.on_block(&block(1, 7));
+        // Recorded before the authority was jailed.
+        let early = Vote::new_signed(VoteKind::Precommit, 1, [7; 32], &keys[3]);
+        assert!(gadget.on_vote(early).unwrap().finalized.is_none());
+        gadget.set_jailed(&authorities[3..]);
+        assert_eq!(gadget.voters(), authorities[..3]);
+
+        let late = Vote::new_signed(VoteKind::Precommit, 1, [7; 32], &keys[3]);
+        assert!(matches!(
+            gadget.on_vote(late),
+            Err(FinalityError::JailedVoter)
+        ));
+        // With the jailed vote this would be three of four; it is two of three.
+        for key in &keys[..2] {
+            let vote = Vote::new_signed(VoteKind::Precommit, 1, [7; 32], key);
+            assert!(gadget.on_vote(vote).unwrap().finalized.is_none());
+        }
+        let vote = Vote::new_signed(VoteKind::Precommit, 1, [7; 32], &keys[2]);
+        let certificate = gadget.on_vote(vote).unwrap().finalized.unwrap();
+        assert_eq!(certificate.precommits.len(), 3);
+    }
+
+    #[test]
+    fn test_rejects_votes_far_ahead_of_tip() {
+        let keys = authority_keys(4);
+        let authorities: Vec<PublicKey> = keys
+            .iter()
+            .map(|k| PublicKey::from(k.verifying_key()))
+            .collect();
+        let mut gadget = FinalityGadget::new(authorities, None, 0);
+
+        let near = Vote::new_signed(VoteKind::Prevote, MAX_VOTE_LOOKAHEAD, [7; 32], &keys[0]);
+        assert!(gadget.on_vote(near).is_ok());
+        let far = Vote::new_signed(VoteKind::Prevote, MAX_VOTE_LOOKAHEAD + 1, [7; 32], &keys[0]);
+        assert!(matches!(
+            gadget.on_vote(far.clone()),
+            Err(FinalityError::TooFarAhead(_))
+        ));
+
+        // Once the node sees a block the window moves with it.
+        gadget.on_block(&block(1, 1));
+        assert!(gadget.on_vote(far).is_ok());
+    }
+}
@@ -0,0 +1,276 @@
+//! Instant-seal / manual-seal consensus for development and tests.
+//!
+//! `InstantSealConsensus` performs no authority checks and never waits on
+//! block timing. In [`SealMode::Instant`] the runtime seals a block as soon as
+//! a transaction is accepted; in [`SealMode::Manual`] blocks are only sealed
+//! when the application calls `Runtime::seal`. Block timestamps come from a
+//! pluggable [`Clock`], so tests can drive time explicitly with a
+//! [`ManualClock`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::consensus::{ConsensusEngine, ConsensusError};
+use crate::types::{Block, ChainState, Transaction};
+
+/// Source of block timestamps (Unix seconds).
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// Wall-clock time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// Clones share the same underlying time, so a test can keep one handle and
+/// hand another to the consensus engine.
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(start: u64) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(start)),
+        }
+    }
+
+    pub fn set(&self, timestamp: u64) {
+        self.now.store(timestamp, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// When the runtime should seal blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SealMode {
+    /// Seal a block on every accepted transaction.
+    Instant,
+    /// Seal only on an explicit `Runtime::seal` / `Runtime::produce_block` call.
+    Manual,
+}
+
+/// Development consensus engine that seals blocks immediately or on demand.
+pub struct InstantSealConsensus<K: Clock = SystemClock> {
+    mode: SealMode,
+    clock: K,
+}
+
+impl InstantSealConsensus<SystemClock> {
+    pub fn new(mode: SealMode) -> Self {
+        Self::with_clock(mode, SystemClock)
+    }
+}
+
+impl<K: Clock> InstantSealConsensus<K> {
+    pub fn with_clock(mode: SealMode, clock: K) -> Self {
+        Self { mode, clock }
+    }
+
+    pub fn mode(&self) -> SealMode {
+        self.mode
+    }
+
+    pub fn clock(&self) -> &K {
+        &self.clock
+    }
+}
+
+impl<K: Clock> ConsensusEngine for InstantSealConsensus<K> {
+    fn validate_block(
+        &self,
+        block: &Block,
+        chain_state: &ChainState,
+    ) -> Result<(), ConsensusError> {
+        if block.index != chain_state.latest_block_index + 1 {
+            return Err(ConsensusError::ValidationFailed(format!(
+                "Invalid block index: expected {}, got {}",
+                chain_state.latest_block_index + 1,
+                block.index
+            )));
+        }
+        if block.prev_hash != chain_state.latest_block_hash {
+            return Err(ConsensusError::MismatchedPrevHash);
+        }
+        Ok(())
+    }
+
+    fn generate_block(
+        &self,
+        pending_transactions: &[Transaction],
+        prev_block: &Block,
+        _chain_state: &ChainState,
+    ) -> Result<Block, ConsensusError> {
+        if pending_transactions.is_empty() {
+            return Err(ConsensusError::NoPendingTransactions);
+        }
+        // Timestamps must strictly increase even if the clock is frozen.
+        let timestamp = self.clock.now().max(prev_block.timestamp + 1);
+        let mut block = Block {
+            index: prev_block.index + 1,
+            timestamp,
+            prev_hash: prev_block.hash,
+            hash: [0u8; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: pending_transactions.to_vec(),
+            metadata: None,
+            seal: None,
+        };
+        block.transactions_root = block.compute_transactions_root();
+        block.hash = block
+            .calculate_hash()
+            .map_err(|e| ConsensusError::ValidationFailed(format!("Hash error: {:?}", e)))?;
+        Ok(block)
+    }
+
+    fn seal_on_submit(&self) -> bool {
+        self.mode == SealMode::Instant
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Address, PublicKey, TransactionPayload, TransactionSignature};
+    use ed25519_dalek::SigningKey;
+
+    fn genesis() -> Block {
+        let mut block = Block {
+            index: 0,
+            timestamp: 0,
+            prev_hash: [0; 32],
+            hash: [0; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: Vec::new(),
+            metadata: None,
+            seal: None,
+        };
+        block.hash = block.calculate_hash().unwrap();
+        block
+    }
+
+    fn data_tx() -> Transaction {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let sender = PublicKey::from(signing_key.verifying_key());
+        let mut tx = Transaction {
+            hash: [0; 32],
+            sender,
+            nonce: 1,
+            timestamp: 0,
+            recipient: Address::Wallet(sender),
+            payload: TransactionPayload::Data { data: vec![1] },
+            signature: TransactionSignature::from_bytes(&[0; 64]).unwrap(),
+            gas_limit: 0,
+            priority: 0,
+            metadata: None,
+        };
+        tx.sign(&signing_key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_block_timestamps_follow_manual_clock() {
+        let clock = ManualClock::new(1_000);
+        let engine = InstantSealConsensus::with_clock(SealMode::Manual, clock.clone());
+        let genesis = genesis();
+        let state = ChainState {
+            latest_block_hash: genesis.hash,
+            latest_block_index: 0,
+            accounts_root_hash: [0; 32],
+            total_supply: 0,
+            finalized_height: 0,
+            jailed_authorities: Vec::new(),
+        };
+
+        let block = engine
+            .generate_block(&[data_tx()], &genesis, &state)
+            .unwrap();
+        assert_eq!(block.timestamp, 1_000);
+        assert!(engine.validate_block(&block, &state).is_ok());
+        assert!(!engine.seal_on_submit());
+
+        // A frozen clock still yields strictly increasing timestamps.
+        let next = engine.generate_block(&[data_tx()], &block, &state).unwrap();
+        assert_eq!(next.timestamp, 1_001);
+
+        clock.advance(60);
+        let later = engine.generate_block(&[data_tx()], &block, &state).unwrap();
+        assert_eq!(later.timestamp, 1_060);
+    }
+
+    #[test]
+    fn test_rejects_block_not_on_tip() {
+        let engine = InstantSealConsensus::with_clock(SealMode::Instant, ManualClock::new(5));
+        assert!(engine.seal_on_submit());
+        let genesis = genesis();
+        let state = ChainState {
+            latest_block_hash: [9; 32],
+            latest_block_index: 0,
+            accounts_root_hash: [0; 32],
+            total_supply: 0,
+            finalized_height: 0,
+            jailed_authorities: Vec::new(),
+        };
+        let block = engine
+            .generate_block(&[data_tx()], &genesis, &state)
+            .unwrap();
+        assert!(matches!(
+            engine.validate_block(&block, &state),
+            Err(ConsensusError::MismatchedPrevHash)
+        ));
+    }
+
+    #[test]
+    fn test_fork_choice_prefers_longer_branch() {
+        let engine = InstantSealConsensus::with_clock(SealMode::Manual, ManualClock::new(5));
+        let genesis = genesis();
+        let state = ChainState {
+            latest_block_hash: genesis.hash,
+            latest_block_index: 0,
+            accounts_root_hash: [0; 32],
+            total_supply: 0,
+            finalized_height: 0,
+            jailed_authorities: Vec::new(),
+        };
+        let a1 = engine
+            .generate_block(&[data_tx()], &genesis, &state)
+            .unwrap();
+        let b1 = engine
+            .generate_block(&[data_tx(), data_tx()], &genesis, &state)
+            .unwrap();
+        let b2 = engine.generate_block(&[data_tx()], &b1, &state).unwrap();
+
+        // Equal length keeps the current tip; a longer branch wins.
+        assert!(!engine.prefers(&b1, &a1));
+        assert!(engine.prefers(&b2, &a1));
+        assert!(!engine.prefers(&a1, &b2));
+    }
+}
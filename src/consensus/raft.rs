@@ -0,0 +1,1077 @@
+//! Raft-based crash-fault-tolerant consensus for small clusters.
+//!
+//! Every node runs a [`RaftConsensus`] engine. Nodes elect a leader, and only
+//! the leader may generate blocks. A generated block is appended to the
+//! replicated log and shipped to followers over a [`Transport`]. The leader's
+//! runtime applies it only once a quorum of nodes has stored it (see
+//! [`ConsensusEngine::agree_on_block`]). Followers collect committed blocks
+//! with [`RaftConsensus::take_committed_blocks`] and import them into their
+//! own runtime. A cluster of `2f + 1` nodes keeps making progress with `f`
+//! nodes down. A newly elected leader first appends a no-op entry, so blocks
+//! its predecessor left uncommitted commit without waiting for a new one.
+//!
+//! Time is logical: the host calls [`RaftConsensus::tick`] at a fixed
+//! interval (e.g. every 100ms), or lets [`RaftConsensus::spawn_ticker`] do
+//! so, to drive election timeouts and heartbeats.
+//!
+//! The term, vote and log are written through [`RaftStorage`] before any message
+//! reflecting them is sent, so a restarted node resumes with the promises it
+//! made. The commit index is not persisted: a restarted node hands out its
+//! committed blocks again, which the runtime skips as already known.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::consensus::{ConsensusEngine, ConsensusError};
+use crate::storage::{RaftStorage, StorageError};
+use crate::sync::{MessageHandler, NetworkMessage, Transport};
+use crate::types::{Block, ChainState, PublicKey, Transaction};
+
+/// Tunables for a Raft node.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Minimum ticks without hearing from a leader before starting an
+    /// election. The actual timeout is randomized in `[min, 2 * min)`.
+    pub election_timeout_ticks: u64,
+    /// Ticks between leader heartbeats. Must be well below the election timeout.
+    pub heartbeat_interval_ticks: u64,
+    /// How long [`ConsensusEngine::agree_on_block`] waits for a quorum.
+    pub commit_timeout: Duration,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout_ticks: 10,
+            heartbeat_interval_ticks: 2,
+            commit_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A replicated log entry carrying one block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RaftEntry {
+    pub term: u64,
+    /// `None` for the no-op a new leader appends, which commits the entries
+    /// of earlier terms without waiting for the next block.
+    pub block: Option<Block>,
+}
+
+/// The part of a node's state that must survive a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RaftHardState {
+    pub current_term: u64,
+    pub voted_for: Option<PublicKey>,
+}
+
+/// Raft protocol messages, carried in [`NetworkMessage::Raft`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    VoteResponse {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<RaftEntry>,
+        leader_commit: u64,
+    },
+    AppendResponse {
+        term: u64,
+        success: bool,
+        /// On success, the last index the follower now holds; on failure, a
+        /// hint for where the leader should retry from.
+        match_index: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The Raft state machine, independent of threads and I/O.
+///
+/// Outgoing messages are queued in an outbox and sent by the caller after
+/// releasing any locks, so handlers may safely be re-entered by a synchronous
+/// transport.
+struct RaftNode {
+    id: PublicKey,
+    peers: Vec<PublicKey>,
+    config: RaftConfig,
+    role: RaftRole,
+    current_term: u64,
+    voted_for: Option<PublicKey>,
+    leader: Option<PublicKey>,
+    /// Log entries; Raft index `i` lives at `log[i - 1]`.
+    log: Vec<RaftEntry>,
+    commit_index: u64,
+    /// Highest index handed to the local runtime.
+    last_applied: u64,
+    elapsed_ticks: u64,
+    election_timeout: u64,
+    votes: BTreeSet<PublicKey>,
+    next_index: BTreeMap<PublicKey, u64>,
+    match_index: BTreeMap<PublicKey, u64>,
+    outbox: Vec<(PublicKey, RaftMessage)>,
+    /// Term or vote changed since the last [`RaftNode::persist`].
+    hard_state_dirty: bool,
+    /// Lowest log index changed since the last [`RaftNode::persist`].
+    log_dirty_from: Option<u64>,
+}
+
+impl RaftNode {
+    fn new(id: PublicKey, peers: Vec<PublicKey>, config: RaftConfig) -> Self {
+        let peers = peers.into_iter().filter(|p| *p != id).collect();
+        let mut node = Self {
+            id,
+            peers,
+            config,
+            role: RaftRole::Follower,
+            current_term: 0,
+            voted_for: None,
+            leader: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            elapsed_ticks: 0,
+            election_timeout: 0,
+            votes: BTreeSet::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            outbox: Vec::new(),
+            hard_state_dirty: false,
+            log_dirty_from: None,
+        };
+        node.reset_election_timer();
+        node
+    }
+
+    /// Resume from the state a previous run persisted.
+    fn restore(&mut self, hard_state: RaftHardState, log: Vec<RaftEntry>) {
+        self.current_term = hard_state.current_term;
+        self.voted_for = hard_state.voted_for;
+        self.log = log;
+    }
+
+    fn mark_log_dirty(&mut self, index: u64) {
+        self.log_dirty_from = Some(self.log_dirty_from.map_or(index, |from| from.min(index)));
+    }
+
+    /// Write the term, vote and changed log suffix through `storage`.
+    ///
+    /// On failure the changes stay marked and are retried on the next call.
+    fn persist(&mut self, storage: &dyn RaftStorage) -> Result<(), StorageError> {
+        if let Some(from) = self.log_dirty_from {
+            let entries = self.log.get(from as usize - 1..).unwrap_or_default();
+            storage.put_raft_entries(from, entries)?;
+            self.log_dirty_from = None;
+        }
+        if self.hard_state_dirty {
+            storage.put_raft_hard_state(&RaftHardState {
+                current_term: self.current_term,
+                voted_for: self.voted_for,
+            })?;
+            self.hard_state_dirty = false;
+        }
+        Ok(())
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        if index == 0 {
+            0
+        } else {
+            self.log.get(index as usize - 1).map_or(0, |e| e.term)
+        }
+    }
+
+    fn reset_election_timer(&mut self) {
+        let min = self.config.election_timeout_ticks.max(1);
+        self.elapsed_ticks = 0;
+        self.election_timeout = rand::thread_rng().gen_range(min..min * 2);
+    }
+
+    fn tick(&mut self) {
+        self.elapsed_ticks += 1;
+        match self.role {
+            RaftRole::Leader => {
+                if self.elapsed_ticks >= self.config.heartbeat_interval_ticks {
+                    self.elapsed_ticks = 0;
+                    self.broadcast_append();
+                }
+            }
+            RaftRole::Follower | RaftRole::Candidate => {
+                if self.elapsed_ticks >= self.election_timeout {
+                    self.start_election();
+                }
+            }
+        }
+    }
+
+    fn start_election(&mut self) {
+        self.current_term += 1;
+        self.role = RaftRole::Candidate;
+        self.voted_for = Some(self.id);
+        self.hard_state_dirty = true;
+        self.leader = None;
+        self.votes = BTreeSet::from([self.id]);
+        self.reset_election_timer();
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let request = RaftMessage::RequestVote {
+            term: self.current_term,
+            last_log_index: self.last_log_index(),
+            last_log_term: self.term_at(self.last_log_index()),
+        };
+        for peer in self.peers.clone() {
+            self.outbox.push((peer, request.clone()));
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<PublicKey>) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            self.hard_state_dirty = true;
+        }
+        self.role = RaftRole::Follower;
+        self.leader = leader;
+    }
+
+    fn become_leader(&mut self) {
+        self.role = RaftRole::Leader;
+        self.leader = Some(self.id);
+        self.elapsed_ticks = 0;
+        let next = self.last_log_index() + 1;
+        self.next_index = self.peers.iter().map(|p| (*p, next)).collect();
+        self.match_index = self.peers.iter().map(|p| (*p, 0)).collect();
+        // Entries of earlier terms only commit along with one of this term.
+        self.log.push(RaftEntry {
+            term: self.current_term,
+            block: None,
+        });
+        self.mark_log_dirty(next);
+        self.advance_commit();
+        self.broadcast_append();
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: PublicKey) {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1).max(1);
+        let prev_log_index = next - 1;
+        let message = RaftMessage::AppendEntries {
+            term: self.current_term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries: self.log[prev_log_index as usize..].to_vec(),
+            leader_commit: self.commit_index,
+        };
+        self.outbox.push((peer, message));
+    }
+
+    /// Append a block to the leader's log and start replicating it.
+    fn propose(&mut self, block: Block) -> Result<u64, ConsensusError> {
+        if self.role != RaftRole::Leader {
+            return Err(ConsensusError::NotLeader);
+        }
+        self.log.push(RaftEntry {
+            term: self.current_term,
+            block: Some(block),
+        });
+        let index = self.last_log_index();
+        self.mark_log_dirty(index);
+        self.advance_commit();
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    fn advance_commit(&mut self) {
+        let mut indices: Vec<u64> = self.match_index.values().copied().collect();
+        indices.push(self.last_log_index());
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+        let candidate = indices[self.quorum() - 1];
+        // Only entries from the current term are committed by counting
+        // replicas; earlier entries commit along with them.
+        if candidate > self.commit_index && self.term_at(candidate) == self.current_term {
+            self.commit_index = candidate;
+        }
+    }
+
+    fn step(&mut self, from: PublicKey, message: RaftMessage) {
+        let term = match &message {
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::VoteResponse { term, .. }
+            | RaftMessage::AppendEntries { term, .. }
+            | RaftMessage::AppendResponse { term, .. } => *term,
+        };
+        if term > self.current_term {
+            self.become_follower(term, None);
+        }
+
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let my_last_term = self.term_at(self.last_log_index());
+                let up_to_date = last_log_term > my_last_term
+                    || (last_log_term == my_last_term && last_log_index >= self.last_log_index());
+                let granted = term == self.current_term
+                    && self.voted_for.is_none_or(|v| v == from)
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.hard_state_dirty = true;
+                    self.reset_election_timer();
+                }
+                self.outbox.push((
+                    from,
+                    RaftMessage::VoteResponse {
+                        term: self.current_term,
+                        granted,
+                    },
+                ));
+            }
+            RaftMessage::VoteResponse { term, granted } => {
+                if self.role == RaftRole::Candidate && term == self.current_term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.current_term {
+                    self.outbox.push((
+                        from,
+                        RaftMessage::AppendResponse {
+                            term: self.current_term,
+                            success: false,
+                            match_index: 0,
+                        },
+                    ));
+                    return;
+                }
+                self.become_follower(term, Some(from));
+                self.reset_election_timer();
+
+                if prev_log_index > self.last_log_index()
+                    || self.term_at(prev_log_index) != prev_log_term
+                {
+                    let hint = self.last_log_index().min(prev_log_index.saturating_sub(1));
+                    self.outbox.push((
+                        from,
+                        RaftMessage::AppendResponse {
+                            term: self.current_term,
+                            success: false,
+                            match_index: hint,
+                        },
+                    ));
+                    return;
+                }
+
+                // Anything past the entries sent may be stale and is not
+                // vouched for, but is only dropped on a conflict.
+                let last_new_index = prev_log_index + entries.len() as u64;
+                for (offset, entry) in entries.into_iter().enumerate() {
+                    let index = prev_log_index + 1 + offset as u64;
+                    if index <= self.last_log_index() {
+                        if self.term_at(index) == entry.term {
+                            continue;
+                        }
+                        self.log.truncate(index as usize - 1);
+                    }
+                    self.log.push(entry);
+                    self.mark_log_dirty(index);
+                }
+                self.commit_index = self.commit_index.max(leader_commit.min(last_new_index));
+                self.outbox.push((
+                    from,
+                    RaftMessage::AppendResponse {
+                        term: self.current_term,
+                        success: true,
+                        match_index: last_new_index,
+                    },
+                ));
+            }
+            RaftMessage::AppendResponse {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != RaftRole::Leader || term != self.current_term {
+                    return;
+                }
+                if success {
+                    let match_index = match_index.min(self.last_log_index());
+                    let current = self.match_index.entry(from).or_insert(0);
+                    *current = (*current).max(match_index);
+                    self.next_index.insert(from, *current + 1);
+                    self.advance_commit();
+                } else {
+                    let next = self.next_index.get(&from).copied().unwrap_or(1);
+                    self.next_index
+                        .insert(from, (match_index + 1).min(next.saturating_sub(1)).max(1));
+                    self.send_append(from);
+                }
+            }
+        }
+    }
+}
+
+struct RaftShared<T: Transport, S: RaftStorage> {
+    node: Mutex<RaftNode>,
+    committed: Condvar,
+    transport: T,
+    storage: S,
+}
+
+impl<T: Transport, S: RaftStorage> RaftShared<T, S> {
+    fn lock(&self) -> MutexGuard<'_, RaftNode> {
+        self.node.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send queued messages. Must be called without holding the node lock.
+    fn flush(&self, outbox: Vec<(PublicKey, RaftMessage)>) {
+        for (to, message) in outbox {
+            if let Err(e) = self.transport.send(&to, NetworkMessage::Raft(message)) {
+                eprintln!("Raft send to {} failed: {}", hex::encode(to.to_bytes()), e);
+            }
+        }
+    }
+
+    /// Run `f` against the node, persist what it changed, then deliver
+    /// whatever it queued.
+    ///
+    /// If persisting fails the queued messages are dropped, since they may
+    /// reflect a vote or log entries that are not durable; the protocol
+    /// retries them.
+    fn with_node<R>(&self, f: impl FnOnce(&mut RaftNode) -> R) -> Result<R, ConsensusError> {
+        let (result, outbox) = {
+            let mut node = self.lock();
+            let result = f(&mut node);
+            let outbox = std::mem::take(&mut node.outbox);
+            node.persist(&self.storage)?;
+            (result, outbox)
+        };
+        self.committed.notify_all();
+        self.flush(outbox);
+        Ok(result)
+    }
+}
+
+impl<T: Transport, S: RaftStorage> MessageHandler for RaftShared<T, S> {
+    fn handle_message(&self, from: &PublicKey, message: NetworkMessage) {
+        if let NetworkMessage::Raft(message) = message {
+            if let Err(e) = self.with_node(|node| node.step(*from, message)) {
+                eprintln!("Raft state could not be persisted: {}", e);
+            }
+        }
+    }
+}
+
+/// Raft consensus engine. See the [module docs](self) for the overall flow.
+pub struct RaftConsensus<T: Transport, S: RaftStorage> {
+    shared: Arc<RaftShared<T, S>>,
+    commit_timeout: Duration,
+}
+
+impl<T: Transport + 'static, S: RaftStorage + 'static> RaftConsensus<T, S> {
+    /// Create a node identified by `id` in a cluster of `members` (which may
+    /// include `id` itself), resuming from the term, vote and log persisted
+    /// in `storage`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the persisted state cannot be read.
+    pub fn new(
+        id: PublicKey,
+        members: Vec<PublicKey>,
+        config: RaftConfig,
+        transport: T,
+        storage: S,
+    ) -> Result<Self, ConsensusError> {
+        let commit_timeout = config.commit_timeout;
+        let mut node = RaftNode::new(id, members, config);
+        if let Some(hard_state) = storage.get_raft_hard_state()? {
+            node.restore(hard_state, storage.get_raft_entries()?);
+        }
+        Ok(Self {
+            shared: Arc::new(RaftShared {
+                node: Mutex::new(node),
+                committed: Condvar::new(),
+                transport,
+                storage,
+            }),
+            commit_timeout,
+        })
+    }
+
+    /// Handler to register with the transport so inbound Raft messages reach
+    /// this node.
+    pub fn handler(&self) -> Arc<dyn MessageHandler> {
+        Arc::clone(&self.shared) as Arc<dyn MessageHandler>
+    }
+
+    /// Call [`RaftConsensus::tick`] every `interval` on a background task.
+    ///
+    /// Returns `None` when called outside a tokio runtime.
+    pub fn spawn_ticker(&self, interval: Duration) -> Option<tokio::task::JoinHandle<()>> {
+        let handle = tokio::runtime::Handle::try_current().ok()?;
+        let shared = Arc::clone(&self.shared);
+        Some(handle.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = shared.with_node(RaftNode::tick) {
+                    eprintln!("Raft tick failed: {}", e);
+                }
+            }
+        }))
+    }
+}
+
+impl<T: Transport, S: RaftStorage> RaftConsensus<T, S> {
+    /// Advance logical time by one tick.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a new term or vote cannot be persisted.
+    pub fn tick(&self) -> Result<(), ConsensusError> {
+        self.shared.with_node(RaftNode::tick)
+    }
+
+    /// Start an election immediately instead of waiting for a timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the new term and vote cannot be persisted.
+    pub fn campaign(&self) -> Result<(), ConsensusError> {
+        self.shared.with_node(RaftNode::start_election)
+    }
+
+    pub fn role(&self) -> RaftRole {
+        self.shared.lock().role
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role() == RaftRole::Leader
+    }
+
+    pub fn leader(&self) -> Option<PublicKey> {
+        self.shared.lock().leader
+    }
+
+    pub fn current_term(&self) -> u64 {
+        self.shared.lock().current_term
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.shared.lock().commit_index
+    }
+
+    /// Committed blocks not yet handed to the local runtime, in log order.
+    ///
+    /// Followers pass these to `Runtime::import_block`. Blocks the leader
+    /// applied itself through `agree_on_block` are not returned again.
+    pub fn take_committed_blocks(&self) -> Vec<Block> {
+        let mut node = self.shared.lock();
+        let from = node.last_applied as usize;
+        let to = node.commit_index as usize;
+        node.last_applied = node.commit_index;
+        node.log[from..to]
+            .iter()
+            .filter_map(|e| e.block.clone())
+            .collect()
+    }
+}
+
+impl<T: Transport, S: RaftStorage> ConsensusEngine for RaftConsensus<T, S> {
+    fn validate_block(
+        &self,
+        block: &Block,
+        chain_state: &ChainState,
+    ) -> Result<(), ConsensusError> {
+        if block.index != chain_state.latest_block_index + 1 {
+            return Err(ConsensusError::ValidationFailed(format!(
+                "Invalid block index: expected {}, got {}",
+                chain_state.latest_block_index + 1,
+                block.index
+            )));
+        }
+        if block.prev_hash != chain_state.latest_block_hash {
+            return Err(ConsensusError::MismatchedPrevHash);
+        }
+        Ok(())
+    }
+
+    fn generate_block(
+        &self,
+        pending_transactions: &[Transaction],
+        prev_block: &Block,
+        _chain_state: &ChainState,
+    ) -> Result<Block, ConsensusError> {
+        if !self.is_leader() {
+            return Err(ConsensusError::NotLeader);
+        }
+        if pending_transactions.is_empty() {
+            return Err(ConsensusError::NoPendingTransactions);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut block = Block {
+            index: prev_block.index + 1,
+            timestamp: now.max(prev_block.timestamp + 1),
+            prev_hash: prev_block.hash,
+            hash: [0u8; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: pending_transactions.to_vec(),
+            metadata: None,
+            seal: None,
+        };
+        block.transactions_root = block.compute_transactions_root();
+        block.hash = block
+            .calculate_hash()
+            .map_err(|e| ConsensusError::ValidationFailed(format!("Hash error: {:?}", e)))?;
+        Ok(block)
+    }
+
+    /// Committed Raft entries are never rolled back, so there is no fork to choose.
+    fn prefers(&self, _candidate: &Block, _current: &Block) -> bool {
+        false
+    }
+
+    fn agree_on_block(&self, block: &Block) -> Result<(), ConsensusError> {
+        let (index, term) = self.shared.with_node(|node| {
+            node.propose(block.clone())
+                .map(|index| (index, node.current_term))
+        })??;
+
+        let node = self.shared.lock();
+        let (mut node, _) = self
+            .shared
+            .committed
+            .wait_timeout_while(node, self.commit_timeout, |n| {
+                n.commit_index < index && n.current_term == term && n.role == RaftRole::Leader
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        if node.commit_index < index {
+            if node.current_term != term || node.role != RaftRole::Leader {
+                return Err(ConsensusError::NotLeader);
+            }
+            // The entry may still commit under a later leader, in which case
+            // take_committed_blocks hands it out like any other. Give up the
+            // term so this node does not keep building on an entry its
+            // runtime never applied.
+            node.become_follower(term, None);
+            node.reset_election_timer();
+            return Err(ConsensusError::QuorumTimeout);
+        }
+        // Skip over no-ops, but leave blocks before this one to be taken.
+        if node.last_applied < index
+            && node.log[node.last_applied as usize..index as usize - 1]
+                .iter()
+                .all(|e| e.block.is_none())
+        {
+            node.last_applied = index;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::sync::{CustomSync, InProcessNetwork, InProcessTransport, Peer};
+    use crate::types::{Address, TransactionPayload, TransactionSignature};
+    use ed25519_dalek::SigningKey;
+
+    type TestNode = RaftConsensus<InProcessTransport, MemoryStorage>;
+
+    fn node_id(seed: u8) -> PublicKey {
+        PublicKey::from(SigningKey::from_bytes(&[seed; 32]).verifying_key())
+    }
+
+    fn config() -> RaftConfig {
+        RaftConfig {
+            commit_timeout: Duration::from_millis(50),
+            ..RaftConfig::default()
+        }
+    }
+
+    fn cluster(size: u8) -> (InProcessNetwork, Vec<MemoryStorage>, Vec<TestNode>) {
+        let network = InProcessNetwork::new();
+        let members: Vec<PublicKey> = (1..=size).map(node_id).collect();
+        let storages: Vec<MemoryStorage> = members.iter().map(|_| MemoryStorage::new()).collect();
+        let nodes = members
+            .iter()
+            .zip(&storages)
+            .map(|(id, storage)| {
+                let node = RaftConsensus::new(
+                    *id,
+                    members.clone(),
+                    config(),
+                    network.transport(*id),
+                    storage.clone(),
+                )
+                .unwrap();
+                network.register(*id, node.handler());
+                node
+            })
+            .collect();
+        (network, storages, nodes)
+    }
+
+    fn tick_until_leader(nodes: &[TestNode], skip: Option<usize>) -> usize {
+        for _ in 0..1_000 {
+            for (i, node) in nodes.iter().enumerate() {
+                if Some(i) != skip {
+                    node.tick().unwrap();
+                }
+            }
+            let leaders: Vec<usize> = (0..nodes.len())
+                .filter(|i| Some(*i) != skip && nodes[*i].is_leader())
+                .collect();
+            if leaders.len() == 1 {
+                return leaders[0];
+            }
+        }
+        panic!("no leader elected");
+    }
+
+    fn next_block(prev: &Block) -> Block {
+        let signing_key = SigningKey::from_bytes(&[42u8; 32]);
+        let sender = PublicKey::from(signing_key.verifying_key());
+        let mut tx = Transaction {
+            hash: [0; 32],
+            sender,
+            nonce: prev.index + 1,
+            timestamp: 0,
+            recipient: Address::Wallet(sender),
+            payload: TransactionPayload::Data { data: vec![1] },
+            signature: TransactionSignature::from_bytes(&[0; 64]).unwrap(),
+            gas_limit: 0,
+            priority: 0,
+            metadata: None,
+        };
+        tx.sign(&signing_key).unwrap();
+        let mut block = Block {
+            index: prev.index + 1,
+            timestamp: prev.timestamp + 1,
+            prev_hash: prev.hash,
+            hash: [0; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: vec![tx],
+            metadata: None,
+            seal: None,
+        };
+        block.hash = block.calculate_hash().unwrap();
+        block
+    }
+
+    fn genesis() -> Block {
+        let mut block = Block {
+            index: 0,
+            timestamp: 0,
+            prev_hash: [0; 32],
+            hash: [0; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: Vec::new(),
+            metadata: None,
+            seal: None,
+        };
+        block.hash = block.calculate_hash().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_replicates_to_quorum_and_survives_leader_crash() {
+        let (network, _storages, nodes) = cluster(3);
+        let leader = tick_until_leader(&nodes, None);
+
+        let block1 = next_block(&genesis());
+        nodes[leader].agree_on_block(&block1).unwrap();
+        // The leader applied block1 itself; it must not be handed out again.
+        assert!(nodes[leader].take_committed_blocks().is_empty());
+
+        // Followers learn the commit index with the next heartbeat.
+        for _ in 0..RaftConfig::default().heartbeat_interval_ticks {
+            nodes[leader].tick().unwrap();
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            if i != leader {
+                assert_eq!(node.take_committed_blocks(), vec![block1.clone()]);
+            }
+        }
+
+        // Crash the leader; the two survivors elect a new one and keep committing.
+        network.disconnect(&node_id(leader as u8 + 1));
+        let new_leader = tick_until_leader(&nodes, Some(leader));
+        assert_ne!(new_leader, leader);
+        let block2 = next_block(&block1);
+        nodes[new_leader].agree_on_block(&block2).unwrap();
+        // Each leader's no-op precedes its block.
+        assert_eq!(nodes[new_leader].commit_index(), 4);
+    }
+
+    #[test]
+    fn test_no_commit_without_quorum() {
+        let (network, _storages, nodes) = cluster(3);
+        let leader = tick_until_leader(&nodes, None);
+        for i in 0..nodes.len() {
+            if i != leader {
+                network.disconnect(&node_id(i as u8 + 1));
+            }
+        }
+        let block = next_block(&genesis());
+        assert!(matches!(
+            nodes[leader].agree_on_block(&block),
+            Err(ConsensusError::QuorumTimeout)
+        ));
+        // Only the leader's no-op, committed before the partition.
+        assert_eq!(nodes[leader].commit_index(), 1);
+        // The leader gives up its term rather than build on the entry.
+        assert!(!nodes[leader].is_leader());
+    }
+
+    #[test]
+    fn test_new_leader_with_shorter_log_repairs_followers() {
+        let (network, _storages, nodes) = cluster(5);
+        let old = tick_until_leader(&nodes, None);
+        let ahead = (old + 1) % nodes.len();
+        let others: Vec<usize> = (0..nodes.len())
+            .filter(|i| *i != old && *i != ahead)
+            .collect();
+
+        // Only `ahead` stores two blocks the old leader cannot commit.
+        for &i in &others {
+            network.disconnect(&node_id(i as u8 + 1));
+        }
+        let block1 = next_block(&genesis());
+        let block2 = next_block(&block1);
+        for block in [&block1, &block2] {
+            nodes[old]
+                .shared
+                .with_node(|node| node.propose(block.clone()))
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(nodes[ahead].shared.lock().log.len(), 3);
+        assert_eq!(nodes[old].commit_index(), 1);
+
+        // The others elect one of them, whose log is shorter.
+        network.disconnect(&node_id(old as u8 + 1));
+        for &i in &others {
+            network.reconnect(&node_id(i as u8 + 1));
+        }
+        let new = others[0];
+        nodes[new].campaign().unwrap();
+        assert!(nodes[new].is_leader());
+
+        let block = next_block(&genesis());
+        nodes[new].agree_on_block(&block).unwrap();
+        for _ in 0..RaftConfig::default().heartbeat_interval_ticks {
+            nodes[new].tick().unwrap();
+        }
+        let leader_log = nodes[new].shared.lock().log.clone();
+        assert_eq!(leader_log.len(), 3);
+        assert_eq!(nodes[ahead].shared.lock().log, leader_log);
+        assert_eq!(nodes[ahead].take_committed_blocks(), vec![block.clone()]);
+        let leader = nodes[new].shared.lock();
+        assert!(leader.match_index.values().all(|&index| index <= 3));
+        drop(leader);
+
+        // A follower only vouches for the entries it was sent, not for a
+        // longer log it may still hold.
+        let mut follower = nodes[ahead].shared.lock();
+        follower.log.push(RaftEntry {
+            term: 1,
+            block: Some(block2),
+        });
+        let term = follower.current_term;
+        let prev_log_term = follower.term_at(1);
+        follower.step(
+            node_id(new as u8 + 1),
+            RaftMessage::AppendEntries {
+                term,
+                prev_log_index: 1,
+                prev_log_term,
+                entries: Vec::new(),
+                leader_commit: 0,
+            },
+        );
+        assert!(matches!(
+            follower.outbox.pop(),
+            Some((
+                _,
+                RaftMessage::AppendResponse {
+                    success: true,
+                    match_index: 1,
+                    ..
+                }
+            ))
+        ));
+        assert_eq!(follower.log.len(), 4);
+    }
+
+    #[test]
+    fn test_term_vote_and_log_survive_restart() {
+        let (network, storages, nodes) = cluster(3);
+        let leader = tick_until_leader(&nodes, None);
+        let block1 = next_block(&genesis());
+        nodes[leader].agree_on_block(&block1).unwrap();
+        // Only a majority has to hold the block, so restart one that does.
+        let follower = (0..nodes.len())
+            .find(|&i| i != leader && nodes[i].shared.lock().log.len() > 1)
+            .unwrap();
+        let term = nodes[follower].current_term();
+        let voted_for = nodes[follower].shared.lock().voted_for;
+
+        let id = node_id(follower as u8 + 1);
+        let members: Vec<PublicKey> = (1..=3).map(node_id).collect();
+        let restarted = RaftConsensus::new(
+            id,
+            members,
+            config(),
+            network.transport(id),
+            storages[follower].clone(),
+        )
+        .unwrap();
+        assert_eq!(restarted.current_term(), term);
+        assert_eq!(restarted.shared.lock().voted_for, voted_for);
+        assert_eq!(restarted.shared.lock().log[1].block, Some(block1));
+    }
+
+    #[test]
+    fn test_followers_cannot_generate_blocks() {
+        let (_network, _storages, nodes) = cluster(3);
+        let leader = tick_until_leader(&nodes, None);
+        let follower = (leader + 1) % nodes.len();
+        let genesis = genesis();
+        let state = ChainState {
+            latest_block_hash: genesis.hash,
+            latest_block_index: 0,
+            accounts_root_hash: [0; 32],
+            total_supply: 0,
+            finalized_height: 0,
+            jailed_authorities: Vec::new(),
+        };
+        let txs = next_block(&genesis).transactions;
+        assert!(matches!(
+            nodes[follower].generate_block(&txs, &genesis, &state),
+            Err(ConsensusError::NotLeader)
+        ));
+        assert!(nodes[leader].generate_block(&txs, &genesis, &state).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_cluster_commits_over_tcp() {
+        let members: Vec<PublicKey> = (1..=3).map(node_id).collect();
+        let mut syncs = Vec::new();
+        let mut peers = Vec::new();
+        for id in &members {
+            let sync = Arc::new(CustomSync::new(*id, "127.0.0.1:0".parse().unwrap()));
+            let address = sync.spawn_server().await.unwrap();
+            peers.push(Peer { id: *id, address });
+            syncs.push(sync);
+        }
+        let mut nodes = Vec::new();
+        for (id, sync) in members.iter().zip(&syncs) {
+            for peer in &peers {
+                if peer.id != *id {
+                    sync.add_peer(peer.clone()).await;
+                }
+            }
+            let node = RaftConsensus::new(
+                *id,
+                members.clone(),
+                RaftConfig {
+                    commit_timeout: Duration::from_secs(5),
+                    ..RaftConfig::default()
+                },
+                Arc::clone(sync),
+                MemoryStorage::new(),
+            )
+            .unwrap();
+            sync.set_message_handler(node.handler());
+            node.spawn_ticker(Duration::from_millis(10)).unwrap();
+            nodes.push(node);
+        }
+
+        let leader = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let leaders: Vec<usize> =
+                    (0..nodes.len()).filter(|i| nodes[*i].is_leader()).collect();
+                if leaders.len() == 1 {
+                    return leaders[0];
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no leader elected");
+
+        let block1 = next_block(&genesis());
+        tokio::task::block_in_place(|| nodes[leader].agree_on_block(&block1)).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while nodes.iter().any(|node| node.commit_index() < 2) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("followers did not learn the commit");
+        for (i, node) in nodes.iter().enumerate() {
+            if i != leader {
+                assert_eq!(node.take_committed_blocks(), vec![block1.clone()]);
+            }
+        }
+    }
+}
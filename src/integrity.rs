@@ -0,0 +1,572 @@
+//! Database integrity checks and repair.
+//!
+//! [`verify`] walks the canonical chain from genesis (or from the snapshot
+//! the node was bootstrapped from) and cross-checks everything that can be
+//! recomputed from what is stored: block hashes and parent links, the
+//! transaction and receipt roots, transaction signatures, the transaction
+//! records and `tx_by_block` index entries of every block body, the state
+//! tree under every retained state root, and the flat state at the tip.
+//! On an unpruned database every block is also re-executed from the recorded
+//! genesis state, which rechecks each block's state and receipts roots.
+//! Data below the [`crate::storage::PruneHorizon`] is only checked as far as
+//! it is retained.
+//!
+//! Block bodies and the state tree are the source of truth. Issues whose fix
+//! can be derived from them are [`Issue::is_repairable`] and are fixed by
+//! [`repair`]: records and index entries are copied from the bodies,
+//! receipts are regenerated by re-execution, and the flat state is rebuilt
+//! from the tree leaves. The others need the chain to be resynced or
+//! restored from a snapshot.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+use crate::contracts::BaaLSContractEngine;
+use crate::ledger::{Ledger, LedgerError};
+use crate::merkle::{
+    account_key, contract_code_key, contract_storage_key, state_tree_from_entries,
+    SparseMerkleTree, TrieNode, EMPTY_ROOT,
+};
+use crate::quota;
+use crate::storage::{
+    MemoryStorage, PruneHorizon, StateEntry, Storage, StorageBatch, StorageError, StorageOperation,
+};
+use crate::types::{Address, BlockHeader, ChainState, ContractId, PublicKey, TransactionPayload};
+
+/// An inconsistency found by [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// The chain state is missing altogether.
+    MissingChainState,
+    /// No canonical block is stored at this height.
+    MissingBlock(u64),
+    /// The stored hash does not match the block's contents.
+    BlockHashMismatch(u64),
+    /// The block does not extend the block below it.
+    BrokenLink(u64),
+    TransactionsRootMismatch(u64),
+    /// The transaction's hash or signature does not check out.
+    InvalidSignature {
+        height: u64,
+        tx_hash: [u8; 32],
+    },
+    /// The transaction record is absent or differs from the block body.
+    MissingTransaction {
+        height: u64,
+        tx_hash: [u8; 32],
+        tx_index_in_block: u32,
+    },
+    /// The block's `tx_by_block` entry for the transaction is absent.
+    MissingIndexEntry {
+        height: u64,
+        tx_hash: [u8; 32],
+        tx_index_in_block: u32,
+    },
+    /// `tx_by_block` lists a transaction the block does not contain.
+    StaleIndexEntry {
+        height: u64,
+        tx_hash: [u8; 32],
+    },
+    MissingReceipt {
+        height: u64,
+        tx_hash: [u8; 32],
+        tx_index_in_block: u32,
+    },
+    ReceiptsRootMismatch(u64),
+    /// A state tree node under the state root at `height` is absent.
+    MissingTrieNode {
+        height: u64,
+        hash: [u8; 32],
+    },
+    /// A state tree node is stored under the wrong hash.
+    CorruptTrieNode {
+        height: u64,
+        hash: [u8; 32],
+    },
+    /// The chain state does not point at the canonical tip.
+    ChainStateMismatch,
+    /// The flat state at the tip does not hash to the tip's state root.
+    StateRootMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// Re-executing the block on top of its parent state does not reproduce
+    /// its state or receipts root (height 0: the recorded genesis state does
+    /// not match the genesis block). Later blocks are not re-executed.
+    ExecutionMismatch(u64),
+}
+
+impl Issue {
+    /// Whether [`repair`] can fix this issue.
+    ///
+    /// Missing receipts are only regenerated on an unpruned database, and
+    /// the flat state only as far as the tree leaves can be matched to the
+    /// accounts, contracts and slots named in the retained history.
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            self,
+            Issue::MissingTransaction { .. }
+                | Issue::MissingIndexEntry { .. }
+                | Issue::MissingReceipt { .. }
+                | Issue::StateRootMismatch { .. }
+        )
+    }
+}
+
+/// The outcome of [`verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Number of canonical blocks inspected.
+    pub blocks_checked: u64,
+    pub issues: Vec<Issue>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Check the whole database, which must not change while this runs.
+///
+/// Only storage failures are returned as errors; inconsistencies are
+/// collected in the report.
+pub fn verify<S: Storage>(storage: &S) -> Result<IntegrityReport, StorageError> {
+    let mut report = IntegrityReport::default();
+    let Some(chain_state) = storage.get_chain_state()? else {
+        report.issues.push(Issue::MissingChainState);
+        return Ok(report);
+    };
+    let horizon = storage.get_prune_horizon()?;
+    let tip = chain_state.latest_block_index;
+
+    let mut parent: Option<BlockHeader> = None;
+    let mut checked_nodes = HashSet::new();
+    for height in 0..=tip {
+        let Some(header) = storage.get_block_header_by_height(height)? else {
+            // A node bootstrapped from a snapshot has no blocks below it.
+            if parent.is_some() || height >= horizon.bodies {
+                report.issues.push(Issue::MissingBlock(height));
+            }
+            parent = None;
+            continue;
+        };
+        report.blocks_checked += 1;
+        let hash = header.hash()?;
+
+        if let Some(parent) = &parent {
+            if !header.extends(parent) {
+                report.issues.push(Issue::BrokenLink(height));
+            }
+        }
+        if height >= horizon.bodies {
+            check_body(storage, height, hash, &mut report.issues)?;
+        }
+        if height >= horizon.state {
+            check_state_tree(
+                storage,
+                height,
+                header.state_root,
+                &mut checked_nodes,
+                &mut report.issues,
+            )?;
+        }
+        if height == tip
+            && (hash != chain_state.latest_block_hash
+                || header.state_root != chain_state.accounts_root_hash)
+        {
+            report.issues.push(Issue::ChainStateMismatch);
+        }
+        parent = Some(header);
+    }
+
+    let (actual, _) =
+        state_tree_from_entries(storage, &storage.get_state_entries()?, &chain_state)?;
+    if actual != chain_state.accounts_root_hash {
+        report.issues.push(Issue::StateRootMismatch {
+            expected: chain_state.accounts_root_hash,
+            actual,
+        });
+    }
+
+    if let Some(replay) = replay(storage, tip)? {
+        if let Some(height) = replay.diverged_at {
+            report.issues.push(Issue::ExecutionMismatch(height));
+        }
+    }
+    Ok(report)
+}
+
+/// The canonical chain re-executed into scratch storage.
+struct Replay {
+    storage: MemoryStorage,
+    /// First block whose execution did not reproduce its header.
+    diverged_at: Option<u64>,
+}
+
+/// Re-execute the canonical blocks up to `target` from the recorded genesis
+/// state. `None` if the history needed for that is not retained.
+fn replay<S: Storage>(storage: &S, target: u64) -> Result<Option<Replay>, StorageError> {
+    if storage.get_prune_horizon()? != PruneHorizon::default() {
+        return Ok(None);
+    }
+    let Some(genesis) = storage.get_genesis_state()? else {
+        return Ok(None);
+    };
+    let Some(genesis_header) = storage.get_block_header_by_height(0)? else {
+        return Ok(None);
+    };
+
+    let scratch = MemoryStorage::new();
+    let mut batch = StorageBatch::default();
+    batch
+        .ops
+        .extend(genesis.into_iter().map(StorageOperation::from));
+    scratch.apply_batch(batch)?;
+    let ledger = Ledger::new(
+        Arc::new(scratch.clone()),
+        Arc::new(BaaLSContractEngine::new(scratch.clone())),
+    );
+    let mut replay = Replay {
+        storage: scratch.clone(),
+        diverged_at: None,
+    };
+    let initialized = ledger.initialize_chain().map_err(|e| match e {
+        LedgerError::StorageError(e) => Some(e),
+        _ => None,
+    });
+    let mut chain_state = match initialized {
+        Ok(()) => scratch.get_chain_state()?.ok_or(StorageError::NotFound)?,
+        Err(Some(e)) => return Err(e),
+        Err(None) => {
+            replay.diverged_at = Some(0);
+            return Ok(Some(replay));
+        }
+    };
+    if chain_state.accounts_root_hash != genesis_header.state_root {
+        replay.diverged_at = Some(0);
+        return Ok(Some(replay));
+    }
+
+    for height in 1..=target {
+        // Missing blocks are reported by the chain walk.
+        let Some(block) = storage.get_block_by_height(height)? else {
+            break;
+        };
+        match ledger.apply_block(block, &mut chain_state) {
+            Ok(()) => {}
+            Err(LedgerError::StorageError(e)) => return Err(e),
+            Err(_) => {
+                replay.diverged_at = Some(height);
+                break;
+            }
+        }
+    }
+    Ok(Some(replay))
+}
+
+/// Check the body of the canonical block at `height`, whose header hashes
+/// to `header_hash`.
+fn check_body<S: Storage>(
+    storage: &S,
+    height: u64,
+    header_hash: [u8; 32],
+    issues: &mut Vec<Issue>,
+) -> Result<(), StorageError> {
+    let Some(block) = storage.get_block_by_height(height)? else {
+        issues.push(Issue::MissingBlock(height));
+        return Ok(());
+    };
+    if block.hash != header_hash {
+        issues.push(Issue::BlockHashMismatch(height));
+    }
+    if block.compute_transactions_root() != block.transactions_root {
+        issues.push(Issue::TransactionsRootMismatch(height));
+    }
+
+    let mut indexed: HashMap<[u8; 32], usize> = HashMap::new();
+    for tx in storage.get_transactions_by_block(&block.hash)? {
+        *indexed.entry(tx.hash).or_default() += 1;
+    }
+    let mut receipt_hashes = Vec::with_capacity(block.transactions.len());
+    for (index, tx) in block.transactions.iter().enumerate() {
+        let tx_index_in_block = index as u32;
+        if !tx.verify_signature()? {
+            issues.push(Issue::InvalidSignature {
+                height,
+                tx_hash: tx.hash,
+            });
+        }
+        if storage.get_transaction(&tx.hash)?.as_ref() != Some(tx) {
+            issues.push(Issue::MissingTransaction {
+                height,
+                tx_hash: tx.hash,
+                tx_index_in_block,
+            });
+        }
+        // The index only yields transactions whose record exists, so a
+        // missing record hides its index entry too.
+        match indexed.get_mut(&tx.hash) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => issues.push(Issue::MissingIndexEntry {
+                height,
+                tx_hash: tx.hash,
+                tx_index_in_block,
+            }),
+        }
+        match storage.get_receipt(&block.hash, tx_index_in_block)? {
+            Some(receipt) => receipt_hashes.push(receipt.hash()?),
+            None => issues.push(Issue::MissingReceipt {
+                height,
+                tx_hash: tx.hash,
+                tx_index_in_block,
+            }),
+        }
+    }
+    for (tx_hash, count) in indexed {
+        if count > 0 {
+            issues.push(Issue::StaleIndexEntry { height, tx_hash });
+        }
+    }
+    if receipt_hashes.len() == block.transactions.len()
+        && crate::merkle::merkle_root(&receipt_hashes) != block.receipts_root
+    {
+        issues.push(Issue::ReceiptsRootMismatch(height));
+    }
+    Ok(())
+}
+
+/// Check that every node under `root` is stored under its own hash.
+/// Subtrees already in `checked` are skipped, since consecutive roots share
+/// most of their nodes.
+fn check_state_tree<S: Storage>(
+    storage: &S,
+    height: u64,
+    root: [u8; 32],
+    checked: &mut HashSet<[u8; 32]>,
+    issues: &mut Vec<Issue>,
+) -> Result<(), StorageError> {
+    let mut pending = vec![root];
+    while let Some(hash) = pending.pop() {
+        if hash == EMPTY_ROOT || !checked.insert(hash) {
+            continue;
+        }
+        match storage.get_trie_node(&hash)? {
+            None => issues.push(Issue::MissingTrieNode { height, hash }),
+            Some(node) if node.hash() != hash => {
+                issues.push(Issue::CorruptTrieNode { height, hash })
+            }
+            Some(TrieNode::Internal { left, right }) => {
+                pending.push(left);
+                pending.push(right);
+            }
+            Some(TrieNode::Leaf { .. }) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Fix the repairable issues in `report`, in one atomic batch. Returns the
+/// number of issues fixed.
+pub fn repair<S: Storage>(storage: &S, report: &IntegrityReport) -> Result<usize, StorageError> {
+    let receipts_height = report
+        .issues
+        .iter()
+        .filter_map(|issue| match issue {
+            Issue::MissingReceipt { height, .. } => Some(*height),
+            _ => None,
+        })
+        .max();
+    let replay = match receipts_height {
+        Some(height) => replay(storage, height)?,
+        None => None,
+    };
+
+    let mut batch = StorageBatch::default();
+    let mut repaired = 0;
+    for issue in &report.issues {
+        match *issue {
+            Issue::MissingTransaction {
+                height,
+                tx_index_in_block,
+                ..
+            } => {
+                let block = storage
+                    .get_block_by_height(height)?
+                    .ok_or(StorageError::NotFound)?;
+                let tx = block
+                    .transactions
+                    .get(tx_index_in_block as usize)
+                    .ok_or(StorageError::NotFound)?;
+                batch
+                    .ops
+                    .push(StorageOperation::PutTransaction(Box::new(tx.clone())));
+            }
+            Issue::MissingIndexEntry {
+                height,
+                tx_hash,
+                tx_index_in_block,
+            } => {
+                let header = storage
+                    .get_block_header_by_height(height)?
+                    .ok_or(StorageError::NotFound)?;
+                batch.ops.push(StorageOperation::IndexTransaction {
+                    tx_hash,
+                    block_hash: header.hash()?,
+                    tx_index_in_block,
+                });
+            }
+            Issue::MissingReceipt {
+                height,
+                tx_index_in_block,
+                ..
+            } => {
+                let Some(replay) = &replay else { continue };
+                if replay
+                    .diverged_at
+                    .is_some_and(|diverged| diverged <= height)
+                {
+                    continue;
+                }
+                // Up to `height` the replay applied the very same blocks.
+                let block_hash = storage
+                    .get_block_header_by_height(height)?
+                    .ok_or(StorageError::NotFound)?
+                    .hash()?;
+                let Some(receipt) = replay.storage.get_receipt(&block_hash, tx_index_in_block)?
+                else {
+                    continue;
+                };
+                batch.ops.push(StorageOperation::PutReceipt {
+                    block_hash,
+                    tx_index_in_block,
+                    receipt: Box::new(receipt),
+                });
+            }
+            Issue::StateRootMismatch { .. } => {
+                let chain_state = storage.get_chain_state()?.ok_or(StorageError::NotFound)?;
+                let Some(ops) = rebuild_flat_state(storage, &chain_state)? else {
+                    continue;
+                };
+                batch.ops.extend(ops);
+            }
+            _ => continue,
+        }
+        repaired += 1;
+    }
+    storage.apply_batch(batch)?;
+    Ok(repaired)
+}
+
+/// Writes replacing the flat state with the leaves of the state tree at the
+/// tip, or `None` if some leaf cannot be matched to an account, contract or
+/// slot.
+///
+/// Leaves are keyed by hashes, so candidates are taken from the flat state,
+/// the genesis state, the undo records and the deployed code of the
+/// retained blocks, and looked up in the tree.
+fn rebuild_flat_state<S: Storage>(
+    storage: &S,
+    chain_state: &ChainState,
+) -> Result<Option<Vec<StorageOperation>>, StorageError> {
+    let mut accounts: BTreeSet<PublicKey> = BTreeSet::new();
+    let mut contracts: BTreeSet<ContractId> = BTreeSet::new();
+    let mut slots: BTreeSet<(ContractId, Vec<u8>)> = BTreeSet::new();
+    // The tree only commits to the hash of contract code.
+    let mut code_by_hash: HashMap<[u8; 32], Vec<u8>> = HashMap::new();
+    let mut note = |entry: StateEntry| match entry {
+        StateEntry::Account(address, _) => {
+            accounts.insert(address);
+        }
+        StateEntry::ContractCode(contract_id, code) => {
+            code_by_hash.insert(Sha256::digest(&code).into(), code);
+            contracts.insert(contract_id);
+        }
+        StateEntry::ContractStorage(contract_id, key, _) => {
+            slots.insert((contract_id, key));
+        }
+    };
+    storage.get_state_entries()?.into_iter().for_each(&mut note);
+    storage
+        .get_genesis_state()?
+        .unwrap_or_default()
+        .into_iter()
+        .for_each(&mut note);
+    for height in 1..=chain_state.latest_block_index {
+        if let Some(block) = storage.get_block_by_height(height)? {
+            for tx in block.transactions {
+                if let TransactionPayload::ContractDeploy { wasm_bytes } = tx.payload {
+                    code_by_hash.insert(Sha256::digest(&wasm_bytes).into(), wasm_bytes);
+                }
+            }
+        }
+        let Some(header) = storage.get_block_header_by_height(height)? else {
+            continue;
+        };
+        let Some(undo) = storage.get_block_undo(&header.hash()?)? else {
+            continue;
+        };
+        accounts.extend(undo.accounts.into_iter().map(|(address, _)| address));
+        for (contract_id, code) in undo.contract_code {
+            if let Some(code) = code {
+                code_by_hash.insert(Sha256::digest(&code).into(), code);
+            }
+            contracts.insert(contract_id);
+        }
+        slots.extend(
+            undo.contract_storage
+                .into_iter()
+                .map(|(contract_id, key, _)| (contract_id, key)),
+        );
+    }
+
+    let tree = SparseMerkleTree::new(storage, chain_state.accounts_root_hash);
+    let mut entries = Vec::new();
+    let mut ops = Vec::new();
+    for address in accounts {
+        match tree.get(&account_key(&address))? {
+            Some(value) => {
+                entries.push(StateEntry::Account(address, bincode::deserialize(&value)?))
+            }
+            None => ops.push(StorageOperation::DeleteAccount(address)),
+        }
+    }
+    let mut owners: BTreeSet<ContractId> = slots.iter().map(|(id, _)| id.clone()).collect();
+    owners.extend(contracts.iter().cloned());
+    for contract_id in contracts {
+        match tree.get(&contract_code_key(&contract_id))? {
+            Some(hash) => {
+                let code = <[u8; 32]>::try_from(hash.as_slice())
+                    .ok()
+                    .and_then(|hash| code_by_hash.get(&hash));
+                let Some(code) = code else {
+                    return Ok(None);
+                };
+                entries.push(StateEntry::ContractCode(contract_id, code.clone()));
+            }
+            None => ops.push(StorageOperation::DeleteContractCode(contract_id)),
+        }
+    }
+    for (contract_id, key) in slots {
+        match tree.get(&contract_storage_key(&contract_id, &key))? {
+            Some(value) => entries.push(StateEntry::ContractStorage(contract_id, key, value)),
+            None => ops.push(StorageOperation::ContractStorageRemove(contract_id, key)),
+        }
+    }
+
+    // Leaves no candidate matched would change the root.
+    let (root, _) = state_tree_from_entries(storage, &entries, chain_state)?;
+    if root != chain_state.accounts_root_hash {
+        return Ok(None);
+    }
+    let usage = quota::state_usage(&entries);
+    for contract_id in owners {
+        let address = Address::Contract(contract_id);
+        let bytes = usage.get(&address).copied().unwrap_or(0);
+        ops.push(StorageOperation::PutStorageUsage(address, bytes));
+    }
+    ops.extend(entries.into_iter().map(StorageOperation::from));
+    Ok(Some(ops))
+}
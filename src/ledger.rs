@@ -4,13 +4,26 @@
 //! and maintaining the chain state. It ensures that all state transitions are
 //! valid and deterministic.
 
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
 use crate::contracts::ContractEngine;
-use crate::storage::{Storage, StorageBatch, StorageError, StorageOperation};
-use crate::types::{Account, Block, ChainState, CryptoError, PublicKey, TransactionPayload};
+use crate::merkle::{
+    account_key, chain_params_key, chain_params_leaf, contract_code_key, contract_storage_key,
+    SparseMerkleTree, TrieNode, EMPTY_ROOT,
+};
+use crate::quota::{self, StorageQuotas};
+use crate::storage::{
+    Order, PruneHorizon, StateEntry, Storage, StorageBatch, StorageError, StorageOperation,
+    TagQuery, TaggedTransaction, TxPosition,
+};
+use crate::types::{
+    Account, Address, Block, BlockHeader, BlockUndo, ChainState, CommitCertificate, ContractId,
+    CryptoError, PublicKey, Transaction, TransactionPayload, TransactionReceipt,
+};
 
 #[derive(Debug, Error)]
 pub enum LedgerError {
@@ -40,6 +53,71 @@ pub enum LedgerError {
     ContractError(#[from] crate::contracts::ContractError),
     #[error("Not found")]
     NotFound,
+    #[error("Invalid commit certificate: {0}")]
+    InvalidCertificate(String),
+    #[error("Cannot revert to height {0}: chain is finalized up to height {1}")]
+    BelowFinalized(u64, u64),
+    #[error("Invalid equivocation evidence: {0}")]
+    InvalidEvidence(String),
+    #[error("State root mismatch: block commits {0:x?}, execution produced {1:x?}")]
+    StateRootMismatch([u8; 32], [u8; 32]),
+}
+
+/// Blocks swapped out and in by [`Ledger::reorganize`].
+#[derive(Debug, Clone, Default)]
+pub struct Reorganization {
+    /// Formerly canonical blocks, tip first.
+    pub reverted: Vec<Block>,
+    /// Newly canonical blocks, in application order.
+    pub applied: Vec<Block>,
+}
+
+/// Writes and resulting chain state of an executed, not yet committed block.
+pub struct BlockExecution {
+    batch: StorageBatch,
+    chain_state: ChainState,
+    roots: ExecutionRoots,
+    /// Root over the executed transactions; the committed block must carry
+    /// exactly these.
+    transactions_root: [u8; 32],
+    /// Written under the final block hash on commit.
+    receipts: Vec<TransactionReceipt>,
+    undo: BlockUndo,
+    /// Storage usage of every owner the block changes, before and after.
+    usage: BTreeMap<Address, (u64, u64)>,
+    /// Positions of the transactions that wrote to each owner's storage.
+    writers: BTreeMap<Address, Vec<usize>>,
+}
+
+impl BlockExecution {
+    /// The header commitments the block must carry to be committed.
+    pub fn roots(&self) -> ExecutionRoots {
+        self.roots
+    }
+
+    /// For every owner the block grows past its quota, the position of the
+    /// last transaction that wrote to it. Leaving those out and executing
+    /// again ends with a block within `quotas`.
+    pub fn over_quota(&self, quotas: &StorageQuotas) -> Vec<usize> {
+        let mut positions: Vec<usize> = self
+            .usage
+            .iter()
+            .filter(|(address, &(before, after))| {
+                after > before && quotas.limit_for(address).is_some_and(|limit| after > limit)
+            })
+            .filter_map(|(address, _)| self.writers.get(address)?.last().copied())
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+}
+
+/// Header commitments that only block execution can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionRoots {
+    pub state_root: [u8; 32],
+    pub receipts_root: [u8; 32],
 }
 
 pub struct Ledger<S: Storage, C: ContractEngine> {
@@ -62,6 +140,25 @@ impl<S: Storage, C: ContractEngine> Ledger<S, C> {
             return Ok(());
         }
 
+        let mut initial_chain_state = ChainState {
+            latest_block_hash: [0; 32],
+            latest_block_index: 0,
+            accounts_root_hash: EMPTY_ROOT,
+            total_supply: 0,     // No native token for now
+            finalized_height: 0, // Genesis is final by definition
+            jailed_authorities: Vec::new(),
+        };
+
+        // State written before the chain existed (e.g. pre-funded accounts)
+        // is the genesis allocation; the genesis block commits to it like to
+        // any other state.
+        let genesis_state = self.storage.get_state_entries()?;
+        let (state_root, trie_nodes) = crate::merkle::state_tree_from_entries(
+            self.storage.as_ref(),
+            &genesis_state,
+            &initial_chain_state,
+        )?;
+
         // Create a genesis block
         let genesis_block = Block {
             index: 0,
@@ -69,30 +166,35 @@ impl<S: Storage, C: ContractEngine> Ledger<S, C> {
             prev_hash: [0; 32], // Genesis block has no previous hash
             hash: [0; 32],      // Will be calculated after creation
             nonce: 0,
+            state_root,
+            transactions_root: EMPTY_ROOT,
+            receipts_root: EMPTY_ROOT,
             transactions: Vec::new(),
             metadata: None,
+            seal: None,
         };
 
         let calculated_genesis_hash = genesis_block.calculate_hash()?;
         let mut genesis_block = genesis_block;
         genesis_block.hash = calculated_genesis_hash;
-
-        let initial_chain_state = ChainState {
-            latest_block_hash: genesis_block.hash,
-            latest_block_index: 0,
-            accounts_root_hash: [0; 32], // Placeholder, will be updated by Merkle tree impl
-            total_supply: 0,             // No native token for now
-        };
+        initial_chain_state.latest_block_hash = genesis_block.hash;
+        initial_chain_state.accounts_root_hash = state_root;
 
         let mut batch = StorageBatch::default();
-        batch.ops.push(StorageOperation::Put(
-            bincode::serialize(&genesis_block.hash)?,
-            bincode::serialize(&genesis_block)?,
-        ));
-        batch.ops.push(StorageOperation::Put(
-            bincode::serialize("global:current")?,
-            bincode::serialize(&initial_chain_state)?,
-        ));
+        batch
+            .ops
+            .push(StorageOperation::PutGenesisState(genesis_state));
+        for node in trie_nodes {
+            batch
+                .ops
+                .push(StorageOperation::PutTrieNode(Box::new(node)));
+        }
+        batch
+            .ops
+            .push(StorageOperation::PutBlock(Box::new(genesis_block.clone())));
+        batch.ops.push(StorageOperation::PutChainState(Box::new(
+            initial_chain_state,
+        )));
 
         self.storage.apply_batch(batch)?;
         println!(
@@ -130,6 +232,14 @@ impl<S: Storage, C: ContractEngine> Ledger<S, C> {
             )));
         }
 
+        let transactions_root = block.compute_transactions_root();
+        if transactions_root != block.transactions_root {
+            return Err(LedgerError::BlockValidation(format!(
+                "Invalid transactions root: expected {:x?}, got {:x?}",
+                transactions_root, block.transactions_root
+            )));
+        }
+
         // Timestamp check (simplified for MVP, typically more robust logic needed)
         if block.index > 0
             && block.timestamp
@@ -152,25 +262,170 @@ impl<S: Storage, C: ContractEngine> Ledger<S, C> {
                     tx.hash
                 )));
             }
+            tx.check_tags().map_err(|reason| {
+                LedgerError::BlockValidation(format!(
+                    "Invalid tags for transaction {:x?}: {}",
+                    tx.hash, reason
+                ))
+            })?;
             // Further transaction validation (nonce, balance) will happen during state transition
         }
 
         Ok(())
     }
 
+    /// Execute `block` on top of `current_chain_state` and commit the result.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a transaction is invalid, if the resulting state
+    /// root differs from `block.state_root`, or if the write fails. Nothing
+    /// is written in that case.
     pub fn apply_block(
         &self,
         block: Block,
         current_chain_state: &mut ChainState,
     ) -> Result<(), LedgerError> {
+        let execution = self.execute_block(&block, current_chain_state)?;
+        self.commit_block(block, execution, current_chain_state)
+    }
+
+    /// Commit a block executed by [`Ledger::execute_block`] against
+    /// `current_chain_state`.
+    ///
+    /// Block producers execute a block once to fill in its header roots,
+    /// then seal it and commit the same execution here. The block may have
+    /// been rehashed meanwhile, but its transactions must be the executed
+    /// ones and the chain state must not have moved.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the block's transactions or header roots differ
+    /// from the execution's, if its hash is not its header's, or if the write
+    /// fails. Nothing is written in that case.
+    pub fn commit_block(
+        &self,
+        block: Block,
+        execution: BlockExecution,
+        current_chain_state: &mut ChainState,
+    ) -> Result<(), LedgerError> {
+        let BlockExecution {
+            mut batch,
+            mut chain_state,
+            roots,
+            transactions_root,
+            receipts,
+            mut undo,
+            ..
+        } = execution;
+        if block.transactions_root != transactions_root
+            || block.compute_transactions_root() != transactions_root
+        {
+            return Err(LedgerError::BlockValidation(
+                "Block transactions differ from the executed ones".to_string(),
+            ));
+        }
+        let calculated_hash = block.calculate_hash()?;
+        if calculated_hash != block.hash {
+            return Err(LedgerError::BlockValidation(format!(
+                "Invalid block hash: expected {:x?}, got {:x?}",
+                calculated_hash, block.hash
+            )));
+        }
+        if roots.state_root != block.state_root {
+            return Err(LedgerError::StateRootMismatch(
+                block.state_root,
+                roots.state_root,
+            ));
+        }
+        if roots.receipts_root != block.receipts_root {
+            return Err(LedgerError::BlockValidation(format!(
+                "Invalid receipts root: expected {:x?}, got {:x?}",
+                roots.receipts_root, block.receipts_root
+            )));
+        }
+        if undo.prev_chain_state.latest_block_hash != current_chain_state.latest_block_hash {
+            return Err(LedgerError::StateTransition(
+                "Block was executed against a different chain tip".to_string(),
+            ));
+        }
+
+        // Finality may have advanced since the block was executed.
+        chain_state.latest_block_hash = block.hash;
+        chain_state.finalized_height = current_chain_state.finalized_height;
+        undo.prev_chain_state = current_chain_state.clone();
+        batch.ops.push(StorageOperation::PutChainState(Box::new(
+            chain_state.clone(),
+        )));
+        // Persist the block's transactions and receipts, the tx -> block
+        // index, the address histories and the tag index
+        for (i, receipt) in receipts.into_iter().enumerate() {
+            batch.ops.push(StorageOperation::PutReceipt {
+                block_hash: block.hash,
+                tx_index_in_block: i as u32,
+                receipt: Box::new(receipt),
+            });
+        }
+        for (i, tx) in block.transactions.iter().enumerate() {
+            batch
+                .ops
+                .push(StorageOperation::PutTransaction(Box::new(tx.clone())));
+            batch.ops.push(StorageOperation::IndexTransaction {
+                tx_hash: tx.hash,
+                block_hash: block.hash,
+                tx_index_in_block: i as u32,
+            });
+            batch.index_history(
+                tx,
+                TxPosition {
+                    height: block.index,
+                    tx_index_in_block: i as u32,
+                },
+            );
+        }
+        batch
+            .ops
+            .push(StorageOperation::PutBlockUndo(block.hash, Box::new(undo)));
+        batch.ops.push(StorageOperation::PutBlock(Box::new(block)));
+        self.storage.apply_batch(batch)?;
+        *current_chain_state = chain_state;
+        Ok(())
+    }
+
+    /// Run a block's transactions against current state without writing
+    /// anything, collecting the state writes into a [`BlockExecution`].
+    ///
+    /// Only the block's transactions are read, so the execution stays valid
+    /// if the header is rehashed or sealed afterwards.
+    pub fn execute_block(
+        &self,
+        block: &Block,
+        current_chain_state: &ChainState,
+    ) -> Result<BlockExecution, LedgerError> {
         let mut batch = StorageBatch::default();
         let mut accounts_to_update: BTreeMap<PublicKey, Account> = BTreeMap::new();
+        let mut previous_accounts: BTreeMap<PublicKey, Option<Account>> = BTreeMap::new();
+        let mut newly_jailed: Vec<PublicKey> = Vec::new();
+        let mut receipts: Vec<TransactionReceipt> = Vec::new();
+        let mut writers: BTreeMap<Address, Vec<usize>> = BTreeMap::new();
+        let contract_storage = ContractOverlay::new(self.storage.as_ref());
 
-        for tx in &block.transactions {
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let mut receipt = TransactionReceipt {
+                tx_hash: tx.hash,
+                success: true,
+                output: Vec::new(),
+                error: None,
+            };
             let sender_pk = tx.sender;
-            let mut sender_account = self.storage.get_account(&sender_pk)?.ok_or_else(|| {
-                LedgerError::AccountNotFound(format!("Sender account not found: {:?}", sender_pk))
-            })?;
+            let mut sender_account = self
+                .load_account(&sender_pk, &accounts_to_update, &mut previous_accounts)?
+                .ok_or_else(|| {
+                    LedgerError::AccountNotFound(format!(
+                        "Sender account not found: {:?}",
+                        sender_pk
+                    ))
+                })?;
 
             // Nonce Check
             if sender_account.nonce() + 1 != tx.nonce {
@@ -207,7 +462,9 @@ impl<S: Storage, C: ContractEngine> Ledger<S, C> {
                     }
 
                     if let Some(mut recipient_account) = match tx.recipient {
-                        crate::types::Address::Wallet(pk) => self.storage.get_account(&pk)?,
+                        crate::types::Address::Wallet(pk) => {
+                            self.load_account(&pk, &accounts_to_update, &mut previous_accounts)?
+                        }
                         crate::types::Address::Contract(_) => {
                             return Err(LedgerError::StateTransition(
                                 "Cannot transfer native token to a contract directly".to_string(),
@@ -249,7 +506,7 @@ impl<S: Storage, C: ContractEngine> Ledger<S, C> {
                         &tx.sender,
                         wasm_bytes,
                         None, // No init_payload in new variant
-                        self.storage.as_ref(),
+                        &contract_storage,
                         tx.gas_limit,
                     )?;
                     // Update sender account to reflect new contract (if it's a contract account)
@@ -268,50 +525,1148 @@ impl<S: Storage, C: ContractEngine> Ledger<S, C> {
                         crate::types::Address::Contract(cid) => cid,
                         _ => return Err(LedgerError::InvalidTransactionPayload),
                     };
-                    let _execution_result = self.contract_engine.call_contract(
+                    // A failing call is recorded in its receipt and its
+                    // writes are dropped; it does not invalidate the block.
+                    let checkpoint = contract_storage.checkpoint();
+                    match self.contract_engine.call_contract(
                         &tx.sender,
                         contract_id,
                         method,
                         args,
-                        self.storage.as_ref(),
-                    );
-                    // TODO: Handle execution result
+                        &contract_storage,
+                    ) {
+                        Ok(output) => receipt.output = output,
+                        Err(e) => {
+                            contract_storage.restore(checkpoint);
+                            receipt.success = false;
+                            receipt.error = Some(e.to_string());
+                        }
+                    }
                 }
                 TransactionPayload::Data { data: _ } => {
                     // For MVP, just allow storing data. No specific state changes yet.
                 }
+                TransactionPayload::SubmitEvidence { evidence } => {
+                    if !evidence.verify() {
+                        return Err(LedgerError::InvalidEvidence(
+                            "Signatures do not prove equivocation".to_string(),
+                        ));
+                    }
+                    let offender = evidence.offender();
+                    if current_chain_state.jailed_authorities.contains(&offender)
+                        || newly_jailed.contains(&offender)
+                    {
+                        return Err(LedgerError::InvalidEvidence(format!(
+                            "Authority {:?} is already jailed",
+                            offender
+                        )));
+                    }
+                    newly_jailed.push(offender);
+                }
+            }
+
+            if quota::data_bytes(tx) > 0 {
+                writers
+                    .entry(Address::Wallet(tx.sender))
+                    .or_default()
+                    .push(position);
+            }
+            for id in contract_storage.take_written() {
+                writers
+                    .entry(Address::Contract(ContractId::from_bytes(&id)))
+                    .or_default()
+                    .push(position);
             }
 
             // Remove from mempool after successful processing
             batch
                 .ops
-                .push(StorageOperation::Delete(bincode::serialize(&tx.hash)?));
+                .push(StorageOperation::RemovePendingTransaction(tx.hash));
+            receipts.push(receipt);
+        }
+
+        let receipt_hashes = receipts
+            .iter()
+            .map(TransactionReceipt::hash)
+            .collect::<Result<Vec<_>, _>>()?;
+        let receipts_root = crate::merkle::merkle_root(&receipt_hashes);
+
+        let (contract_writes, contract_code, contract_storage) = contract_storage.into_parts();
+
+        // Charge data transactions to their senders and contract state
+        // changes to the contracts
+        let mut usage_deltas: BTreeMap<Address, i128> = BTreeMap::new();
+        for tx in &block.transactions {
+            *usage_deltas.entry(Address::Wallet(tx.sender)).or_default() +=
+                i128::from(quota::data_bytes(tx));
+        }
+        for op in &contract_writes {
+            let (contract_id, bytes) = match op {
+                StorageOperation::PutContractCode(contract_id, code) => {
+                    (contract_id, code.len() as u64)
+                }
+                StorageOperation::ContractStorageWrite(contract_id, key, value) => {
+                    (contract_id, quota::slot_bytes(key, Some(value)))
+                }
+                _ => continue,
+            };
+            *usage_deltas
+                .entry(Address::Contract(contract_id.clone()))
+                .or_default() += i128::from(bytes);
+        }
+        for (contract_id, code) in &contract_code {
+            *usage_deltas
+                .entry(Address::Contract(contract_id.clone()))
+                .or_default() -= code.as_ref().map_or(0, |code| code.len() as i128);
+        }
+        for (contract_id, key, value) in &contract_storage {
+            *usage_deltas
+                .entry(Address::Contract(contract_id.clone()))
+                .or_default() -= i128::from(quota::slot_bytes(key, value.as_deref()));
+        }
+        let usage = self.usage_changes(usage_deltas)?;
+        for (address, &(_, after)) in &usage {
+            batch
+                .ops
+                .push(StorageOperation::PutStorageUsage(address.clone(), after));
+        }
+
+        // Fold every state change into the state tree
+        let mut state_tree = SparseMerkleTree::new(
+            self.storage.as_ref(),
+            current_chain_state.accounts_root_hash,
+        );
+        for (address, account) in &accounts_to_update {
+            state_tree.update(account_key(address), Some(bincode::serialize(account)?))?;
+        }
+        for op in &contract_writes {
+            match op {
+                StorageOperation::PutContractCode(contract_id, code) => state_tree.update(
+                    contract_code_key(contract_id),
+                    Some(Sha256::digest(code).to_vec()),
+                )?,
+                StorageOperation::DeleteContractCode(contract_id) => {
+                    state_tree.update(contract_code_key(contract_id), None)?
+                }
+                StorageOperation::ContractStorageWrite(contract_id, key, value) => state_tree
+                    .update(contract_storage_key(contract_id, key), Some(value.clone()))?,
+                StorageOperation::ContractStorageRemove(contract_id, key) => {
+                    state_tree.update(contract_storage_key(contract_id, key), None)?
+                }
+                _ => {}
+            }
+        }
+        // The chain state moves along; its block hash is filled in on commit
+        let mut next_chain_state = current_chain_state.clone();
+        next_chain_state.latest_block_index = block.index;
+        if !newly_jailed.is_empty() {
+            next_chain_state.jailed_authorities.extend(newly_jailed);
+            state_tree.update(chain_params_key(), chain_params_leaf(&next_chain_state)?)?;
+        }
+        let state_root = state_tree.root();
+        next_chain_state.accounts_root_hash = state_root;
+        for node in state_tree.into_new_nodes() {
+            batch
+                .ops
+                .push(StorageOperation::PutTrieNode(Box::new(node)));
         }
 
-        // Apply account updates (Merkle root calculation would go here in a full implementation)
         for (address, account) in accounts_to_update {
-            batch.ops.push(StorageOperation::Put(
-                bincode::serialize(&address)?,
-                bincode::serialize(&account)?,
-            ));
+            batch
+                .ops
+                .push(StorageOperation::PutAccount(address, Box::new(account)));
+        }
+        batch.ops.extend(contract_writes);
+
+        let undo = BlockUndo {
+            prev_chain_state: current_chain_state.clone(),
+            accounts: previous_accounts.into_iter().collect(),
+            contract_code,
+            contract_storage,
+        };
+
+        Ok(BlockExecution {
+            batch,
+            chain_state: next_chain_state,
+            roots: ExecutionRoots {
+                state_root,
+                receipts_root,
+            },
+            transactions_root: block.compute_transactions_root(),
+            receipts,
+            undo,
+            usage,
+            writers,
+        })
+    }
+
+    /// Each owner's storage usage before and after moving it by its delta.
+    fn usage_changes(
+        &self,
+        deltas: BTreeMap<Address, i128>,
+    ) -> Result<BTreeMap<Address, (u64, u64)>, LedgerError> {
+        let mut changes = BTreeMap::new();
+        for (address, delta) in deltas {
+            if delta == 0 {
+                continue;
+            }
+            let used = self.storage.get_storage_usage(&address)?;
+            let next = u64::try_from(i128::from(used) + delta).unwrap_or(0);
+            changes.insert(address, (used, next));
+        }
+        Ok(changes)
+    }
+
+    /// Read an account as seen part-way through a block, remembering its
+    /// pre-block value for the undo record the first time it is touched.
+    fn load_account(
+        &self,
+        address: &PublicKey,
+        pending: &BTreeMap<PublicKey, Account>,
+        previous: &mut BTreeMap<PublicKey, Option<Account>>,
+    ) -> Result<Option<Account>, LedgerError> {
+        if let Some(account) = pending.get(address) {
+            return Ok(Some(account.clone()));
         }
+        let account = self.storage.get_account(address)?;
+        previous.entry(*address).or_insert_with(|| account.clone());
+        Ok(account)
+    }
+
+    /// Roll back the chain tip `block` using its undo record.
+    ///
+    /// The block's transactions are deleted along with it; a branch that
+    /// re-includes one stores it again when its block is applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `block` is not the tip, if reverting it would cross
+    /// the finalized height, or if its undo record is missing.
+    pub fn revert_block(
+        &self,
+        block: &Block,
+        current_chain_state: &mut ChainState,
+    ) -> Result<(), LedgerError> {
+        if block.hash != current_chain_state.latest_block_hash || block.index == 0 {
+            return Err(LedgerError::StateTransition(format!(
+                "Block {:x?} is not a revertible chain tip",
+                block.hash
+            )));
+        }
+        self.check_revert(block.index - 1, current_chain_state)?;
+        let undo = self
+            .storage
+            .get_block_undo(&block.hash)?
+            .ok_or(LedgerError::NotFound)?;
 
-        // Update chain state
-        current_chain_state.latest_block_hash = block.hash;
-        current_chain_state.latest_block_index = block.index;
-        // Merkle root for accounts_root_hash would be calculated and updated here
-        batch.ops.push(StorageOperation::Put(
-            bincode::serialize("global:current")?,
-            bincode::serialize(current_chain_state)?,
-        ));
+        // Give back what the block charged: its data transactions, and the
+        // difference between the contract state it wrote and what it replaced
+        let mut usage_deltas: BTreeMap<Address, i128> = BTreeMap::new();
+        for tx in &block.transactions {
+            *usage_deltas.entry(Address::Wallet(tx.sender)).or_default() -=
+                i128::from(quota::data_bytes(tx));
+        }
+        for (contract_id, code) in &undo.contract_code {
+            let current = self.storage.get_contract_code(contract_id)?;
+            let size = |code: Option<&Vec<u8>>| code.map_or(0, |code| code.len() as i128);
+            *usage_deltas
+                .entry(Address::Contract(contract_id.clone()))
+                .or_default() += size(code.as_ref()) - size(current.as_ref());
+        }
+        for (contract_id, key, value) in &undo.contract_storage {
+            let current = self.storage.contract_storage_read(contract_id, key)?;
+            *usage_deltas
+                .entry(Address::Contract(contract_id.clone()))
+                .or_default() += i128::from(quota::slot_bytes(key, value.as_deref()))
+                - i128::from(quota::slot_bytes(key, current.as_deref()));
+        }
 
-        // Index transactions by block hash as part of the batch
+        let mut batch = StorageBatch::default();
+        for (address, (_, after)) in self.usage_changes(usage_deltas)? {
+            batch
+                .ops
+                .push(StorageOperation::PutStorageUsage(address, after));
+        }
+        for (address, account) in undo.accounts {
+            batch.ops.push(match account {
+                Some(account) => StorageOperation::PutAccount(address, Box::new(account)),
+                None => StorageOperation::DeleteAccount(address),
+            });
+        }
+        for (contract_id, code) in undo.contract_code {
+            batch.ops.push(match code {
+                Some(code) => StorageOperation::PutContractCode(contract_id, code),
+                None => StorageOperation::DeleteContractCode(contract_id),
+            });
+        }
+        for (contract_id, key, value) in undo.contract_storage {
+            batch.ops.push(match value {
+                Some(value) => StorageOperation::ContractStorageWrite(contract_id, key, value),
+                None => StorageOperation::ContractStorageRemove(contract_id, key),
+            });
+        }
         for (i, tx) in block.transactions.iter().enumerate() {
-            self.storage
-                .index_transaction(&tx.hash, &block.hash, i as u32)?;
+            batch.ops.push(StorageOperation::DeleteReceipt {
+                block_hash: block.hash,
+                tx_index_in_block: i as u32,
+            });
+            batch.ops.push(StorageOperation::DeleteTransaction(tx.hash));
+            batch.ops.push(StorageOperation::UnindexTransaction {
+                block_hash: block.hash,
+                tx_index_in_block: i as u32,
+            });
+            // The history indices are keyed by height, which the replacing
+            // branch reuses.
+            batch.unindex_history(
+                tx,
+                TxPosition {
+                    height: block.index,
+                    tx_index_in_block: i as u32,
+                },
+            );
+        }
+        batch
+            .ops
+            .push(StorageOperation::RemoveBlockHeight(block.index));
+        batch
+            .ops
+            .push(StorageOperation::DeleteBlockUndo(block.hash));
+
+        // Finality only moves forward; the check above guarantees the
+        // restored tip is still at or above it.
+        let mut prev_chain_state = undo.prev_chain_state;
+        prev_chain_state.finalized_height = current_chain_state.finalized_height;
+        batch.ops.push(StorageOperation::PutChainState(Box::new(
+            prev_chain_state.clone(),
+        )));
+
+        self.storage.apply_batch(batch)?;
+        *current_chain_state = prev_chain_state;
+        Ok(())
+    }
+
+    /// Switch the canonical chain to the branch ending in `new_tip`.
+    ///
+    /// Every block of the new branch must already be stored (see
+    /// [`Storage::put_side_block`]). Canonical blocks above the common
+    /// ancestor are reverted, then the new branch is validated with
+    /// `validate` and the ledger's own checks and applied. If any block of the
+    /// new branch fails, the original chain is restored and the error returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the branch does not connect to the canonical chain,
+    /// if the common ancestor is below the finalized height, or if the new
+    /// branch is invalid.
+    pub fn reorganize<E: From<LedgerError>>(
+        &self,
+        new_tip: &Block,
+        current_chain_state: &mut ChainState,
+        mut validate: impl FnMut(&Block, &ChainState) -> Result<(), E>,
+    ) -> Result<Reorganization, E> {
+        let mut branch = Vec::new();
+        let mut cursor = new_tip.clone();
+        while !self.is_canonical(&cursor)? {
+            if cursor.index == 0 {
+                return Err(LedgerError::NotFound.into());
+            }
+            let parent = self
+                .storage
+                .get_block(&cursor.prev_hash)
+                .map_err(LedgerError::from)?
+                .ok_or(LedgerError::NotFound)?;
+            branch.push(cursor);
+            cursor = parent;
+        }
+        branch.reverse();
+        let ancestor = cursor;
+        self.check_revert(ancestor.index, current_chain_state)?;
+
+        let mut reorganization = Reorganization::default();
+        while current_chain_state.latest_block_index > ancestor.index {
+            let tip = self
+                .storage
+                .get_block(&current_chain_state.latest_block_hash)
+                .map_err(LedgerError::from)?
+                .ok_or(LedgerError::NotFound)?;
+            self.revert_block(&tip, current_chain_state)?;
+            reorganization.reverted.push(tip);
+        }
+
+        for block in branch {
+            let result = validate(&block, current_chain_state).and_then(|()| {
+                self.validate_block(&block, current_chain_state)?;
+                self.apply_block(block.clone(), current_chain_state)?;
+                Ok(())
+            });
+            if let Err(e) = result {
+                self.restore(&reorganization, current_chain_state)?;
+                return Err(e);
+            }
+            reorganization.applied.push(block);
+        }
+        Ok(reorganization)
+    }
+
+    /// Undo a partially applied reorganization.
+    fn restore(
+        &self,
+        reorganization: &Reorganization,
+        current_chain_state: &mut ChainState,
+    ) -> Result<(), LedgerError> {
+        for block in reorganization.applied.iter().rev() {
+            self.revert_block(block, current_chain_state)?;
+        }
+        for block in reorganization.reverted.iter().rev() {
+            self.apply_block(block.clone(), current_chain_state)?;
+        }
+        Ok(())
+    }
+
+    fn is_canonical(&self, block: &Block) -> Result<bool, LedgerError> {
+        Ok(self
+            .storage
+            .get_block_by_height(block.index)?
+            .is_some_and(|canonical| canonical.hash == block.hash))
+    }
+
+    /// Record a commit certificate and advance the finalized height.
+    ///
+    /// The certificate must carry a supermajority of `authorities` and refer
+    /// to the canonical block at its height. Certificates at or below the
+    /// current finalized height are ignored.
+    pub fn apply_commit_certificate(
+        &self,
+        certificate: &CommitCertificate,
+        authorities: &[PublicKey],
+        current_chain_state: &mut ChainState,
+    ) -> Result<(), LedgerError> {
+        if certificate.height <= current_chain_state.finalized_height {
+            return Ok(());
+        }
+        // Jailed authorities no longer take part in finality.
+        let voters: Vec<PublicKey> = authorities
+            .iter()
+            .filter(|authority| !current_chain_state.jailed_authorities.contains(authority))
+            .copied()
+            .collect();
+        if !certificate.verify(&voters) {
+            return Err(LedgerError::InvalidCertificate(
+                "Not signed by a supermajority of authorities".to_string(),
+            ));
+        }
+        let block = self
+            .storage
+            .get_block_by_height(certificate.height)?
+            .ok_or(LedgerError::NotFound)?;
+        if block.hash != certificate.block_hash {
+            return Err(LedgerError::InvalidCertificate(format!(
+                "Block {:x?} is not canonical at height {}",
+                certificate.block_hash, certificate.height
+            )));
         }
 
+        let mut next_chain_state = current_chain_state.clone();
+        next_chain_state.finalized_height = certificate.height;
+        let mut batch = StorageBatch::default();
+        batch
+            .ops
+            .push(StorageOperation::PutCommitCertificate(Box::new(
+                certificate.clone(),
+            )));
+        batch.ops.push(StorageOperation::PutChainState(Box::new(
+            next_chain_state.clone(),
+        )));
         self.storage.apply_batch(batch)?;
+        *current_chain_state = next_chain_state;
+        Ok(())
+    }
+
+    /// Check that the chain may be rolled back until `height` is the tip.
+    ///
+    /// Finalized blocks are never reverted.
+    pub fn check_revert(&self, height: u64, chain_state: &ChainState) -> Result<(), LedgerError> {
+        if height < chain_state.finalized_height {
+            return Err(LedgerError::BelowFinalized(
+                height,
+                chain_state.finalized_height,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Storage handed to the contract engine during block application.
+///
+/// Contract code and storage writes are buffered rather than written, so they
+/// can join the block's [`StorageBatch`] and commit atomically with the rest of
+/// the block. Reads see buffered writes first. The value each key held before
+/// the block is remembered for the block's [`BlockUndo`].
+///
+/// Non-contract calls go straight to the underlying storage; the contract
+/// engine does not make them.
+struct ContractOverlay<'a> {
+    inner: &'a dyn Storage,
+    state: Mutex<OverlayState>,
+}
+
+/// A contract storage key: contract id and key within the contract.
+type ContractSlot = ([u8; 32], Vec<u8>);
+
+#[derive(Default, Clone)]
+struct OverlayState {
+    /// Pending code per contract (`None` for a deletion).
+    code: BTreeMap<[u8; 32], Option<Vec<u8>>>,
+    /// Pending storage values (`None` for a removal).
+    storage: BTreeMap<ContractSlot, Option<Vec<u8>>>,
+    /// Pre-block code of every contract written.
+    previous_code: BTreeMap<[u8; 32], Option<Vec<u8>>>,
+    /// Pre-block value of every storage key written.
+    previous_storage: BTreeMap<ContractSlot, Option<Vec<u8>>>,
+    /// Contracts written since the last [`ContractOverlay::take_written`].
+    written: BTreeSet<[u8; 32]>,
+}
+
+type OverlayParts = (
+    Vec<StorageOperation>,
+    Vec<(ContractId, Option<Vec<u8>>)>,
+    Vec<(ContractId, Vec<u8>, Option<Vec<u8>>)>,
+);
+
+impl<'a> ContractOverlay<'a> {
+    fn new(inner: &'a dyn Storage) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(OverlayState::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, OverlayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Split into the batch operations for the buffered writes and the
+    /// previous contract code and storage values for the undo record.
+    fn into_parts(self) -> OverlayParts {
+        let state = self.state.into_inner().unwrap_or_else(|e| e.into_inner());
+        let mut ops = Vec::new();
+        for (id, code) in state.code {
+            let contract_id = ContractId::from_bytes(&id);
+            ops.push(match code {
+                Some(code) => StorageOperation::PutContractCode(contract_id, code),
+                None => StorageOperation::DeleteContractCode(contract_id),
+            });
+        }
+        for ((id, key), value) in state.storage {
+            let contract_id = ContractId::from_bytes(&id);
+            ops.push(match value {
+                Some(value) => StorageOperation::ContractStorageWrite(contract_id, key, value),
+                None => StorageOperation::ContractStorageRemove(contract_id, key),
+            });
+        }
+        let previous_code = state
+            .previous_code
+            .into_iter()
+            .map(|(id, code)| (ContractId::from_bytes(&id), code))
+            .collect();
+        let previous_storage = state
+            .previous_storage
+            .into_iter()
+            .map(|((id, key), value)| (ContractId::from_bytes(&id), key, value))
+            .collect();
+        (ops, previous_code, previous_storage)
+    }
+
+    /// A copy of the buffered writes to roll back to with
+    /// [`ContractOverlay::restore`].
+    fn checkpoint(&self) -> OverlayState {
+        self.lock().clone()
+    }
+
+    /// Discard every write made since `checkpoint` was taken.
+    fn restore(&self, checkpoint: OverlayState) {
+        *self.lock() = checkpoint;
+    }
+
+    /// The contracts written since the last call.
+    fn take_written(&self) -> BTreeSet<[u8; 32]> {
+        std::mem::take(&mut self.lock().written)
+    }
+
+    fn write_code(
+        &self,
+        contract_id: &ContractId,
+        code: Option<Vec<u8>>,
+    ) -> Result<(), StorageError> {
+        let mut state = self.lock();
+        if let Entry::Vacant(entry) = state.previous_code.entry(contract_id.id) {
+            entry.insert(self.inner.get_contract_code(contract_id)?);
+        }
+        state.code.insert(contract_id.id, code);
+        state.written.insert(contract_id.id);
+        Ok(())
+    }
+
+    fn write_storage(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+    ) -> Result<(), StorageError> {
+        let mut state = self.lock();
+        let slot = (contract_id.id, key.to_vec());
+        if let Entry::Vacant(entry) = state.previous_storage.entry(slot.clone()) {
+            entry.insert(self.inner.contract_storage_read(contract_id, key)?);
+        }
+        state.storage.insert(slot, value);
+        state.written.insert(contract_id.id);
         Ok(())
     }
 }
+
+impl Storage for ContractOverlay<'_> {
+    fn put_block(&self, block: &Block) -> Result<(), StorageError> {
+        self.inner.put_block(block)
+    }
+
+    fn get_block(&self, hash: &[u8; 32]) -> Result<Option<Block>, StorageError> {
+        self.inner.get_block(hash)
+    }
+
+    fn get_latest_block(&self) -> Result<Option<Block>, StorageError> {
+        self.inner.get_latest_block()
+    }
+
+    fn get_chain_height(&self) -> Result<u64, StorageError> {
+        self.inner.get_chain_height()
+    }
+
+    fn get_block_by_height(&self, height: u64) -> Result<Option<Block>, StorageError> {
+        self.inner.get_block_by_height(height)
+    }
+
+    fn get_block_header_by_height(&self, height: u64) -> Result<Option<BlockHeader>, StorageError> {
+        self.inner.get_block_header_by_height(height)
+    }
+
+    fn put_side_block(&self, block: &Block) -> Result<(), StorageError> {
+        self.inner.put_side_block(block)
+    }
+
+    fn remove_block_height(&self, height: u64) -> Result<(), StorageError> {
+        self.inner.remove_block_height(height)
+    }
+
+    fn put_block_undo(&self, block_hash: &[u8; 32], undo: &BlockUndo) -> Result<(), StorageError> {
+        self.inner.put_block_undo(block_hash, undo)
+    }
+
+    fn get_block_undo(&self, block_hash: &[u8; 32]) -> Result<Option<BlockUndo>, StorageError> {
+        self.inner.get_block_undo(block_hash)
+    }
+
+    fn delete_block_undo(&self, block_hash: &[u8; 32]) -> Result<(), StorageError> {
+        self.inner.delete_block_undo(block_hash)
+    }
+
+    fn put_transaction(&self, tx: &Transaction) -> Result<(), StorageError> {
+        self.inner.put_transaction(tx)
+    }
+
+    fn get_transaction(&self, tx_hash: &[u8; 32]) -> Result<Option<Transaction>, StorageError> {
+        self.inner.get_transaction(tx_hash)
+    }
+
+    fn get_receipt(
+        &self,
+        block_hash: &[u8; 32],
+        tx_index_in_block: u32,
+    ) -> Result<Option<TransactionReceipt>, StorageError> {
+        self.inner.get_receipt(block_hash, tx_index_in_block)
+    }
+
+    fn get_block_receipts(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Vec<TransactionReceipt>, StorageError> {
+        self.inner.get_block_receipts(block_hash)
+    }
+
+    fn get_pending_transactions(&self) -> Result<Vec<Transaction>, StorageError> {
+        self.inner.get_pending_transactions()
+    }
+
+    fn remove_pending_transaction(&self, tx_hash: &[u8; 32]) -> Result<(), StorageError> {
+        self.inner.remove_pending_transaction(tx_hash)
+    }
+
+    fn index_transaction(
+        &self,
+        tx_hash: &[u8; 32],
+        block_hash: &[u8; 32],
+        tx_index_in_block: u32,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .index_transaction(tx_hash, block_hash, tx_index_in_block)
+    }
+
+    fn get_transaction_by_id(
+        &self,
+        tx_hash: &[u8; 32],
+    ) -> Result<Option<Transaction>, StorageError> {
+        self.inner.get_transaction_by_id(tx_hash)
+    }
+
+    fn get_transactions_by_block(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Vec<Transaction>, StorageError> {
+        self.inner.get_transactions_by_block(block_hash)
+    }
+
+    fn get_transactions_by_block_page(
+        &self,
+        block_hash: &[u8; 32],
+        after: Option<u32>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(u32, Transaction)>, StorageError> {
+        self.inner
+            .get_transactions_by_block_page(block_hash, after, order, limit)
+    }
+
+    fn get_address_transactions(
+        &self,
+        address: &Address,
+        after: Option<TxPosition>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(TxPosition, [u8; 32])>, StorageError> {
+        self.inner
+            .get_address_transactions(address, after, order, limit)
+    }
+
+    fn get_tagged_transactions(
+        &self,
+        query: &TagQuery,
+        after: Option<&TaggedTransaction>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<TaggedTransaction>, StorageError> {
+        self.inner
+            .get_tagged_transactions(query, after, order, limit)
+    }
+
+    fn put_account(&self, address: &PublicKey, account: &Account) -> Result<(), StorageError> {
+        self.inner.put_account(address, account)
+    }
+
+    fn get_account(&self, address: &PublicKey) -> Result<Option<Account>, StorageError> {
+        self.inner.get_account(address)
+    }
+
+    fn delete_account(&self, address: &PublicKey) -> Result<(), StorageError> {
+        self.inner.delete_account(address)
+    }
+
+    fn put_chain_state(&self, state: &ChainState) -> Result<(), StorageError> {
+        self.inner.put_chain_state(state)
+    }
+
+    fn get_chain_state(&self) -> Result<Option<ChainState>, StorageError> {
+        self.inner.get_chain_state()
+    }
+
+    fn put_commit_certificate(&self, certificate: &CommitCertificate) -> Result<(), StorageError> {
+        self.inner.put_commit_certificate(certificate)
+    }
+
+    fn get_commit_certificate(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<CommitCertificate>, StorageError> {
+        self.inner.get_commit_certificate(block_hash)
+    }
+
+    fn put_contract_code(
+        &self,
+        contract_id: &ContractId,
+        wasm_bytes: &[u8],
+    ) -> Result<(), StorageError> {
+        self.write_code(contract_id, Some(wasm_bytes.to_vec()))
+    }
+
+    fn get_trie_node(&self, hash: &[u8; 32]) -> Result<Option<TrieNode>, StorageError> {
+        self.inner.get_trie_node(hash)
+    }
+
+    fn get_trie_node_hashes(&self) -> Result<Vec<[u8; 32]>, StorageError> {
+        self.inner.get_trie_node_hashes()
+    }
+
+    fn get_state_entries(&self) -> Result<Vec<StateEntry>, StorageError> {
+        self.inner.get_state_entries()
+    }
+
+    fn get_prune_horizon(&self) -> Result<PruneHorizon, StorageError> {
+        self.inner.get_prune_horizon()
+    }
+
+    fn get_genesis_state(&self) -> Result<Option<Vec<StateEntry>>, StorageError> {
+        self.inner.get_genesis_state()
+    }
+
+    fn is_reindexing(&self) -> Result<bool, StorageError> {
+        self.inner.is_reindexing()
+    }
+
+    fn get_storage_usage(&self, address: &Address) -> Result<u64, StorageError> {
+        self.inner.get_storage_usage(address)
+    }
+
+    fn get_contract_code(&self, contract_id: &ContractId) -> Result<Option<Vec<u8>>, StorageError> {
+        if let Some(code) = self.lock().code.get(&contract_id.id) {
+            return Ok(code.clone());
+        }
+        self.inner.get_contract_code(contract_id)
+    }
+
+    fn delete_contract_code(&self, contract_id: &ContractId) -> Result<(), StorageError> {
+        self.write_code(contract_id, None)
+    }
+
+    fn contract_storage_read(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        if let Some(value) = self.lock().storage.get(&(contract_id.id, key.to_vec())) {
+            return Ok(value.clone());
+        }
+        self.inner.contract_storage_read(contract_id, key)
+    }
+
+    fn contract_storage_write(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), StorageError> {
+        self.write_storage(contract_id, key, Some(value.to_vec()))
+    }
+
+    fn contract_storage_remove(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+    ) -> Result<(), StorageError> {
+        self.write_storage(contract_id, key, None)
+    }
+
+    fn apply_batch(&self, batch: StorageBatch) -> Result<(), StorageError> {
+        self.inner.apply_batch(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::ContractError;
+    use crate::storage::MemoryStorage;
+    use crate::types::TransactionSignature;
+    use ed25519_dalek::SigningKey;
+
+    /// Deploys every contract under one id; `set` writes `key=value`,
+    /// `remove` removes the key given.
+    struct SlotEngine;
+
+    impl ContractEngine for SlotEngine {
+        fn deploy_contract(
+            &self,
+            _deployer: &PublicKey,
+            wasm_bytes: &[u8],
+            _init_payload: Option<&[u8]>,
+            storage: &dyn Storage,
+            _gas_limit: u64,
+        ) -> Result<ContractId, ContractError> {
+            let contract_id = ContractId::from_bytes(&[7; 32]);
+            storage.put_contract_code(&contract_id, wasm_bytes)?;
+            Ok(contract_id)
+        }
+
+        fn call_contract(
+            &self,
+            _caller: &PublicKey,
+            contract_id: &ContractId,
+            method_name: &str,
+            args: &[u8],
+            storage: &dyn Storage,
+        ) -> Result<Vec<u8>, ContractError> {
+            match method_name {
+                "set" => {
+                    let separator = args.iter().position(|&b| b == b'=').unwrap();
+                    storage.contract_storage_write(
+                        contract_id,
+                        &args[..separator],
+                        &args[separator + 1..],
+                    )?;
+                }
+                "remove" => storage.contract_storage_remove(contract_id, args)?,
+                "trap" => {
+                    storage.contract_storage_write(contract_id, args, b"partial")?;
+                    return Err(ContractError::ExecutionError("trap".to_string()));
+                }
+                other => return Err(ContractError::ExecutionError(other.to_string())),
+            }
+            Ok(Vec::new())
+        }
+
+        fn query_contract(
+            &self,
+            _contract_id: &ContractId,
+            _payload: &[u8],
+            _storage: &dyn Storage,
+        ) -> Result<Vec<u8>, ContractError> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn tx(key: &SigningKey, nonce: u64, payload: TransactionPayload) -> Transaction {
+        let mut tx = Transaction {
+            hash: [0; 32],
+            sender: PublicKey::from(key.verifying_key()),
+            nonce,
+            timestamp: 0,
+            recipient: Address::Contract(ContractId::from_bytes(&[7; 32])),
+            payload,
+            signature: TransactionSignature::from_bytes(&[0; 64]).unwrap(),
+            gas_limit: 0,
+            priority: 0,
+            metadata: None,
+        };
+        tx.sign(key).unwrap();
+        tx
+    }
+
+    fn call(key: &SigningKey, nonce: u64, method: &str, args: &[u8]) -> Transaction {
+        let payload = TransactionPayload::ContractCall {
+            method: method.to_string(),
+            args: args.to_vec(),
+        };
+        tx(key, nonce, payload)
+    }
+
+    fn next_block(chain_state: &ChainState, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            index: chain_state.latest_block_index + 1,
+            timestamp: chain_state.latest_block_index + 1,
+            prev_hash: chain_state.latest_block_hash,
+            hash: [0; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions,
+            metadata: None,
+            seal: None,
+        };
+        block.transactions_root = block.compute_transactions_root();
+        block
+    }
+
+    fn commit(
+        ledger: &Ledger<MemoryStorage, SlotEngine>,
+        chain_state: &mut ChainState,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let mut block = next_block(chain_state, transactions);
+        let execution = ledger.execute_block(&block, chain_state).unwrap();
+        block.state_root = execution.roots().state_root;
+        block.receipts_root = execution.roots().receipts_root;
+        block.hash = block.calculate_hash().unwrap();
+        ledger
+            .commit_block(block.clone(), execution, chain_state)
+            .unwrap();
+        block
+    }
+
+    #[test]
+    fn test_contract_storage_is_charged_and_refunded() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let storage = Arc::new(MemoryStorage::new());
+        storage
+            .put_account(
+                &PublicKey::from(key.verifying_key()),
+                &Account::Wallet {
+                    balance: 100,
+                    nonce: 0,
+                },
+            )
+            .unwrap();
+        let ledger = Ledger::new(Arc::clone(&storage), Arc::new(SlotEngine));
+        ledger.initialize_chain().unwrap();
+        let mut chain_state = storage.get_chain_state().unwrap().unwrap();
+        let contract = Address::Contract(ContractId::from_bytes(&[7; 32]));
+        let usage = || storage.get_storage_usage(&contract).unwrap();
+
+        let deploy = TransactionPayload::ContractDeploy {
+            wasm_bytes: vec![0; 8],
+        };
+        commit(&ledger, &mut chain_state, vec![tx(&key, 1, deploy)]);
+        assert_eq!(usage(), 8);
+        commit(
+            &ledger,
+            &mut chain_state,
+            vec![call(&key, 2, "set", b"k=vvvv")],
+        );
+        assert_eq!(usage(), 13);
+        // Overwriting charges the difference, removing gives it all back.
+        commit(
+            &ledger,
+            &mut chain_state,
+            vec![call(&key, 3, "set", b"k=vv")],
+        );
+        assert_eq!(usage(), 11);
+        let removal = commit(
+            &ledger,
+            &mut chain_state,
+            vec![call(&key, 4, "remove", b"k")],
+        );
+        assert_eq!(usage(), 8);
+        ledger.revert_block(&removal, &mut chain_state).unwrap();
+        assert_eq!(usage(), 11);
+
+        // Only the transaction that takes the contract past its quota, the
+        // last writer, is flagged; shrinking below the quota is fine.
+        let quotas = StorageQuotas {
+            account_bytes: None,
+            contract_bytes: Some(13),
+        };
+        let block = next_block(
+            &chain_state,
+            vec![call(&key, 4, "set", b"j=v"), call(&key, 5, "set", b"i=v")],
+        );
+        let execution = ledger.execute_block(&block, &chain_state).unwrap();
+        assert_eq!(execution.over_quota(&quotas), [1]);
+        let block = next_block(&chain_state, block.transactions[..1].to_vec());
+        let execution = ledger.execute_block(&block, &chain_state).unwrap();
+        assert!(execution.over_quota(&quotas).is_empty());
+        let quotas = StorageQuotas {
+            account_bytes: None,
+            contract_bytes: Some(5),
+        };
+        let block = next_block(&chain_state, vec![call(&key, 4, "remove", b"k")]);
+        let execution = ledger.execute_block(&block, &chain_state).unwrap();
+        assert!(execution.over_quota(&quotas).is_empty());
+    }
+
+    #[test]
+    fn test_commit_requires_the_executed_block() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let storage = Arc::new(MemoryStorage::new());
+        storage
+            .put_account(
+                &PublicKey::from(key.verifying_key()),
+                &Account::Wallet {
+                    balance: 100,
+                    nonce: 0,
+                },
+            )
+            .unwrap();
+        let ledger = Ledger::new(Arc::clone(&storage), Arc::new(SlotEngine));
+        ledger.initialize_chain().unwrap();
+        let mut chain_state = storage.get_chain_state().unwrap().unwrap();
+        let deploy = |nonce| {
+            tx(
+                &key,
+                nonce,
+                TransactionPayload::ContractDeploy {
+                    wasm_bytes: vec![0; 8],
+                },
+            )
+        };
+        let executed = next_block(&chain_state, vec![deploy(1)]);
+        let roots = ledger
+            .execute_block(&executed, &chain_state)
+            .unwrap()
+            .roots();
+        let seal = |mut block: Block| {
+            block.state_root = roots.state_root;
+            block.receipts_root = roots.receipts_root;
+            block.hash = block.calculate_hash().unwrap();
+            block
+        };
+
+        // Other transactions, under either root, and a stale hash are refused.
+        let other = seal(next_block(&chain_state, vec![deploy(2)]));
+        let mut swapped = seal(executed.clone());
+        swapped.transactions = other.transactions.clone();
+        let mut stale = seal(executed.clone());
+        stale.hash = [9; 32];
+        for block in [other, swapped, stale] {
+            let execution = ledger.execute_block(&executed, &chain_state).unwrap();
+            assert!(matches!(
+                ledger.commit_block(block, execution, &mut chain_state),
+                Err(LedgerError::BlockValidation(_))
+            ));
+        }
+        assert_eq!(chain_state.latest_block_index, 0);
+
+        let execution = ledger.execute_block(&executed, &chain_state).unwrap();
+        ledger
+            .commit_block(seal(executed), execution, &mut chain_state)
+            .unwrap();
+        assert_eq!(chain_state.latest_block_index, 1);
+    }
+
+    #[test]
+    fn test_failed_call_discards_its_writes() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let storage = Arc::new(MemoryStorage::new());
+        storage
+            .put_account(
+                &PublicKey::from(key.verifying_key()),
+                &Account::Wallet {
+                    balance: 100,
+                    nonce: 0,
+                },
+            )
+            .unwrap();
+        let ledger = Ledger::new(Arc::clone(&storage), Arc::new(SlotEngine));
+        ledger.initialize_chain().unwrap();
+        let mut chain_state = storage.get_chain_state().unwrap().unwrap();
+        let contract_id = ContractId::from_bytes(&[7; 32]);
+        let deploy = TransactionPayload::ContractDeploy {
+            wasm_bytes: vec![0; 8],
+        };
+        commit(&ledger, &mut chain_state, vec![tx(&key, 1, deploy)]);
+
+        // The trapping call's write is dropped; the calls around it keep theirs.
+        let block = commit(
+            &ledger,
+            &mut chain_state,
+            vec![
+                call(&key, 2, "set", b"a=1"),
+                call(&key, 3, "trap", b"b"),
+                call(&key, 4, "set", b"c=3"),
+            ],
+        );
+        let receipts: Vec<bool> = storage
+            .get_block_receipts(&block.hash)
+            .unwrap()
+            .iter()
+            .map(|receipt| receipt.success)
+            .collect();
+        assert_eq!(receipts, [true, false, true]);
+        let read = |key: &[u8]| storage.contract_storage_read(&contract_id, key).unwrap();
+        assert_eq!(read(b"a"), Some(b"1".to_vec()));
+        assert_eq!(read(b"b"), None);
+        assert_eq!(read(b"c"), Some(b"3".to_vec()));
+        assert_eq!(
+            storage
+                .get_storage_usage(&Address::Contract(contract_id))
+                .unwrap(),
+            12
+        );
+    }
+}
@@ -8,8 +8,14 @@
 //! ## Core Modules
 //!
 //! - [`types`]: Core data structures (Block, Transaction, etc.)
-//! - [`storage`]: Persistent storage layer using sled
+//! - [`storage`]: Storage trait and backends (sled, in-memory)
 //! - [`ledger`]: Block validation and state transition logic
+//! - [`merkle`]: Sparse Merkle tree committing to the global state
+//! - [`pruning`]: Retention modes and pruning of historical data
+//! - [`quota`]: Storage accounting and per-owner quotas
+//! - [`snapshot`]: State snapshot export and import
+//! - [`integrity`]: Database integrity checks and repair
+//! - [`reindex`]: Rebuilding derived data by replaying stored blocks
 //! - [`consensus`]: Consensus engine (Proof-of-Authority)
 //! - [`runtime`]: Main runtime orchestrator
 //! - [`contracts`]: WASM smart contract execution engine
@@ -17,8 +23,14 @@
 
 pub mod consensus;
 pub mod contracts;
+pub mod integrity;
 pub mod ledger;
+pub mod merkle;
+pub mod pruning;
+pub mod quota;
+pub mod reindex;
 pub mod runtime;
+pub mod snapshot;
 pub mod storage;
 pub mod sync;
 pub mod types;
@@ -1,11 +1,16 @@
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use baals::consensus::PoAConsensus;
 use baals::contracts::{BaaLSContractEngine, ContractEngine};
+use baals::integrity;
+use baals::quota::StorageQuotas;
+use baals::reindex;
 use baals::runtime::Runtime;
-use baals::storage::SledStorage;
+use baals::snapshot::{self, Snapshot};
+use baals::storage::{SledBackend, SledStorage, StorageError};
 use baals::sync::NoopSync;
 use baals::types::{format_hex, Address, ContractId, PublicKey, Transaction, TransactionPayload};
 
@@ -39,6 +44,16 @@ enum Commands {
         #[command(subcommand)]
         action: DevCommands,
     },
+    /// State snapshot operations
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotCommands,
+    },
+    /// Database maintenance
+    Db {
+        #[command(subcommand)]
+        action: DbCommands,
+    },
 }
 
 #[derive(Subcommand)]
@@ -99,6 +114,9 @@ enum TransactionCommands {
         /// Data payload (hex)
         #[arg(short, long)]
         data: String,
+        /// Indexed tag as name=value; may be repeated
+        #[arg(long = "tag")]
+        tags: Vec<String>,
     },
 }
 
@@ -143,6 +161,14 @@ enum DevCommands {
         /// Data directory
         #[arg(short, long, default_value = "./data")]
         data_dir: PathBuf,
+        /// Most data transaction bytes a wallet may store. Local admission
+        /// policy only: blocks imported from peers are applied regardless
+        #[arg(long)]
+        account_quota: Option<u64>,
+        /// Most code and storage bytes a contract may hold. Local admission
+        /// policy only: blocks imported from peers are applied regardless
+        #[arg(long)]
+        contract_quota: Option<u64>,
     },
     /// Generate a test block
     GenerateBlock,
@@ -150,6 +176,62 @@ enum DevCommands {
     ChainState,
 }
 
+#[derive(Subcommand)]
+enum SnapshotCommands {
+    /// Export the state at the chain tip
+    Export {
+        /// Data directory
+        #[arg(short, long, default_value = "./data")]
+        data_dir: PathBuf,
+        /// Snapshot output directory
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Bootstrap an empty data directory from a snapshot
+    Import {
+        /// Data directory
+        #[arg(short, long, default_value = "./data")]
+        data_dir: PathBuf,
+        /// Snapshot directory
+        #[arg(short, long)]
+        from: PathBuf,
+        /// Hash of the block the snapshot must have been taken at (hex)
+        #[arg(short, long)]
+        trusted_hash: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Check the chain and state for corruption
+    Verify {
+        /// Data directory
+        #[arg(short, long, default_value = "./data")]
+        data_dir: PathBuf,
+        /// Fix the issues that can be rebuilt from block bodies
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Rebuild state and indices by replaying every stored block
+    Reindex {
+        /// Data directory
+        #[arg(short, long, default_value = "./data")]
+        data_dir: PathBuf,
+    },
+}
+
+/// Open the sled database at `data_dir`, reporting schema migrations.
+fn open_storage(data_dir: impl AsRef<Path>) -> Result<SledStorage, StorageError> {
+    SledStorage::open_with_progress(SledBackend::open(data_dir)?, |migration| {
+        println!(
+            "Migrating database schema {} -> {}: {}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        );
+    })
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
@@ -290,7 +372,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         format_hex(&transaction.hash)
                     );
                 }
-                TransactionCommands::Data { key_file, data } => {
+                TransactionCommands::Data {
+                    key_file,
+                    data,
+                    tags,
+                } => {
                     let key_bytes = std::fs::read(key_file)?;
                     let key_array: [u8; 32] = key_bytes
                         .as_slice()
@@ -299,6 +385,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let signing_key = ed25519_dalek::SigningKey::from_bytes(&key_array);
                     let public_key = PublicKey::from(signing_key.verifying_key());
                     let data_bytes = hex::decode(data)?;
+                    let mut metadata = std::collections::BTreeMap::new();
+                    for tag in tags {
+                        let (name, value) = tag
+                            .split_once('=')
+                            .ok_or("Tags must be given as name=value")?;
+                        metadata.insert(name.to_string(), value.to_string());
+                    }
                     let timestamp = SystemTime::now()
                         .duration_since(UNIX_EPOCH)
                         .unwrap()
@@ -313,7 +406,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         signature: ed25519_dalek::Signature::from_bytes(&[0u8; 64]).into(),
                         gas_limit: 0,
                         priority: 0,
-                        metadata: None,
+                        metadata: (!metadata.is_empty()).then_some(metadata),
                     };
                     println!(
                         "Data transaction created: {}",
@@ -326,7 +419,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             // Use a dummy key for PoAConsensus
             let test_key = PublicKey::from_bytes(&[1u8; 32])?;
             let consensus = PoAConsensus::new(test_key, 1000);
-            let storage = SledStorage::new("./data")?;
+            let storage = open_storage("./data")?;
             let contract_engine = BaaLSContractEngine::new(storage.clone());
             let sync_layer = NoopSync;
             let runtime = Runtime::new(storage, consensus, contract_engine, sync_layer)?;
@@ -414,14 +507,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         Commands::Dev { action } => {
             match action {
-                DevCommands::Start { data_dir } => {
+                DevCommands::Start {
+                    data_dir,
+                    account_quota,
+                    contract_quota,
+                } => {
                     println!("Starting BaaLS node with data directory: {:?}", data_dir);
-                    let test_key = PublicKey::from_bytes(&[1u8; 32])?;
-                    let consensus = PoAConsensus::new(test_key, 1000);
-                    let storage = SledStorage::new(data_dir)?;
+                    // A dev node is its own authority, sealing with a fixed test key.
+                    let test_key = ed25519_dalek::SigningKey::from_bytes(&[1u8; 32]);
+                    let consensus = PoAConsensus::with_signing_key(test_key, 1000);
+                    let storage = open_storage(data_dir)?;
                     let contract_engine = BaaLSContractEngine::new(storage.clone());
                     let sync_layer = NoopSync;
-                    let runtime = Runtime::new(storage, consensus, contract_engine, sync_layer)?;
+                    let runtime = Runtime::new(storage, consensus, contract_engine, sync_layer)?
+                        .with_quotas(StorageQuotas {
+                            account_bytes: *account_quota,
+                            contract_bytes: *contract_quota,
+                        });
                     runtime.start()?;
                     println!("Node started successfully");
                 }
@@ -433,7 +535,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 DevCommands::ChainState => {
                     let test_key = PublicKey::from_bytes(&[1u8; 32])?;
                     let consensus = PoAConsensus::new(test_key, 1000);
-                    let storage = SledStorage::new("./data")?;
+                    let storage = open_storage("./data")?;
                     let contract_engine = BaaLSContractEngine::new(storage.clone());
                     let sync_layer = NoopSync;
                     let runtime = Runtime::new(storage, consensus, contract_engine, sync_layer)?;
@@ -444,10 +546,88 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         "  Latest Block: {}",
                         format_hex(&chain_state.latest_block_hash)
                     );
+                    println!("  Finalized Height: {}", chain_state.finalized_height);
                     println!("  Total Supply: {}", chain_state.total_supply);
                 }
             }
         }
+        Commands::Snapshot { action } => match action {
+            SnapshotCommands::Export { data_dir, out } => {
+                let storage = open_storage(data_dir)?;
+                let snapshot = snapshot::export(&storage)?;
+                snapshot.write_to_dir(out)?;
+                println!(
+                    "Exported snapshot at height {} ({} chunks)",
+                    snapshot.manifest.height(),
+                    snapshot.chunks.len()
+                );
+                println!("  Block: {}", format_hex(&snapshot.manifest.block.hash));
+                println!(
+                    "  State root: {}",
+                    format_hex(&snapshot.manifest.state_root())
+                );
+            }
+            SnapshotCommands::Import {
+                data_dir,
+                from,
+                trusted_hash,
+            } => {
+                let trusted_hash = <[u8; 32]>::try_from(hex::decode(trusted_hash)?.as_slice())
+                    .map_err(|_| "Invalid trusted hash length")?;
+                let snapshot = Snapshot::read_from_dir(from)?;
+                let storage = open_storage(data_dir)?;
+                let chain_state = snapshot::import(&storage, &snapshot, trusted_hash)?;
+                println!(
+                    "Imported snapshot at height {}: {}",
+                    chain_state.latest_block_index,
+                    format_hex(&chain_state.latest_block_hash)
+                );
+            }
+        },
+        Commands::Db { action } => match action {
+            DbCommands::Verify { data_dir, repair } => {
+                let storage = open_storage(data_dir)?;
+                let report = integrity::verify(&storage)?;
+                println!("Checked {} blocks", report.blocks_checked);
+                for issue in &report.issues {
+                    let note = if issue.is_repairable() {
+                        " (repairable)"
+                    } else {
+                        ""
+                    };
+                    println!("  {:?}{}", issue, note);
+                }
+                if report.is_ok() {
+                    println!("Database is consistent");
+                } else if *repair {
+                    let repaired = integrity::repair(&storage, &report)?;
+                    println!("Repaired {} of {} issues", repaired, report.issues.len());
+                } else {
+                    println!(
+                        "Found {} issues; rerun with --repair to fix the repairable ones",
+                        report.issues.len()
+                    );
+                }
+            }
+            DbCommands::Reindex { data_dir } => {
+                let storage = open_storage(data_dir)?;
+                if reindex::is_in_progress(&storage)? {
+                    println!("Resuming interrupted reindex");
+                }
+                let chain_state = reindex::reindex(&storage, |progress| {
+                    if progress.height % 1000 == 0 || progress.height == progress.target {
+                        println!("  Replayed block {}/{}", progress.height, progress.target);
+                    }
+                    ControlFlow::Continue(())
+                })?
+                .ok_or("Reindex stopped before reaching the tip")?;
+                println!(
+                    "Reindexed {} blocks, state root {}",
+                    chain_state.latest_block_index,
+                    format_hex(&chain_state.accounts_root_hash)
+                );
+            }
+        },
     }
 
     Ok(())
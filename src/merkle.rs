@@ -0,0 +1,504 @@
+//! Sparse Merkle tree over the global state.
+//!
+//! Every account, contract code and contract storage slot is a leaf keyed by a
+//! 256-bit hash of its identity (see [`account_key`], [`contract_code_key`] and
+//! [`contract_storage_key`]). One more leaf, at [`chain_params_key`], covers
+//! the chain state fields that are not derived from those (see
+//! [`chain_params_leaf`]). The tree is kept in compact form: a subtree that
+//! holds a single leaf is represented by that leaf, so its shape, and therefore
+//! the root, depends only on the set of leaves and not on insertion order.
+//!
+//! Nodes are content-addressed by their hash and never overwritten, so every
+//! historical root stays readable for as long as its nodes are kept.
+//! Updates are buffered in memory; [`SparseMerkleTree::into_new_nodes`]
+//! returns the nodes the caller must persist (the ledger adds them to the
+//! block's storage batch).
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::storage::{StateEntry, Storage, StorageError};
+use crate::types::{ChainState, ContractId, PublicKey, StateProof};
+
+/// Root of the tree with no leaves.
+pub const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+const LEAF_DOMAIN: u8 = 0;
+const INTERNAL_DOMAIN: u8 = 1;
+
+/// A stored node of the state tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrieNode {
+    Leaf { key: [u8; 32], value: Vec<u8> },
+    Internal { left: [u8; 32], right: [u8; 32] },
+}
+
+impl TrieNode {
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            TrieNode::Leaf { key, value } => leaf_hash(key, value),
+            TrieNode::Internal { left, right } => internal_hash(left, right),
+        }
+    }
+}
+
+pub fn leaf_hash(key: &[u8; 32], value: &[u8]) -> [u8; 32] {
+    leaf_hash_from_value_hash(key, &Sha256::digest(value).into())
+}
+
+pub fn leaf_hash_from_value_hash(key: &[u8; 32], value_hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_DOMAIN]);
+    hasher.update(key);
+    hasher.update(value_hash);
+    hasher.finalize().into()
+}
+
+pub fn internal_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([INTERNAL_DOMAIN]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Hash of an item in a [`merkle_root`] list.
+pub fn list_leaf_hash(item: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_DOMAIN]);
+    hasher.update(item);
+    hasher.finalize().into()
+}
+
+/// Root of a binary Merkle tree over an ordered list, e.g. a block's
+/// transaction hashes.
+///
+/// An unpaired node at the end of a level is carried up unchanged rather
+/// than hashed with itself, so no two different lists share a root.
+pub fn merkle_root(items: &[[u8; 32]]) -> [u8; 32] {
+    let mut level: Vec<[u8; 32]> = items.iter().map(list_leaf_hash).collect();
+    if level.is_empty() {
+        return EMPTY_ROOT;
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => internal_hash(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Sibling hashes proving `items[index]` under [`merkle_root`], bottom up.
+pub fn merkle_path(items: &[[u8; 32]], index: usize) -> Vec<[u8; 32]> {
+    let mut level: Vec<[u8; 32]> = items.iter().map(list_leaf_hash).collect();
+    let mut index = index;
+    let mut path = Vec::new();
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(index ^ 1) {
+            path.push(*sibling);
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => internal_hash(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        index /= 2;
+    }
+    path
+}
+
+/// Recompute a [`merkle_root`] from one item and its [`merkle_path`].
+///
+/// Returns `None` if the path does not fit a list of `count` items.
+pub fn merkle_root_from_path(
+    item: &[u8; 32],
+    index: usize,
+    count: usize,
+    path: &[[u8; 32]],
+) -> Option<[u8; 32]> {
+    if index >= count {
+        return None;
+    }
+    let mut node = list_leaf_hash(item);
+    let mut siblings = path.iter();
+    let (mut index, mut len) = (index, count);
+    while len > 1 {
+        if index % 2 == 1 {
+            node = internal_hash(siblings.next()?, &node);
+        } else if index + 1 < len {
+            node = internal_hash(&node, siblings.next()?);
+        }
+        index /= 2;
+        len = len.div_ceil(2);
+    }
+    siblings.next().is_none().then_some(node)
+}
+
+/// Bit `depth` of `key`, most significant first.
+pub fn key_bit(key: &[u8; 32], depth: usize) -> bool {
+    (key[depth / 8] >> (7 - depth % 8)) & 1 == 1
+}
+
+fn hashed_key(domain: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(domain);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Tree key of an account; the leaf value is the bincode-encoded `Account`.
+pub fn account_key(address: &PublicKey) -> [u8; 32] {
+    hashed_key(b"account", &[&address.to_bytes()])
+}
+
+/// Tree key of a contract's code; the leaf value is the SHA-256 of the code.
+pub fn contract_code_key(contract_id: &ContractId) -> [u8; 32] {
+    hashed_key(b"code", &[&contract_id.id])
+}
+
+/// Tree key of a contract storage slot; the leaf value is the raw value.
+pub fn contract_storage_key(contract_id: &ContractId, key: &[u8]) -> [u8; 32] {
+    hashed_key(b"storage", &[&contract_id.id, key])
+}
+
+/// Tree key of the chain parameters leaf.
+pub fn chain_params_key() -> [u8; 32] {
+    hashed_key(b"chain", &[])
+}
+
+/// Leaf value committing to the jailed authorities and the total supply of
+/// `chain_state`, or `None` while both still hold their genesis values so
+/// that chains which never jailed anyone keep their roots.
+pub fn chain_params_leaf(chain_state: &ChainState) -> Result<Option<Vec<u8>>, bincode::Error> {
+    if chain_state.jailed_authorities.is_empty() && chain_state.total_supply == 0 {
+        return Ok(None);
+    }
+    bincode::serialize(&(&chain_state.jailed_authorities, chain_state.total_supply)).map(Some)
+}
+
+/// A view of the state tree at some root, with pending updates.
+pub struct SparseMerkleTree<'a> {
+    storage: &'a dyn Storage,
+    root: [u8; 32],
+    new_nodes: BTreeMap<[u8; 32], TrieNode>,
+}
+
+impl<'a> SparseMerkleTree<'a> {
+    pub fn new(storage: &'a dyn Storage, root: [u8; 32]) -> Self {
+        Self {
+            storage,
+            root,
+            new_nodes: BTreeMap::new(),
+        }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    /// Nodes created by updates that are not yet in storage.
+    ///
+    /// Intermediate nodes superseded by later updates are left out.
+    pub fn into_new_nodes(mut self) -> Vec<TrieNode> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![self.root];
+        while let Some(hash) = stack.pop() {
+            if let Some(node) = self.new_nodes.remove(&hash) {
+                if let TrieNode::Internal { left, right } = &node {
+                    stack.extend([*left, *right]);
+                }
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+
+    fn load(&self, hash: &[u8; 32]) -> Result<TrieNode, StorageError> {
+        if let Some(node) = self.new_nodes.get(hash) {
+            return Ok(node.clone());
+        }
+        self.storage
+            .get_trie_node(hash)?
+            .ok_or(StorageError::NotFound)
+    }
+
+    fn store(&mut self, node: TrieNode) -> [u8; 32] {
+        let hash = node.hash();
+        self.new_nodes.insert(hash, node);
+        hash
+    }
+
+    /// Look up the value stored under `key`.
+    pub fn get(&self, key: &[u8; 32]) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut hash = self.root;
+        let mut depth = 0;
+        while hash != EMPTY_ROOT {
+            match self.load(&hash)? {
+                TrieNode::Leaf {
+                    key: leaf_key,
+                    value,
+                } => {
+                    return Ok((leaf_key == *key).then_some(value));
+                }
+                TrieNode::Internal { left, right } => {
+                    hash = if key_bit(key, depth) { right } else { left };
+                    depth += 1;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Prove the value (or absence) of `key` against the current root.
+    pub fn prove(&self, key: &[u8; 32]) -> Result<StateProof, StorageError> {
+        let mut siblings = Vec::new();
+        let mut hash = self.root;
+        let mut depth = 0;
+        while hash != EMPTY_ROOT {
+            match self.load(&hash)? {
+                TrieNode::Leaf {
+                    key: leaf_key,
+                    value,
+                } => {
+                    let (value, other_leaf) = if leaf_key == *key {
+                        (Some(value), None)
+                    } else {
+                        (None, Some((leaf_key, Sha256::digest(&value).into())))
+                    };
+                    return Ok(StateProof {
+                        key: *key,
+                        value,
+                        siblings,
+                        other_leaf,
+                    });
+                }
+                TrieNode::Internal { left, right } => {
+                    let (next, sibling) = if key_bit(key, depth) {
+                        (right, left)
+                    } else {
+                        (left, right)
+                    };
+                    siblings.push(sibling);
+                    hash = next;
+                    depth += 1;
+                }
+            }
+        }
+        Ok(StateProof {
+            key: *key,
+            value: None,
+            siblings,
+            other_leaf: None,
+        })
+    }
+
+    /// Set (`Some`) or remove (`None`) the value under `key`.
+    pub fn update(&mut self, key: [u8; 32], value: Option<Vec<u8>>) -> Result<(), StorageError> {
+        self.root = self.update_at(self.root, 0, key, value)?;
+        Ok(())
+    }
+
+    fn update_at(
+        &mut self,
+        hash: [u8; 32],
+        depth: usize,
+        key: [u8; 32],
+        value: Option<Vec<u8>>,
+    ) -> Result<[u8; 32], StorageError> {
+        if hash == EMPTY_ROOT {
+            return Ok(match value {
+                Some(value) => self.store(TrieNode::Leaf { key, value }),
+                None => EMPTY_ROOT,
+            });
+        }
+        match self.load(&hash)? {
+            TrieNode::Leaf { key: leaf_key, .. } if leaf_key == key => Ok(match value {
+                Some(value) => self.store(TrieNode::Leaf { key, value }),
+                None => EMPTY_ROOT,
+            }),
+            TrieNode::Leaf { key: leaf_key, .. } => match value {
+                // Removing an absent key leaves the tree unchanged.
+                None => Ok(hash),
+                Some(value) => {
+                    let leaf = self.store(TrieNode::Leaf { key, value });
+                    Ok(self.split(depth, (hash, leaf_key), (leaf, key)))
+                }
+            },
+            TrieNode::Internal { left, right } => {
+                let (left, right) = if key_bit(&key, depth) {
+                    (left, self.update_at(right, depth + 1, key, value)?)
+                } else {
+                    (self.update_at(left, depth + 1, key, value)?, right)
+                };
+                self.join(left, right)
+            }
+        }
+    }
+
+    /// Build the smallest subtree at `depth` holding two leaves with distinct keys.
+    fn split(
+        &mut self,
+        depth: usize,
+        (a, a_key): ([u8; 32], [u8; 32]),
+        (b, b_key): ([u8; 32], [u8; 32]),
+    ) -> [u8; 32] {
+        let a_bit = key_bit(&a_key, depth);
+        if a_bit == key_bit(&b_key, depth) {
+            let child = self.split(depth + 1, (a, a_key), (b, b_key));
+            let (left, right) = if a_bit {
+                (EMPTY_ROOT, child)
+            } else {
+                (child, EMPTY_ROOT)
+            };
+            self.store(TrieNode::Internal { left, right })
+        } else if a_bit {
+            self.store(TrieNode::Internal { left: b, right: a })
+        } else {
+            self.store(TrieNode::Internal { left: a, right: b })
+        }
+    }
+
+    /// Combine two children, collapsing a lone leaf into its parent's place.
+    fn join(&mut self, left: [u8; 32], right: [u8; 32]) -> Result<[u8; 32], StorageError> {
+        match (left == EMPTY_ROOT, right == EMPTY_ROOT) {
+            (true, true) => return Ok(EMPTY_ROOT),
+            (true, false) if matches!(self.load(&right)?, TrieNode::Leaf { .. }) => {
+                return Ok(right)
+            }
+            (false, true) if matches!(self.load(&left)?, TrieNode::Leaf { .. }) => return Ok(left),
+            _ => {}
+        }
+        Ok(self.store(TrieNode::Internal { left, right }))
+    }
+}
+
+/// Build the tree holding exactly `entries` and the chain parameters of
+/// `chain_state` from scratch, returning its root and the nodes it consists
+/// of.
+///
+/// Recomputes the state root committed by a block from the flat state, e.g.
+/// to check a snapshot or the database.
+pub fn state_tree_from_entries(
+    storage: &dyn Storage,
+    entries: &[StateEntry],
+    chain_state: &ChainState,
+) -> Result<([u8; 32], Vec<TrieNode>), StorageError> {
+    let mut tree = SparseMerkleTree::new(storage, EMPTY_ROOT);
+    if let Some(params) = chain_params_leaf(chain_state)? {
+        tree.update(chain_params_key(), Some(params))?;
+    }
+    for entry in entries {
+        match entry {
+            StateEntry::Account(address, account) => {
+                tree.update(account_key(address), Some(bincode::serialize(account)?))?
+            }
+            StateEntry::ContractCode(contract_id, code) => tree.update(
+                contract_code_key(contract_id),
+                Some(Sha256::digest(code).to_vec()),
+            )?,
+            StateEntry::ContractStorage(contract_id, key, value) => {
+                tree.update(contract_storage_key(contract_id, key), Some(value.clone()))?
+            }
+        }
+    }
+    let root = tree.root();
+    Ok((root, tree.into_new_nodes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn key(n: u8) -> [u8; 32] {
+        Sha256::digest([n]).into()
+    }
+
+    #[test]
+    fn test_root_is_independent_of_update_order() {
+        let storage = MemoryStorage::new();
+        let mut forward = SparseMerkleTree::new(&storage, EMPTY_ROOT);
+        for n in 0..20 {
+            forward.update(key(n), Some(vec![n])).unwrap();
+        }
+        let mut backward = SparseMerkleTree::new(&storage, EMPTY_ROOT);
+        for n in (0..20).rev() {
+            backward.update(key(n), Some(vec![n])).unwrap();
+        }
+        assert_ne!(forward.root(), EMPTY_ROOT);
+        assert_eq!(forward.root(), backward.root());
+        assert_eq!(forward.get(&key(7)).unwrap(), Some(vec![7]));
+        assert_eq!(forward.get(&key(42)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_state_proofs_verify_against_root() {
+        let storage = MemoryStorage::new();
+        let mut tree = SparseMerkleTree::new(&storage, EMPTY_ROOT);
+        for n in 0..10 {
+            tree.update(key(n), Some(vec![n])).unwrap();
+        }
+        let root = tree.root();
+
+        let present = tree.prove(&key(4)).unwrap();
+        assert_eq!(present.value, Some(vec![4]));
+        assert!(present.verify(&root, &key(4)));
+        assert!(!present.verify(&root, &key(5)));
+        let mut forged = present.clone();
+        forged.value = Some(vec![5]);
+        assert!(!forged.verify(&root, &key(4)));
+
+        let absent = tree.prove(&key(42)).unwrap();
+        assert_eq!(absent.value, None);
+        assert!(absent.verify(&root, &key(42)));
+        assert!(!absent.verify(&EMPTY_ROOT, &key(42)));
+    }
+
+    #[test]
+    fn test_list_paths_rebuild_root() {
+        for count in 1..=9u8 {
+            let items: Vec<[u8; 32]> = (0..count).map(key).collect();
+            let root = merkle_root(&items);
+            for (index, item) in items.iter().enumerate() {
+                let path = merkle_path(&items, index);
+                let count = items.len();
+                assert_eq!(merkle_root_from_path(item, index, count, &path), Some(root));
+                assert_ne!(
+                    merkle_root_from_path(&key(99), index, count, &path),
+                    Some(root)
+                );
+            }
+        }
+        assert_eq!(merkle_root(&[]), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_removing_leaves_restores_previous_root() {
+        let storage = MemoryStorage::new();
+        let mut tree = SparseMerkleTree::new(&storage, EMPTY_ROOT);
+        tree.update(key(1), Some(vec![1])).unwrap();
+        tree.update(key(2), Some(vec![2])).unwrap();
+        let two_leaves = tree.root();
+
+        tree.update(key(3), Some(vec![3])).unwrap();
+        tree.update(key(2), Some(vec![9])).unwrap();
+        tree.update(key(3), None).unwrap();
+        tree.update(key(2), Some(vec![2])).unwrap();
+        assert_eq!(tree.root(), two_leaves);
+
+        tree.update(key(1), None).unwrap();
+        tree.update(key(2), None).unwrap();
+        assert_eq!(tree.root(), EMPTY_ROOT);
+    }
+}
@@ -0,0 +1,252 @@
+//! Retention modes and pruning of historical chain data.
+//!
+//! Every applied block leaves behind its body (transactions, receipts and
+//! index entries), an undo record and the state tree nodes it wrote. An
+//! archive node keeps all of it; constrained devices can instead keep only
+//! the recent past. Pruning advances the [`PruneHorizon`] and deletes what
+//! falls below it in a single atomic batch, so an interrupted run leaves the
+//! database as it was. Block headers are never pruned.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::merkle::{key_bit, TrieNode, EMPTY_ROOT};
+use crate::storage::{
+    PruneHorizon, Storage, StorageBatch, StorageError, StorageOperation, TxPosition,
+};
+use crate::types::ChainState;
+
+/// How much history a node keeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetentionMode {
+    /// Keep everything.
+    #[default]
+    Archive,
+    /// Keep historical state (and the ability to reorganize) for the last
+    /// `keep_blocks` blocks; block bodies are kept.
+    Pruned { keep_blocks: u64 },
+    /// Keep only headers. Bodies and state are kept for the tip alone, since
+    /// the next block is built on top of it.
+    HeaderOnly,
+}
+
+impl RetentionMode {
+    /// The horizon this mode targets for a chain whose tip is at `tip`.
+    pub fn target_horizon(&self, tip: u64) -> PruneHorizon {
+        match *self {
+            RetentionMode::Archive => PruneHorizon::default(),
+            RetentionMode::Pruned { keep_blocks } => PruneHorizon {
+                state: (tip + 1).saturating_sub(keep_blocks.max(1)),
+                bodies: 0,
+            },
+            RetentionMode::HeaderOnly => PruneHorizon {
+                state: tip,
+                bodies: tip,
+            },
+        }
+    }
+}
+
+/// Prune everything `mode` no longer retains for the chain at `chain_state`.
+///
+/// The horizon only moves forward: switching back to a more generous mode
+/// does not bring data back. Callers must keep blocks from being applied
+/// while this runs, as a block may write back a state tree node that is
+/// about to be deleted.
+///
+/// Returns the horizon in effect afterwards.
+pub fn prune<S: Storage + ?Sized>(
+    storage: &S,
+    chain_state: &ChainState,
+    mode: RetentionMode,
+) -> Result<PruneHorizon, StorageError> {
+    let current = storage.get_prune_horizon()?;
+    let target = mode.target_horizon(chain_state.latest_block_index);
+    let next = PruneHorizon {
+        state: current.state.max(target.state),
+        bodies: current.bodies.max(target.bodies),
+    };
+    if next == current {
+        return Ok(current);
+    }
+
+    let mut batch = StorageBatch::default();
+    for height in current.bodies..next.bodies {
+        let Some(mut block) = storage.get_block_by_height(height)? else {
+            continue;
+        };
+        for (index, tx) in block.transactions.iter().enumerate() {
+            batch.ops.push(StorageOperation::DeleteTransaction(tx.hash));
+            batch.ops.push(StorageOperation::DeleteReceipt {
+                block_hash: block.hash,
+                tx_index_in_block: index as u32,
+            });
+            batch.ops.push(StorageOperation::UnindexTransaction {
+                block_hash: block.hash,
+                tx_index_in_block: index as u32,
+            });
+            batch.unindex_history(
+                tx,
+                TxPosition {
+                    height,
+                    tx_index_in_block: index as u32,
+                },
+            );
+        }
+        block.transactions.clear();
+        batch.ops.push(StorageOperation::PutBlock(Box::new(block)));
+    }
+
+    if next.state > current.state {
+        // Reverting block `h` restores the state at `h - 1`, so its undo
+        // record is useless once that state is gone.
+        for height in current.state + 1..=next.state {
+            if let Some(header) = storage.get_block_header_by_height(height)? {
+                batch
+                    .ops
+                    .push(StorageOperation::DeleteBlockUndo(header.hash()?));
+            }
+        }
+        for hash in unreachable_trie_nodes(storage, current.state, next.state, chain_state)? {
+            batch.ops.push(StorageOperation::DeleteTrieNode(hash));
+        }
+    }
+
+    batch.ops.push(StorageOperation::PutPruneHorizon(next));
+    storage.apply_batch(batch)?;
+    Ok(next)
+}
+
+/// Bits in a state tree key.
+const KEY_BITS: usize = 256;
+
+/// A state tree node and where it sits: the path to it follows the first
+/// `depth` bits of `path`. A leaf's path is its key, which leads to it at
+/// whatever depth it ends up.
+struct PlacedNode {
+    hash: [u8; 32],
+    path: [u8; 32],
+    depth: usize,
+}
+
+/// Nodes of the tree at `old_root` that the tree at `new_root` no longer
+/// holds in the same place. Only subtrees whose hashes differ are visited,
+/// so the cost follows the size of the change, not of the state.
+fn replaced_nodes<S: Storage + ?Sized>(
+    storage: &S,
+    old_root: [u8; 32],
+    new_root: [u8; 32],
+) -> Result<Vec<PlacedNode>, StorageError> {
+    let mut replaced = Vec::new();
+    let mut pending = vec![(old_root, new_root, [0u8; 32], 0)];
+    while let Some((old, new, path, depth)) = pending.pop() {
+        if old == EMPTY_ROOT || old == new {
+            continue;
+        }
+        match storage.get_trie_node(&old)? {
+            Some(TrieNode::Leaf { key, .. }) => replaced.push(PlacedNode {
+                hash: old,
+                path: key,
+                depth: KEY_BITS,
+            }),
+            Some(TrieNode::Internal { left, right }) => {
+                replaced.push(PlacedNode {
+                    hash: old,
+                    path,
+                    depth,
+                });
+                let (new_left, new_right) = match storage.get_trie_node(&new)? {
+                    Some(TrieNode::Internal { left, right }) if new != EMPTY_ROOT => (left, right),
+                    _ => (EMPTY_ROOT, EMPTY_ROOT),
+                };
+                let mut right_path = path;
+                right_path[depth / 8] |= 0x80 >> (depth % 8);
+                pending.push((left, new_left, path, depth + 1));
+                pending.push((right, new_right, right_path, depth + 1));
+            }
+            None => {}
+        }
+    }
+    Ok(replaced)
+}
+
+/// Whether the tree at `root` still holds `node`.
+fn holds<S: Storage + ?Sized>(
+    storage: &S,
+    root: [u8; 32],
+    node: &PlacedNode,
+) -> Result<bool, StorageError> {
+    let mut hash = root;
+    let mut depth = 0;
+    while hash != node.hash {
+        if hash == EMPTY_ROOT || depth >= node.depth {
+            return Ok(false);
+        }
+        let Some(TrieNode::Internal { left, right }) = storage.get_trie_node(&hash)? else {
+            return Ok(false);
+        };
+        hash = if key_bit(&node.path, depth) {
+            right
+        } else {
+            left
+        };
+        depth += 1;
+    }
+    Ok(true)
+}
+
+/// State tree nodes only needed by the states at heights `from..to`.
+///
+/// These are the nodes each block in `from + 1..=to` replaced, minus any
+/// that a state from `to` onwards holds again, as identical subtrees can
+/// come back (a storage slot reset to an earlier value, say). Nodes written
+/// by blocks that were later reverted are not in any of these trees and
+/// are left alone.
+fn unreachable_trie_nodes<S: Storage + ?Sized>(
+    storage: &S,
+    from: u64,
+    to: u64,
+    chain_state: &ChainState,
+) -> Result<Vec<[u8; 32]>, StorageError> {
+    let mut replaced = Vec::new();
+    let mut previous = storage
+        .get_block_header_by_height(from)?
+        .map(|header| header.state_root);
+    for height in from + 1..=to {
+        let root = storage
+            .get_block_header_by_height(height)?
+            .map(|header| header.state_root);
+        if let (Some(old), Some(new)) = (previous, root) {
+            replaced.extend(replaced_nodes(storage, old, new)?);
+        }
+        previous = root;
+    }
+
+    let mut retained = vec![chain_state.accounts_root_hash];
+    for height in to..=chain_state.latest_block_index {
+        if let Some(header) = storage.get_block_header_by_height(height)? {
+            retained.push(header.state_root);
+        }
+    }
+    retained.sort_unstable();
+    retained.dedup();
+
+    let mut unreachable = HashSet::new();
+    for node in replaced {
+        if unreachable.contains(&node.hash) {
+            continue;
+        }
+        let mut held = false;
+        for root in &retained {
+            if holds(storage, *root, &node)? {
+                held = true;
+                break;
+            }
+        }
+        if !held {
+            unreachable.insert(node.hash);
+        }
+    }
+    Ok(unreachable.into_iter().collect())
+}
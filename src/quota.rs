@@ -0,0 +1,83 @@
+//! Storage accounting and per-owner quotas.
+//!
+//! The ledger keeps a byte count for every address that stores data on the
+//! node. A wallet is charged the payload and tags of every data transaction
+//! it sends; those bytes stay charged, since pruning only drops them on some
+//! nodes. A contract is charged its code and storage slots as they currently
+//! stand, so removing a slot gives its bytes back. A node restored from a
+//! snapshot has no data transaction history and starts wallets at zero.
+//!
+//! # Quotas are a local admission policy
+//!
+//! Quotas are not consensus rules and block validation never checks them.
+//! They only decide what this node queues and puts in the blocks it
+//! produces: a transaction is refused if its sender's stored and queued
+//! data would exceed the quota, and sealing leaves out transactions that
+//! would grow an owner past its quota. Blocks imported from peers are
+//! applied whatever they store, and the usage they add is recorded even past
+//! the quota, so nodes with different quotas, or a node restored from a
+//! snapshot, never disagree on the chain. Owners already over a lowered
+//! quota may still shrink.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::StateEntry;
+use crate::types::{Address, Transaction, TransactionPayload};
+
+/// Byte limits per owner; `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageQuotas {
+    /// Data transaction bytes a wallet may write.
+    pub account_bytes: Option<u64>,
+    /// Code and storage bytes a contract may hold.
+    pub contract_bytes: Option<u64>,
+}
+
+impl StorageQuotas {
+    /// The quota that applies to `address`.
+    pub fn limit_for(&self, address: &Address) -> Option<u64> {
+        match address {
+            Address::Wallet(_) => self.account_bytes,
+            Address::Contract(_) => self.contract_bytes,
+        }
+    }
+}
+
+/// Bytes a transaction charges its sender: the payload and tags of a data
+/// transaction, nothing for any other.
+pub fn data_bytes(tx: &Transaction) -> u64 {
+    let TransactionPayload::Data { data } = &tx.payload else {
+        return 0;
+    };
+    let tags: usize = tx
+        .tags()
+        .map(|(name, value)| name.len() + value.len())
+        .sum();
+    (data.len() + tags) as u64
+}
+
+/// Bytes a contract storage slot takes, zero if it is empty.
+pub fn slot_bytes(key: &[u8], value: Option<&[u8]>) -> u64 {
+    value.map_or(0, |value| (key.len() + value.len()) as u64)
+}
+
+/// Usage of every contract in a full state, e.g. one restored from a
+/// snapshot.
+pub fn state_usage(entries: &[StateEntry]) -> BTreeMap<Address, u64> {
+    let mut usage = BTreeMap::new();
+    for entry in entries {
+        let (contract_id, bytes) = match entry {
+            StateEntry::Account(..) => continue,
+            StateEntry::ContractCode(contract_id, code) => (contract_id, code.len() as u64),
+            StateEntry::ContractStorage(contract_id, key, value) => {
+                (contract_id, slot_bytes(key, Some(value)))
+            }
+        };
+        *usage
+            .entry(Address::Contract(contract_id.clone()))
+            .or_default() += bytes;
+    }
+    usage
+}
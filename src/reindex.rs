@@ -0,0 +1,160 @@
+//! Rebuilding all derived data by replaying the stored blocks.
+//!
+//! Everything but the blocks themselves (accounts, contract code and
+//! storage, the state tree, transaction records, receipts, the transaction,
+//! address history and tag indices, storage usage, undo records and the
+//! chain state) is derived from applying them, so it can be thrown away and
+//! recomputed, e.g. after the state got corrupted or its model changed.
+//!
+//! State that predates the chain (accounts funded directly in storage before
+//! the runtime was first started) is not in any block. It is recorded as the
+//! genesis state when the chain is initialized and replayed from there, so
+//! reindexing needs the full history of an archive node.
+//!
+//! Reindexing commits after every block and keeps a checkpoint in the `meta`
+//! tree; an interrupted or stopped run continues where it left off when
+//! started again. Until then [`crate::runtime::Runtime::new`] refuses the
+//! database.
+
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::contracts::BaaLSContractEngine;
+use crate::ledger::{Ledger, LedgerError};
+use crate::quota;
+use crate::storage::{
+    KvBackend, KvStorage, KvTree, PruneHorizon, Storage, StorageBatch, StorageError,
+    StorageOperation, REINDEX_CHECKPOINT_KEY,
+};
+use crate::types::{ChainState, CryptoError};
+
+#[derive(Debug, Error)]
+pub enum ReindexError {
+    #[error("Storage error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Ledger error: {0}")]
+    LedgerError(#[from] LedgerError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] bincode::Error),
+    #[error("Crypto error: {0}")]
+    CryptoError(#[from] CryptoError),
+    #[error("Cannot reindex a pruned database (history kept from height {0})")]
+    Pruned(u64),
+    #[error("Block {0} is missing")]
+    MissingBlock(u64),
+    #[error("The genesis state is not recorded")]
+    MissingGenesisState,
+}
+
+/// Reported after every replayed block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReindexProgress {
+    /// Height of the block just applied.
+    pub height: u64,
+    /// Height of the tip being rebuilt.
+    pub target: u64,
+}
+
+/// Survives interruptions; its presence means the derived data is wiped.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    target: u64,
+    finalized_height: u64,
+}
+
+/// Whether a reindex was interrupted and has to be resumed before the
+/// database can be used.
+pub fn is_in_progress<B: KvBackend>(storage: &KvStorage<B>) -> Result<bool, StorageError> {
+    storage.is_reindexing()
+}
+
+/// Wipe the derived data of `storage` and rebuild it from the canonical
+/// blocks, or resume an interrupted run.
+///
+/// No runtime may use `storage` meanwhile. `progress` is called after every
+/// block; returning [`ControlFlow::Break`] stops the run there, to be resumed
+/// by the next call. Returns the rebuilt chain state, or `None` if stopped.
+pub fn reindex<B: KvBackend + Clone>(
+    storage: &KvStorage<B>,
+    mut progress: impl FnMut(ReindexProgress) -> ControlFlow<()>,
+) -> Result<Option<ChainState>, ReindexError> {
+    let checkpoint = match storage
+        .backend()
+        .get(KvTree::Meta, REINDEX_CHECKPOINT_KEY)?
+    {
+        Some(encoded) => bincode::deserialize(&encoded)?,
+        None => start(storage)?,
+    };
+
+    let storage = Arc::new(storage.clone());
+    let contract_engine = Arc::new(BaaLSContractEngine::new(storage.as_ref().clone()));
+    let ledger = Ledger::new(Arc::clone(&storage), contract_engine);
+    ledger.initialize_chain()?;
+    let mut chain_state = storage.get_chain_state()?.ok_or(StorageError::NotFound)?;
+
+    for height in chain_state.latest_block_index + 1..=checkpoint.target {
+        let block = storage
+            .get_block_by_height(height)?
+            .ok_or(ReindexError::MissingBlock(height))?;
+        ledger.validate_block(&block, &chain_state)?;
+        ledger.apply_block(block, &mut chain_state)?;
+        let step = progress(ReindexProgress {
+            height,
+            target: checkpoint.target,
+        });
+        if step.is_break() && height < checkpoint.target {
+            return Ok(None);
+        }
+    }
+
+    // Finality is not recorded in blocks; restore it from before the wipe.
+    chain_state.finalized_height = checkpoint.finalized_height.min(checkpoint.target);
+    let mut batch = StorageBatch::default();
+    batch.ops.push(StorageOperation::PutChainState(Box::new(
+        chain_state.clone(),
+    )));
+    let mut writes = storage.encode_batch(batch)?;
+    writes.push((KvTree::Meta, REINDEX_CHECKPOINT_KEY.to_vec(), None));
+    storage.backend().write_batch(writes)?;
+    Ok(Some(chain_state))
+}
+
+/// Replace the derived data with the pre-chain state and record the
+/// checkpoint, in one batch.
+fn start<B: KvBackend>(storage: &KvStorage<B>) -> Result<Checkpoint, ReindexError> {
+    let horizon = storage.get_prune_horizon()?;
+    if horizon != PruneHorizon::default() {
+        return Err(ReindexError::Pruned(horizon.state.max(horizon.bodies)));
+    }
+    let checkpoint = Checkpoint {
+        target: storage.get_chain_height()?,
+        finalized_height: storage
+            .get_chain_state()?
+            .map_or(0, |chain_state| chain_state.finalized_height),
+    };
+
+    let genesis = storage
+        .get_genesis_state()?
+        .ok_or(ReindexError::MissingGenesisState)?;
+    let mut batch = StorageBatch::default();
+    for (address, bytes) in quota::state_usage(&genesis) {
+        batch
+            .ops
+            .push(StorageOperation::PutStorageUsage(address, bytes));
+    }
+    batch
+        .ops
+        .extend(genesis.into_iter().map(StorageOperation::from));
+    let mut writes = storage.derived_data_deletes()?;
+    writes.extend(storage.encode_batch(batch)?);
+    writes.push((
+        KvTree::Meta,
+        REINDEX_CHECKPOINT_KEY.to_vec(),
+        Some(bincode::serialize(&checkpoint)?),
+    ));
+    storage.backend().write_batch(writes)?;
+    Ok(checkpoint)
+}
@@ -7,15 +7,30 @@
 use ed25519_dalek::SigningKey;
 use rand::rngs::OsRng;
 use rand::Rng;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::consensus::{ConsensusEngine, ConsensusError};
+use crate::consensus::{
+    ConsensusEngine, ConsensusError, EquivocationDetector, FinalityError, FinalityGadget,
+    FinalityOutput,
+};
 use crate::contracts::BaaLSContractEngine;
+use crate::integrity::{self, IntegrityReport};
 use crate::ledger::{Ledger, LedgerError};
-use crate::storage::{Storage, StorageError};
-use crate::sync::SyncLayer;
-use crate::types::{Account, Block, ChainState, ContractId, CryptoError, PublicKey, Transaction};
+use crate::merkle::{self, SparseMerkleTree};
+use crate::pruning::{self, RetentionMode};
+use crate::quota::{self, StorageQuotas};
+use crate::snapshot::{self, Snapshot, SnapshotError};
+use crate::storage::{
+    Order, PruneHorizon, Storage, StorageError, TagQuery, TaggedTransaction, TxPosition,
+};
+use crate::sync::{MessageHandler, NetworkMessage, SyncError, SyncLayer};
+use crate::types::{
+    Account, Address, Block, BlockHeader, ChainState, CommitCertificate, ContractId, CryptoError,
+    EquivocationEvidence, PublicKey, SignedHeader, StateProof, Transaction, TransactionPayload,
+    TransactionProof, TransactionReceipt, Vote,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum RuntimeError {
@@ -25,6 +40,8 @@ pub enum RuntimeError {
     LedgerError(#[from] LedgerError),
     #[error("Consensus error: {0}")]
     ConsensusError(#[from] ConsensusError),
+    #[error("Finality error: {0}")]
+    FinalityError(#[from] FinalityError),
     #[error("Crypto error: {0}")]
     CryptoError(#[from] CryptoError),
     #[error("Failed to initialize chain")]
@@ -37,8 +54,36 @@ pub enum RuntimeError {
     AlreadyRunning,
     #[error("Runtime not running")]
     NotRunning,
+    #[error("No canonical block at height {0}")]
+    UnknownHeight(u64),
+    #[error("Snapshot error: {0}")]
+    SnapshotError(#[from] SnapshotError),
+    #[error("Sync error: {0}")]
+    SyncError(#[from] SyncError),
+    #[error("A reindex was interrupted; finish it before starting the node")]
+    ReindexInProgress,
 }
 
+/// How many heights below the tip signed headers are remembered for
+/// equivocation detection.
+pub const EVIDENCE_WINDOW: u64 = 256;
+
+/// Most tag index entries [`Runtime::get_tagged_transactions`] examines
+/// per call.
+pub const MAX_TAG_SCAN: usize = 1024;
+
+/// One page of [`Runtime::get_tagged_transactions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaggedPage {
+    pub matches: Vec<(TaggedTransaction, Transaction)>,
+    /// Where the next page starts; `None` once the query is exhausted.
+    pub next: Option<TaggedTransaction>,
+}
+
+/// Most headers sent in answer to one
+/// [`NetworkMessage::GetHeaders`] request.
+pub const MAX_HEADERS_PER_RESPONSE: u64 = 512;
+
 /// The main runtime orchestrator for BaaLS blockchain.
 ///
 /// The runtime connects storage, consensus, ledger, and sync components
@@ -55,9 +100,17 @@ pub struct Runtime<S: Storage, C: ConsensusEngine, Y: SyncLayer> {
     consensus: Arc<C>,
     mempool: Arc<Mutex<Vec<Transaction>>>,
     chain_state: Arc<Mutex<ChainState>>,
+    /// Serializes block production, which waits for consensus without
+    /// holding the mempool or chain state locks.
+    seal_lock: Arc<Mutex<()>>,
     _is_running: Arc<Mutex<bool>>,
     sync_layer: Arc<Y>,
     contract_engine_arc: Arc<BaaLSContractEngine<S>>,
+    equivocation_detector: Arc<Mutex<EquivocationDetector>>,
+    pending_evidence: Arc<Mutex<Vec<EquivocationEvidence>>>,
+    finality: Option<Arc<Mutex<FinalityGadget>>>,
+    retention: RetentionMode,
+    quotas: StorageQuotas,
 }
 
 impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static> Runtime<S, C, Y> {
@@ -74,13 +127,18 @@ impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static>
     ///
     /// # Errors
     ///
-    /// Returns an error if chain initialization fails.
+    /// Returns an error if chain initialization fails, or
+    /// [`RuntimeError::ReindexInProgress`] if an interrupted reindex left the
+    /// derived data incomplete.
     pub fn new(
         storage: S,
         consensus: C,
         contract_engine: BaaLSContractEngine<S>,
         sync_layer: Y,
     ) -> Result<Self, RuntimeError> {
+        if storage.is_reindexing()? {
+            return Err(RuntimeError::ReindexInProgress);
+        }
         let storage_arc = Arc::new(storage);
         let contract_engine_arc = Arc::new(contract_engine);
         let ledger = Arc::new(Ledger::new(
@@ -101,12 +159,145 @@ impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static>
             consensus: Arc::new(consensus),
             mempool: Arc::new(Mutex::new(Vec::new())),
             chain_state: Arc::new(Mutex::new(initial_chain_state)),
+            seal_lock: Arc::new(Mutex::new(())),
             _is_running: Arc::new(Mutex::new(false)),
             sync_layer: Arc::new(sync_layer),
             contract_engine_arc,
+            equivocation_detector: Arc::new(Mutex::new(EquivocationDetector::new())),
+            pending_evidence: Arc::new(Mutex::new(Vec::new())),
+            finality: None,
+            retention: RetentionMode::Archive,
+            quotas: StorageQuotas::default(),
         })
     }
 
+    /// Set how much history [`Runtime::prune`] keeps. Defaults to
+    /// [`RetentionMode::Archive`].
+    pub fn with_retention(mut self, retention: RetentionMode) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn retention(&self) -> RetentionMode {
+        self.retention
+    }
+
+    /// Take part in BFT finality (see [`crate::consensus::finality`]) among
+    /// the consensus engine's authorities: as a voter when `signing_key` is
+    /// one of them, otherwise as an observer assembling certificates.
+    ///
+    /// Votes are gossiped through the sync layer. Votes received from peers
+    /// are fed in through [`Runtime::handle_vote`], which the runtime's
+    /// [`MessageHandler`] implementation calls.
+    pub fn with_finality(mut self, signing_key: Option<SigningKey>) -> Result<Self, RuntimeError> {
+        let chain_state = self.get_chain_state()?;
+        let mut gadget = FinalityGadget::new(
+            self.consensus.authorities(),
+            signing_key,
+            chain_state.finalized_height,
+        );
+        gadget.set_jailed(&chain_state.jailed_authorities);
+        self.finality = Some(Arc::new(Mutex::new(gadget)));
+        Ok(self)
+    }
+
+    /// Limit how many bytes each account and contract may store, when
+    /// admitting transactions and producing blocks (see [`crate::quota`]).
+    /// Imported blocks are not checked. Defaults to no limits.
+    pub fn with_quotas(mut self, quotas: StorageQuotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn quotas(&self) -> StorageQuotas {
+        self.quotas
+    }
+
+    /// Bytes currently charged to `address` (see [`crate::quota`]).
+    pub fn get_storage_usage(&self, address: &Address) -> Result<u64, RuntimeError> {
+        Ok(self.storage.get_storage_usage(address)?)
+    }
+
+    /// Prune data the retention mode no longer keeps.
+    ///
+    /// Holds the chain state lock for the duration, so no block is applied
+    /// concurrently.
+    pub fn prune(&self) -> Result<PruneHorizon, RuntimeError> {
+        let chain_state = self.chain_state.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
+        })?;
+        Ok(pruning::prune(
+            self.storage.as_ref(),
+            &chain_state,
+            self.retention,
+        )?)
+    }
+
+    /// Snapshot the state at the current tip.
+    ///
+    /// Holds the chain state lock, so the snapshot is consistent.
+    pub fn export_snapshot(&self) -> Result<Snapshot, RuntimeError> {
+        let _chain_state = self.chain_state.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
+        })?;
+        Ok(snapshot::export(self.storage.as_ref())?)
+    }
+
+    /// Check the database with [`integrity::verify`].
+    ///
+    /// Holds the chain state lock, so no blocks are applied meanwhile.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport, RuntimeError> {
+        let _chain_state = self.chain_state.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
+        })?;
+        Ok(integrity::verify(self.storage.as_ref())?)
+    }
+
+    /// Run [`Runtime::prune`] every `interval` on a background task.
+    ///
+    /// The task stops at the first failed run and completes with its error,
+    /// so the caller decides whether to report it, restart pruning or shut
+    /// down. Returns `None` in archive mode or when called outside a tokio
+    /// runtime.
+    pub fn spawn_pruner(
+        &self,
+        interval: Duration,
+    ) -> Option<tokio::task::JoinHandle<Result<(), RuntimeError>>> {
+        if self.retention == RetentionMode::Archive {
+            return None;
+        }
+        let handle = tokio::runtime::Handle::try_current().ok()?;
+        let storage = Arc::clone(&self.storage);
+        let chain_state = Arc::clone(&self.chain_state);
+        let retention = self.retention;
+        Some(handle.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let storage = Arc::clone(&storage);
+                let chain_state = Arc::clone(&chain_state);
+                let result = tokio::task::spawn_blocking(move || {
+                    let chain_state = chain_state.lock().map_err(|_| {
+                        RuntimeError::InvalidTransaction(
+                            "Failed to acquire chain state lock".to_string(),
+                        )
+                    })?;
+                    pruning::prune(storage.as_ref(), &chain_state, retention)
+                        .map_err(RuntimeError::from)
+                })
+                .await;
+                match result {
+                    Ok(result) => {
+                        result?;
+                    }
+                    Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                    // The tokio runtime is shutting down.
+                    Err(_) => return Ok(()),
+                }
+            }
+        }))
+    }
+
     pub fn generate_keypair() -> Result<SigningKey, RuntimeError> {
         let mut csprng = OsRng;
         // Use random bytes to create a signing key
@@ -150,51 +341,121 @@ impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static>
                 "Invalid transaction signature".to_string(),
             ));
         }
+        transaction
+            .check_tags()
+            .map_err(RuntimeError::InvalidTransaction)?;
+        if let TransactionPayload::SubmitEvidence { evidence } = &transaction.payload {
+            self.check_offender(evidence)?;
+        }
 
         // Check sender account nonce from current chain state
-        let _current_chain_state = self.chain_state.lock().map_err(|_| {
-            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
-        })?;
-        let sender_pk = transaction.sender;
-        let sender_account = self.storage.get_account(&sender_pk)?.unwrap_or({
-            // If account doesn't exist, allow it for now, Ledger will create it for transfers.
-            // For production, stricter rules might apply, e.g., requiring initial balance.
-            Account::Wallet {
-                balance: 0,
-                nonce: 0,
-            }
-        });
+        {
+            let _current_chain_state = self.chain_state.lock().map_err(|_| {
+                RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
+            })?;
+            let sender_pk = transaction.sender;
+            let sender_account = self.storage.get_account(&sender_pk)?.unwrap_or({
+                // If account doesn't exist, allow it for now, Ledger will create it for transfers.
+                // For production, stricter rules might apply, e.g., requiring initial balance.
+                Account::Wallet {
+                    balance: 0,
+                    nonce: 0,
+                }
+            });
 
-        if transaction.nonce <= sender_account.nonce() {
-            return Err(RuntimeError::InvalidTransaction(format!(
-                "Invalid nonce: expected greater than {}, got {}",
-                sender_account.nonce(),
-                transaction.nonce
-            )));
+            if transaction.nonce <= sender_account.nonce() {
+                return Err(RuntimeError::InvalidTransaction(format!(
+                    "Invalid nonce: expected greater than {}, got {}",
+                    sender_account.nonce(),
+                    transaction.nonce
+                )));
+            }
+            // For MVP, we're not handling out-of-order nonces in mempool explicitly.
+            // This will be handled by ledger during block application.
         }
-        // For MVP, we're not handling out-of-order nonces in mempool explicitly.
-        // This will be handled by ledger during block application.
 
         let hash = transaction.hash;
-        self.mempool
-            .lock()
-            .map_err(|_| {
+        {
+            let mut mempool = self.mempool.lock().map_err(|_| {
                 RuntimeError::InvalidTransaction("Failed to acquire mempool lock".to_string())
-            })?
-            .push(transaction);
+            })?;
+            // Refuse data the sender has no room for, counting what it has
+            // queued already, rather than leave it for every seal to skip.
+            let sender = Address::Wallet(transaction.sender);
+            let bytes = quota::data_bytes(&transaction);
+            if let Some(limit) = self.quotas.limit_for(&sender).filter(|_| bytes > 0) {
+                let queued: u64 = mempool
+                    .iter()
+                    .filter(|pending| pending.sender == transaction.sender)
+                    .map(quota::data_bytes)
+                    .sum();
+                let needed = self.storage.get_storage_usage(&sender)? + queued + bytes;
+                if needed > limit {
+                    return Err(RuntimeError::InvalidTransaction(format!(
+                        "Storage quota exceeded: {} bytes needed, {} allowed",
+                        needed, limit
+                    )));
+                }
+            }
+            mempool.push(transaction);
+        }
         println!("Transaction submitted: {}", crate::types::format_hex(&hash));
+
+        if self.consensus.seal_on_submit() {
+            if let Err(e) = self.seal() {
+                // A transaction that cannot be sealed must not stay behind to
+                // fail every later seal.
+                self.mempool
+                    .lock()
+                    .map_err(|_| {
+                        RuntimeError::InvalidTransaction(
+                            "Failed to acquire mempool lock".to_string(),
+                        )
+                    })?
+                    .retain(|pending| pending.hash != hash);
+                return Err(e);
+            }
+        }
         Ok(())
     }
 
     /// Produce a new block from pending transactions.
     ///
+    /// Async wrapper around [`Runtime::seal`], kept for callers that drive
+    /// block production from a tokio task. On a multi-threaded tokio runtime
+    /// the wait for consensus (e.g. a Raft quorum) moves off the worker
+    /// thread, so the tasks delivering peer acknowledgements keep running.
+    ///
+    /// # Errors
+    ///
+    /// See [`Runtime::seal`].
+    pub async fn produce_block(&self) -> Result<Block, RuntimeError> {
+        match tokio::runtime::Handle::try_current().map(|h| h.runtime_flavor()) {
+            Ok(tokio::runtime::RuntimeFlavor::MultiThread) => {
+                tokio::task::block_in_place(|| self.seal())
+            }
+            _ => self.seal(),
+        }
+    }
+
+    /// Seal a new block from pending transactions.
+    ///
     /// This method:
-    /// 1. Collects transactions from the mempool
+    /// 1. Collects transactions from the mempool, leaving out those that
+    ///    would exceed a storage quota
     /// 2. Uses the consensus engine to create a new block
     /// 3. Validates and applies the block to the ledger
     /// 4. Broadcasts the block to peers (if sync is enabled)
     /// 5. Clears processed transactions from the mempool
     ///
+    /// Engines that seal on demand (e.g. [`crate::consensus::SealMode::Manual`])
+    /// are driven entirely through this call.
+    ///
+    /// The mempool and chain state are only locked while the block is built
+    /// and while it is applied, not while the consensus engine waits for
+    /// agreement, so transactions can be submitted and blocks queried
+    /// meanwhile.
+    ///
     /// # Returns
     ///
     /// The newly created and applied block.
@@ -202,53 +463,420 @@ impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static>
     /// # Errors
     ///
     /// Returns an error if:
-    /// - Mempool is empty
+    /// - Mempool is empty, or holds only transactions left out for quotas
     /// - Block generation fails
     /// - Block validation fails
     /// - Block application to ledger fails
-    pub async fn produce_block(&self) -> Result<Block, RuntimeError> {
-        let mempool = self.mempool.lock().map_err(|_| {
-            RuntimeError::InvalidTransaction("Failed to acquire mempool lock".to_string())
+    pub fn seal(&self) -> Result<Block, RuntimeError> {
+        let _sealing = self.seal_lock.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire seal lock".to_string())
         })?;
-        if mempool.is_empty() {
+        let pending = self
+            .mempool
+            .lock()
+            .map_err(|_| {
+                RuntimeError::InvalidTransaction("Failed to acquire mempool lock".to_string())
+            })?
+            .clone();
+        if pending.is_empty() {
             return Err(ConsensusError::NoPendingTransactions.into());
         }
 
-        let current_chain_state = self.chain_state.lock().map_err(|_| {
-            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
-        })?;
+        let current_chain_state = self.get_chain_state()?;
         let prev_block = self
             .storage
             .get_block(&current_chain_state.latest_block_hash)?
             .ok_or(StorageError::NotFound)?;
 
-        let new_block =
-            self.consensus
-                .generate_block(&mempool, &prev_block, &current_chain_state)?;
+        // Commit to the execution results before the block is hashed and
+        // sealed; the same execution is committed below. Transactions that
+        // would take an owner past its quota are left out and stay queued,
+        // with the later ones of their senders, which need their nonces.
+        let mut candidates = pending;
+        let (mut new_block, execution) = loop {
+            if candidates.is_empty() {
+                return Err(ConsensusError::NoPendingTransactions.into());
+            }
+            let block =
+                self.consensus
+                    .generate_block(&candidates, &prev_block, &current_chain_state)?;
+            let execution = self.ledger.execute_block(&block, &current_chain_state)?;
+            let skipped: Vec<&Transaction> = execution
+                .over_quota(&self.quotas)
+                .into_iter()
+                .map(|position| &block.transactions[position])
+                .collect();
+            if skipped.is_empty() {
+                break (block, execution);
+            }
+            candidates = block
+                .transactions
+                .iter()
+                .filter(|tx| {
+                    !skipped
+                        .iter()
+                        .any(|skip| skip.sender == tx.sender && skip.nonce <= tx.nonce)
+                })
+                .cloned()
+                .collect();
+        };
+        let roots = execution.roots();
+        new_block.state_root = roots.state_root;
+        new_block.receipts_root = roots.receipts_root;
+        self.consensus.seal_block(&mut new_block)?;
 
-        // Release mempool lock before acquiring chain_state lock to avoid deadlock if called from external thread
-        drop(mempool);
+        // Validate and apply block to ledger
+        self.validate_block(&new_block, &current_chain_state)?;
+        self.ledger
+            .validate_block(&new_block, &current_chain_state)?;
+        // Remember our own header, so a conflicting one is caught; done
+        // before committing, so a failure leaves nothing applied.
+        if let Some(header) = new_block.signed_header() {
+            self.observe_header(header)?;
+        }
+        self.consensus.agree_on_block(&new_block)?;
 
-        let mut current_chain_state_mut = self.chain_state.lock().map_err(|_| {
+        let mut current_chain_state = self.chain_state.lock().map_err(|_| {
             RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
         })?;
-
-        // Validate and apply block to ledger
-        self.ledger
-            .validate_block(&new_block, &current_chain_state_mut)?;
-        // Pass contract_engine to apply_block
+        // Blocks imported while waiting for consensus moved the tip.
+        if current_chain_state.latest_block_hash != prev_block.hash {
+            return Err(ConsensusError::MismatchedPrevHash.into());
+        }
         self.ledger
-            .apply_block(new_block.clone(), &mut current_chain_state_mut)?;
+            .commit_block(new_block.clone(), execution, &mut current_chain_state)?;
+        drop(current_chain_state);
+        // Transactions submitted meanwhile stay queued.
+        self.update_mempool(std::slice::from_ref(&new_block), &[])?;
 
         println!(
             "Block produced and applied: {}",
             crate::types::format_hex(&new_block.hash)
         );
 
-        // Optionally broadcast the new block
+        self.blocks_committed(std::slice::from_ref(&new_block));
+        self.broadcast_block(&new_block);
+        Ok(new_block)
+    }
+
+    /// Import a block produced elsewhere (e.g. committed by a Raft leader or
+    /// received from a peer).
+    ///
+    /// A block extending the current tip is applied directly. A block on a
+    /// competing branch is stored as a side block, and if the consensus
+    /// engine's fork choice prefers it over the current tip the chain is
+    /// reorganized onto it. Transactions of newly canonical blocks are removed
+    /// from the local mempool; transactions of orphaned blocks that the new
+    /// branch does not include are returned to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the block's parent is unknown, if consensus or
+    /// ledger validation fails, or if the block cannot be applied.
+    pub fn import_block(&self, block: Block) -> Result<(), RuntimeError> {
+        // Check for equivocation before validation: a conflicting block at an
+        // already-filled height is exactly what validation would reject.
+        if let Some(header) = block.signed_header() {
+            self.observe_header(header)?;
+        }
+
+        let mut current_chain_state = self.chain_state.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
+        })?;
+        if block.prev_hash == current_chain_state.latest_block_hash {
+            self.validate_block(&block, &current_chain_state)?;
+            self.ledger.validate_block(&block, &current_chain_state)?;
+            self.ledger
+                .apply_block(block.clone(), &mut current_chain_state)?;
+            drop(current_chain_state);
+            self.update_mempool(std::slice::from_ref(&block), &[])?;
+            println!("Block imported: {}", crate::types::format_hex(&block.hash));
+            self.blocks_committed(std::slice::from_ref(&block));
+            return Ok(());
+        }
+
+        if self.storage.get_block(&block.hash)?.is_some() {
+            // Already known, either canonical or as a side block.
+            return Ok(());
+        }
+        if self.storage.get_block(&block.prev_hash)?.is_none() {
+            return Err(LedgerError::BlockValidation(format!(
+                "Unknown parent block: {}",
+                crate::types::format_hex(&block.prev_hash)
+            ))
+            .into());
+        }
+        self.storage.put_side_block(&block)?;
+
+        let tip = self
+            .storage
+            .get_block(&current_chain_state.latest_block_hash)?
+            .ok_or(StorageError::NotFound)?;
+        if !self.consensus.prefers(&block, &tip) {
+            println!(
+                "Side block stored: {}",
+                crate::types::format_hex(&block.hash)
+            );
+            return Ok(());
+        }
+
+        let reorganization = self.ledger.reorganize(
+            &block,
+            &mut current_chain_state,
+            |candidate, chain_state| -> Result<(), RuntimeError> {
+                self.validate_block(candidate, chain_state)
+            },
+        )?;
+        drop(current_chain_state);
+        self.update_mempool(&reorganization.applied, &reorganization.reverted)?;
+        self.blocks_committed(&reorganization.applied);
+        println!(
+            "Chain reorganized to {}: {} block(s) reverted, {} applied",
+            crate::types::format_hex(&block.hash),
+            reorganization.reverted.len(),
+            reorganization.applied.len()
+        );
+        Ok(())
+    }
+
+    /// Consensus validation of `block`, including the checks that need the
+    /// engine's authority set.
+    fn validate_block(&self, block: &Block, chain_state: &ChainState) -> Result<(), RuntimeError> {
+        self.consensus.validate_block(block, chain_state)?;
+        for tx in &block.transactions {
+            if let TransactionPayload::SubmitEvidence { evidence } = &tx.payload {
+                self.check_offender(evidence)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Evidence is only meaningful against one of the engine's authorities.
+    fn check_offender(&self, evidence: &EquivocationEvidence) -> Result<(), RuntimeError> {
+        if !self.consensus.authorities().contains(&evidence.offender()) {
+            return Err(LedgerError::InvalidEvidence(format!(
+                "{:?} is not an authority",
+                evidence.offender()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Follow-up work once `blocks` became canonical: forget headers too old
+    /// to matter for equivocation and start finality votes on the blocks.
+    ///
+    /// The blocks are committed by then, so failures are only logged.
+    fn blocks_committed(&self, blocks: &[Block]) {
+        let Some(tip) = blocks.last() else {
+            return;
+        };
+        self.equivocation_detector
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .prune_below(tip.index.saturating_sub(EVIDENCE_WINDOW));
+
+        let Some(finality) = &self.finality else {
+            return;
+        };
+        let output = {
+            let mut gadget = finality.lock().unwrap_or_else(|e| e.into_inner());
+            // Jailing only changes when blocks are applied or reverted.
+            match self.get_chain_state() {
+                Ok(chain_state) => gadget.set_jailed(&chain_state.jailed_authorities),
+                Err(e) => eprintln!("Finality skipped the jailed set: {}", e),
+            }
+            let mut output = FinalityOutput::default();
+            for block in blocks {
+                let next = gadget.on_block(block);
+                output.broadcast.extend(next.broadcast);
+                output.evidence.extend(next.evidence);
+                output.finalized = next.finalized.or(output.finalized);
+            }
+            output
+        };
+        if let Err(e) = self.handle_finality_output(output) {
+            eprintln!("Error finalizing blocks: {}", e);
+        }
+    }
+
+    /// Feed a finality vote received from a peer to the finality gadget.
+    ///
+    /// Votes for heights that are already final are ignored. Does nothing
+    /// unless the runtime was built [`Runtime::with_finality`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the gadget rejects the vote, or if the
+    /// certificate it completes cannot be applied.
+    pub fn handle_vote(&self, vote: Vote) -> Result<(), RuntimeError> {
+        let Some(finality) = &self.finality else {
+            return Ok(());
+        };
+        let output = match finality
+            .lock()
+            .map_err(|_| {
+                RuntimeError::InvalidTransaction("Failed to acquire finality lock".to_string())
+            })?
+            .on_vote(vote)
+        {
+            Ok(output) => output,
+            Err(FinalityError::AlreadyFinalized(_)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        self.handle_finality_output(output)
+    }
+
+    /// Gossip the gadget's votes, report its evidence and apply its
+    /// certificate.
+    fn handle_finality_output(&self, output: FinalityOutput) -> Result<(), RuntimeError> {
+        for vote in output.broadcast {
+            self.broadcast_vote(vote);
+        }
+        for evidence in output.evidence {
+            self.report_evidence(evidence)?;
+        }
+        if let Some(certificate) = output.finalized {
+            self.apply_commit_certificate(&certificate, &self.consensus.authorities())?;
+        }
+        Ok(())
+    }
+
+    /// Drop transactions included in `applied` from the mempool and return
+    /// those of `orphaned` blocks that did not make it into the new chain.
+    fn update_mempool(&self, applied: &[Block], orphaned: &[Block]) -> Result<(), RuntimeError> {
+        let included: HashSet<[u8; 32]> = applied
+            .iter()
+            .flat_map(|b| b.transactions.iter().map(|tx| tx.hash))
+            .collect();
+        let mut mempool = self.mempool.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire mempool lock".to_string())
+        })?;
+        mempool.retain(|tx| !included.contains(&tx.hash));
+        // Orphaned blocks are listed tip first; re-queue oldest first so
+        // nonces stay in order.
+        for block in orphaned.iter().rev() {
+            for tx in &block.transactions {
+                if !included.contains(&tx.hash) && !mempool.iter().any(|t| t.hash == tx.hash) {
+                    mempool.push(tx.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a signed header (from a block or from gossip) for equivocation.
+    ///
+    /// Headers not signed by an authority are ignored. Evidence found is
+    /// queued for [`Runtime::take_pending_evidence`] and gossiped to peers.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an internal lock is poisoned.
+    pub fn observe_header(
+        &self,
+        header: SignedHeader,
+    ) -> Result<Option<EquivocationEvidence>, RuntimeError> {
+        // Anyone can sign a header; only authorities' are worth remembering.
+        if !self.consensus.authorities().contains(&header.signer) {
+            return Ok(None);
+        }
+        let evidence = self
+            .equivocation_detector
+            .lock()
+            .map_err(|_| {
+                RuntimeError::InvalidTransaction("Failed to acquire detector lock".to_string())
+            })?
+            .observe_header(header);
+        if let Some(evidence) = &evidence {
+            self.report_evidence(evidence.clone())?;
+        }
+        Ok(evidence)
+    }
+
+    /// Queue evidence for on-chain submission and gossip it to peers.
+    ///
+    /// Evidence against an authority that is already jailed is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the evidence does not verify or does not name an
+    /// authority.
+    pub fn report_evidence(&self, evidence: EquivocationEvidence) -> Result<(), RuntimeError> {
+        if !evidence.verify() {
+            return Err(RuntimeError::InvalidTransaction(
+                "Equivocation evidence does not verify".to_string(),
+            ));
+        }
+        self.check_offender(&evidence)?;
+        if self
+            .get_chain_state()?
+            .jailed_authorities
+            .contains(&evidence.offender())
+        {
+            return Ok(());
+        }
+        eprintln!(
+            "Equivocation by authority {} at height {}",
+            crate::types::format_hex(&evidence.offender().to_bytes()),
+            evidence.height()
+        );
+        {
+            let mut pending = self.pending_evidence.lock().map_err(|_| {
+                RuntimeError::InvalidTransaction("Failed to acquire evidence lock".to_string())
+            })?;
+            if pending.contains(&evidence) {
+                return Ok(());
+            }
+            pending.push(evidence.clone());
+        }
+
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let sync_layer_clone = Arc::clone(&self.sync_layer);
+            handle.spawn(async move {
+                let peers = sync_layer_clone.discover_peers().await.unwrap_or_default();
+                if let Err(e) = sync_layer_clone.broadcast_evidence(&evidence, &peers).await {
+                    eprintln!("Error broadcasting evidence: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Evidence collected so far, to be wrapped in
+    /// `TransactionPayload::SubmitEvidence` transactions and submitted.
+    pub fn take_pending_evidence(&self) -> Result<Vec<EquivocationEvidence>, RuntimeError> {
+        Ok(std::mem::take(&mut *self.pending_evidence.lock().map_err(
+            |_| RuntimeError::InvalidTransaction("Failed to acquire evidence lock".to_string()),
+        )?))
+    }
+
+    /// Gossip a finality vote to peers in the background, like
+    /// [`Runtime::broadcast_block`].
+    fn broadcast_vote(&self, vote: Vote) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
         let sync_layer_clone = Arc::clone(&self.sync_layer);
-        let new_block_clone = new_block.clone();
-        tokio::spawn(async move {
+        handle.spawn(async move {
+            let peers = sync_layer_clone.discover_peers().await.unwrap_or_default();
+            if let Err(e) = sync_layer_clone.broadcast_vote(&vote, &peers).await {
+                eprintln!("Error broadcasting vote: {}", e);
+            }
+        });
+    }
+
+    /// Broadcast a block to peers in the background.
+    ///
+    /// Broadcasting needs a tokio runtime; when sealing from plain sync code
+    /// (tests, instant-seal dev chains) there is none and the block stays local.
+    fn broadcast_block(&self, block: &Block) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let sync_layer_clone = Arc::clone(&self.sync_layer);
+        let new_block_clone = block.clone();
+        handle.spawn(async move {
             let peers = sync_layer_clone.discover_peers().await.unwrap_or_else(|e| {
                 eprintln!("Error discovering peers: {}", e);
                 Vec::new()
@@ -260,17 +888,6 @@ impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static>
                 eprintln!("Error broadcasting block: {}", e);
             }
         });
-
-        // Clear included transactions from mempool (this would be more sophisticated in real impl)
-        // For MVP, we clear all for simplicity after block generation.
-        self.mempool
-            .lock()
-            .map_err(|_| {
-                RuntimeError::InvalidTransaction("Failed to acquire mempool lock".to_string())
-            })?
-            .clear();
-
-        Ok(new_block)
     }
 
     pub fn get_chain_state(&self) -> Result<ChainState, RuntimeError> {
@@ -287,8 +904,302 @@ impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static>
         Ok(self.storage.get_block(hash)?)
     }
 
+    /// Store a commit certificate and mark its block (and all before it) final.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the certificate does not verify against
+    /// `authorities` or does not match the canonical chain.
+    pub fn apply_commit_certificate(
+        &self,
+        certificate: &CommitCertificate,
+        authorities: &[PublicKey],
+    ) -> Result<(), RuntimeError> {
+        let mut current_chain_state = self.chain_state.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
+        })?;
+        self.ledger
+            .apply_commit_certificate(certificate, authorities, &mut current_chain_state)?;
+        Ok(())
+    }
+
+    pub fn get_commit_certificate(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<CommitCertificate>, RuntimeError> {
+        Ok(self.storage.get_commit_certificate(block_hash)?)
+    }
+
+    /// Look up an applied transaction.
+    ///
+    /// Once block bodies have been pruned, a hash that is not found may
+    /// belong to a pruned block, and the node cannot tell the two apart:
+    /// this then fails with [`StorageError::Pruned`] instead of returning
+    /// `None`.
     pub fn get_transaction(&self, tx_hash: &[u8; 32]) -> Result<Option<Transaction>, RuntimeError> {
-        Ok(self.storage.get_transaction(tx_hash)?)
+        self.unless_pruned(self.storage.get_transaction(tx_hash)?)
+    }
+
+    pub fn get_transactions_by_block(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Vec<Transaction>, RuntimeError> {
+        Ok(self.storage.get_transactions_by_block(block_hash)?)
+    }
+
+    /// One page of a block's transactions with their positions in it.
+    ///
+    /// Pass the position of the last transaction of the previous page as
+    /// `after` to get the next one.
+    pub fn get_transactions_by_block_page(
+        &self,
+        block_hash: &[u8; 32],
+        after: Option<u32>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(u32, Transaction)>, RuntimeError> {
+        Ok(self
+            .storage
+            .get_transactions_by_block_page(block_hash, after, order, limit)?)
+    }
+
+    /// One page of the canonical transactions `address` sent or received,
+    /// oldest first for [`Order::Ascending`].
+    ///
+    /// Pass the position of the last transaction of the previous page as
+    /// `after` to get the next one. Transactions whose block body has been
+    /// pruned are no longer listed.
+    pub fn get_transactions_for_address(
+        &self,
+        address: &Address,
+        after: Option<TxPosition>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(TxPosition, Transaction)>, RuntimeError> {
+        let mut page = Vec::new();
+        for (position, tx_hash) in self
+            .storage
+            .get_address_transactions(address, after, order, limit)?
+        {
+            let tx = self
+                .storage
+                .get_transaction(&tx_hash)?
+                .ok_or(StorageError::NotFound)?;
+            page.push((position, tx));
+        }
+        Ok(page)
+    }
+
+    /// One page of the canonical data transactions matching `query` whose
+    /// other tags include every entry of `filters`.
+    ///
+    /// Only `query` is served by the tag index; `filters` are checked on
+    /// the transactions it yields, at most [`MAX_TAG_SCAN`] of them per
+    /// call. A page can therefore hold fewer than `limit` matches, even
+    /// none, and still not be the last: pass its `next` as `after` to
+    /// continue.
+    pub fn get_tagged_transactions(
+        &self,
+        query: &TagQuery,
+        filters: &BTreeMap<String, String>,
+        after: Option<&TaggedTransaction>,
+        order: Order,
+        limit: usize,
+    ) -> Result<TaggedPage, RuntimeError> {
+        let mut matches = Vec::new();
+        let mut cursor = after.cloned();
+        let mut scanned = 0;
+        loop {
+            let batch = limit
+                .saturating_sub(matches.len())
+                .min(MAX_TAG_SCAN - scanned);
+            if batch == 0 {
+                return Ok(TaggedPage {
+                    matches,
+                    next: cursor,
+                });
+            }
+            let entries =
+                self.storage
+                    .get_tagged_transactions(query, cursor.as_ref(), order, batch)?;
+            let exhausted = entries.len() < batch;
+            scanned += entries.len();
+            for tagged in entries {
+                let tx = self
+                    .storage
+                    .get_transaction(&tagged.tx_hash)?
+                    .ok_or(StorageError::NotFound)?;
+                let tags: BTreeMap<&str, &str> = tx.tags().collect();
+                let accepted = filters
+                    .iter()
+                    .all(|(name, value)| tags.get(name.as_str()) == Some(&value.as_str()));
+                cursor = Some(tagged.clone());
+                if accepted {
+                    matches.push((tagged, tx));
+                }
+            }
+            if exhausted {
+                return Ok(TaggedPage {
+                    matches,
+                    next: None,
+                });
+            }
+        }
+    }
+
+    /// Receipt of transaction `tx_hash` in block `block_hash`.
+    ///
+    /// Like [`Runtime::get_transaction`], fails with
+    /// [`StorageError::Pruned`] instead of returning `None` once block
+    /// bodies have been pruned.
+    pub fn get_receipt(
+        &self,
+        block_hash: &[u8; 32],
+        tx_hash: &[u8; 32],
+    ) -> Result<Option<TransactionReceipt>, RuntimeError> {
+        let receipt = match self.tx_position(block_hash, tx_hash)? {
+            Some(index) => self.storage.get_receipt(block_hash, index)?,
+            None => None,
+        };
+        self.unless_pruned(receipt)
+    }
+
+    /// Position of `tx_hash` in block `block_hash`, if the block is known
+    /// and contains it.
+    fn tx_position(
+        &self,
+        block_hash: &[u8; 32],
+        tx_hash: &[u8; 32],
+    ) -> Result<Option<u32>, RuntimeError> {
+        Ok(self.storage.get_block(block_hash)?.and_then(|block| {
+            block
+                .transactions
+                .iter()
+                .position(|tx| tx.hash == *tx_hash)
+                .map(|index| index as u32)
+        }))
+    }
+
+    /// `found`, unless it is missing while block bodies have been pruned.
+    /// See [`Runtime::get_transaction`].
+    fn unless_pruned<T>(&self, found: Option<T>) -> Result<Option<T>, RuntimeError> {
+        if found.is_none() {
+            let horizon = self.storage.get_prune_horizon()?;
+            if horizon.bodies > 0 {
+                return Err(StorageError::Pruned(horizon.bodies - 1).into());
+            }
+        }
+        Ok(found)
+    }
+
+    /// Canonical headers from `from_height` on, at most `max_count` of them.
+    ///
+    /// Serves header-only sync ([`crate::sync::NetworkMessage::GetHeaders`]).
+    pub fn get_headers(
+        &self,
+        from_height: u64,
+        max_count: u64,
+    ) -> Result<Vec<BlockHeader>, RuntimeError> {
+        let mut headers = Vec::new();
+        for height in from_height..from_height.saturating_add(max_count) {
+            match self.storage.get_block_header_by_height(height)? {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Snapshot of the transactions waiting in the mempool.
+    pub fn pending_transactions(&self) -> Result<Vec<Transaction>, RuntimeError> {
+        Ok(self
+            .mempool
+            .lock()
+            .map_err(|_| {
+                RuntimeError::InvalidTransaction("Failed to acquire mempool lock".to_string())
+            })?
+            .clone())
+    }
+
+    /// Merkle proof of an account against the current tip's state root.
+    pub fn prove_account(&self, address: &PublicKey) -> Result<StateProof, RuntimeError> {
+        self.prove_state(&merkle::account_key(address))
+    }
+
+    /// Merkle proof of a contract storage key against the current tip's
+    /// state root.
+    pub fn prove_contract_storage(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+    ) -> Result<StateProof, RuntimeError> {
+        self.prove_state(&merkle::contract_storage_key(contract_id, key))
+    }
+
+    fn prove_state(&self, key: &[u8; 32]) -> Result<StateProof, RuntimeError> {
+        let chain_state = self.chain_state.lock().map_err(|_| {
+            RuntimeError::InvalidTransaction("Failed to acquire chain state lock".to_string())
+        })?;
+        let tree = SparseMerkleTree::new(self.storage.as_ref(), chain_state.accounts_root_hash);
+        Ok(tree.prove(key)?)
+    }
+
+    /// Merkle proof that `tx_hash` is included in the block `block_hash`.
+    ///
+    /// Returns `None` if the block is unknown or does not contain the
+    /// transaction.
+    pub fn prove_transaction(
+        &self,
+        block_hash: &[u8; 32],
+        tx_hash: &[u8; 32],
+    ) -> Result<Option<TransactionProof>, RuntimeError> {
+        let Some(block) = self.storage.get_block(block_hash)? else {
+            return Ok(None);
+        };
+        let hashes: Vec<[u8; 32]> = block.transactions.iter().map(|tx| tx.hash).collect();
+        let Some(index) = hashes.iter().position(|hash| hash == tx_hash) else {
+            return Ok(None);
+        };
+        Ok(Some(TransactionProof {
+            tx_hash: *tx_hash,
+            index: index as u32,
+            tx_count: hashes.len() as u32,
+            siblings: merkle::merkle_path(&hashes, index),
+        }))
+    }
+
+    /// The receipt of `tx_hash` in block `block_hash` and a Merkle proof
+    /// of it, checked with [`BlockHeader::verify_receipt`].
+    ///
+    /// Returns `None` if the block is unknown or does not contain the
+    /// transaction.
+    pub fn prove_receipt(
+        &self,
+        block_hash: &[u8; 32],
+        tx_hash: &[u8; 32],
+    ) -> Result<Option<(TransactionReceipt, TransactionProof)>, RuntimeError> {
+        let Some(index) = self.tx_position(block_hash, tx_hash)? else {
+            return self.unless_pruned(None);
+        };
+        let receipts = self.storage.get_block_receipts(block_hash)?;
+        let hashes = receipts
+            .iter()
+            .map(TransactionReceipt::hash)
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(receipt) = receipts.into_iter().nth(index as usize) else {
+            return Ok(None);
+        };
+        let proof = TransactionProof {
+            tx_hash: hashes[index as usize],
+            index,
+            tx_count: hashes.len() as u32,
+            siblings: merkle::merkle_path(&hashes, index as usize),
+        };
+        Ok(Some((receipt, proof)))
+    }
+
+    pub fn consensus(&self) -> &C {
+        self.consensus.as_ref()
     }
 
     pub fn contract_engine(&self) -> &BaaLSContractEngine<S> {
@@ -332,7 +1243,1212 @@ impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static>
             .map_err(RuntimeError::StorageError)
     }
 
+    /// The account as it was right after the canonical block at `height` was
+    /// applied.
+    ///
+    /// Read from the state tree under that block's `state_root`, so only
+    /// state written by blocks is visible. Fails with
+    /// [`StorageError::Pruned`] if that state has been pruned.
+    pub fn get_account_at(
+        &self,
+        address: &PublicKey,
+        height: u64,
+    ) -> Result<Option<Account>, RuntimeError> {
+        let root = self.state_root_at(height)?;
+        let tree = SparseMerkleTree::new(self.storage.as_ref(), root);
+        let encoded = tree.get(&merkle::account_key(address))?;
+        Ok(encoded
+            .map(|e| bincode::deserialize(&e))
+            .transpose()
+            .map_err(StorageError::from)?)
+    }
+
+    /// A contract storage value as it was right after the canonical block at
+    /// `height` was applied.
+    pub fn contract_storage_read_at(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+        height: u64,
+    ) -> Result<Option<Vec<u8>>, RuntimeError> {
+        let root = self.state_root_at(height)?;
+        let tree = SparseMerkleTree::new(self.storage.as_ref(), root);
+        Ok(tree.get(&merkle::contract_storage_key(contract_id, key))?)
+    }
+
+    fn state_root_at(&self, height: u64) -> Result<[u8; 32], RuntimeError> {
+        let header = self
+            .storage
+            .get_block_header_by_height(height)?
+            .ok_or(RuntimeError::UnknownHeight(height))?;
+        if height < self.storage.get_prune_horizon()?.state {
+            return Err(StorageError::Pruned(height).into());
+        }
+        Ok(header.state_root)
+    }
+
     pub fn storage(&self) -> &S {
         &self.storage
     }
 }
+
+/// Requests and consensus gossip from peers: header requests, finality
+/// votes and equivocation evidence. Register a shared runtime with the
+/// network, e.g. `CustomSync::set_message_handler`.
+impl<S: Storage + 'static, C: ConsensusEngine + 'static, Y: SyncLayer + 'static> MessageHandler
+    for Runtime<S, C, Y>
+{
+    fn handle_message(&self, from: &PublicKey, message: NetworkMessage) {
+        let result = match message {
+            NetworkMessage::GetHeaders {
+                from_height,
+                max_count,
+            } => self
+                .get_headers(from_height, max_count.min(MAX_HEADERS_PER_RESPONSE))
+                .and_then(|headers| {
+                    self.sync_layer
+                        .send_to(from, NetworkMessage::HeadersResponse { headers })
+                        .map_err(RuntimeError::from)
+                }),
+            NetworkMessage::FinalityVote(vote) => self.handle_vote(*vote),
+            NetworkMessage::Evidence(evidence) => self.report_evidence(*evidence),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Rejected message from peer: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::{InstantSealConsensus, ManualClock, PoAConsensus, SealMode};
+    use crate::integrity::Issue;
+    use crate::reindex;
+    use crate::storage::{MemoryStorage, StateEntry, StorageBatch, StorageOperation};
+    use crate::sync::NoopSync;
+    use crate::types::{
+        Address, TransactionPayload, TransactionSignature, MAX_TAGS, MAX_TAG_NAME_LEN,
+        MAX_TAG_VALUE_LEN,
+    };
+    use std::ops::ControlFlow;
+
+    type TestRuntime = Runtime<MemoryStorage, InstantSealConsensus<ManualClock>, NoopSync>;
+    type PoARuntime = Runtime<MemoryStorage, PoAConsensus, NoopSync>;
+
+    fn runtime(clock: ManualClock, funded: &[PublicKey]) -> TestRuntime {
+        runtime_with_mode(SealMode::Manual, clock, funded)
+    }
+
+    fn runtime_with_mode(mode: SealMode, clock: ManualClock, funded: &[PublicKey]) -> TestRuntime {
+        let storage = MemoryStorage::new();
+        for address in funded {
+            storage
+                .put_account(
+                    address,
+                    &Account::Wallet {
+                        balance: 100,
+                        nonce: 0,
+                    },
+                )
+                .unwrap();
+        }
+        let contract_engine = BaaLSContractEngine::new(storage.clone());
+        let consensus = InstantSealConsensus::with_clock(mode, clock);
+        Runtime::new(storage, consensus, contract_engine, NoopSync).unwrap()
+    }
+
+    fn key(seed: u8) -> (SigningKey, PublicKey) {
+        let signing_key = SigningKey::from_bytes(&[seed; 32]);
+        let public_key = PublicKey::from(signing_key.verifying_key());
+        (signing_key, public_key)
+    }
+
+    fn transfer(from: &SigningKey, to: PublicKey, nonce: u64, amount: u64) -> Transaction {
+        let mut tx = Transaction {
+            hash: [0; 32],
+            sender: PublicKey::from(from.verifying_key()),
+            nonce,
+            timestamp: 0,
+            recipient: Address::Wallet(to),
+            payload: TransactionPayload::Transfer { amount },
+            signature: TransactionSignature::from_bytes(&[0; 64]).unwrap(),
+            gas_limit: 0,
+            priority: 0,
+            metadata: None,
+        };
+        tx.sign(from).unwrap();
+        tx
+    }
+
+    fn balance(runtime: &TestRuntime, address: &PublicKey) -> u64 {
+        match runtime.get_account(address).unwrap() {
+            Some(Account::Wallet { balance, .. }) => balance,
+            other => panic!("unexpected account {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sealed_block_is_persisted_with_its_transactions() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let runtime = runtime(ManualClock::new(10), &[alice]);
+
+        let first = transfer(&alice_key, bob, 1, 30);
+        let second = transfer(&alice_key, bob, 2, 20);
+        runtime.submit_transaction(first.clone()).unwrap();
+        runtime.submit_transaction(second.clone()).unwrap();
+        let block = runtime.seal().unwrap();
+
+        assert_eq!(runtime.get_block_by_height(1).unwrap(), Some(block.clone()));
+        assert_eq!(runtime.get_block(&block.hash).unwrap(), Some(block.clone()));
+        assert_eq!(runtime.get_transaction(&first.hash).unwrap(), Some(first));
+        assert_eq!(
+            runtime.get_transactions_by_block(&block.hash).unwrap(),
+            block.transactions
+        );
+        let last = runtime
+            .get_transactions_by_block_page(&block.hash, None, Order::Descending, 1)
+            .unwrap();
+        assert_eq!(last, [(1, block.transactions[1].clone())]);
+        let rest = runtime
+            .get_transactions_by_block_page(&block.hash, Some(1), Order::Descending, 10)
+            .unwrap();
+        assert_eq!(rest, [(0, block.transactions[0].clone())]);
+        assert_eq!(
+            runtime.get_chain_state().unwrap().latest_block_hash,
+            block.hash
+        );
+        assert_eq!(
+            runtime
+                .storage()
+                .get_chain_state()
+                .unwrap()
+                .unwrap()
+                .latest_block_index,
+            1
+        );
+        assert_eq!(balance(&runtime, &alice), 50);
+        assert_eq!(balance(&runtime, &bob), 50);
+    }
+
+    #[test]
+    fn test_instant_seal_seals_on_submit_and_drops_failed_transactions() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let runtime = runtime_with_mode(SealMode::Instant, ManualClock::new(10), &[alice]);
+
+        let first = transfer(&alice_key, bob, 1, 30);
+        runtime.submit_transaction(first.clone()).unwrap();
+        let block = runtime.get_block_by_height(1).unwrap().unwrap();
+        assert_eq!(block.transactions, vec![first]);
+        assert!(runtime.pending_transactions().unwrap().is_empty());
+
+        // An overdraft fails to seal and is not left in the mempool.
+        let overdraft = transfer(&alice_key, bob, 2, 1_000);
+        assert!(runtime.submit_transaction(overdraft).is_err());
+        assert!(runtime.pending_transactions().unwrap().is_empty());
+        assert_eq!(runtime.get_chain_state().unwrap().latest_block_index, 1);
+
+        runtime
+            .submit_transaction(transfer(&alice_key, bob, 2, 20))
+            .unwrap();
+        assert_eq!(runtime.get_chain_state().unwrap().latest_block_index, 2);
+        assert_eq!(balance(&runtime, &alice), 50);
+        assert_eq!(balance(&runtime, &bob), 50);
+    }
+
+    #[test]
+    fn test_block_with_wrong_state_root_is_rejected() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let ours = runtime(ManualClock::new(10), &[alice]);
+        let theirs = runtime(ManualClock::new(10), &[alice]);
+
+        theirs
+            .submit_transaction(transfer(&alice_key, bob, 1, 30))
+            .unwrap();
+        let block = theirs.seal().unwrap();
+        assert_ne!(block.state_root, [0; 32]);
+        assert_eq!(
+            theirs.get_chain_state().unwrap().accounts_root_hash,
+            block.state_root
+        );
+
+        let mut tampered = block.clone();
+        tampered.state_root = [7; 32];
+        tampered.hash = tampered.calculate_hash().unwrap();
+        assert!(matches!(
+            ours.import_block(tampered),
+            Err(RuntimeError::LedgerError(LedgerError::StateRootMismatch(
+                ..
+            )))
+        ));
+        assert_eq!(ours.get_chain_state().unwrap().latest_block_index, 0);
+
+        ours.import_block(block.clone()).unwrap();
+        assert_eq!(
+            ours.get_chain_state().unwrap().accounts_root_hash,
+            block.state_root
+        );
+    }
+
+    #[test]
+    fn test_proofs_verify_against_block() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let (_, carol) = key(3);
+        let runtime = runtime(ManualClock::new(10), &[alice]);
+        let transfers: Vec<Transaction> = (1..=3)
+            .map(|nonce| transfer(&alice_key, bob, nonce, 10))
+            .collect();
+        for tx in &transfers {
+            runtime.submit_transaction(tx.clone()).unwrap();
+        }
+        let block = runtime.seal().unwrap();
+
+        let header = runtime.get_headers(1, 10).unwrap().remove(0);
+        assert_eq!(header.hash().unwrap(), block.hash);
+        assert!(header.extends(&runtime.get_headers(0, 1).unwrap()[0]));
+
+        let proof = runtime.prove_account(&bob).unwrap();
+        assert!(header.verify_state(&proof, &merkle::account_key(&bob)));
+        assert_eq!(
+            proof.account(),
+            Some(Account::Wallet {
+                balance: 30,
+                nonce: 0
+            })
+        );
+        let absent = runtime.prove_account(&carol).unwrap();
+        assert!(absent.verify(&block.state_root, &merkle::account_key(&carol)));
+        assert_eq!(absent.value, None);
+
+        for tx in &transfers {
+            let proof = runtime
+                .prove_transaction(&block.hash, &tx.hash)
+                .unwrap()
+                .unwrap();
+            assert!(header.verify_transaction(&proof));
+            let receipt = runtime.get_receipt(&block.hash, &tx.hash).unwrap().unwrap();
+            assert!(receipt.success);
+            let (proven, proof) = runtime
+                .prove_receipt(&block.hash, &tx.hash)
+                .unwrap()
+                .unwrap();
+            assert_eq!(proven, receipt);
+            assert!(header.verify_receipt(&receipt, &proof));
+        }
+        assert_ne!(header.receipts_root, [0; 32]);
+        assert!(runtime
+            .prove_transaction(&block.hash, &[0; 32])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_longer_branch_reorganizes_chain() {
+        let (alice_key, alice) = key(1);
+        let (bob_key, bob) = key(2);
+        let (_, carol) = key(3);
+        let ours = runtime(ManualClock::new(10), &[alice, bob]);
+        let theirs = runtime(ManualClock::new(20), &[alice, bob]);
+
+        let orphaned = transfer(&alice_key, carol, 1, 40);
+        let shared = transfer(&bob_key, carol, 1, 5);
+        ours.submit_transaction(orphaned.clone()).unwrap();
+        ours.submit_transaction(shared.clone()).unwrap();
+        let a1 = ours.seal().unwrap();
+
+        theirs.submit_transaction(shared.clone()).unwrap();
+        let b1 = theirs.seal().unwrap();
+        theirs
+            .submit_transaction(transfer(&bob_key, carol, 2, 5))
+            .unwrap();
+        let b2 = theirs.seal().unwrap();
+
+        // An equally long branch is only stored.
+        ours.import_block(b1.clone()).unwrap();
+        assert_eq!(ours.get_chain_state().unwrap().latest_block_hash, a1.hash);
+        assert_eq!(balance(&ours, &carol), 45);
+
+        ours.import_block(b2.clone()).unwrap();
+        let state = ours.get_chain_state().unwrap();
+        assert_eq!(state.latest_block_hash, b2.hash);
+        assert_eq!(ours.storage().get_chain_state().unwrap().unwrap(), state);
+        assert_eq!(ours.get_block_by_height(1).unwrap(), Some(b1));
+        assert_eq!(ours.get_block_by_height(2).unwrap(), Some(b2));
+        assert_eq!(balance(&ours, &alice), 100);
+        assert_eq!(balance(&ours, &bob), 90);
+        assert_eq!(balance(&ours, &carol), 10);
+        assert!(ours.storage().get_block_undo(&a1.hash).unwrap().is_none());
+        // Only the transaction the new branch re-includes is still stored.
+        assert_eq!(ours.get_transaction(&orphaned.hash).unwrap(), None);
+        assert_eq!(ours.get_transaction(&shared.hash).unwrap(), Some(shared));
+        assert!(ours.get_transactions_by_block(&a1.hash).unwrap().is_empty());
+
+        // The orphaned transfer is back in the mempool and can be re-sealed.
+        assert_eq!(ours.pending_transactions().unwrap(), vec![orphaned.clone()]);
+        ours.seal().unwrap();
+        assert_eq!(balance(&ours, &carol), 50);
+        assert_eq!(
+            ours.get_transaction(&orphaned.hash).unwrap(),
+            Some(orphaned)
+        );
+    }
+
+    #[test]
+    fn test_address_history_is_paginated_and_follows_reorgs() {
+        let (alice_key, alice) = key(1);
+        let (bob_key, bob) = key(2);
+        let (_, carol) = key(3);
+        let ours = runtime(ManualClock::new(10), &[alice, bob]);
+        let theirs = runtime(ManualClock::new(20), &[alice, bob]);
+
+        ours.submit_transaction(transfer(&alice_key, carol, 1, 1))
+            .unwrap();
+        ours.submit_transaction(transfer(&alice_key, carol, 2, 1))
+            .unwrap();
+        let a1 = ours.seal().unwrap();
+        ours.submit_transaction(transfer(&bob_key, alice, 1, 1))
+            .unwrap();
+        let a2 = ours.seal().unwrap();
+        let expected: Vec<[u8; 32]> = a1
+            .transactions
+            .iter()
+            .chain(&a2.transactions)
+            .map(|tx| tx.hash)
+            .collect();
+
+        let alice = Address::Wallet(alice);
+        let page = |after, order, limit| {
+            ours.get_transactions_for_address(&alice, after, order, limit)
+                .unwrap()
+        };
+        let hashes = |page: &[(TxPosition, Transaction)]| -> Vec<[u8; 32]> {
+            page.iter().map(|(_, tx)| tx.hash).collect()
+        };
+        let first = page(None, Order::Ascending, 2);
+        assert_eq!(hashes(&first), expected[..2]);
+        assert_eq!(
+            first[1].0,
+            TxPosition {
+                height: 1,
+                tx_index_in_block: 1
+            }
+        );
+        let rest = page(Some(first[1].0), Order::Ascending, 2);
+        assert_eq!(hashes(&rest), expected[2..]);
+        let newest = page(None, Order::Descending, 2);
+        assert_eq!(hashes(&newest), [expected[2], expected[1]]);
+        let oldest = page(Some(newest[1].0), Order::Descending, 2);
+        assert_eq!(hashes(&oldest), expected[..1]);
+
+        // Once the blocks are reorganized away, so is their history.
+        for nonce in 1..=3 {
+            theirs
+                .submit_transaction(transfer(&bob_key, carol, nonce, 1))
+                .unwrap();
+            ours.import_block(theirs.seal().unwrap()).unwrap();
+        }
+        assert_eq!(ours.get_chain_state().unwrap().latest_block_index, 3);
+        assert!(page(None, Order::Ascending, 10).is_empty());
+        let carol_history = ours
+            .get_transactions_for_address(&Address::Wallet(carol), None, Order::Ascending, 10)
+            .unwrap();
+        let heights: Vec<u64> = carol_history
+            .iter()
+            .map(|(position, _)| position.height)
+            .collect();
+        assert_eq!(heights, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_data_transactions_are_queryable_by_tag() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let runtime = runtime(ManualClock::new(10), &[alice]);
+        let reading = |nonce: u64, device: &str, kind: &str| {
+            let mut tx = transfer(&alice_key, alice, nonce, 0);
+            tx.payload = TransactionPayload::Data {
+                data: vec![nonce as u8],
+            };
+            tx.metadata = Some(BTreeMap::from([
+                ("device_id".to_string(), device.to_string()),
+                ("kind".to_string(), kind.to_string()),
+            ]));
+            tx.sign(&alice_key).unwrap();
+            tx
+        };
+        let readings = [
+            reading(1, "d1", "temperature"),
+            reading(2, "d2", "temperature"),
+            reading(3, "d1", "humidity"),
+            reading(4, "d1", "temperature"),
+        ];
+        // Only data transactions are tagged.
+        let mut tagged_transfer = transfer(&alice_key, bob, 5, 1);
+        tagged_transfer.metadata = Some(BTreeMap::from([(
+            "device_id".to_string(),
+            "d1".to_string(),
+        )]));
+        tagged_transfer.sign(&alice_key).unwrap();
+        for tx in readings.iter().chain([&tagged_transfer]) {
+            runtime.submit_transaction(tx.clone()).unwrap();
+        }
+        runtime.seal().unwrap();
+
+        let query = |query: &TagQuery, filters: &[(&str, &str)], after, order, limit| {
+            let filters = filters
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            runtime
+                .get_tagged_transactions(query, &filters, after, order, limit)
+                .unwrap()
+        };
+        let data = |page: &[(TaggedTransaction, Transaction)]| -> Vec<u8> {
+            page.iter()
+                .map(|(_, tx)| match &tx.payload {
+                    TransactionPayload::Data { data } => data[0],
+                    other => panic!("unexpected payload {:?}", other),
+                })
+                .collect()
+        };
+
+        let d1 = TagQuery::equals("device_id", "d1");
+        let all = query(&d1, &[], None, Order::Ascending, 10);
+        assert_eq!(all.next, None);
+        let mut all = data(&all.matches);
+        all.sort();
+        assert_eq!(all, [1, 3, 4]);
+        let temperatures = query(&d1, &[("kind", "temperature")], None, Order::Descending, 1);
+        assert_eq!(temperatures.matches.len(), 1);
+        let rest = query(
+            &d1,
+            &[("kind", "temperature")],
+            temperatures.next.as_ref(),
+            Order::Descending,
+            1,
+        );
+        let mut both = data(&temperatures.matches);
+        both.extend(data(&rest.matches));
+        both.sort();
+        assert_eq!(both, [1, 4]);
+
+        let devices = query(
+            &TagQuery::range("device_id", "d", "d2"),
+            &[],
+            None,
+            Order::Descending,
+            10,
+        );
+        assert_eq!(devices.matches.len(), 3);
+        assert!(devices
+            .matches
+            .iter()
+            .all(|(tagged, _)| tagged.value == "d1"));
+        assert_eq!(
+            data(&query(&TagQuery::any("kind"), &[], None, Order::Ascending, 1).matches),
+            [3]
+        );
+        assert!(query(
+            &TagQuery::equals("device_id", "d"),
+            &[],
+            None,
+            Order::Ascending,
+            10
+        )
+        .matches
+        .is_empty());
+    }
+
+    #[test]
+    fn test_oversized_tags_are_rejected() {
+        let (alice_key, alice) = key(1);
+        let runtime = runtime(ManualClock::new(10), &[alice]);
+        let tagged = |metadata: BTreeMap<String, String>| {
+            let mut tx = transfer(&alice_key, alice, 1, 0);
+            tx.payload = TransactionPayload::Data { data: vec![1] };
+            tx.metadata = Some(metadata);
+            tx.sign(&alice_key).unwrap();
+            tx
+        };
+        let too_many = (0..=MAX_TAGS)
+            .map(|i| (format!("tag{}", i), String::new()))
+            .collect();
+        let long_name = BTreeMap::from([("n".repeat(MAX_TAG_NAME_LEN + 1), String::new())]);
+        let long_value = BTreeMap::from([("kind".to_string(), "v".repeat(MAX_TAG_VALUE_LEN + 1))]);
+        for metadata in [too_many, long_name, long_value] {
+            assert!(matches!(
+                runtime.submit_transaction(tagged(metadata)),
+                Err(RuntimeError::InvalidTransaction(_))
+            ));
+        }
+        let largest = (0..MAX_TAGS)
+            .map(|i| {
+                (
+                    format!("{:0>1$}", i, MAX_TAG_NAME_LEN),
+                    "v".repeat(MAX_TAG_VALUE_LEN),
+                )
+            })
+            .collect();
+        runtime.submit_transaction(tagged(largest)).unwrap();
+    }
+
+    #[test]
+    fn test_storage_quotas_are_enforced_and_reverted() {
+        let (alice_key, alice) = key(1);
+        let (bob_key, bob) = key(2);
+        let quotas = StorageQuotas {
+            account_bytes: Some(10),
+            contract_bytes: Some(10),
+        };
+        let ours = runtime(ManualClock::new(10), &[alice, bob]).with_quotas(quotas);
+        let theirs = runtime(ManualClock::new(10), &[alice, bob]);
+        let other = runtime(ManualClock::new(20), &[alice, bob]);
+        let data = |nonce, len| {
+            let mut tx = transfer(&alice_key, alice, nonce, 0);
+            tx.payload = TransactionPayload::Data { data: vec![0; len] };
+            tx.sign(&alice_key).unwrap();
+            tx
+        };
+        let alice_address = Address::Wallet(alice);
+
+        theirs.submit_transaction(data(1, 6)).unwrap();
+        ours.import_block(theirs.seal().unwrap()).unwrap();
+        assert_eq!(ours.get_storage_usage(&alice_address).unwrap(), 6);
+
+        // Queued data counts against the quota too.
+        ours.submit_transaction(data(2, 2)).unwrap();
+        assert!(matches!(
+            ours.submit_transaction(data(3, 3)),
+            Err(RuntimeError::InvalidTransaction(_))
+        ));
+        ours.seal().unwrap();
+        assert_eq!(ours.get_storage_usage(&alice_address).unwrap(), 8);
+
+        // Quotas are not consensus: a block from a node without them is
+        // applied all the same.
+        theirs
+            .import_block(ours.get_block_by_height(2).unwrap().unwrap())
+            .unwrap();
+        theirs.submit_transaction(data(3, 6)).unwrap();
+        ours.import_block(theirs.seal().unwrap()).unwrap();
+        assert_eq!(ours.get_storage_usage(&alice_address).unwrap(), 14);
+
+        // Sealing leaves out a deploy too large for its contract, and the
+        // sender's later transactions, but not the others.
+        let mut deploy = transfer(&bob_key, bob, 1, 0);
+        deploy.payload = TransactionPayload::ContractDeploy {
+            wasm_bytes: vec![0; 11],
+        };
+        deploy.sign(&bob_key).unwrap();
+        ours.submit_transaction(deploy.clone()).unwrap();
+        let mut after_deploy = transfer(&bob_key, bob, 2, 0);
+        after_deploy.payload = TransactionPayload::Data { data: Vec::new() };
+        after_deploy.sign(&bob_key).unwrap();
+        ours.submit_transaction(after_deploy).unwrap();
+        assert!(matches!(
+            ours.seal(),
+            Err(RuntimeError::ConsensusError(
+                ConsensusError::NoPendingTransactions
+            ))
+        ));
+        ours.submit_transaction(data(4, 0)).unwrap();
+        let block = ours.seal().unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(ours.pending_transactions().unwrap().len(), 2);
+
+        // Reorganizing the data away gives its bytes back.
+        for nonce in 1..=5 {
+            other
+                .submit_transaction(transfer(&alice_key, alice, nonce, 0))
+                .unwrap();
+            ours.import_block(other.seal().unwrap()).unwrap();
+        }
+        assert_eq!(ours.get_chain_state().unwrap().latest_block_index, 5);
+        assert_eq!(ours.get_storage_usage(&alice_address).unwrap(), 0);
+        assert_eq!(ours.quotas(), quotas);
+    }
+
+    #[test]
+    fn test_imported_blocks_are_applied_over_quota() {
+        let (alice_key, alice) = key(1);
+        let quotas = StorageQuotas {
+            account_bytes: Some(4),
+            contract_bytes: None,
+        };
+        let ours = runtime(ManualClock::new(10), &[alice]).with_quotas(quotas);
+        let theirs = runtime(ManualClock::new(10), &[alice]);
+
+        let mut data = transfer(&alice_key, alice, 1, 0);
+        data.payload = TransactionPayload::Data { data: vec![0; 8] };
+        data.sign(&alice_key).unwrap();
+        assert!(matches!(
+            ours.submit_transaction(data.clone()),
+            Err(RuntimeError::InvalidTransaction(_))
+        ));
+
+        // Refused here, but applied when a peer's block carries it.
+        theirs.submit_transaction(data).unwrap();
+        let block = theirs.seal().unwrap();
+        ours.import_block(block.clone()).unwrap();
+        assert_eq!(
+            ours.get_chain_state().unwrap().latest_block_hash,
+            block.hash
+        );
+        assert_eq!(ours.get_storage_usage(&Address::Wallet(alice)).unwrap(), 8);
+    }
+
+    fn poa_runtime(authority: &SigningKey, funded: &[PublicKey]) -> PoARuntime {
+        let storage = MemoryStorage::new();
+        for address in funded {
+            storage
+                .put_account(
+                    address,
+                    &Account::Wallet {
+                        balance: 100,
+                        nonce: 0,
+                    },
+                )
+                .unwrap();
+        }
+        let contract_engine = BaaLSContractEngine::new(storage.clone());
+        let consensus = PoAConsensus::with_signing_key(authority.clone(), 1000);
+        Runtime::new(storage, consensus, contract_engine, NoopSync).unwrap()
+    }
+
+    #[test]
+    fn test_finality_gadget_finalizes_sealed_blocks() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        // A lone authority is its own supermajority.
+        let runtime = poa_runtime(&alice_key, &[alice])
+            .with_finality(Some(alice_key.clone()))
+            .unwrap();
+
+        runtime
+            .submit_transaction(transfer(&alice_key, bob, 1, 10))
+            .unwrap();
+        let block = runtime.seal().unwrap();
+        assert_eq!(runtime.get_chain_state().unwrap().finalized_height, 1);
+        let certificate = runtime
+            .get_commit_certificate(&block.hash)
+            .unwrap()
+            .unwrap();
+        assert!(certificate.verify(&[alice]));
+
+        // Votes for final heights are stale, not errors.
+        let stale = Vote::new_signed(crate::types::VoteKind::Prevote, 1, block.hash, &alice_key);
+        runtime.handle_message(&alice, NetworkMessage::FinalityVote(Box::new(stale)));
+        assert!(runtime
+            .handle_vote(Vote::new_signed(
+                crate::types::VoteKind::Precommit,
+                1,
+                block.hash,
+                &alice_key
+            ))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_poa_requires_a_seal_from_the_authority() {
+        let (alice_key, alice) = key(1);
+        let (mallory_key, _) = key(3);
+        let ours = poa_runtime(&alice_key, &[alice]);
+        let theirs = poa_runtime(&alice_key, &[alice]);
+        let forged = poa_runtime(&mallory_key, &[alice]);
+
+        theirs
+            .submit_transaction(transfer(&alice_key, alice, 1, 10))
+            .unwrap();
+        let block = theirs.seal().unwrap();
+        let mut unsealed = block.clone();
+        unsealed.seal = None;
+        assert!(matches!(
+            ours.import_block(unsealed),
+            Err(RuntimeError::ConsensusError(
+                ConsensusError::ValidationFailed(_)
+            ))
+        ));
+        forged
+            .submit_transaction(transfer(&alice_key, alice, 1, 10))
+            .unwrap();
+        assert!(matches!(
+            ours.import_block(forged.seal().unwrap()),
+            Err(RuntimeError::ConsensusError(
+                ConsensusError::UnauthorizedSigner
+            ))
+        ));
+        assert_eq!(ours.get_chain_state().unwrap().latest_block_index, 0);
+
+        ours.import_block(block).unwrap();
+        assert_eq!(ours.get_chain_state().unwrap().latest_block_index, 1);
+    }
+
+    #[test]
+    fn test_evidence_must_name_an_authority() {
+        let (alice_key, alice) = key(1);
+        let (mallory_key, _) = key(3);
+        let runtime = poa_runtime(&alice_key, &[alice]);
+        let double_vote = |key: &SigningKey| EquivocationEvidence::DoubleVote {
+            first: Vote::new_signed(crate::types::VoteKind::Prevote, 4, [1; 32], key),
+            second: Vote::new_signed(crate::types::VoteKind::Prevote, 4, [2; 32], key),
+        };
+
+        assert!(matches!(
+            runtime.report_evidence(double_vote(&mallory_key)),
+            Err(RuntimeError::LedgerError(LedgerError::InvalidEvidence(_)))
+        ));
+        let mut tx = transfer(&alice_key, alice, 1, 0);
+        tx.payload = TransactionPayload::SubmitEvidence {
+            evidence: Box::new(double_vote(&mallory_key)),
+        };
+        tx.sign(&alice_key).unwrap();
+        assert!(matches!(
+            runtime.submit_transaction(tx),
+            Err(RuntimeError::LedgerError(LedgerError::InvalidEvidence(_)))
+        ));
+
+        // Gossiped evidence against the authority is queued.
+        runtime.handle_message(
+            &alice,
+            NetworkMessage::Evidence(Box::new(double_vote(&alice_key))),
+        );
+        assert_eq!(
+            runtime.take_pending_evidence().unwrap(),
+            vec![double_vote(&alice_key)]
+        );
+    }
+
+    #[test]
+    fn test_reorg_below_finalized_height_is_rejected() {
+        let (alice_key, alice) = key(1);
+        let (bob_key, bob) = key(2);
+        let ours = runtime(ManualClock::new(10), &[alice, bob]);
+        let theirs = runtime(ManualClock::new(20), &[alice, bob]);
+
+        ours.submit_transaction(transfer(&alice_key, bob, 1, 1))
+            .unwrap();
+        let a1 = ours.seal().unwrap();
+        let (authority_key, authority) = key(9);
+        let certificate = CommitCertificate {
+            height: 1,
+            block_hash: a1.hash,
+            precommits: vec![crate::types::Vote::new_signed(
+                crate::types::VoteKind::Precommit,
+                1,
+                a1.hash,
+                &authority_key,
+            )],
+        };
+        ours.apply_commit_certificate(&certificate, &[authority])
+            .unwrap();
+
+        for nonce in 1..=2 {
+            theirs
+                .submit_transaction(transfer(&bob_key, alice, nonce, 1))
+                .unwrap();
+            let block = theirs.seal().unwrap();
+            let result = ours.import_block(block);
+            if nonce == 2 {
+                assert!(matches!(
+                    result,
+                    Err(RuntimeError::LedgerError(LedgerError::BelowFinalized(0, 1)))
+                ));
+            }
+        }
+        assert_eq!(ours.get_chain_state().unwrap().latest_block_hash, a1.hash);
+    }
+
+    #[test]
+    fn test_state_is_queryable_at_past_heights() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let runtime = runtime(ManualClock::new(10), &[alice]);
+
+        for (nonce, amount) in [(1, 30), (2, 20)] {
+            runtime
+                .submit_transaction(transfer(&alice_key, bob, nonce, amount))
+                .unwrap();
+            runtime.seal().unwrap();
+        }
+
+        let balance_at = |height| match runtime.get_account_at(&bob, height).unwrap() {
+            Some(Account::Wallet { balance, .. }) => balance,
+            other => panic!("unexpected account {:?}", other),
+        };
+        assert_eq!(runtime.get_account_at(&bob, 0).unwrap(), None);
+        assert_eq!(balance_at(1), 30);
+        assert_eq!(balance_at(2), 50);
+        assert_eq!(balance(&runtime, &bob), 50);
+        assert!(matches!(
+            runtime.get_account_at(&bob, 3),
+            Err(RuntimeError::UnknownHeight(3))
+        ));
+    }
+
+    #[test]
+    fn test_header_only_retention_prunes_bodies_and_state() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let runtime =
+            runtime(ManualClock::new(10), &[alice]).with_retention(RetentionMode::HeaderOnly);
+
+        let mut blocks = Vec::new();
+        for nonce in 1..=3 {
+            runtime
+                .submit_transaction(transfer(&alice_key, bob, nonce, 10))
+                .unwrap();
+            blocks.push(runtime.seal().unwrap());
+        }
+        let horizon = runtime.prune().unwrap();
+        assert_eq!(
+            horizon,
+            PruneHorizon {
+                state: 3,
+                bodies: 3
+            }
+        );
+
+        assert!(matches!(
+            runtime.get_block_by_height(1),
+            Err(RuntimeError::StorageError(StorageError::Pruned(1)))
+        ));
+        assert!(matches!(
+            runtime.get_account_at(&bob, 2),
+            Err(RuntimeError::StorageError(StorageError::Pruned(2)))
+        ));
+        assert!(matches!(
+            runtime.get_transaction(&blocks[0].transactions[0].hash),
+            Err(RuntimeError::StorageError(StorageError::Pruned(_)))
+        ));
+        assert!(matches!(
+            runtime.get_receipt(&blocks[0].hash, &blocks[0].transactions[0].hash),
+            Err(RuntimeError::StorageError(StorageError::Pruned(_)))
+        ));
+        let headers = runtime.get_headers(0, 10).unwrap();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[1].hash().unwrap(), blocks[0].hash);
+
+        // Only the nodes of the tip's state tree are left.
+        let mut reachable = Vec::new();
+        let mut pending = vec![blocks[2].state_root];
+        while let Some(hash) = pending.pop() {
+            if let Some(node) = runtime.storage.get_trie_node(&hash).unwrap() {
+                if let crate::merkle::TrieNode::Internal { left, right } = node {
+                    pending.extend([left, right]);
+                }
+                reachable.push(hash);
+            }
+        }
+        let mut stored = runtime.storage.get_trie_node_hashes().unwrap();
+        reachable.sort_unstable();
+        stored.sort_unstable();
+        assert_eq!(stored, reachable);
+
+        // The tip is intact, so the chain keeps growing on top of it.
+        assert_eq!(
+            runtime.get_block_by_height(3).unwrap(),
+            Some(blocks[2].clone())
+        );
+        runtime
+            .submit_transaction(transfer(&alice_key, bob, 4, 10))
+            .unwrap();
+        runtime.seal().unwrap();
+        assert_eq!(balance(&runtime, &bob), 40);
+        assert!(runtime.prove_account(&bob).is_ok());
+    }
+
+    #[test]
+    fn test_untouched_genesis_accounts_are_committed_to() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let (_, carol) = key(3);
+        let runtime = runtime(ManualClock::new(10), &[alice, carol]);
+        let genesis = runtime.get_block_by_height(0).unwrap().unwrap();
+        assert_eq!(
+            genesis.state_root,
+            runtime.get_chain_state().unwrap().accounts_root_hash
+        );
+
+        runtime
+            .submit_transaction(transfer(&alice_key, bob, 1, 10))
+            .unwrap();
+        runtime.seal().unwrap();
+
+        // Carol never transacts, yet her account is part of the state root.
+        assert!(runtime.prove_account(&carol).is_ok());
+        let report = runtime.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert!(runtime.export_snapshot().is_ok());
+    }
+
+    #[test]
+    fn test_integrity_check_finds_and_repairs_damage() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let runtime = runtime(ManualClock::new(10), &[alice]);
+        for nonce in 1..=2 {
+            runtime
+                .submit_transaction(transfer(&alice_key, bob, nonce, 10))
+                .unwrap();
+            runtime.seal().unwrap();
+        }
+        let report = runtime.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.blocks_checked, 3);
+
+        let block = runtime.get_block_by_height(1).unwrap().unwrap();
+        let tx_hash = block.transactions[0].hash;
+        let mut batch = StorageBatch::default();
+        batch.ops.push(StorageOperation::DeleteTransaction(tx_hash));
+        batch.ops.push(StorageOperation::UnindexTransaction {
+            block_hash: block.hash,
+            tx_index_in_block: 0,
+        });
+        batch.ops.push(StorageOperation::DeleteReceipt {
+            block_hash: block.hash,
+            tx_index_in_block: 0,
+        });
+        runtime.storage.apply_batch(batch).unwrap();
+
+        let report = runtime.verify_integrity().unwrap();
+        assert_eq!(report.issues.len(), 3);
+        assert!(report.issues.contains(&Issue::MissingReceipt {
+            height: 1,
+            tx_hash,
+            tx_index_in_block: 0
+        }));
+        assert_eq!(
+            integrity::repair(runtime.storage.as_ref(), &report).unwrap(),
+            3
+        );
+        let report = runtime.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+
+        // Flat state drifting from the tree is rebuilt from the tree leaves.
+        let mut batch = StorageBatch::default();
+        batch.ops.push(StorageOperation::DeleteAccount(alice));
+        batch.ops.push(StorageOperation::PutAccount(
+            bob,
+            Box::new(Account::Wallet {
+                balance: 1_000,
+                nonce: 0,
+            }),
+        ));
+        runtime.storage.apply_batch(batch).unwrap();
+        let report = runtime.verify_integrity().unwrap();
+        assert!(matches!(
+            report.issues[..],
+            [Issue::StateRootMismatch { .. }]
+        ));
+        assert_eq!(
+            integrity::repair(runtime.storage.as_ref(), &report).unwrap(),
+            1
+        );
+        assert!(runtime.verify_integrity().unwrap().is_ok());
+        assert_eq!(balance(&runtime, &alice), 80);
+        assert_eq!(balance(&runtime, &bob), 20);
+
+        // Blocks that do not re-execute to their roots are reported.
+        let mut batch = StorageBatch::default();
+        batch.ops.push(StorageOperation::PutGenesisState(vec![
+            StateEntry::Account(
+                alice,
+                Account::Wallet {
+                    balance: 5,
+                    nonce: 0,
+                },
+            ),
+        ]));
+        runtime.storage.apply_batch(batch).unwrap();
+        assert_eq!(
+            runtime.verify_integrity().unwrap().issues,
+            vec![Issue::ExecutionMismatch(0)]
+        );
+    }
+
+    #[test]
+    fn test_reindex_rebuilds_state_and_resumes() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let runtime = runtime(ManualClock::new(10), &[alice]);
+        for nonce in 1..=3 {
+            runtime
+                .submit_transaction(transfer(&alice_key, bob, nonce, 10))
+                .unwrap();
+            runtime.seal().unwrap();
+        }
+        let expected = runtime.get_chain_state().unwrap();
+        runtime
+            .storage
+            .put_account(
+                &bob,
+                &Account::Wallet {
+                    balance: 1_000,
+                    nonce: 0,
+                },
+            )
+            .unwrap();
+
+        let storage = runtime.storage.as_ref().clone();
+        let stopped = reindex::reindex(&storage, |progress| {
+            if progress.height < 2 {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        })
+        .unwrap();
+        assert_eq!(stopped, None);
+        assert!(reindex::is_in_progress(&storage).unwrap());
+        let contract_engine = BaaLSContractEngine::new(storage.clone());
+        let consensus = InstantSealConsensus::with_clock(SealMode::Manual, ManualClock::new(10));
+        assert!(matches!(
+            Runtime::new(storage.clone(), consensus, contract_engine, NoopSync),
+            Err(RuntimeError::ReindexInProgress)
+        ));
+
+        let mut replayed = Vec::new();
+        let chain_state = reindex::reindex(&storage, |progress| {
+            replayed.push(progress.height);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(replayed, vec![3]);
+        assert_eq!(chain_state, Some(expected));
+        assert!(!reindex::is_in_progress(&storage).unwrap());
+        assert_eq!(balance(&runtime, &bob), 30);
+        assert_eq!(balance(&runtime, &alice), 70);
+        assert!(integrity::verify(&storage).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_node_bootstraps_from_snapshot() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let source = runtime(ManualClock::new(10), &[alice]);
+        for nonce in 1..=2 {
+            source
+                .submit_transaction(transfer(&alice_key, bob, nonce, 10))
+                .unwrap();
+            source.seal().unwrap();
+        }
+        let snapshot = source.export_snapshot().unwrap();
+        let tip = source.get_chain_state().unwrap().latest_block_hash;
+
+        let mut tampered = snapshot.clone();
+        tampered.chunks[0][0] ^= 1;
+        let target = MemoryStorage::new();
+        assert!(matches!(
+            snapshot::import(&target, &tampered, tip),
+            Err(SnapshotError::ChunkChecksumMismatch(0))
+        ));
+        assert!(matches!(
+            snapshot::import(&target, &snapshot, [0; 32]),
+            Err(SnapshotError::UntrustedBlock)
+        ));
+        let mut jailing = snapshot.clone();
+        jailing.manifest.chain_state.jailed_authorities.push(alice);
+        assert!(matches!(
+            snapshot::import(&target, &jailing, tip),
+            Err(SnapshotError::StateRootMismatch(..))
+        ));
+        snapshot::import(&target, &snapshot, tip).unwrap();
+
+        let contract_engine = BaaLSContractEngine::new(target.clone());
+        let consensus = InstantSealConsensus::with_clock(SealMode::Manual, ManualClock::new(10));
+        let node = Runtime::new(target, consensus, contract_engine, NoopSync).unwrap();
+        assert_eq!(node.get_chain_state().unwrap().latest_block_hash, tip);
+        assert_eq!(balance(&node, &bob), 20);
+        assert!(matches!(
+            node.get_block_by_height(1),
+            Err(RuntimeError::StorageError(StorageError::Pruned(1)))
+        ));
+
+        node.submit_transaction(transfer(&alice_key, bob, 3, 10))
+            .unwrap();
+        node.seal().unwrap();
+        assert_eq!(balance(&node, &bob), 30);
+    }
+
+    /// Records the messages the runtime sends to single peers.
+    #[derive(Default)]
+    struct RecordingSync(Mutex<Vec<(PublicKey, NetworkMessage)>>);
+
+    #[async_trait::async_trait]
+    impl SyncLayer for RecordingSync {
+        async fn sync_with_peer(
+            &self,
+            _peer: &crate::sync::Peer,
+            _local_chain_state: &ChainState,
+        ) -> Result<Block, SyncError> {
+            Err(SyncError::BlockNotFound)
+        }
+
+        async fn discover_peers(&self) -> Result<Vec<crate::sync::Peer>, SyncError> {
+            Ok(Vec::new())
+        }
+
+        async fn broadcast_block(
+            &self,
+            _block: &Block,
+            _peers: &[crate::sync::Peer],
+        ) -> Result<(), SyncError> {
+            Ok(())
+        }
+
+        fn send_to(&self, peer: &PublicKey, message: NetworkMessage) -> Result<(), SyncError> {
+            self.0.lock().unwrap().push((*peer, message));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_header_requests_are_answered() {
+        let (alice_key, alice) = key(1);
+        let (_, bob) = key(2);
+        let storage = MemoryStorage::new();
+        storage
+            .put_account(
+                &alice,
+                &Account::Wallet {
+                    balance: 100,
+                    nonce: 0,
+                },
+            )
+            .unwrap();
+        let contract_engine = BaaLSContractEngine::new(storage.clone());
+        let consensus = InstantSealConsensus::with_clock(SealMode::Manual, ManualClock::new(10));
+        let runtime = Runtime::new(
+            storage,
+            consensus,
+            contract_engine,
+            RecordingSync::default(),
+        )
+        .unwrap();
+        for nonce in 1..=2 {
+            runtime
+                .submit_transaction(transfer(&alice_key, bob, nonce, 10))
+                .unwrap();
+            runtime.seal().unwrap();
+        }
+
+        runtime.handle_message(
+            &bob,
+            NetworkMessage::GetHeaders {
+                from_height: 1,
+                max_count: u64::MAX,
+            },
+        );
+        let sent = runtime.sync_layer.0.lock().unwrap();
+        let [(to, NetworkMessage::HeadersResponse { headers })] = sent.as_slice() else {
+            panic!("unexpected replies {:?}", sent);
+        };
+        assert_eq!(*to, bob);
+        assert_eq!(*headers, runtime.get_headers(1, 2).unwrap());
+    }
+}
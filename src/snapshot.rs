@@ -0,0 +1,297 @@
+//! State snapshots for bootstrapping nodes without replaying the chain.
+//!
+//! A snapshot is the flat state at the chain tip (accounts, contract code and
+//! contract storage) split into chunks, plus a [`SnapshotManifest`] holding
+//! the tip block, its chain state and the SHA-256 checksum of every chunk.
+//! Importing checks each chunk against its checksum and rebuilds the state
+//! tree from the chunks and the chain parameters (see
+//! [`crate::merkle::chain_params_leaf`]), which must reproduce the block's
+//! `state_root`. The block itself must match a hash the caller trusts, so a
+//! snapshot can be fetched from an untrusted source.
+//!
+//! On disk a snapshot is a directory with a `manifest` file and one
+//! `chunk-NNNNN` file per chunk, all bincode encoded.
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::merkle::state_tree_from_entries;
+use crate::quota;
+use crate::storage::{
+    PruneHorizon, StateEntry, Storage, StorageBatch, StorageError, StorageOperation, TxPosition,
+};
+use crate::types::{Block, ChainState, CryptoError};
+
+/// Format version written into every manifest.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Approximate upper bound on the encoded size of a chunk.
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+const MANIFEST_FILE: &str = "manifest";
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Storage error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] bincode::Error),
+    #[error("Crypto error: {0}")]
+    CryptoError(#[from] CryptoError),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Chunk {0} does not match its checksum")]
+    ChunkChecksumMismatch(usize),
+    #[error("Snapshot has {actual} chunks, manifest lists {expected}")]
+    ChunkCountMismatch { expected: usize, actual: usize },
+    #[error("Snapshot block hash does not match the trusted hash")]
+    UntrustedBlock,
+    #[error("Snapshot manifest is inconsistent: {0}")]
+    InvalidManifest(String),
+    #[error("State root mismatch: expected {0:x?}, got {1:x?}")]
+    StateRootMismatch([u8; 32], [u8; 32]),
+    #[error("Target storage already holds a chain")]
+    AlreadyInitialized,
+}
+
+/// Describes a snapshot and commits to its chunks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    /// The tip block the snapshot was taken at; its `state_root` commits to
+    /// the snapshot's contents.
+    pub block: Block,
+    /// Chain state at `block`. The state root covers its jailed authorities
+    /// and total supply; its finalized height is not trusted on import.
+    pub chain_state: ChainState,
+    /// SHA-256 of each encoded chunk, in order.
+    pub chunk_hashes: Vec<[u8; 32]>,
+}
+
+impl SnapshotManifest {
+    pub fn height(&self) -> u64 {
+        self.block.index
+    }
+
+    pub fn state_root(&self) -> [u8; 32] {
+        self.block.state_root
+    }
+}
+
+/// A manifest together with its encoded chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub manifest: SnapshotManifest,
+    pub chunks: Vec<Vec<u8>>,
+}
+
+impl Snapshot {
+    /// Write the snapshot into `dir`, creating it if needed.
+    pub fn write_to_dir(&self, dir: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            fs::write(dir.join(chunk_file(index)), chunk)?;
+        }
+        // Written last, so a directory with a manifest is complete.
+        fs::write(dir.join(MANIFEST_FILE), bincode::serialize(&self.manifest)?)?;
+        Ok(())
+    }
+
+    /// Read a snapshot written by [`Snapshot::write_to_dir`].
+    ///
+    /// Only the manifest is decoded here; chunks are checked on import.
+    pub fn read_from_dir(dir: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let dir = dir.as_ref();
+        let manifest: SnapshotManifest = bincode::deserialize(&fs::read(dir.join(MANIFEST_FILE))?)?;
+        let chunks = (0..manifest.chunk_hashes.len())
+            .map(|index| fs::read(dir.join(chunk_file(index))))
+            .collect::<Result<_, _>>()?;
+        Ok(Snapshot { manifest, chunks })
+    }
+}
+
+fn chunk_file(index: usize) -> String {
+    format!("chunk-{:05}", index)
+}
+
+/// Snapshot the current state of `storage`, which must not change while
+/// this runs.
+///
+/// Fails with [`SnapshotError::StateRootMismatch`] if the flat state does not
+/// match the tip's state root, e.g. because it was written outside of blocks
+/// after the chain was initialized.
+pub fn export<S: Storage>(storage: &S) -> Result<Snapshot, SnapshotError> {
+    let chain_state = storage.get_chain_state()?.ok_or(StorageError::NotFound)?;
+    let block = storage
+        .get_block(&chain_state.latest_block_hash)?
+        .ok_or(StorageError::NotFound)?;
+    let entries = storage.get_state_entries()?;
+
+    let (root, _) = build_state_tree(storage, &entries, &chain_state)?;
+    if root != block.state_root {
+        return Err(SnapshotError::StateRootMismatch(block.state_root, root));
+    }
+
+    let mut chunks = Vec::new();
+    let mut current: Vec<StateEntry> = Vec::new();
+    let mut current_size = 0;
+    for entry in entries {
+        let size = bincode::serialized_size(&entry)?;
+        if !current.is_empty() && current_size + size > CHUNK_SIZE {
+            chunks.push(bincode::serialize(&std::mem::take(&mut current))?);
+            current_size = 0;
+        }
+        current_size += size;
+        current.push(entry);
+    }
+    if !current.is_empty() {
+        chunks.push(bincode::serialize(&current)?);
+    }
+
+    let manifest = SnapshotManifest {
+        version: SNAPSHOT_VERSION,
+        block,
+        chain_state,
+        chunk_hashes: chunks
+            .iter()
+            .map(|chunk| Sha256::digest(chunk).into())
+            .collect(),
+    };
+    Ok(Snapshot { manifest, chunks })
+}
+
+/// Verify `snapshot` and install it into an empty `storage`.
+///
+/// The snapshot block becomes the node's starting point: it is stored as the
+/// finalized tip, and everything below it is reported as pruned. The
+/// snapshot must have been taken at `trusted_block_hash`; nothing else ties
+/// it to the real chain.
+///
+/// Returns the installed chain state.
+pub fn import<S: Storage>(
+    storage: &S,
+    snapshot: &Snapshot,
+    trusted_block_hash: [u8; 32],
+) -> Result<ChainState, SnapshotError> {
+    let manifest = &snapshot.manifest;
+    if manifest.version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(manifest.version));
+    }
+    if storage.get_chain_state()?.is_some() {
+        return Err(SnapshotError::AlreadyInitialized);
+    }
+    let block = &manifest.block;
+    if trusted_block_hash != block.hash {
+        return Err(SnapshotError::UntrustedBlock);
+    }
+    if block.calculate_hash()? != block.hash
+        || block.compute_transactions_root() != block.transactions_root
+    {
+        return Err(SnapshotError::InvalidManifest(
+            "block hash or transactions root does not match the block".to_string(),
+        ));
+    }
+    if manifest.chain_state.latest_block_hash != block.hash
+        || manifest.chain_state.latest_block_index != block.index
+        || manifest.chain_state.accounts_root_hash != block.state_root
+    {
+        return Err(SnapshotError::InvalidManifest(
+            "chain state does not describe the snapshot block".to_string(),
+        ));
+    }
+    if snapshot.chunks.len() != manifest.chunk_hashes.len() {
+        return Err(SnapshotError::ChunkCountMismatch {
+            expected: manifest.chunk_hashes.len(),
+            actual: snapshot.chunks.len(),
+        });
+    }
+
+    let mut entries = Vec::new();
+    for (index, (chunk, hash)) in snapshot
+        .chunks
+        .iter()
+        .zip(&manifest.chunk_hashes)
+        .enumerate()
+    {
+        if <[u8; 32]>::from(Sha256::digest(chunk)) != *hash {
+            return Err(SnapshotError::ChunkChecksumMismatch(index));
+        }
+        entries.extend(bincode::deserialize::<Vec<StateEntry>>(chunk)?);
+    }
+
+    // Also covers the jailed authorities and total supply of the manifest's
+    // chain state.
+    let (root, nodes) = build_state_tree(storage, &entries, &manifest.chain_state)?;
+    if root != block.state_root {
+        return Err(SnapshotError::StateRootMismatch(block.state_root, root));
+    }
+
+    let mut batch = StorageBatch::default();
+    batch.ops.extend(nodes);
+    for (address, bytes) in quota::state_usage(&entries) {
+        batch
+            .ops
+            .push(StorageOperation::PutStorageUsage(address, bytes));
+    }
+    batch
+        .ops
+        .extend(entries.into_iter().map(StorageOperation::from));
+    for (index, tx) in block.transactions.iter().enumerate() {
+        batch
+            .ops
+            .push(StorageOperation::PutTransaction(Box::new(tx.clone())));
+        batch.ops.push(StorageOperation::IndexTransaction {
+            tx_hash: tx.hash,
+            block_hash: block.hash,
+            tx_index_in_block: index as u32,
+        });
+        batch.index_history(
+            tx,
+            TxPosition {
+                height: block.index,
+                tx_index_in_block: index as u32,
+            },
+        );
+    }
+    batch
+        .ops
+        .push(StorageOperation::PutBlock(Box::new(block.clone())));
+    // There are no undo records below the snapshot, so it cannot be reverted.
+    let chain_state = ChainState {
+        finalized_height: block.index,
+        ..manifest.chain_state.clone()
+    };
+    batch.ops.push(StorageOperation::PutChainState(Box::new(
+        chain_state.clone(),
+    )));
+    batch
+        .ops
+        .push(StorageOperation::PutPruneHorizon(PruneHorizon {
+            state: block.index,
+            bodies: block.index,
+        }));
+    storage.apply_batch(batch)?;
+    Ok(chain_state)
+}
+
+/// Build the state tree of `entries` and `chain_state` from scratch,
+/// returning its root and the writes storing its nodes.
+fn build_state_tree<S: Storage>(
+    storage: &S,
+    entries: &[StateEntry],
+    chain_state: &ChainState,
+) -> Result<([u8; 32], Vec<StorageOperation>), SnapshotError> {
+    let (root, nodes) = state_tree_from_entries(storage, entries, chain_state)?;
+    let nodes = nodes
+        .into_iter()
+        .map(|node| StorageOperation::PutTrieNode(Box::new(node)))
+        .collect();
+    Ok((root, nodes))
+}
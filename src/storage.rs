@@ -1,27 +1,166 @@
 //! Persistent storage layer for BaaLS blockchain.
 //!
-//! This module provides an abstraction over the underlying storage engine (sled)
+//! This module provides an abstraction over the underlying storage engine
 //! for persisting blocks, transactions, accounts, and contract state.
+//!
+//! [`KvStorage`] implements [`Storage`] on top of any ordered key-value
+//! store with named trees and atomic multi-tree writes ([`KvBackend`]), so
+//! key layout and encoding live in one place:
+//!
+//! - [`sled_backend`]: sled, the default on-disk backend ([`SledStorage`])
+//! - [`memory_backend`]: in-process maps for tests and sandboxes ([`MemoryStorage`])
+//! - `rocksdb_backend`: RocksDB column families (`RocksDbStorage`, `rocksdb` feature)
+//! - `sqlite_backend`: SQLite tables with queryable columns (`SqliteStorage`, `sqlite` feature)
+//!
+//! Every backend's layout is versioned; see [`schema`].
+//!
+//! `encrypted_backend` wraps any of these to encrypt values at rest
+//! (`EncryptedStorage`, `encryption` feature).
+
+#[cfg(feature = "encryption")]
+pub mod encrypted_backend;
+mod keys;
+pub mod memory_backend;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb_backend;
+pub mod schema;
+pub mod sled_backend;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_backend;
+
+#[cfg(feature = "encryption")]
+pub use encrypted_backend::{EncryptedBackend, EncryptedStorage, EncryptionKey};
+pub use memory_backend::{MemoryBackend, MemoryStorage};
+#[cfg(feature = "rocksdb")]
+pub use rocksdb_backend::{RocksDbBackend, RocksDbStorage};
+pub use sled_backend::{SledBackend, SledStorage};
+#[cfg(feature = "sqlite")]
+pub use sqlite_backend::{SqliteBackend, SqliteStorage};
 
 use bincode;
 use hex;
-use sled::{Db, Tree};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use thiserror::Error;
 
-use crate::types::PublicKey;
-use crate::types::{Account, Block, ChainState, ContractId, CryptoError, Transaction};
+use keys::{KeyBuilder, KeyReader};
+
+use crate::consensus::raft::{RaftEntry, RaftHardState};
+use crate::merkle::TrieNode;
+use crate::types::{
+    Account, Block, BlockHeader, BlockUndo, ChainState, CommitCertificate, ContractId, CryptoError,
+    Transaction, TransactionReceipt,
+};
+use crate::types::{Address, PublicKey};
 
 #[derive(Debug, Error)]
 pub enum StorageError {
     #[error("Database error: {0}")]
     DatabaseError(#[from] sled::Error),
+    #[cfg(feature = "rocksdb")]
+    #[error("RocksDB error: {0}")]
+    RocksDbError(#[from] rocksdb::Error),
+    #[cfg(feature = "rocksdb")]
+    #[error("RocksDB column family {0} is missing")]
+    MissingColumnFamily(&'static str),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[cfg(feature = "encryption")]
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
     #[error("Data not found")]
     NotFound,
     #[error("Serialization error: {0}")]
     SerializationError(#[from] bincode::Error),
     #[error("Crypto error: {0}")]
     CryptoError(#[from] CryptoError),
+    #[error("Data at height {0} has been pruned")]
+    Pruned(u64),
+    #[error(
+        "Database schema version {found} is not supported by this build (supports up to {supported})"
+    )]
+    IncompatibleSchema { found: u32, supported: u32 },
+    #[error("Corrupt schema version record: {}", hex::encode(.0))]
+    CorruptSchemaVersion(Vec<u8>),
+    #[error("Corrupt key: {0}")]
+    CorruptKey(String),
+}
+
+/// One entry of the flat current state: the data the state root commits to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StateEntry {
+    Account(PublicKey, Account),
+    ContractCode(ContractId, Vec<u8>),
+    ContractStorage(ContractId, Vec<u8>, Vec<u8>),
+}
+
+/// Lowest heights whose data survived pruning (see [`crate::pruning`]).
+///
+/// Block bodies (transactions, receipts, index entries) below `bodies` and
+/// historical state below `state` are gone; reading them yields
+/// [`StorageError::Pruned`]. Headers are always kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PruneHorizon {
+    pub state: u64,
+    pub bodies: u64,
+}
+
+/// Where a transaction sits in the canonical chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TxPosition {
+    pub height: u64,
+    pub tx_index_in_block: u32,
+}
+
+/// Which data transactions a tag query matches: those carrying tag `name`
+/// with a value in `from..to`, compared as strings.
+///
+/// Values compare bytewise, so numbers only range correctly when written
+/// with a fixed width (`"0042"`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagQuery {
+    pub name: String,
+    /// Lowest matching value, inclusive; unbounded if `None`.
+    pub from: Option<String>,
+    /// Value matching stops at, exclusive; unbounded if `None`.
+    pub to: Option<String>,
+}
+
+impl TagQuery {
+    /// Every transaction carrying tag `name`.
+    pub fn any(name: impl Into<String>) -> Self {
+        TagQuery {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Transactions whose tag `name` is exactly `value`.
+    pub fn equals(name: impl Into<String>, value: impl Into<String>) -> Self {
+        let value = value.into();
+        // Appending a zero byte gives the least string above `value`.
+        let next = format!("{}\0", value);
+        Self::range(name, value, next)
+    }
+
+    /// Transactions whose tag `name` lies in `from..to`.
+    pub fn range(name: impl Into<String>, from: impl Into<String>, to: impl Into<String>) -> Self {
+        TagQuery {
+            name: name.into(),
+            from: Some(from.into()),
+            to: Some(to.into()),
+        }
+    }
+}
+
+/// A match of a [`TagQuery`]. Matches are ordered by value, then position.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaggedTransaction {
+    /// The value of the queried tag.
+    pub value: String,
+    pub position: TxPosition,
+    pub tx_hash: [u8; 32],
 }
 
 /// Storage abstraction for blockchain persistence.
@@ -44,6 +183,30 @@ pub trait Storage: Send + Sync {
     /// Retrieve a block by its height (index).
     fn get_block_by_height(&self, height: u64) -> Result<Option<Block>, StorageError>;
 
+    /// Retrieve the header of the canonical block at `height`.
+    ///
+    /// Unlike [`Storage::get_block_by_height`] this also works for blocks
+    /// whose body has been pruned.
+    fn get_block_header_by_height(&self, height: u64) -> Result<Option<BlockHeader>, StorageError>;
+
+    /// Store a block that is not on the canonical chain (yet).
+    ///
+    /// Side blocks are retrievable by hash only; they get a height entry once
+    /// a reorganization makes them canonical.
+    fn put_side_block(&self, block: &Block) -> Result<(), StorageError>;
+
+    /// Drop the canonical height entry for `height`, keeping the block body.
+    fn remove_block_height(&self, height: u64) -> Result<(), StorageError>;
+
+    /// Store the undo record for an applied block.
+    fn put_block_undo(&self, block_hash: &[u8; 32], undo: &BlockUndo) -> Result<(), StorageError>;
+
+    /// Retrieve the undo record for an applied block.
+    fn get_block_undo(&self, block_hash: &[u8; 32]) -> Result<Option<BlockUndo>, StorageError>;
+
+    /// Delete the undo record of a reverted block.
+    fn delete_block_undo(&self, block_hash: &[u8; 32]) -> Result<(), StorageError>;
+
     // Transaction Management
 
     /// Store a transaction in the database.
@@ -58,6 +221,23 @@ pub trait Storage: Send + Sync {
     /// Remove a pending transaction from the mempool.
     fn remove_pending_transaction(&self, tx_hash: &[u8; 32]) -> Result<(), StorageError>;
 
+    /// Retrieve the receipt of the transaction at `tx_index_in_block` in
+    /// block `block_hash`.
+    ///
+    /// Receipts are kept per block, as the same transaction can have a
+    /// different outcome on another branch.
+    fn get_receipt(
+        &self,
+        block_hash: &[u8; 32],
+        tx_index_in_block: u32,
+    ) -> Result<Option<TransactionReceipt>, StorageError>;
+
+    /// The receipts of a block, in block order.
+    fn get_block_receipts(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Vec<TransactionReceipt>, StorageError>;
+
     // New: Transaction indexing for fast lookup
     fn index_transaction(
         &self,
@@ -69,11 +249,42 @@ pub trait Storage: Send + Sync {
         &self,
         tx_hash: &[u8; 32],
     ) -> Result<Option<Transaction>, StorageError>;
+    /// The transactions of a block, in block order.
     fn get_transactions_by_block(
         &self,
         block_hash: &[u8; 32],
     ) -> Result<Vec<Transaction>, StorageError>;
 
+    /// One page of a block's transactions with their positions, in `order`,
+    /// starting after position `after` and at most `limit` of them.
+    fn get_transactions_by_block_page(
+        &self,
+        block_hash: &[u8; 32],
+        after: Option<u32>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(u32, Transaction)>, StorageError>;
+
+    /// Hashes of canonical transactions sent or received by `address`, in
+    /// `order`, starting after `after` and at most `limit` of them.
+    fn get_address_transactions(
+        &self,
+        address: &Address,
+        after: Option<TxPosition>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(TxPosition, [u8; 32])>, StorageError>;
+
+    /// Canonical data transactions matching `query`, in `order`, starting
+    /// after the match `after` and at most `limit` of them.
+    fn get_tagged_transactions(
+        &self,
+        query: &TagQuery,
+        after: Option<&TaggedTransaction>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<TaggedTransaction>, StorageError>;
+
     // Account State Management (used by Ledger)
 
     /// Store an account's state.
@@ -89,6 +300,46 @@ pub trait Storage: Send + Sync {
     fn put_chain_state(&self, state: &ChainState) -> Result<(), StorageError>;
     fn get_chain_state(&self) -> Result<Option<ChainState>, StorageError>;
 
+    // Finality
+
+    /// Store the commit certificate finalizing a block.
+    fn put_commit_certificate(&self, certificate: &CommitCertificate) -> Result<(), StorageError>;
+
+    /// Retrieve the commit certificate for a block, if it has been finalized.
+    fn get_commit_certificate(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<CommitCertificate>, StorageError>;
+
+    // State Tree
+
+    /// Retrieve a node of the state Merkle tree by its hash.
+    fn get_trie_node(&self, hash: &[u8; 32]) -> Result<Option<TrieNode>, StorageError>;
+
+    /// Hashes of every stored state tree node, for garbage collection.
+    fn get_trie_node_hashes(&self) -> Result<Vec<[u8; 32]>, StorageError>;
+
+    /// Every account, contract and contract storage slot of the current
+    /// state, e.g. for exporting a snapshot.
+    fn get_state_entries(&self) -> Result<Vec<StateEntry>, StorageError>;
+
+    /// The state the chain started from, as recorded by
+    /// [`crate::ledger::Ledger::initialize_chain`]. `None` for a node
+    /// bootstrapped from a snapshot.
+    fn get_genesis_state(&self) -> Result<Option<Vec<StateEntry>>, StorageError>;
+
+    /// Whether a [`crate::reindex`] run was interrupted; the derived data is
+    /// incomplete until it is resumed.
+    fn is_reindexing(&self) -> Result<bool, StorageError>;
+
+    /// Bytes charged to `address` (see [`crate::quota`]); zero if none.
+    fn get_storage_usage(&self, address: &Address) -> Result<u64, StorageError>;
+
+    // Pruning
+
+    /// The current prune horizon; all zero for an unpruned database.
+    fn get_prune_horizon(&self) -> Result<PruneHorizon, StorageError>;
+
     // Contract Code & State (used by ContractEngine)
     fn put_contract_code(
         &self,
@@ -96,6 +347,7 @@ pub trait Storage: Send + Sync {
         wasm_bytes: &[u8],
     ) -> Result<(), StorageError>;
     fn get_contract_code(&self, contract_id: &ContractId) -> Result<Option<Vec<u8>>, StorageError>;
+    fn delete_contract_code(&self, contract_id: &ContractId) -> Result<(), StorageError>;
     fn contract_storage_read(
         &self,
         contract_id: &ContractId,
@@ -117,84 +369,693 @@ pub trait Storage: Send + Sync {
     fn apply_batch(&self, batch: StorageBatch) -> Result<(), StorageError>;
 }
 
+/// Persistence for a [`crate::consensus::RaftConsensus`] node: its term,
+/// vote and log.
+pub trait RaftStorage: Send + Sync {
+    /// Store the Raft term and vote.
+    fn put_raft_hard_state(&self, state: &RaftHardState) -> Result<(), StorageError>;
+
+    /// The stored Raft term and vote, if any.
+    fn get_raft_hard_state(&self) -> Result<Option<RaftHardState>, StorageError>;
+
+    /// Replace the Raft log from `first_index` on with `entries`, atomically.
+    fn put_raft_entries(&self, first_index: u64, entries: &[RaftEntry])
+        -> Result<(), StorageError>;
+
+    /// The stored Raft log, in index order starting at index 1.
+    fn get_raft_entries(&self) -> Result<Vec<RaftEntry>, StorageError>;
+}
+
+/// A set of writes committed atomically by [`Storage::apply_batch`].
+///
+/// Either every operation becomes visible or none does, which is what lets
+/// the ledger apply a block all-or-nothing.
 #[derive(Default)]
 pub struct StorageBatch {
     pub ops: Vec<StorageOperation>,
 }
 
+impl StorageBatch {
+    /// Add the canonical transaction `tx` at `position` to the address
+    /// histories and, for data transactions, the tag index.
+    pub fn index_history(&mut self, tx: &Transaction, position: TxPosition) {
+        for address in tx.addresses() {
+            self.ops.push(StorageOperation::IndexAddressTransaction {
+                address,
+                position,
+                tx_hash: tx.hash,
+            });
+        }
+        for (name, value) in tx.tags() {
+            self.ops.push(StorageOperation::IndexTag {
+                name: name.to_string(),
+                value: value.to_string(),
+                position,
+                tx_hash: tx.hash,
+            });
+        }
+    }
+
+    /// Undo [`StorageBatch::index_history`].
+    pub fn unindex_history(&mut self, tx: &Transaction, position: TxPosition) {
+        for address in tx.addresses() {
+            self.ops
+                .push(StorageOperation::UnindexAddressTransaction { address, position });
+        }
+        for (name, value) in tx.tags() {
+            self.ops.push(StorageOperation::UnindexTag {
+                name: name.to_string(),
+                value: value.to_string(),
+                position,
+            });
+        }
+    }
+}
+
+/// A typed write; each variant knows which tree it targets.
 pub enum StorageOperation {
-    Put(Vec<u8>, Vec<u8>),
-    Delete(Vec<u8>),
-}
-
-pub struct SledStorage {
-    db: Db,
-    blocks_tree: Tree,
-    transactions_tree: Tree,
-    mempool_tree: Tree,
-    accounts_tree: Tree,
-    contract_code_tree: Tree,
-    contract_storage_tree: Tree,
-    chain_state_tree: Tree,
-    tx_by_block_tree: Tree,
-}
-
-impl SledStorage {
-    pub fn new(path: impl AsRef<Path>) -> Result<Self, StorageError> {
-        let db = sled::open(path)?;
-        Ok(Self {
-            blocks_tree: db.open_tree("blocks")?,
-            transactions_tree: db.open_tree("transactions")?,
-            mempool_tree: db.open_tree("mempool")?,
-            accounts_tree: db.open_tree("accounts")?,
-            contract_code_tree: db.open_tree("contract_code")?,
-            contract_storage_tree: db.open_tree("contract_storage")?,
-            chain_state_tree: db.open_tree("chain_state")?,
-            tx_by_block_tree: db.open_tree("tx_by_block")?,
-            db,
-        })
-    }
-}
-
-impl Clone for SledStorage {
-    fn clone(&self) -> Self {
-        Self {
-            db: self.db.clone(),
-            blocks_tree: self.blocks_tree.clone(),
-            transactions_tree: self.transactions_tree.clone(),
-            mempool_tree: self.mempool_tree.clone(),
-            accounts_tree: self.accounts_tree.clone(),
-            contract_code_tree: self.contract_code_tree.clone(),
-            contract_storage_tree: self.contract_storage_tree.clone(),
-            chain_state_tree: self.chain_state_tree.clone(),
-            tx_by_block_tree: self.tx_by_block_tree.clone(),
+    /// Store a canonical block by hash, with a height entry pointing at it.
+    PutBlock(Box<Block>),
+    /// Drop the canonical height entry for a block.
+    RemoveBlockHeight(u64),
+    PutTransaction(Box<Transaction>),
+    DeleteTransaction([u8; 32]),
+    PutReceipt {
+        block_hash: [u8; 32],
+        tx_index_in_block: u32,
+        receipt: Box<TransactionReceipt>,
+    },
+    DeleteReceipt {
+        block_hash: [u8; 32],
+        tx_index_in_block: u32,
+    },
+    IndexTransaction {
+        tx_hash: [u8; 32],
+        block_hash: [u8; 32],
+        tx_index_in_block: u32,
+    },
+    UnindexTransaction {
+        block_hash: [u8; 32],
+        tx_index_in_block: u32,
+    },
+    /// Record a canonical transaction in `address`'s history.
+    IndexAddressTransaction {
+        address: Address,
+        position: TxPosition,
+        tx_hash: [u8; 32],
+    },
+    UnindexAddressTransaction {
+        address: Address,
+        position: TxPosition,
+    },
+    /// Record a canonical data transaction carrying tag `name` = `value`.
+    IndexTag {
+        name: String,
+        value: String,
+        position: TxPosition,
+        tx_hash: [u8; 32],
+    },
+    UnindexTag {
+        name: String,
+        value: String,
+        position: TxPosition,
+    },
+    RemovePendingTransaction([u8; 32]),
+    PutAccount(PublicKey, Box<Account>),
+    DeleteAccount(PublicKey),
+    PutChainState(Box<ChainState>),
+    PutCommitCertificate(Box<CommitCertificate>),
+    PutBlockUndo([u8; 32], Box<BlockUndo>),
+    DeleteBlockUndo([u8; 32]),
+    /// Store a state tree node under its own hash.
+    PutTrieNode(Box<TrieNode>),
+    DeleteTrieNode([u8; 32]),
+    /// Set the bytes charged to an address; zero clears the entry.
+    PutStorageUsage(Address, u64),
+    PutPruneHorizon(PruneHorizon),
+    /// Record the state the chain started from; written once, with genesis.
+    PutGenesisState(Vec<StateEntry>),
+    PutContractCode(ContractId, Vec<u8>),
+    DeleteContractCode(ContractId),
+    ContractStorageWrite(ContractId, Vec<u8>, Vec<u8>),
+    ContractStorageRemove(ContractId, Vec<u8>),
+}
+
+impl From<StateEntry> for StorageOperation {
+    /// The write installing `entry` into the flat state.
+    fn from(entry: StateEntry) -> Self {
+        match entry {
+            StateEntry::Account(address, account) => {
+                StorageOperation::PutAccount(address, Box::new(account))
+            }
+            StateEntry::ContractCode(contract_id, code) => {
+                StorageOperation::PutContractCode(contract_id, code)
+            }
+            StateEntry::ContractStorage(contract_id, key, value) => {
+                StorageOperation::ContractStorageWrite(contract_id, key, value)
+            }
         }
     }
 }
 
-impl Storage for SledStorage {
-    fn put_block(&self, block: &Block) -> Result<(), StorageError> {
-        let block_hash = block.hash;
-        let block_height = block.index;
-        let encoded = bincode::serialize(block)?;
+/// The named key spaces every backend provides, one per kind of record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KvTree {
+    Blocks,
+    Transactions,
+    Receipts,
+    TxByBlock,
+    Mempool,
+    Accounts,
+    ChainState,
+    Certificates,
+    BlockUndo,
+    StateTrie,
+    ContractCode,
+    ContractStorage,
+    Meta,
+    AddressTxs,
+    DataTags,
+    Usage,
+    Raft,
+}
+
+impl KvTree {
+    pub const ALL: [KvTree; 17] = [
+        KvTree::Blocks,
+        KvTree::Transactions,
+        KvTree::Receipts,
+        KvTree::TxByBlock,
+        KvTree::Mempool,
+        KvTree::Accounts,
+        KvTree::ChainState,
+        KvTree::Certificates,
+        KvTree::BlockUndo,
+        KvTree::StateTrie,
+        KvTree::ContractCode,
+        KvTree::ContractStorage,
+        KvTree::Meta,
+        KvTree::AddressTxs,
+        KvTree::DataTags,
+        KvTree::Usage,
+        KvTree::Raft,
+    ];
+
+    /// Name of the tree on disk; matches the sled tree names.
+    pub fn name(self) -> &'static str {
+        match self {
+            KvTree::Blocks => "blocks",
+            KvTree::Transactions => "transactions",
+            KvTree::Receipts => "receipts",
+            KvTree::TxByBlock => "tx_by_block",
+            KvTree::Mempool => "mempool",
+            KvTree::Accounts => "accounts",
+            KvTree::ChainState => "chain_state",
+            KvTree::Certificates => "commit_certificates",
+            KvTree::BlockUndo => "block_undo",
+            KvTree::StateTrie => "state_trie",
+            KvTree::ContractCode => "contract_code",
+            KvTree::ContractStorage => "contract_storage",
+            KvTree::Meta => "meta",
+            KvTree::AddressTxs => "address_txs",
+            KvTree::DataTags => "data_tags",
+            KvTree::Usage => "usage",
+            KvTree::Raft => "raft",
+        }
+    }
+}
+
+/// Which way to walk an ordered index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Order {
+    /// Oldest (smallest key) first.
+    #[default]
+    Ascending,
+    /// Newest (largest key) first.
+    Descending,
+}
+
+/// A key and its value.
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// A write to one key; `None` deletes it.
+pub type KvWrite = (KvTree, Vec<u8>, Option<Vec<u8>>);
+
+/// An ordered key-value store with named trees.
+///
+/// Keys within a tree are ordered bytewise; [`KvStorage`] relies on that for
+/// height-ordered block keys and prefix scans.
+pub trait KvBackend: Send + Sync {
+    fn get(&self, tree: KvTree, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    fn insert(&self, tree: KvTree, key: &[u8], value: &[u8]) -> Result<(), StorageError>;
+
+    fn remove(&self, tree: KvTree, key: &[u8]) -> Result<(), StorageError>;
+
+    /// All entries whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, tree: KvTree, prefix: &[u8]) -> Result<Vec<KvPair>, StorageError>;
+
+    /// The entry with the greatest key starting with `prefix`.
+    fn last_with_prefix(&self, tree: KvTree, prefix: &[u8])
+        -> Result<Option<KvPair>, StorageError>;
+
+    /// Up to `limit` entries with `start <= key < end`, in `order`.
+    fn scan_range(
+        &self,
+        tree: KvTree,
+        start: &[u8],
+        end: &[u8],
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, StorageError>;
+
+    /// Apply `writes` in order, atomically across trees.
+    fn write_batch(&self, writes: Vec<KvWrite>) -> Result<(), StorageError>;
+}
+
+/// Smallest key greater than every key starting with `prefix`, if any.
+pub(crate) fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut bound = prefix.to_vec();
+    while let Some(last) = bound.pop() {
+        if last < u8::MAX {
+            bound.push(last + 1);
+            return Some(bound);
+        }
+    }
+    None
+}
+
+/// [`Storage`] over any [`KvBackend`].
+#[derive(Clone)]
+pub struct KvStorage<B: KvBackend> {
+    backend: B,
+}
+
+fn block_key(hash: &[u8; 32]) -> Vec<u8> {
+    KeyBuilder::new(keys::BLOCK).hash(hash).build()
+}
+
+fn height_key(height: u64) -> Vec<u8> {
+    KeyBuilder::new(keys::BLOCK_HEIGHT).u64(height).build()
+}
+
+fn pending_key(tx_hash: &[u8; 32]) -> Vec<u8> {
+    KeyBuilder::new(keys::PENDING).hash(tx_hash).build()
+}
+
+fn block_tx_prefix(block_hash: &[u8; 32]) -> Vec<u8> {
+    KeyBuilder::new(keys::BLOCK_TX).hash(block_hash).build()
+}
 
-        self.blocks_tree.insert(block_hash, encoded.clone())?;
-        self.blocks_tree
-            .insert(format!("height:{:0>20}", block_height).as_bytes(), encoded)?;
+fn tx_index_key(block_hash: &[u8; 32], tx_index_in_block: u32) -> Vec<u8> {
+    KeyBuilder::new(keys::BLOCK_TX)
+        .hash(block_hash)
+        .u32(tx_index_in_block)
+        .build()
+}
+
+fn receipt_key(block_hash: &[u8; 32], tx_index_in_block: u32) -> Vec<u8> {
+    KeyBuilder::new(keys::RECEIPT)
+        .hash(block_hash)
+        .u32(tx_index_in_block)
+        .build()
+}
+
+fn parse_tx_index_key(key: &[u8]) -> Result<u32, StorageError> {
+    let mut reader = KeyReader::new(key, keys::BLOCK_TX)?;
+    reader.hash()?;
+    reader.u32()
+}
+
+/// `address` under `wallet_tag` or `contract_tag`, depending on its kind.
+fn address_key(address: &Address, wallet_tag: u8, contract_tag: u8) -> KeyBuilder {
+    match address {
+        Address::Wallet(public_key) => KeyBuilder::new(wallet_tag).hash(public_key.as_bytes()),
+        Address::Contract(contract_id) => KeyBuilder::new(contract_tag).hash(&contract_id.id),
+    }
+}
+
+fn address_tx_prefix(address: &Address) -> KeyBuilder {
+    address_key(address, keys::WALLET_TX, keys::CONTRACT_TX)
+}
+
+fn usage_key(address: &Address) -> Vec<u8> {
+    address_key(address, keys::WALLET_USAGE, keys::CONTRACT_USAGE).build()
+}
+
+fn address_tx_key(address: &Address, position: TxPosition) -> Vec<u8> {
+    address_tx_prefix(address)
+        .u64(position.height)
+        .u32(position.tx_index_in_block)
+        .build()
+}
+
+fn parse_address_tx_key(key: &[u8]) -> Result<TxPosition, StorageError> {
+    let mut reader = KeyReader::new(key, key.first().copied().unwrap_or_default())?;
+    reader.hash()?;
+    Ok(TxPosition {
+        height: reader.u64()?,
+        tx_index_in_block: reader.u32()?,
+    })
+}
+
+fn tag_key(name: &str, value: &str, position: TxPosition) -> Vec<u8> {
+    KeyBuilder::new(keys::DATA_TAG)
+        .text(name)
+        .text(value)
+        .u64(position.height)
+        .u32(position.tx_index_in_block)
+        .build()
+}
+
+fn parse_tag_key(key: &[u8]) -> Result<TaggedTransaction, StorageError> {
+    let mut reader = KeyReader::new(key, keys::DATA_TAG)?;
+    reader.text()?;
+    Ok(TaggedTransaction {
+        value: reader.text()?,
+        position: TxPosition {
+            height: reader.u64()?,
+            tx_index_in_block: reader.u32()?,
+        },
+        tx_hash: [0; 32],
+    })
+}
+
+fn contract_state_key(contract_id: &ContractId, key: &[u8]) -> Vec<u8> {
+    KeyBuilder::new(keys::CONTRACT_SLOT)
+        .hash(&contract_id.id)
+        .bytes(key)
+        .build()
+}
+
+fn parse_contract_state_key(key: &[u8]) -> Result<(ContractId, Vec<u8>), StorageError> {
+    let mut reader = KeyReader::new(key, keys::CONTRACT_SLOT)?;
+    let id = reader.hash()?;
+    Ok((ContractId::from_bytes(&id), reader.rest().to_vec()))
+}
+
+const CHAIN_STATE_KEY: &[u8] = &[keys::CHAIN_STATE];
+const PRUNE_HORIZON_KEY: &[u8] = &[keys::PRUNE_HORIZON];
+const RAFT_STATE_KEY: &[u8] = &[keys::RAFT_STATE];
+const GENESIS_STATE_KEY: &[u8] = &[keys::GENESIS_STATE];
+/// Present while a reindex is running (`Meta`); see [`crate::reindex`].
+pub(crate) const REINDEX_CHECKPOINT_KEY: &[u8] = &[keys::REINDEX_CHECKPOINT];
+
+fn raft_entry_key(index: u64) -> Vec<u8> {
+    KeyBuilder::new(keys::RAFT_ENTRY).u64(index).build()
+}
+
+fn decode<T: serde::de::DeserializeOwned>(
+    encoded: Option<Vec<u8>>,
+) -> Result<Option<T>, StorageError> {
+    Ok(encoded.map(|e| bincode::deserialize(&e)).transpose()?)
+}
+
+fn hash_from_key(key: &[u8]) -> Result<[u8; 32], StorageError> {
+    key.try_into()
+        .map_err(|_| StorageError::CorruptKey(hex::encode(key)))
+}
+
+impl<B: KvBackend> KvStorage<B> {
+    pub fn with_backend(backend: B) -> Self {
+        Self { backend }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Writes deleting everything applying the canonical blocks recreates:
+    /// state, the state tree, transaction records, the transaction, address
+    /// history and tag indices, storage usage, receipts, undo records and the
+    /// chain state. Blocks, commit certificates, the mempool and the prune
+    /// horizon are kept.
+    pub(crate) fn derived_data_deletes(&self) -> Result<Vec<KvWrite>, StorageError> {
+        let mut writes = Vec::new();
+        for tree in [
+            KvTree::Transactions,
+            KvTree::Receipts,
+            KvTree::TxByBlock,
+            KvTree::AddressTxs,
+            KvTree::DataTags,
+            KvTree::Usage,
+            KvTree::Accounts,
+            KvTree::BlockUndo,
+            KvTree::StateTrie,
+            KvTree::ContractCode,
+            KvTree::ContractStorage,
+        ] {
+            for (key, _) in self.backend.scan_prefix(tree, b"")? {
+                writes.push((tree, key, None));
+            }
+        }
+        writes.push((KvTree::ChainState, CHAIN_STATE_KEY.to_vec(), None));
+        Ok(writes)
+    }
+
+    /// The raw writes [`Storage::apply_batch`] would commit for `batch`.
+    pub(crate) fn encode_batch(&self, batch: StorageBatch) -> Result<Vec<KvWrite>, StorageError> {
+        encode_batch(batch)
+    }
+
+    /// The canonical block at `height`, whether or not its body is pruned.
+    fn canonical_block(&self, height: u64) -> Result<Option<Block>, StorageError> {
+        match self.backend.get(KvTree::Blocks, &height_key(height))? {
+            Some(hash) => self.block_at(&hash).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The block a height entry points at, which is written along with it.
+    fn block_at(&self, hash: &[u8]) -> Result<Block, StorageError> {
+        let encoded = self
+            .backend
+            .get(KvTree::Blocks, &block_key(&hash_from_key(hash)?))?
+            .ok_or(StorageError::NotFound)?;
+        Ok(bincode::deserialize(&encoded)?)
+    }
+
+    fn check_body_retained(&self, height: u64) -> Result<(), StorageError> {
+        if height < self.get_prune_horizon()?.bodies {
+            return Err(StorageError::Pruned(height));
+        }
         Ok(())
     }
+}
+
+/// The raw writes committing `batch` in the layout [`KvStorage`] reads.
+fn encode_batch(batch: StorageBatch) -> Result<Vec<KvWrite>, StorageError> {
+    let mut writes: Vec<KvWrite> = Vec::new();
+    for op in batch.ops {
+        match op {
+            StorageOperation::PutBlock(block) => {
+                let encoded = bincode::serialize(&block)?;
+                writes.push((KvTree::Blocks, block_key(&block.hash), Some(encoded)));
+                writes.push((
+                    KvTree::Blocks,
+                    height_key(block.index),
+                    Some(block.hash.to_vec()),
+                ));
+            }
+            StorageOperation::RemoveBlockHeight(height) => {
+                writes.push((KvTree::Blocks, height_key(height), None));
+            }
+            StorageOperation::PutTransaction(tx) => {
+                let encoded = bincode::serialize(&tx)?;
+                writes.push((KvTree::Transactions, tx.hash.to_vec(), Some(encoded)));
+            }
+            StorageOperation::DeleteTransaction(tx_hash) => {
+                writes.push((KvTree::Transactions, tx_hash.to_vec(), None));
+            }
+            StorageOperation::PutReceipt {
+                block_hash,
+                tx_index_in_block,
+                receipt,
+            } => {
+                let encoded = bincode::serialize(&receipt)?;
+                writes.push((
+                    KvTree::Receipts,
+                    receipt_key(&block_hash, tx_index_in_block),
+                    Some(encoded),
+                ));
+            }
+            StorageOperation::DeleteReceipt {
+                block_hash,
+                tx_index_in_block,
+            } => {
+                writes.push((
+                    KvTree::Receipts,
+                    receipt_key(&block_hash, tx_index_in_block),
+                    None,
+                ));
+            }
+            StorageOperation::IndexTransaction {
+                tx_hash,
+                block_hash,
+                tx_index_in_block,
+            } => {
+                writes.push((
+                    KvTree::TxByBlock,
+                    tx_index_key(&block_hash, tx_index_in_block),
+                    Some(tx_hash.to_vec()),
+                ));
+            }
+            StorageOperation::UnindexTransaction {
+                block_hash,
+                tx_index_in_block,
+            } => {
+                writes.push((
+                    KvTree::TxByBlock,
+                    tx_index_key(&block_hash, tx_index_in_block),
+                    None,
+                ));
+            }
+            StorageOperation::IndexAddressTransaction {
+                address,
+                position,
+                tx_hash,
+            } => {
+                writes.push((
+                    KvTree::AddressTxs,
+                    address_tx_key(&address, position),
+                    Some(tx_hash.to_vec()),
+                ));
+            }
+            StorageOperation::UnindexAddressTransaction { address, position } => {
+                writes.push((KvTree::AddressTxs, address_tx_key(&address, position), None));
+            }
+            StorageOperation::IndexTag {
+                name,
+                value,
+                position,
+                tx_hash,
+            } => {
+                writes.push((
+                    KvTree::DataTags,
+                    tag_key(&name, &value, position),
+                    Some(tx_hash.to_vec()),
+                ));
+            }
+            StorageOperation::UnindexTag {
+                name,
+                value,
+                position,
+            } => {
+                writes.push((KvTree::DataTags, tag_key(&name, &value, position), None));
+            }
+            StorageOperation::RemovePendingTransaction(tx_hash) => {
+                writes.push((KvTree::Mempool, pending_key(&tx_hash), None));
+            }
+            StorageOperation::PutAccount(address, account) => {
+                let encoded = bincode::serialize(&account)?;
+                writes.push((KvTree::Accounts, address.to_bytes().to_vec(), Some(encoded)));
+            }
+            StorageOperation::DeleteAccount(address) => {
+                writes.push((KvTree::Accounts, address.to_bytes().to_vec(), None));
+            }
+            StorageOperation::PutChainState(state) => {
+                let encoded = bincode::serialize(&state)?;
+                writes.push((KvTree::ChainState, CHAIN_STATE_KEY.to_vec(), Some(encoded)));
+            }
+            StorageOperation::PutCommitCertificate(certificate) => {
+                let encoded = bincode::serialize(&certificate)?;
+                writes.push((
+                    KvTree::Certificates,
+                    certificate.block_hash.to_vec(),
+                    Some(encoded),
+                ));
+            }
+            StorageOperation::PutBlockUndo(block_hash, undo) => {
+                let encoded = bincode::serialize(&undo)?;
+                writes.push((KvTree::BlockUndo, block_hash.to_vec(), Some(encoded)));
+            }
+            StorageOperation::DeleteBlockUndo(block_hash) => {
+                writes.push((KvTree::BlockUndo, block_hash.to_vec(), None));
+            }
+            StorageOperation::PutTrieNode(node) => {
+                let encoded = bincode::serialize(&node)?;
+                writes.push((KvTree::StateTrie, node.hash().to_vec(), Some(encoded)));
+            }
+            StorageOperation::DeleteTrieNode(hash) => {
+                writes.push((KvTree::StateTrie, hash.to_vec(), None));
+            }
+            StorageOperation::PutStorageUsage(address, bytes) => {
+                let value = (bytes > 0).then(|| bytes.to_be_bytes().to_vec());
+                writes.push((KvTree::Usage, usage_key(&address), value));
+            }
+            StorageOperation::PutPruneHorizon(horizon) => {
+                let encoded = bincode::serialize(&horizon)?;
+                writes.push((
+                    KvTree::ChainState,
+                    PRUNE_HORIZON_KEY.to_vec(),
+                    Some(encoded),
+                ));
+            }
+            StorageOperation::PutGenesisState(entries) => {
+                let encoded = bincode::serialize(&entries)?;
+                writes.push((
+                    KvTree::ChainState,
+                    GENESIS_STATE_KEY.to_vec(),
+                    Some(encoded),
+                ));
+            }
+            StorageOperation::PutContractCode(contract_id, wasm_bytes) => {
+                writes.push((
+                    KvTree::ContractCode,
+                    contract_id.id.to_vec(),
+                    Some(wasm_bytes),
+                ));
+            }
+            StorageOperation::DeleteContractCode(contract_id) => {
+                writes.push((KvTree::ContractCode, contract_id.id.to_vec(), None));
+            }
+            StorageOperation::ContractStorageWrite(contract_id, key, value) => {
+                writes.push((
+                    KvTree::ContractStorage,
+                    contract_state_key(&contract_id, &key),
+                    Some(value),
+                ));
+            }
+            StorageOperation::ContractStorageRemove(contract_id, key) => {
+                writes.push((
+                    KvTree::ContractStorage,
+                    contract_state_key(&contract_id, &key),
+                    None,
+                ));
+            }
+        }
+    }
+    Ok(writes)
+}
+
+impl<B: KvBackend> Storage for KvStorage<B> {
+    fn put_block(&self, block: &Block) -> Result<(), StorageError> {
+        let encoded = bincode::serialize(block)?;
+        self.backend.write_batch(vec![
+            (KvTree::Blocks, block_key(&block.hash), Some(encoded)),
+            (
+                KvTree::Blocks,
+                height_key(block.index),
+                Some(block.hash.to_vec()),
+            ),
+        ])
+    }
 
     fn get_block(&self, hash: &[u8; 32]) -> Result<Option<Block>, StorageError> {
-        let encoded = self.blocks_tree.get(hash)?;
-        Ok(encoded.map(|e| bincode::deserialize(&e)).transpose()?)
+        let block: Option<Block> = decode(self.backend.get(KvTree::Blocks, &block_key(hash))?)?;
+        match block {
+            Some(block) => self.check_body_retained(block.index).map(|()| Some(block)),
+            None => Ok(None),
+        }
     }
 
     fn get_latest_block(&self) -> Result<Option<Block>, StorageError> {
-        let mut iter = self.blocks_tree.scan_prefix("height:").rev();
-        if let Some(Ok((_key, encoded))) = iter.next() {
-            Ok(Some(bincode::deserialize(&encoded)?))
-        } else {
-            Ok(None)
+        match self
+            .backend
+            .last_with_prefix(KvTree::Blocks, &[keys::BLOCK_HEIGHT])?
+        {
+            Some((_key, hash)) => self.block_at(&hash).map(Some),
+            None => Ok(None),
         }
     }
 
@@ -203,35 +1064,83 @@ impl Storage for SledStorage {
     }
 
     fn get_block_by_height(&self, height: u64) -> Result<Option<Block>, StorageError> {
-        let encoded = self
-            .blocks_tree
-            .get(format!("height:{:0>20}", height).as_bytes())?;
-        Ok(encoded.map(|e| bincode::deserialize(&e)).transpose()?)
+        self.check_body_retained(height)?;
+        self.canonical_block(height)
+    }
+
+    fn get_block_header_by_height(&self, height: u64) -> Result<Option<BlockHeader>, StorageError> {
+        Ok(self.canonical_block(height)?.map(|block| block.header()))
+    }
+
+    fn put_side_block(&self, block: &Block) -> Result<(), StorageError> {
+        let encoded = bincode::serialize(block)?;
+        self.backend
+            .insert(KvTree::Blocks, &block_key(&block.hash), &encoded)
+    }
+
+    fn remove_block_height(&self, height: u64) -> Result<(), StorageError> {
+        self.backend.remove(KvTree::Blocks, &height_key(height))
+    }
+
+    fn put_block_undo(&self, block_hash: &[u8; 32], undo: &BlockUndo) -> Result<(), StorageError> {
+        let encoded = bincode::serialize(undo)?;
+        self.backend.insert(KvTree::BlockUndo, block_hash, &encoded)
+    }
+
+    fn get_block_undo(&self, block_hash: &[u8; 32]) -> Result<Option<BlockUndo>, StorageError> {
+        decode(self.backend.get(KvTree::BlockUndo, block_hash)?)
+    }
+
+    fn delete_block_undo(&self, block_hash: &[u8; 32]) -> Result<(), StorageError> {
+        self.backend.remove(KvTree::BlockUndo, block_hash)
     }
 
     fn put_transaction(&self, tx: &Transaction) -> Result<(), StorageError> {
         let encoded = bincode::serialize(tx)?;
-        self.transactions_tree.insert(tx.hash, encoded)?;
-        Ok(())
+        self.backend
+            .insert(KvTree::Transactions, &tx.hash, &encoded)
     }
 
     fn get_transaction(&self, tx_hash: &[u8; 32]) -> Result<Option<Transaction>, StorageError> {
-        let encoded = self.transactions_tree.get(tx_hash)?;
-        Ok(encoded.map(|e| bincode::deserialize(&e)).transpose()?)
+        decode(self.backend.get(KvTree::Transactions, tx_hash)?)
+    }
+
+    fn get_receipt(
+        &self,
+        block_hash: &[u8; 32],
+        tx_index_in_block: u32,
+    ) -> Result<Option<TransactionReceipt>, StorageError> {
+        decode(self.backend.get(
+            KvTree::Receipts,
+            &receipt_key(block_hash, tx_index_in_block),
+        )?)
+    }
+
+    fn get_block_receipts(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Vec<TransactionReceipt>, StorageError> {
+        let prefix = KeyBuilder::new(keys::RECEIPT).hash(block_hash).build();
+        self.backend
+            .scan_prefix(KvTree::Receipts, &prefix)?
+            .into_iter()
+            .map(|(_, encoded)| Ok(bincode::deserialize(&encoded)?))
+            .collect()
     }
 
     fn get_pending_transactions(&self) -> Result<Vec<Transaction>, StorageError> {
         let mut transactions = Vec::new();
-        for item in self.mempool_tree.scan_prefix("pending:") {
-            let (_key, encoded) = item?;
+        for (_key, encoded) in self
+            .backend
+            .scan_prefix(KvTree::Mempool, &[keys::PENDING])?
+        {
             transactions.push(bincode::deserialize(&encoded)?);
         }
         Ok(transactions)
     }
 
     fn remove_pending_transaction(&self, tx_hash: &[u8; 32]) -> Result<(), StorageError> {
-        self.mempool_tree.remove(tx_hash)?;
-        Ok(())
+        self.backend.remove(KvTree::Mempool, &pending_key(tx_hash))
     }
 
     // New: Transaction indexing for fast lookup by block
@@ -241,70 +1150,233 @@ impl Storage for SledStorage {
         block_hash: &[u8; 32],
         tx_index_in_block: u32,
     ) -> Result<(), StorageError> {
-        let key = format!(
-            "block_tx:{}:{}:{:0>10}",
-            hex::encode(block_hash),
-            hex::encode(tx_hash),
-            tx_index_in_block
-        );
-        self.tx_by_block_tree.insert(key, tx_hash.as_slice())?;
-        Ok(())
+        self.backend.insert(
+            KvTree::TxByBlock,
+            &tx_index_key(block_hash, tx_index_in_block),
+            tx_hash,
+        )
     }
 
     fn get_transaction_by_id(
         &self,
         tx_hash: &[u8; 32],
     ) -> Result<Option<Transaction>, StorageError> {
-        let encoded = self.transactions_tree.get(tx_hash)?;
-        Ok(encoded.map(|e| bincode::deserialize(&e)).transpose()?)
+        decode(self.backend.get(KvTree::Transactions, tx_hash)?)
     }
 
     fn get_transactions_by_block(
         &self,
         block_hash: &[u8; 32],
     ) -> Result<Vec<Transaction>, StorageError> {
+        Ok(self
+            .get_transactions_by_block_page(block_hash, None, Order::Ascending, usize::MAX)?
+            .into_iter()
+            .map(|(_, tx)| tx)
+            .collect())
+    }
+
+    fn get_transactions_by_block_page(
+        &self,
+        block_hash: &[u8; 32],
+        after: Option<u32>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(u32, Transaction)>, StorageError> {
+        if let Some(encoded) = self.backend.get(KvTree::Blocks, &block_key(block_hash))? {
+            let block: Block = bincode::deserialize(&encoded)?;
+            self.check_body_retained(block.index)?;
+        }
+        let prefix = block_tx_prefix(block_hash);
+        let mut start = prefix.clone();
+        let mut end = prefix_upper_bound(&prefix).unwrap_or_default();
+        match (after, order) {
+            (None, _) => {}
+            (Some(after), Order::Ascending) => {
+                start = tx_index_key(block_hash, after);
+                start.push(0);
+            }
+            (Some(after), Order::Descending) => end = tx_index_key(block_hash, after),
+        }
         let mut transactions = Vec::new();
-        let prefix_string = format!("block_tx:{}:", hex::encode(block_hash));
-        for item in self.tx_by_block_tree.scan_prefix(prefix_string.as_bytes()) {
-            let (_key, tx_hash_bytes) = item?;
-            let tx_hash_array: [u8; 32] = tx_hash_bytes
-                .as_ref()
-                .try_into()
-                .map_err(|_| CryptoError::HashConversionError)?;
-            if let Some(tx) = self.get_transaction(&tx_hash_array)? {
-                transactions.push(tx);
+        for (key, tx_hash) in
+            self.backend
+                .scan_range(KvTree::TxByBlock, &start, &end, order, limit)?
+        {
+            if let Some(tx) = self.get_transaction(&hash_from_key(&tx_hash)?)? {
+                transactions.push((parse_tx_index_key(&key)?, tx));
             }
         }
-        // Transactions might not be in exact order if we don't sort after retrieval,
-        // but for now, simple retrieval by block is the goal.
         Ok(transactions)
     }
 
+    fn get_address_transactions(
+        &self,
+        address: &Address,
+        after: Option<TxPosition>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(TxPosition, [u8; 32])>, StorageError> {
+        let prefix = address_tx_prefix(address).build();
+        // Tags are below 0xff, so the prefix always has an upper bound.
+        let mut start = prefix.clone();
+        let mut end = prefix_upper_bound(&prefix).unwrap_or_default();
+        match (after, order) {
+            (None, _) => {}
+            (Some(after), Order::Ascending) => {
+                start = address_tx_key(address, after);
+                start.push(0);
+            }
+            (Some(after), Order::Descending) => end = address_tx_key(address, after),
+        }
+        self.backend
+            .scan_range(KvTree::AddressTxs, &start, &end, order, limit)?
+            .into_iter()
+            .map(|(key, tx_hash)| Ok((parse_address_tx_key(&key)?, hash_from_key(&tx_hash)?)))
+            .collect()
+    }
+
+    fn get_tagged_transactions(
+        &self,
+        query: &TagQuery,
+        after: Option<&TaggedTransaction>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<TaggedTransaction>, StorageError> {
+        let prefix = KeyBuilder::new(keys::DATA_TAG).text(&query.name).build();
+        let value_key = |value: &str| {
+            KeyBuilder::new(keys::DATA_TAG)
+                .text(&query.name)
+                .text(value)
+                .build()
+        };
+        let mut start = query
+            .from
+            .as_deref()
+            .map_or_else(|| prefix.clone(), value_key);
+        let mut end = match query.to.as_deref() {
+            Some(to) => value_key(to),
+            None => prefix_upper_bound(&prefix).unwrap_or_default(),
+        };
+        if let Some(after) = after {
+            let cursor = tag_key(&query.name, &after.value, after.position);
+            match order {
+                Order::Ascending => {
+                    let mut next = cursor;
+                    next.push(0);
+                    start = start.max(next);
+                }
+                Order::Descending => end = end.min(cursor),
+            }
+        }
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        self.backend
+            .scan_range(KvTree::DataTags, &start, &end, order, limit)?
+            .into_iter()
+            .map(|(key, tx_hash)| {
+                Ok(TaggedTransaction {
+                    tx_hash: hash_from_key(&tx_hash)?,
+                    ..parse_tag_key(&key)?
+                })
+            })
+            .collect()
+    }
+
     fn put_account(&self, address: &PublicKey, account: &Account) -> Result<(), StorageError> {
         let encoded = bincode::serialize(account)?;
-        self.accounts_tree.insert(address.to_bytes(), encoded)?;
-        Ok(())
+        self.backend
+            .insert(KvTree::Accounts, address.as_bytes(), &encoded)
     }
 
     fn get_account(&self, address: &PublicKey) -> Result<Option<Account>, StorageError> {
-        let encoded = self.accounts_tree.get(address.to_bytes())?;
-        Ok(encoded.map(|e| bincode::deserialize(&e)).transpose()?)
+        decode(self.backend.get(KvTree::Accounts, address.as_bytes())?)
     }
 
     fn delete_account(&self, address: &PublicKey) -> Result<(), StorageError> {
-        self.accounts_tree.remove(address.to_bytes())?;
-        Ok(())
+        self.backend.remove(KvTree::Accounts, address.as_bytes())
     }
 
     fn put_chain_state(&self, state: &ChainState) -> Result<(), StorageError> {
         let encoded = bincode::serialize(state)?;
-        self.chain_state_tree.insert("global:current", encoded)?;
-        Ok(())
+        self.backend
+            .insert(KvTree::ChainState, CHAIN_STATE_KEY, &encoded)
     }
 
     fn get_chain_state(&self) -> Result<Option<ChainState>, StorageError> {
-        let encoded = self.chain_state_tree.get("global:current")?;
-        Ok(encoded.map(|e| bincode::deserialize(&e)).transpose()?)
+        decode(self.backend.get(KvTree::ChainState, CHAIN_STATE_KEY)?)
+    }
+
+    fn put_commit_certificate(&self, certificate: &CommitCertificate) -> Result<(), StorageError> {
+        let encoded = bincode::serialize(certificate)?;
+        self.backend
+            .insert(KvTree::Certificates, &certificate.block_hash, &encoded)
+    }
+
+    fn get_commit_certificate(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<CommitCertificate>, StorageError> {
+        decode(self.backend.get(KvTree::Certificates, block_hash)?)
+    }
+
+    fn get_trie_node(&self, hash: &[u8; 32]) -> Result<Option<TrieNode>, StorageError> {
+        decode(self.backend.get(KvTree::StateTrie, hash)?)
+    }
+
+    fn get_trie_node_hashes(&self) -> Result<Vec<[u8; 32]>, StorageError> {
+        self.backend
+            .scan_prefix(KvTree::StateTrie, b"")?
+            .into_iter()
+            .map(|(key, _)| hash_from_key(&key))
+            .collect()
+    }
+
+    fn get_state_entries(&self) -> Result<Vec<StateEntry>, StorageError> {
+        let mut entries = Vec::new();
+        for (key, encoded) in self.backend.scan_prefix(KvTree::Accounts, b"")? {
+            entries.push(StateEntry::Account(
+                PublicKey::from_bytes(&hash_from_key(&key)?)?,
+                bincode::deserialize(&encoded)?,
+            ));
+        }
+        for (key, code) in self.backend.scan_prefix(KvTree::ContractCode, b"")? {
+            entries.push(StateEntry::ContractCode(
+                ContractId::from_bytes(&hash_from_key(&key)?),
+                code,
+            ));
+        }
+        for (key, value) in self.backend.scan_prefix(KvTree::ContractStorage, b"")? {
+            let (contract_id, slot) = parse_contract_state_key(&key)?;
+            entries.push(StateEntry::ContractStorage(contract_id, slot, value));
+        }
+        Ok(entries)
+    }
+
+    fn get_prune_horizon(&self) -> Result<PruneHorizon, StorageError> {
+        Ok(decode(self.backend.get(KvTree::ChainState, PRUNE_HORIZON_KEY)?)?.unwrap_or_default())
+    }
+
+    fn get_genesis_state(&self) -> Result<Option<Vec<StateEntry>>, StorageError> {
+        decode(self.backend.get(KvTree::ChainState, GENESIS_STATE_KEY)?)
+    }
+
+    fn is_reindexing(&self) -> Result<bool, StorageError> {
+        Ok(self
+            .backend
+            .get(KvTree::Meta, REINDEX_CHECKPOINT_KEY)?
+            .is_some())
+    }
+
+    fn get_storage_usage(&self, address: &Address) -> Result<u64, StorageError> {
+        let Some(encoded) = self.backend.get(KvTree::Usage, &usage_key(address))? else {
+            return Ok(0);
+        };
+        let bytes: [u8; 8] = encoded
+            .as_slice()
+            .try_into()
+            .map_err(|_| StorageError::CorruptKey(hex::encode(usage_key(address))))?;
+        Ok(u64::from_be_bytes(bytes))
     }
 
     fn put_contract_code(
@@ -312,13 +1384,16 @@ impl Storage for SledStorage {
         contract_id: &ContractId,
         wasm_bytes: &[u8],
     ) -> Result<(), StorageError> {
-        self.contract_code_tree.insert(contract_id.id, wasm_bytes)?;
-        Ok(())
+        self.backend
+            .insert(KvTree::ContractCode, &contract_id.id, wasm_bytes)
     }
 
     fn get_contract_code(&self, contract_id: &ContractId) -> Result<Option<Vec<u8>>, StorageError> {
-        let encoded = self.contract_code_tree.get(contract_id.id)?;
-        Ok(encoded.map(|e| e.to_vec()))
+        self.backend.get(KvTree::ContractCode, &contract_id.id)
+    }
+
+    fn delete_contract_code(&self, contract_id: &ContractId) -> Result<(), StorageError> {
+        self.backend.remove(KvTree::ContractCode, &contract_id.id)
     }
 
     fn contract_storage_read(
@@ -326,9 +1401,10 @@ impl Storage for SledStorage {
         contract_id: &ContractId,
         key: &[u8],
     ) -> Result<Option<Vec<u8>>, StorageError> {
-        let full_key = format!("state:{}:{}", hex::encode(contract_id.id), hex::encode(key));
-        let encoded = self.contract_storage_tree.get(full_key)?;
-        Ok(encoded.map(|e| e.to_vec()))
+        self.backend.get(
+            KvTree::ContractStorage,
+            &contract_state_key(contract_id, key),
+        )
     }
 
     fn contract_storage_write(
@@ -337,9 +1413,11 @@ impl Storage for SledStorage {
         key: &[u8],
         value: &[u8],
     ) -> Result<(), StorageError> {
-        let full_key = format!("state:{}:{}", hex::encode(contract_id.id), hex::encode(key));
-        self.contract_storage_tree.insert(full_key, value)?;
-        Ok(())
+        self.backend.insert(
+            KvTree::ContractStorage,
+            &contract_state_key(contract_id, key),
+            value,
+        )
     }
 
     fn contract_storage_remove(
@@ -347,24 +1425,504 @@ impl Storage for SledStorage {
         contract_id: &ContractId,
         key: &[u8],
     ) -> Result<(), StorageError> {
-        let full_key = format!("state:{}:{}", hex::encode(contract_id.id), hex::encode(key));
-        self.contract_storage_tree.remove(full_key)?;
-        Ok(())
+        self.backend.remove(
+            KvTree::ContractStorage,
+            &contract_state_key(contract_id, key),
+        )
     }
 
     fn apply_batch(&self, batch: StorageBatch) -> Result<(), StorageError> {
-        let mut tree_batch = sled::Batch::default();
-        for op in batch.ops {
-            match op {
-                StorageOperation::Put(key, value) => {
-                    tree_batch.insert(key, value);
-                }
-                StorageOperation::Delete(key) => {
-                    tree_batch.remove(key);
-                }
-            }
+        self.backend.write_batch(self.encode_batch(batch)?)
+    }
+}
+
+/// Lets one store be shared, e.g. between a [`crate::runtime::Runtime`] and
+/// the contract engine, without the backend having to be `Clone`.
+impl<B: KvBackend> RaftStorage for KvStorage<B> {
+    fn put_raft_hard_state(&self, state: &RaftHardState) -> Result<(), StorageError> {
+        let encoded = bincode::serialize(state)?;
+        self.backend.insert(KvTree::Raft, RAFT_STATE_KEY, &encoded)
+    }
+
+    fn get_raft_hard_state(&self) -> Result<Option<RaftHardState>, StorageError> {
+        decode(self.backend.get(KvTree::Raft, RAFT_STATE_KEY)?)
+    }
+
+    fn put_raft_entries(
+        &self,
+        first_index: u64,
+        entries: &[RaftEntry],
+    ) -> Result<(), StorageError> {
+        let mut writes: Vec<KvWrite> = self
+            .backend
+            .scan_range(
+                KvTree::Raft,
+                &raft_entry_key(first_index),
+                &[keys::RAFT_ENTRY + 1],
+                Order::Ascending,
+                usize::MAX,
+            )?
+            .into_iter()
+            .map(|(key, _)| (KvTree::Raft, key, None))
+            .collect();
+        for (offset, entry) in entries.iter().enumerate() {
+            writes.push((
+                KvTree::Raft,
+                raft_entry_key(first_index + offset as u64),
+                Some(bincode::serialize(entry)?),
+            ));
         }
-        self.db.apply_batch(tree_batch)?;
-        Ok(())
+        self.backend.write_batch(writes)
+    }
+
+    fn get_raft_entries(&self) -> Result<Vec<RaftEntry>, StorageError> {
+        self.backend
+            .scan_prefix(KvTree::Raft, &[keys::RAFT_ENTRY])?
+            .into_iter()
+            .map(|(_, encoded)| Ok(bincode::deserialize(&encoded)?))
+            .collect()
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    fn put_block(&self, block: &Block) -> Result<(), StorageError> {
+        (**self).put_block(block)
+    }
+
+    fn get_block(&self, hash: &[u8; 32]) -> Result<Option<Block>, StorageError> {
+        (**self).get_block(hash)
+    }
+
+    fn get_latest_block(&self) -> Result<Option<Block>, StorageError> {
+        (**self).get_latest_block()
+    }
+
+    fn get_chain_height(&self) -> Result<u64, StorageError> {
+        (**self).get_chain_height()
+    }
+
+    fn get_block_by_height(&self, height: u64) -> Result<Option<Block>, StorageError> {
+        (**self).get_block_by_height(height)
+    }
+
+    fn get_block_header_by_height(&self, height: u64) -> Result<Option<BlockHeader>, StorageError> {
+        (**self).get_block_header_by_height(height)
+    }
+
+    fn put_side_block(&self, block: &Block) -> Result<(), StorageError> {
+        (**self).put_side_block(block)
+    }
+
+    fn remove_block_height(&self, height: u64) -> Result<(), StorageError> {
+        (**self).remove_block_height(height)
+    }
+
+    fn put_block_undo(&self, block_hash: &[u8; 32], undo: &BlockUndo) -> Result<(), StorageError> {
+        (**self).put_block_undo(block_hash, undo)
+    }
+
+    fn get_block_undo(&self, block_hash: &[u8; 32]) -> Result<Option<BlockUndo>, StorageError> {
+        (**self).get_block_undo(block_hash)
+    }
+
+    fn delete_block_undo(&self, block_hash: &[u8; 32]) -> Result<(), StorageError> {
+        (**self).delete_block_undo(block_hash)
+    }
+
+    fn put_transaction(&self, tx: &Transaction) -> Result<(), StorageError> {
+        (**self).put_transaction(tx)
+    }
+
+    fn get_transaction(&self, tx_hash: &[u8; 32]) -> Result<Option<Transaction>, StorageError> {
+        (**self).get_transaction(tx_hash)
+    }
+
+    fn get_pending_transactions(&self) -> Result<Vec<Transaction>, StorageError> {
+        (**self).get_pending_transactions()
+    }
+
+    fn remove_pending_transaction(&self, tx_hash: &[u8; 32]) -> Result<(), StorageError> {
+        (**self).remove_pending_transaction(tx_hash)
+    }
+
+    fn get_receipt(
+        &self,
+        block_hash: &[u8; 32],
+        tx_index_in_block: u32,
+    ) -> Result<Option<TransactionReceipt>, StorageError> {
+        (**self).get_receipt(block_hash, tx_index_in_block)
+    }
+
+    fn get_block_receipts(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Vec<TransactionReceipt>, StorageError> {
+        (**self).get_block_receipts(block_hash)
+    }
+
+    fn index_transaction(
+        &self,
+        tx_hash: &[u8; 32],
+        block_hash: &[u8; 32],
+        tx_index_in_block: u32,
+    ) -> Result<(), StorageError> {
+        (**self).index_transaction(tx_hash, block_hash, tx_index_in_block)
+    }
+
+    fn get_transaction_by_id(
+        &self,
+        tx_hash: &[u8; 32],
+    ) -> Result<Option<Transaction>, StorageError> {
+        (**self).get_transaction_by_id(tx_hash)
+    }
+
+    fn get_transactions_by_block(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Vec<Transaction>, StorageError> {
+        (**self).get_transactions_by_block(block_hash)
+    }
+
+    fn get_transactions_by_block_page(
+        &self,
+        block_hash: &[u8; 32],
+        after: Option<u32>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(u32, Transaction)>, StorageError> {
+        (**self).get_transactions_by_block_page(block_hash, after, order, limit)
+    }
+
+    fn get_address_transactions(
+        &self,
+        address: &Address,
+        after: Option<TxPosition>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<(TxPosition, [u8; 32])>, StorageError> {
+        (**self).get_address_transactions(address, after, order, limit)
+    }
+
+    fn get_tagged_transactions(
+        &self,
+        query: &TagQuery,
+        after: Option<&TaggedTransaction>,
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<TaggedTransaction>, StorageError> {
+        (**self).get_tagged_transactions(query, after, order, limit)
+    }
+
+    fn put_account(&self, address: &PublicKey, account: &Account) -> Result<(), StorageError> {
+        (**self).put_account(address, account)
+    }
+
+    fn get_account(&self, address: &PublicKey) -> Result<Option<Account>, StorageError> {
+        (**self).get_account(address)
+    }
+
+    fn delete_account(&self, address: &PublicKey) -> Result<(), StorageError> {
+        (**self).delete_account(address)
+    }
+
+    fn put_chain_state(&self, state: &ChainState) -> Result<(), StorageError> {
+        (**self).put_chain_state(state)
+    }
+
+    fn get_chain_state(&self) -> Result<Option<ChainState>, StorageError> {
+        (**self).get_chain_state()
+    }
+
+    fn put_commit_certificate(&self, certificate: &CommitCertificate) -> Result<(), StorageError> {
+        (**self).put_commit_certificate(certificate)
+    }
+
+    fn get_commit_certificate(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<CommitCertificate>, StorageError> {
+        (**self).get_commit_certificate(block_hash)
+    }
+
+    fn get_trie_node(&self, hash: &[u8; 32]) -> Result<Option<TrieNode>, StorageError> {
+        (**self).get_trie_node(hash)
+    }
+
+    fn get_trie_node_hashes(&self) -> Result<Vec<[u8; 32]>, StorageError> {
+        (**self).get_trie_node_hashes()
+    }
+
+    fn get_state_entries(&self) -> Result<Vec<StateEntry>, StorageError> {
+        (**self).get_state_entries()
+    }
+
+    fn get_prune_horizon(&self) -> Result<PruneHorizon, StorageError> {
+        (**self).get_prune_horizon()
+    }
+
+    fn get_genesis_state(&self) -> Result<Option<Vec<StateEntry>>, StorageError> {
+        (**self).get_genesis_state()
+    }
+
+    fn is_reindexing(&self) -> Result<bool, StorageError> {
+        (**self).is_reindexing()
+    }
+
+    fn get_storage_usage(&self, address: &Address) -> Result<u64, StorageError> {
+        (**self).get_storage_usage(address)
+    }
+
+    fn put_contract_code(
+        &self,
+        contract_id: &ContractId,
+        wasm_bytes: &[u8],
+    ) -> Result<(), StorageError> {
+        (**self).put_contract_code(contract_id, wasm_bytes)
+    }
+
+    fn get_contract_code(&self, contract_id: &ContractId) -> Result<Option<Vec<u8>>, StorageError> {
+        (**self).get_contract_code(contract_id)
+    }
+
+    fn delete_contract_code(&self, contract_id: &ContractId) -> Result<(), StorageError> {
+        (**self).delete_contract_code(contract_id)
+    }
+
+    fn contract_storage_read(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        (**self).contract_storage_read(contract_id, key)
+    }
+
+    fn contract_storage_write(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), StorageError> {
+        (**self).contract_storage_write(contract_id, key, value)
+    }
+
+    fn contract_storage_remove(
+        &self,
+        contract_id: &ContractId,
+        key: &[u8],
+    ) -> Result<(), StorageError> {
+        (**self).contract_storage_remove(contract_id, key)
+    }
+
+    fn apply_batch(&self, batch: StorageBatch) -> Result<(), StorageError> {
+        (**self).apply_batch(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(index: u64) -> Block {
+        Block {
+            index,
+            timestamp: index,
+            prev_hash: [0; 32],
+            hash: [index as u8 + 1; 32],
+            nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
+            transactions: Vec::new(),
+            metadata: None,
+            seal: None,
+        }
+    }
+
+    #[test]
+    fn test_batch_writes_land_in_their_trees() {
+        check_batch_writes(&SledStorage::temporary().unwrap());
+        check_batch_writes(&MemoryStorage::new());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_batch_writes_land_in_their_tables() {
+        check_batch_writes(&SqliteStorage::in_memory().unwrap());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_storage_rotates_keys() {
+        let inner = MemoryBackend::new();
+        let salt = encrypted_backend::passphrase_salt(&inner).unwrap();
+        let old_key = EncryptionKey::from_passphrase(1, "correct horse", &salt).unwrap();
+        let storage =
+            EncryptedStorage::with_backend(EncryptedBackend::new(inner.clone(), old_key.clone()));
+        check_batch_writes(&storage);
+
+        // The unencrypted salt is skipped without shortening a limited scan.
+        let newest = storage
+            .backend()
+            .scan_range(KvTree::ChainState, &[0], &[0xff], Order::Descending, 1)
+            .unwrap();
+        assert_eq!(newest.len(), 1);
+        assert_ne!(newest[0].0, [keys::ENCRYPTION_SALT]);
+
+        let contract_id = ContractId::from_bytes(&[4; 32]);
+        let raw_key = contract_state_key(&contract_id, b"k");
+        let sealed = inner
+            .get(KvTree::ContractStorage, &raw_key)
+            .unwrap()
+            .unwrap();
+        assert_ne!(sealed, b"v".to_vec());
+
+        // A value copied under another key fails authentication.
+        let other_key = contract_state_key(&contract_id, b"other");
+        inner
+            .insert(KvTree::ContractStorage, &other_key, &sealed)
+            .unwrap();
+        assert!(storage
+            .contract_storage_read(&contract_id, b"other")
+            .is_err());
+        inner.remove(KvTree::ContractStorage, &other_key).unwrap();
+
+        let new_key = EncryptionKey::new(2, [9; 32]);
+        let rotating =
+            EncryptedBackend::new(inner.clone(), new_key.clone()).with_retired_key(old_key);
+        assert!(rotating.rotate().unwrap() > 0);
+        assert_eq!(rotating.rotate().unwrap(), 0);
+
+        let storage = EncryptedStorage::with_backend(EncryptedBackend::new(inner, new_key));
+        assert_eq!(
+            storage.contract_storage_read(&contract_id, b"k").unwrap(),
+            Some(b"v".to_vec())
+        );
+        assert!(storage.get_chain_state().unwrap().is_some());
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_rocksdb_batch_writes_land_in_their_column_families() {
+        let dir = tempfile::TempDir::new().unwrap();
+        check_batch_writes(&RocksDbStorage::new(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_arc_shares_memory_storage() {
+        let storage = Arc::new(MemoryStorage::new());
+        let engine_view = Arc::clone(&storage);
+        let contract_id = ContractId::from_bytes(&[4; 32]);
+        engine_view
+            .contract_storage_write(&contract_id, b"k", b"v")
+            .unwrap();
+        assert_eq!(
+            storage.contract_storage_read(&contract_id, b"k").unwrap(),
+            Some(b"v".to_vec())
+        );
+        assert_eq!(storage.get_latest_block().unwrap(), None);
+        storage.put_block(&block(1)).unwrap();
+        storage.put_block(&block(2)).unwrap();
+        assert_eq!(engine_view.get_latest_block().unwrap(), Some(block(2)));
+        // The height entry only points at the block stored under its hash.
+        assert_eq!(
+            storage
+                .backend()
+                .get(KvTree::Blocks, &height_key(2))
+                .unwrap(),
+            Some(block(2).hash.to_vec())
+        );
+        assert_eq!(storage.get_block_by_height(1).unwrap(), Some(block(1)));
+    }
+
+    fn check_batch_writes<S: Storage>(storage: &S) {
+        let address =
+            PublicKey::from(ed25519_dalek::SigningKey::from_bytes(&[3; 32]).verifying_key());
+        let contract_id = ContractId::from_bytes(&[4; 32]);
+        let state = ChainState {
+            latest_block_hash: [2; 32],
+            latest_block_index: 1,
+            accounts_root_hash: [0; 32],
+            total_supply: 0,
+            finalized_height: 0,
+            jailed_authorities: Vec::new(),
+        };
+
+        let mut batch = StorageBatch::default();
+        batch
+            .ops
+            .push(StorageOperation::PutBlock(Box::new(block(1))));
+        batch.ops.push(StorageOperation::PutAccount(
+            address,
+            Box::new(Account::Wallet {
+                balance: 10,
+                nonce: 0,
+            }),
+        ));
+        batch
+            .ops
+            .push(StorageOperation::PutChainState(Box::new(state.clone())));
+        batch.ops.push(StorageOperation::ContractStorageWrite(
+            contract_id.clone(),
+            b"k".to_vec(),
+            b"v".to_vec(),
+        ));
+        let positions = [(1, 0), (1, 1), (2, 0)].map(|(height, tx_index_in_block)| TxPosition {
+            height,
+            tx_index_in_block,
+        });
+        for (i, position) in positions.into_iter().enumerate() {
+            batch.ops.push(StorageOperation::IndexAddressTransaction {
+                address: Address::Wallet(address),
+                position,
+                tx_hash: [i as u8; 32],
+            });
+        }
+        storage.apply_batch(batch).unwrap();
+
+        assert_eq!(storage.get_chain_state().unwrap(), Some(state));
+        let history = |after, order, limit| {
+            storage
+                .get_address_transactions(&Address::Wallet(address), after, order, limit)
+                .unwrap()
+        };
+        assert_eq!(
+            history(Some(positions[0]), Order::Ascending, 10),
+            [(positions[1], [1; 32]), (positions[2], [2; 32])]
+        );
+        assert_eq!(
+            history(Some(positions[2]), Order::Descending, 1),
+            [(positions[1], [1; 32])]
+        );
+        assert_eq!(history(None, Order::Descending, 10).len(), 3);
+        assert!(storage
+            .get_address_transactions(
+                &Address::Contract(contract_id.clone()),
+                None,
+                Order::Ascending,
+                10
+            )
+            .unwrap()
+            .is_empty());
+        assert_eq!(storage.get_block(&[2; 32]).unwrap(), Some(block(1)));
+        assert_eq!(storage.get_block_by_height(1).unwrap(), Some(block(1)));
+        assert_eq!(
+            storage.get_account(&address).unwrap(),
+            Some(Account::Wallet {
+                balance: 10,
+                nonce: 0
+            })
+        );
+        assert_eq!(
+            storage.contract_storage_read(&contract_id, b"k").unwrap(),
+            Some(b"v".to_vec())
+        );
+
+        let mut batch = StorageBatch::default();
+        batch.ops.push(StorageOperation::RemoveBlockHeight(1));
+        batch.ops.push(StorageOperation::DeleteAccount(address));
+        storage.apply_batch(batch).unwrap();
+        assert_eq!(storage.get_block_by_height(1).unwrap(), None);
+        assert!(storage.get_block(&[2; 32]).unwrap().is_some());
+        assert_eq!(storage.get_account(&address).unwrap(), None);
     }
 }
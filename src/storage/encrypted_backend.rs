@@ -0,0 +1,294 @@
+//! Encryption at rest for any [`KvBackend`]. Enabled by the `encryption`
+//! cargo feature.
+//!
+//! Values are sealed with XChaCha20-Poly1305 under a random nonce; keys stay
+//! in the clear so ordering and prefix scans keep working. The tree name and
+//! key are authenticated as associated data, so a value cannot be moved to
+//! another key undetected. Every stored value carries the id of the key that
+//! sealed it, which is what makes rotation possible: open with the new key
+//! as active and the old one retired, call [`EncryptedBackend::rotate`], then
+//! drop the old key.
+//!
+//! # Keys are not encrypted
+//!
+//! Only values are confidential. Anyone who can read the underlying
+//! database sees every key, and the keys carry a good deal of the chain:
+//! block heights and hashes, transaction hashes, the public keys and
+//! contract ids with storage, usage or transaction history, the raw keys of
+//! contract storage slots, and the name and value of every transaction tag,
+//! along with how many entries each tree holds. Use full-disk encryption as
+//! well if any of that must stay private.
+
+use std::fmt;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use super::{keys, KvBackend, KvPair, KvStorage, KvTree, KvWrite, Order, StorageError};
+
+/// [`crate::storage::Storage`] that encrypts values, but not keys, before
+/// handing them to `B`.
+pub type EncryptedStorage<B> = KvStorage<EncryptedBackend<B>>;
+
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+const SALT_LEN: usize = 16;
+/// Where [`passphrase_salt`] keeps the salt, unencrypted.
+const SALT_KEY: &[u8] = &[keys::ENCRYPTION_SALT];
+
+/// A 256-bit data key and the id recorded next to everything it seals.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: u32,
+    cipher: XChaCha20Poly1305,
+}
+
+impl EncryptionKey {
+    /// Use key material supplied by the host app.
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        Self {
+            id,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// Derive a key from a passphrase with Argon2id.
+    ///
+    /// `salt` must be the same every time the database is opened; see
+    /// [`passphrase_salt`].
+    pub fn from_passphrase(id: u32, passphrase: &str, salt: &[u8]) -> Result<Self, StorageError> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| StorageError::EncryptionError(e.to_string()))?;
+        Ok(Self::new(id, key))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The database's passphrase salt, created on first use.
+///
+/// The salt is not secret and is stored unencrypted in `inner`.
+pub fn passphrase_salt<B: KvBackend>(inner: &B) -> Result<Vec<u8>, StorageError> {
+    if let Some(salt) = inner.get(KvTree::ChainState, SALT_KEY)? {
+        return Ok(salt);
+    }
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    inner.insert(KvTree::ChainState, SALT_KEY, &salt)?;
+    Ok(salt)
+}
+
+#[derive(Clone, Debug)]
+pub struct EncryptedBackend<B: KvBackend> {
+    inner: B,
+    active: EncryptionKey,
+    retired: Vec<EncryptionKey>,
+}
+
+impl<B: KvBackend> EncryptedBackend<B> {
+    /// Seal new values with `active`.
+    pub fn new(inner: B, active: EncryptionKey) -> Self {
+        Self {
+            inner,
+            active,
+            retired: Vec::new(),
+        }
+    }
+
+    /// Keep accepting values sealed with an older key.
+    pub fn with_retired_key(mut self, key: EncryptionKey) -> Self {
+        self.retired.push(key);
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Re-seal every value not yet under the active key, one tree at a time.
+    ///
+    /// Must not run concurrently with other writes, e.g. do it before handing
+    /// the storage to a [`crate::runtime::Runtime`]. An interrupted rotation
+    /// can simply be run again. Returns the number of values re-sealed.
+    pub fn rotate(&self) -> Result<usize, StorageError> {
+        let mut rotated = 0;
+        for tree in KvTree::ALL {
+            let mut writes = Vec::new();
+            for (key, stored) in self.inner.scan_prefix(tree, b"")? {
+                if is_reserved(tree, &key) || self.key_id(&stored)? == self.active.id {
+                    continue;
+                }
+                let value = self.open(tree, &key, &stored)?;
+                writes.push((tree, key.clone(), Some(self.seal(tree, &key, &value)?)));
+            }
+            rotated += writes.len();
+            self.inner.write_batch(writes)?;
+        }
+        Ok(rotated)
+    }
+
+    fn key_id(&self, stored: &[u8]) -> Result<u32, StorageError> {
+        if stored.len() < HEADER_LEN || stored[0] != FORMAT_VERSION {
+            return Err(StorageError::EncryptionError(
+                "value is not in the encrypted format".to_string(),
+            ));
+        }
+        Ok(u32::from_be_bytes([
+            stored[1], stored[2], stored[3], stored[4],
+        ]))
+    }
+
+    fn seal(&self, tree: KvTree, key: &[u8], value: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = associated_data(tree, key);
+        let ciphertext = self
+            .active
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: value,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| StorageError::EncryptionError("encryption failed".to_string()))?;
+
+        let mut stored = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        stored.push(FORMAT_VERSION);
+        stored.extend_from_slice(&self.active.id.to_be_bytes());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+        Ok(stored)
+    }
+
+    fn open(&self, tree: KvTree, key: &[u8], stored: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let id = self.key_id(stored)?;
+        let cipher_key = std::iter::once(&self.active)
+            .chain(&self.retired)
+            .find(|candidate| candidate.id == id)
+            .ok_or_else(|| StorageError::EncryptionError(format!("unknown key id {}", id)))?;
+        let aad = associated_data(tree, key);
+        cipher_key
+            .cipher
+            .decrypt(
+                XNonce::from_slice(&stored[5..HEADER_LEN]),
+                Payload {
+                    msg: &stored[HEADER_LEN..],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                StorageError::EncryptionError(format!(
+                    "value in {} failed authentication",
+                    tree.name()
+                ))
+            })
+    }
+
+    fn open_all(&self, tree: KvTree, entries: Vec<KvPair>) -> Result<Vec<KvPair>, StorageError> {
+        entries
+            .into_iter()
+            .filter(|(key, _)| !is_reserved(tree, key))
+            .map(|(key, stored)| {
+                let value = self.open(tree, &key, &stored)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
+
+fn associated_data(tree: KvTree, key: &[u8]) -> Vec<u8> {
+    [tree.name().as_bytes(), &[0], key].concat()
+}
+
+fn is_reserved(tree: KvTree, key: &[u8]) -> bool {
+    tree == KvTree::ChainState && key == SALT_KEY
+}
+
+impl<B: KvBackend> KvBackend for EncryptedBackend<B> {
+    fn get(&self, tree: KvTree, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner
+            .get(tree, key)?
+            .map(|stored| self.open(tree, key, &stored))
+            .transpose()
+    }
+
+    fn insert(&self, tree: KvTree, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.inner.insert(tree, key, &self.seal(tree, key, value)?)
+    }
+
+    fn remove(&self, tree: KvTree, key: &[u8]) -> Result<(), StorageError> {
+        self.inner.remove(tree, key)
+    }
+
+    fn scan_prefix(&self, tree: KvTree, prefix: &[u8]) -> Result<Vec<KvPair>, StorageError> {
+        self.open_all(tree, self.inner.scan_prefix(tree, prefix)?)
+    }
+
+    fn last_with_prefix(
+        &self,
+        tree: KvTree,
+        prefix: &[u8],
+    ) -> Result<Option<KvPair>, StorageError> {
+        if tree == KvTree::ChainState && SALT_KEY.starts_with(prefix) {
+            // The reserved entry could be the last match; fall back to a scan.
+            return Ok(self.scan_prefix(tree, prefix)?.pop());
+        }
+        self.inner
+            .last_with_prefix(tree, prefix)?
+            .map(|(key, stored)| {
+                let value = self.open(tree, &key, &stored)?;
+                Ok((key, value))
+            })
+            .transpose()
+    }
+
+    fn scan_range(
+        &self,
+        tree: KvTree,
+        start: &[u8],
+        end: &[u8],
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, StorageError> {
+        // Fetch one more if the reserved entry is in range, as it is dropped.
+        let reserved =
+            usize::from(tree == KvTree::ChainState && start <= SALT_KEY && SALT_KEY < end);
+        let stored =
+            self.inner
+                .scan_range(tree, start, end, order, limit.saturating_add(reserved))?;
+        let mut entries = self.open_all(tree, stored)?;
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    fn write_batch(&self, writes: Vec<KvWrite>) -> Result<(), StorageError> {
+        let sealed = writes
+            .into_iter()
+            .map(|(tree, key, value)| {
+                let value = value
+                    .map(|value| self.seal(tree, &key, &value))
+                    .transpose()?;
+                Ok((tree, key, value))
+            })
+            .collect::<Result<_, StorageError>>()?;
+        self.inner.write_batch(sealed)
+    }
+}
@@ -0,0 +1,201 @@
+//! Binary key codec shared by every tree.
+//!
+//! A key is a one-byte tag naming its key space, followed by fixed-width
+//! fields (hashes and big-endian integers), terminated text fields and at
+//! most one raw variable-length field at the end. Bytewise key order is
+//! therefore field order, so every prefix of fields is a valid scan prefix,
+//! integer ranges iterate in numeric order and text in string order.
+//!
+//! Trees holding a single kind of record keyed by a hash (accounts,
+//! transactions, trie nodes, ...) use the bare hash and no tag.
+
+use super::StorageError;
+
+/// Block by hash (`Blocks`).
+pub(crate) const BLOCK: u8 = 0x01;
+/// Hash of the canonical block at a height (`Blocks`).
+pub(crate) const BLOCK_HEIGHT: u8 = 0x02;
+/// Pending transaction by hash (`Mempool`).
+pub(crate) const PENDING: u8 = 0x03;
+/// Transaction of a block by position: block hash, position (`TxByBlock`).
+pub(crate) const BLOCK_TX: u8 = 0x04;
+/// Contract storage slot: contract id, then the raw key (`ContractStorage`).
+pub(crate) const CONTRACT_SLOT: u8 = 0x05;
+/// Current chain state (`ChainState`).
+pub(crate) const CHAIN_STATE: u8 = 0x06;
+/// Prune horizon (`ChainState`).
+pub(crate) const PRUNE_HORIZON: u8 = 0x07;
+/// Transaction sent or received by a wallet: public key, height, position
+/// (`AddressTxs`).
+pub(crate) const WALLET_TX: u8 = 0x08;
+/// Transaction sent to a contract: contract id, height, position
+/// (`AddressTxs`).
+pub(crate) const CONTRACT_TX: u8 = 0x09;
+/// Tagged data transaction: tag name, tag value, height, position
+/// (`DataTags`).
+pub(crate) const DATA_TAG: u8 = 0x0a;
+/// Bytes charged to a wallet (`Usage`).
+pub(crate) const WALLET_USAGE: u8 = 0x0b;
+/// Bytes charged to a contract (`Usage`).
+pub(crate) const CONTRACT_USAGE: u8 = 0x0c;
+/// Raft term and vote (`Raft`).
+pub(crate) const RAFT_STATE: u8 = 0x0d;
+/// Raft log entry by index (`Raft`).
+pub(crate) const RAFT_ENTRY: u8 = 0x0e;
+/// State the chain started from (`ChainState`).
+pub(crate) const GENESIS_STATE: u8 = 0x0f;
+/// Receipt of a block's transaction by position: block hash, position
+/// (`Receipts`).
+pub(crate) const RECEIPT: u8 = 0x10;
+/// Schema version (`Meta`).
+pub(crate) const SCHEMA_VERSION: u8 = 0x11;
+/// Reindex checkpoint (`Meta`).
+pub(crate) const REINDEX_CHECKPOINT: u8 = 0x12;
+/// Passphrase salt, stored unencrypted (`ChainState`).
+#[cfg(feature = "encryption")]
+pub(crate) const ENCRYPTION_SALT: u8 = 0x13;
+
+/// Builds a key field by field.
+pub(crate) struct KeyBuilder(Vec<u8>);
+
+impl KeyBuilder {
+    pub(crate) fn new(tag: u8) -> Self {
+        KeyBuilder(vec![tag])
+    }
+
+    pub(crate) fn hash(mut self, hash: &[u8; 32]) -> Self {
+        self.0.extend_from_slice(hash);
+        self
+    }
+
+    pub(crate) fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(crate) fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    /// A string field that may be followed by others.
+    ///
+    /// Zero bytes are escaped as `00 ff` and the field ends with `00 00`,
+    /// which sorts below any continuation, so a string sorts before every
+    /// string it is a prefix of.
+    pub(crate) fn text(mut self, text: &str) -> Self {
+        for &byte in text.as_bytes() {
+            self.0.push(byte);
+            if byte == 0 {
+                self.0.push(0xff);
+            }
+        }
+        self.0.extend_from_slice(&[0, 0]);
+        self
+    }
+
+    /// A variable-length field; must come last.
+    pub(crate) fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    pub(crate) fn build(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Reads back the fields of a key written by [`KeyBuilder`].
+pub(crate) struct KeyReader<'a> {
+    key: &'a [u8],
+    rest: &'a [u8],
+}
+
+impl<'a> KeyReader<'a> {
+    /// Fails unless `key` starts with `tag`.
+    pub(crate) fn new(key: &'a [u8], tag: u8) -> Result<Self, StorageError> {
+        match key.split_first() {
+            Some((&first, rest)) if first == tag => Ok(KeyReader { key, rest }),
+            _ => Err(corrupt(key)),
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StorageError> {
+        if self.rest.len() < N {
+            return Err(corrupt(self.key));
+        }
+        let (field, rest) = self.rest.split_at(N);
+        self.rest = rest;
+        Ok(field.try_into().expect("split at N"))
+    }
+
+    pub(crate) fn hash(&mut self) -> Result<[u8; 32], StorageError> {
+        self.take()
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, StorageError> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, StorageError> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    pub(crate) fn text(&mut self) -> Result<String, StorageError> {
+        let mut bytes = Vec::new();
+        let mut rest = self.rest.iter();
+        loop {
+            match (rest.next(), rest.clone().next()) {
+                (Some(0), Some(0)) => break,
+                (Some(0), Some(0xff)) => {
+                    bytes.push(0);
+                    rest.next();
+                }
+                (Some(&byte), _) if byte != 0 => bytes.push(byte),
+                _ => return Err(corrupt(self.key)),
+            }
+        }
+        rest.next();
+        self.rest = rest.as_slice();
+        String::from_utf8(bytes).map_err(|_| corrupt(self.key))
+    }
+
+    /// The variable-length field at the end.
+    pub(crate) fn rest(self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+fn corrupt(key: &[u8]) -> StorageError {
+    StorageError::CorruptKey(hex::encode(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_order_follows_field_order() {
+        let key = |height| KeyBuilder::new(BLOCK_HEIGHT).u64(height).build();
+        assert!(key(255) < key(256));
+        assert!(key(1 << 40) < key(u64::MAX));
+
+        let slot = KeyBuilder::new(CONTRACT_SLOT)
+            .hash(&[7; 32])
+            .bytes(b"tail")
+            .build();
+        let mut reader = KeyReader::new(&slot, CONTRACT_SLOT).unwrap();
+        assert_eq!(reader.hash().unwrap(), [7; 32]);
+        assert_eq!(reader.rest(), b"tail");
+        assert!(KeyReader::new(&slot, BLOCK).is_err());
+
+        let tag = |value: &str| KeyBuilder::new(DATA_TAG).text(value).u64(0).build();
+        assert!(tag("a") < tag("a\0"));
+        assert!(tag("a\0") < tag("a\u{1}"));
+        assert!(tag("a\u{1}") < tag("ab"));
+        let key = KeyBuilder::new(DATA_TAG).text("x\0y").u64(9).build();
+        let mut reader = KeyReader::new(&key, DATA_TAG).unwrap();
+        assert_eq!(reader.text().unwrap(), "x\0y");
+        assert_eq!(reader.u64().unwrap(), 9);
+    }
+}
@@ -0,0 +1,146 @@
+//! In-process backend: a map per [`KvTree`] behind one lock, so batches are
+//! trivially atomic. Nothing touches disk.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{
+    prefix_upper_bound, KvBackend, KvPair, KvStorage, KvTree, KvWrite, Order, StorageError,
+};
+
+/// [`crate::storage::Storage`] kept entirely in memory.
+///
+/// Clones share the same data, like clones of [`super::SledStorage`].
+pub type MemoryStorage = KvStorage<MemoryBackend>;
+
+type Trees = BTreeMap<KvTree, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    trees: Arc<RwLock<Trees>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Trees> {
+        // A panic while holding the lock cannot leave a batch half applied:
+        // writes are only made after all of them are known.
+        self.trees.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Trees> {
+        self.trees.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::with_backend(MemoryBackend::new())
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn prefix_range<'a>(
+    tree: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+    prefix: &[u8],
+) -> impl DoubleEndedIterator<Item = (&'a Vec<u8>, &'a Vec<u8>)> {
+    let upper = match prefix_upper_bound(prefix) {
+        Some(bound) => Bound::Excluded(bound),
+        None => Bound::Unbounded,
+    };
+    tree.range((Bound::Included(prefix.to_vec()), upper))
+}
+
+impl KvBackend for MemoryBackend {
+    fn get(&self, tree: KvTree, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self
+            .read()
+            .get(&tree)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn insert(&self, tree: KvTree, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.write()
+            .entry(tree)
+            .or_default()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, tree: KvTree, key: &[u8]) -> Result<(), StorageError> {
+        if let Some(entries) = self.write().get_mut(&tree) {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&self, tree: KvTree, prefix: &[u8]) -> Result<Vec<KvPair>, StorageError> {
+        let trees = self.read();
+        Ok(trees
+            .get(&tree)
+            .map(|entries| {
+                prefix_range(entries, prefix)
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn last_with_prefix(
+        &self,
+        tree: KvTree,
+        prefix: &[u8],
+    ) -> Result<Option<KvPair>, StorageError> {
+        let trees = self.read();
+        Ok(trees.get(&tree).and_then(|entries| {
+            prefix_range(entries, prefix)
+                .next_back()
+                .map(|(key, value)| (key.clone(), value.clone()))
+        }))
+    }
+
+    fn scan_range(
+        &self,
+        tree: KvTree,
+        start: &[u8],
+        end: &[u8],
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, StorageError> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let trees = self.read();
+        let Some(entries) = trees.get(&tree) else {
+            return Ok(Vec::new());
+        };
+        let range = entries.range(start.to_vec()..end.to_vec());
+        let clone = |(key, value): (&Vec<u8>, &Vec<u8>)| (key.clone(), value.clone());
+        Ok(match order {
+            Order::Ascending => range.take(limit).map(clone).collect(),
+            Order::Descending => range.rev().take(limit).map(clone).collect(),
+        })
+    }
+
+    fn write_batch(&self, writes: Vec<KvWrite>) -> Result<(), StorageError> {
+        let mut trees = self.write();
+        for (tree, key, value) in writes {
+            let entries = trees.entry(tree).or_default();
+            match value {
+                Some(value) => entries.insert(key, value),
+                None => entries.remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! RocksDB backend: one column family per [`KvTree`], batches committed as a
+//! single `WriteBatch`. Enabled by the `rocksdb` cargo feature.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, ReadOptions,
+    WriteBatch, DB,
+};
+
+use super::{
+    prefix_upper_bound, KvBackend, KvPair, KvStorage, KvTree, KvWrite, Order, StorageError,
+};
+
+/// [`crate::storage::Storage`] backed by RocksDB.
+pub type RocksDbStorage = KvStorage<RocksDbBackend>;
+
+#[derive(Clone)]
+pub struct RocksDbBackend {
+    db: Arc<DB>,
+}
+
+impl RocksDbBackend {
+    /// Open (or create) the database at `path` with a column family per tree.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let column_families = KvTree::ALL
+            .iter()
+            .map(|tree| ColumnFamilyDescriptor::new(tree.name(), Options::default()));
+        let db = DB::open_cf_descriptors(&options, path, column_families)?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// The column family of `tree`, which `open` creates for every tree.
+    fn cf(&self, tree: KvTree) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(tree.name())
+            .ok_or(StorageError::MissingColumnFamily(tree.name()))
+    }
+}
+
+impl RocksDbStorage {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::open(RocksDbBackend::open(path)?)
+    }
+}
+
+impl KvBackend for RocksDbBackend {
+    fn get(&self, tree: KvTree, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.db.get_cf(self.cf(tree)?, key)?)
+    }
+
+    fn insert(&self, tree: KvTree, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.db.put_cf(self.cf(tree)?, key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: KvTree, key: &[u8]) -> Result<(), StorageError> {
+        self.db.delete_cf(self.cf(tree)?, key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, tree: KvTree, prefix: &[u8]) -> Result<Vec<KvPair>, StorageError> {
+        let mut entries = Vec::new();
+        let mode = IteratorMode::From(prefix, Direction::Forward);
+        for item in self.db.iterator_cf(self.cf(tree)?, mode) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key.into_vec(), value.into_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn last_with_prefix(
+        &self,
+        tree: KvTree,
+        prefix: &[u8],
+    ) -> Result<Option<KvPair>, StorageError> {
+        let upper = prefix_upper_bound(prefix);
+        let mode = match &upper {
+            Some(bound) => IteratorMode::From(bound, Direction::Reverse),
+            None => IteratorMode::End,
+        };
+        for item in self.db.iterator_cf(self.cf(tree)?, mode) {
+            let (key, value) = item?;
+            if key.starts_with(prefix) {
+                return Ok(Some((key.into_vec(), value.into_vec())));
+            }
+            // The seek lands on the bound itself if it exists; anything
+            // else below the prefix means there is no match.
+            if key.as_ref() < prefix {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan_range(
+        &self,
+        tree: KvTree,
+        start: &[u8],
+        end: &[u8],
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, StorageError> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let mut options = ReadOptions::default();
+        options.set_iterate_lower_bound(start.to_vec());
+        options.set_iterate_upper_bound(end.to_vec());
+        let mode = match order {
+            Order::Ascending => IteratorMode::Start,
+            Order::Descending => IteratorMode::End,
+        };
+        self.db
+            .iterator_cf_opt(self.cf(tree)?, options, mode)
+            .take(limit)
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((key.into_vec(), value.into_vec()))
+            })
+            .collect()
+    }
+
+    fn write_batch(&self, writes: Vec<KvWrite>) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        for (tree, key, value) in writes {
+            match value {
+                Some(value) => batch.put_cf(self.cf(tree)?, key, value),
+                None => batch.delete_cf(self.cf(tree)?, key),
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+}
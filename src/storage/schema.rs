@@ -0,0 +1,878 @@
+//! On-disk schema versioning and in-place migrations.
+//!
+//! The schema version covers both the key layout of every tree and the
+//! bincode encoding of the records stored in them, so it must be bumped (and
+//! a [`Migration`] added) whenever either changes, including a field added to
+//! `Block`, `Transaction` or `Account`. The version lives in the `meta` tree
+//! and is checked by [`KvStorage::open`].
+//!
+//! Databases written before versions were recorded carry no stamp and are
+//! read as version 0, the baseline layout: `format!`-built string keys
+//! (`height:{:0>20}`, `state:{hex}:{hex}`, ...) and blocks hashed over their
+//! transactions, without roots. Version 1 is the binary key codec of
+//! [`super::keys`] with header-hashed blocks; see [`baseline_layout`].
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block_key, contract_state_key, decode, encode_batch, hash_from_key, height_key, keys,
+    pending_key, KvBackend, KvStorage, KvTree, KvWrite, Order, PruneHorizon, StateEntry,
+    StorageBatch, StorageError, StorageOperation, TxPosition, CHAIN_STATE_KEY,
+};
+use crate::merkle::{merkle_root, state_tree_from_entries, EMPTY_ROOT};
+use crate::quota;
+use crate::storage::MemoryStorage;
+use crate::types::{
+    Account, Address, Block, ChainState, ContractId, PublicKey, Transaction, TransactionReceipt,
+};
+
+/// Schema version written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA_VERSION_KEY: &[u8] = &[keys::SCHEMA_VERSION];
+
+/// Most writes a migration applies in one batch.
+const MIGRATION_BATCH: usize = 10_000;
+
+/// An upgrade from schema version `from` to `from + 1`.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    /// Pushes the writes that rewrite the database into the next layout.
+    /// They are applied in batches as they come and the version is bumped
+    /// with the last one, so an interrupted migration runs again in full
+    /// on the next open and must accept a database it partly rewrote.
+    pub migrate: fn(&dyn KvBackend, &mut MigrationWriter) -> Result<(), StorageError>,
+}
+
+/// Applies a migration's writes in batches of about [`MIGRATION_BATCH`], so
+/// the writes of a rewrite of the whole database are never all pending.
+pub struct MigrationWriter<'a> {
+    backend: &'a dyn KvBackend,
+    writes: Vec<KvWrite>,
+}
+
+impl<'a> MigrationWriter<'a> {
+    fn new(backend: &'a dyn KvBackend) -> Self {
+        Self {
+            backend,
+            writes: Vec::new(),
+        }
+    }
+
+    /// Queue the writes of one record. They land in the same batch, so a
+    /// record is never left half moved.
+    pub fn push(&mut self, writes: impl IntoIterator<Item = KvWrite>) -> Result<(), StorageError> {
+        self.writes.extend(writes);
+        if self.writes.len() >= MIGRATION_BATCH {
+            self.backend.write_batch(std::mem::take(&mut self.writes))?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), StorageError> {
+        self.backend.write_batch(self.writes)
+    }
+}
+
+/// Every migration, in order.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "baseline layout",
+    migrate: baseline_layout,
+}];
+
+/// A block as the baseline stored it: hashed over its transactions, with
+/// no roots and no seal.
+#[derive(Serialize, Deserialize)]
+struct BaselineBlock {
+    index: u64,
+    timestamp: u64,
+    prev_hash: [u8; 32],
+    hash: [u8; 32],
+    nonce: u64,
+    transactions: Vec<Transaction>,
+    metadata: Option<BTreeMap<String, String>>,
+}
+
+/// The chain state as the baseline stored it, before finality and jailing.
+#[derive(Serialize, Deserialize)]
+struct BaselineChainState {
+    latest_block_hash: [u8; 32],
+    latest_block_index: u64,
+    accounts_root_hash: [u8; 32],
+    total_supply: u64,
+}
+
+impl From<BaselineChainState> for ChainState {
+    fn from(baseline: BaselineChainState) -> Self {
+        ChainState {
+            latest_block_hash: baseline.latest_block_hash,
+            latest_block_index: baseline.latest_block_index,
+            accounts_root_hash: baseline.accounts_root_hash,
+            total_supply: baseline.total_supply,
+            finalized_height: 0,
+            jailed_authorities: Vec::new(),
+        }
+    }
+}
+
+const BASELINE_CHAIN_STATE_KEY: &[u8] = b"global:current";
+
+fn baseline_height_key(height: u64) -> Vec<u8> {
+    format!("height:{:0>20}", height).into_bytes()
+}
+
+fn baseline_block_tx_prefix(block_hash: &[u8; 32]) -> Vec<u8> {
+    format!("block_tx:{}:", hex::encode(block_hash)).into_bytes()
+}
+
+/// Version 1 moved every record to the binary key codec and re-hashed the
+/// blocks over headers that commit to their transactions, receipts and
+/// state.
+///
+/// The baseline kept no receipts, state tree or per-block state: every
+/// transaction of a stored block was applied, so each gets a successful
+/// receipt without output, and only the tip's header commits to the state,
+/// rebuilt from the flat state. Headers below the tip carry the empty root
+/// and their state is recorded as pruned; a chain still at genesis records
+/// its state as the genesis state instead. Blocks keep their contents, but
+/// their hashes, and with them the parent links, change. Blocks outside
+/// the canonical chain and their index entries are dropped.
+///
+/// Blocks are converted one height at a time; only the current state is
+/// read whole, to build the state tree.
+fn baseline_layout(
+    backend: &dyn KvBackend,
+    writer: &mut MigrationWriter,
+) -> Result<(), StorageError> {
+    let entries = baseline_state(backend, writer)?;
+    let chain_state: Option<ChainState> =
+        match backend.get(KvTree::ChainState, BASELINE_CHAIN_STATE_KEY)? {
+            Some(encoded) => Some(bincode::deserialize::<BaselineChainState>(&encoded)?.into()),
+            // Moved by an interrupted run.
+            None => decode(backend.get(KvTree::ChainState, CHAIN_STATE_KEY)?)?,
+        };
+    let tip = chain_state.as_ref().map(|state| state.latest_block_index);
+    let state_root = match &chain_state {
+        Some(chain_state) => {
+            let (root, nodes) =
+                state_tree_from_entries(&MemoryStorage::new(), &entries, chain_state)?;
+            for node in nodes {
+                writer.push(encode_batch(StorageBatch {
+                    ops: vec![StorageOperation::PutTrieNode(Box::new(node))],
+                })?)?;
+            }
+            root
+        }
+        None => EMPTY_ROOT,
+    };
+
+    let mut usage = quota::state_usage(&entries);
+    let mut parent_hash = None;
+    let mut tip_hash = None;
+    for height in 0u64.. {
+        let block = match backend.get(KvTree::Blocks, &baseline_height_key(height))? {
+            Some(encoded) => {
+                let baseline: BaselineBlock = bincode::deserialize(&encoded)?;
+                let root = if tip == Some(height) {
+                    state_root
+                } else {
+                    EMPTY_ROOT
+                };
+                let (block, writes) = rehash_block(backend, baseline, parent_hash, root)?;
+                writer.push(writes)?;
+                block
+            }
+            // Rewritten by an interrupted run.
+            None => match backend.get(KvTree::Blocks, &height_key(height))? {
+                Some(hash) => {
+                    let block_hash = hash_from_key(&hash)?;
+                    decode::<Block>(backend.get(KvTree::Blocks, &block_key(&block_hash))?)?
+                        .ok_or(StorageError::NotFound)?
+                }
+                None => break,
+            },
+        };
+        for tx in &block.transactions {
+            *usage.entry(Address::Wallet(tx.sender)).or_default() += quota::data_bytes(tx);
+        }
+        if tip == Some(height) {
+            tip_hash = Some(block.hash);
+        }
+        parent_hash = Some(block.hash);
+    }
+
+    let mut batch = StorageBatch::default();
+    if let Some(mut chain_state) = chain_state {
+        chain_state.latest_block_hash = tip_hash.unwrap_or(chain_state.latest_block_hash);
+        chain_state.accounts_root_hash = state_root;
+        if chain_state.latest_block_index == 0 {
+            batch.ops.push(StorageOperation::PutGenesisState(entries));
+        } else {
+            batch
+                .ops
+                .push(StorageOperation::PutPruneHorizon(PruneHorizon {
+                    state: chain_state.latest_block_index,
+                    bodies: 0,
+                }));
+        }
+        batch
+            .ops
+            .push(StorageOperation::PutChainState(Box::new(chain_state)));
+    }
+    for (address, bytes) in usage {
+        batch
+            .ops
+            .push(StorageOperation::PutStorageUsage(address, bytes));
+    }
+    let mut writes = encode_batch(batch)?;
+    writes.push((KvTree::ChainState, BASELINE_CHAIN_STATE_KEY.to_vec(), None));
+    writer.push(writes)?;
+
+    // What is left of the baseline blocks and index is off the chain. Keys
+    // in the blocks tree are at most 33 bytes long.
+    for_each_entry(
+        backend,
+        KvTree::Blocks,
+        &[],
+        &[u8::MAX; 33],
+        &mut |key, _| {
+            if key.len() == 32 {
+                writer.push([(KvTree::Blocks, key, None)])?;
+            }
+            Ok(())
+        },
+    )?;
+    for_each_entry(
+        backend,
+        KvTree::TxByBlock,
+        b"block_tx:",
+        b"block_tx;",
+        &mut |key, _| writer.push([(KvTree::TxByBlock, key, None)]),
+    )?;
+    for_each_entry(
+        backend,
+        KvTree::Mempool,
+        b"pending:",
+        b"pending;",
+        &mut |key, tx| {
+            let tx_hash = hash_from_key(&key[b"pending:".len()..])?;
+            writer.push([
+                (KvTree::Mempool, key, None),
+                (KvTree::Mempool, pending_key(&tx_hash), Some(tx)),
+            ])
+        },
+    )
+}
+
+/// The current state, moving baseline contract storage keys to the codec.
+/// Account and contract code keys did not change.
+fn baseline_state(
+    backend: &dyn KvBackend,
+    writer: &mut MigrationWriter,
+) -> Result<Vec<StateEntry>, StorageError> {
+    let mut entries = Vec::new();
+    for (key, encoded) in backend.scan_prefix(KvTree::Accounts, b"")? {
+        let address = PublicKey::from_bytes(&hash_from_key(&key)?)?;
+        let account: Account = bincode::deserialize(&encoded)?;
+        entries.push(StateEntry::Account(address, account));
+    }
+    for (key, code) in backend.scan_prefix(KvTree::ContractCode, b"")? {
+        let contract_id = ContractId::from_bytes(&hash_from_key(&key)?);
+        entries.push(StateEntry::ContractCode(contract_id, code));
+    }
+    for (key, value) in backend.scan_prefix(KvTree::ContractStorage, b"")? {
+        let Some(rest) = key.strip_prefix(b"state:") else {
+            // Moved by an interrupted run.
+            let (contract_id, slot) = super::parse_contract_state_key(&key)?;
+            entries.push(StateEntry::ContractStorage(contract_id, slot, value));
+            continue;
+        };
+        let separator = rest
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or_else(|| baseline_corrupt(&key))?;
+        let contract_id = ContractId::from_bytes(&baseline_hex_hash(&key, &rest[..separator])?);
+        let slot = hex::decode(&rest[separator + 1..]).map_err(|_| baseline_corrupt(&key))?;
+        writer.push([
+            (KvTree::ContractStorage, key.clone(), None),
+            (
+                KvTree::ContractStorage,
+                contract_state_key(&contract_id, &slot),
+                Some(value.clone()),
+            ),
+        ])?;
+        entries.push(StateEntry::ContractStorage(contract_id, slot, value));
+    }
+    Ok(entries)
+}
+
+/// Convert a baseline block into a block extending `parent_hash` (its own
+/// parent hash at genesis) that commits to `state_root`, with the writes
+/// replacing its records, transactions, index entries and receipts and
+/// adding it to the address histories and the tag index.
+fn rehash_block(
+    backend: &dyn KvBackend,
+    baseline: BaselineBlock,
+    parent_hash: Option<[u8; 32]>,
+    state_root: [u8; 32],
+) -> Result<(Block, Vec<KvWrite>), StorageError> {
+    let receipts: Vec<TransactionReceipt> = baseline
+        .transactions
+        .iter()
+        .map(|tx| TransactionReceipt {
+            tx_hash: tx.hash,
+            success: true,
+            output: Vec::new(),
+            error: None,
+        })
+        .collect();
+    let receipt_hashes = receipts
+        .iter()
+        .map(TransactionReceipt::hash)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut block = Block {
+        index: baseline.index,
+        timestamp: baseline.timestamp,
+        prev_hash: parent_hash.unwrap_or(baseline.prev_hash),
+        hash: [0; 32],
+        nonce: baseline.nonce,
+        state_root,
+        transactions_root: [0; 32],
+        receipts_root: merkle_root(&receipt_hashes),
+        transactions: baseline.transactions,
+        metadata: baseline.metadata,
+        seal: None,
+    };
+    block.transactions_root = block.compute_transactions_root();
+    block.hash = block.calculate_hash()?;
+
+    let mut writes = vec![
+        (KvTree::Blocks, baseline_height_key(block.index), None),
+        (KvTree::Blocks, baseline.hash.to_vec(), None),
+    ];
+    for (key, _) in
+        backend.scan_prefix(KvTree::TxByBlock, &baseline_block_tx_prefix(&baseline.hash))?
+    {
+        writes.push((KvTree::TxByBlock, key, None));
+    }
+    let mut batch = StorageBatch::default();
+    batch
+        .ops
+        .push(StorageOperation::PutBlock(Box::new(block.clone())));
+    for (index, (tx, receipt)) in block.transactions.iter().zip(receipts).enumerate() {
+        let tx_index_in_block = index as u32;
+        batch
+            .ops
+            .push(StorageOperation::PutTransaction(Box::new(tx.clone())));
+        batch.ops.push(StorageOperation::IndexTransaction {
+            tx_hash: tx.hash,
+            block_hash: block.hash,
+            tx_index_in_block,
+        });
+        batch.ops.push(StorageOperation::PutReceipt {
+            block_hash: block.hash,
+            tx_index_in_block,
+            receipt: Box::new(receipt),
+        });
+        batch.index_history(
+            tx,
+            TxPosition {
+                height: block.index,
+                tx_index_in_block,
+            },
+        );
+    }
+    writes.extend(encode_batch(batch)?);
+    Ok((block, writes))
+}
+
+/// Call `f` with every entry of `tree` with `start <= key < end`, reading
+/// a page at a time. `f` may delete the key it is given.
+fn for_each_entry(
+    backend: &dyn KvBackend,
+    tree: KvTree,
+    start: &[u8],
+    end: &[u8],
+    f: &mut dyn FnMut(Vec<u8>, Vec<u8>) -> Result<(), StorageError>,
+) -> Result<(), StorageError> {
+    let mut start = start.to_vec();
+    loop {
+        let page = backend.scan_range(tree, &start, end, Order::Ascending, MIGRATION_BATCH)?;
+        let Some((last, _)) = page.last() else {
+            return Ok(());
+        };
+        start = [last.as_slice(), &[0]].concat();
+        for (key, value) in page {
+            f(key, value)?;
+        }
+    }
+}
+
+fn baseline_corrupt(key: &[u8]) -> StorageError {
+    StorageError::CorruptKey(String::from_utf8_lossy(key).into_owned())
+}
+
+fn baseline_hex_hash(key: &[u8], field: &[u8]) -> Result<[u8; 32], StorageError> {
+    let bytes = hex::decode(field).map_err(|_| baseline_corrupt(key))?;
+    bytes.try_into().map_err(|_| baseline_corrupt(key))
+}
+
+/// The schema version recorded in `backend`, if any.
+pub fn stored_version(backend: &dyn KvBackend) -> Result<Option<u32>, StorageError> {
+    let Some(encoded) = backend.get(KvTree::Meta, SCHEMA_VERSION_KEY)? else {
+        return Ok(None);
+    };
+    let bytes: [u8; 4] = encoded
+        .as_slice()
+        .try_into()
+        .map_err(|_| StorageError::CorruptSchemaVersion(encoded.clone()))?;
+    Ok(Some(u32::from_be_bytes(bytes)))
+}
+
+/// Bring `backend` up to [`SCHEMA_VERSION`], returning the version it was at.
+///
+/// `progress` is called with each migration before it runs. A new database
+/// is stamped with the current version and an unstamped one with data is
+/// migrated from the baseline layout. Fails with
+/// [`StorageError::IncompatibleSchema`] if the database was written by a
+/// newer build or no migration path exists.
+pub fn upgrade(
+    backend: &dyn KvBackend,
+    progress: &mut dyn FnMut(&Migration),
+) -> Result<u32, StorageError> {
+    upgrade_with(backend, SCHEMA_VERSION, MIGRATIONS, progress)
+}
+
+fn upgrade_with(
+    backend: &dyn KvBackend,
+    target: u32,
+    migrations: &[Migration],
+    progress: &mut dyn FnMut(&Migration),
+) -> Result<u32, StorageError> {
+    let found = match stored_version(backend)? {
+        Some(version) => version,
+        None if is_empty(backend)? => target,
+        None => 0,
+    };
+    if found > target {
+        return Err(StorageError::IncompatibleSchema {
+            found,
+            supported: target,
+        });
+    }
+
+    let mut version = found;
+    while version < target {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(StorageError::IncompatibleSchema {
+                found,
+                supported: target,
+            })?;
+        progress(migration);
+        let mut writer = MigrationWriter::new(backend);
+        (migration.migrate)(backend, &mut writer)?;
+        version += 1;
+        writer.writes.extend(version_writes(version));
+        writer.finish()?;
+    }
+    if stored_version(backend)?.is_none() {
+        backend.write_batch(version_writes(version))?;
+    }
+    Ok(found)
+}
+
+fn version_writes(version: u32) -> Vec<KvWrite> {
+    vec![(
+        KvTree::Meta,
+        SCHEMA_VERSION_KEY.to_vec(),
+        Some(version.to_be_bytes().to_vec()),
+    )]
+}
+
+fn is_empty(backend: &dyn KvBackend) -> Result<bool, StorageError> {
+    for tree in KvTree::ALL {
+        if backend.last_with_prefix(tree, b"")?.is_some() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+impl<B: KvBackend> KvStorage<B> {
+    /// Wrap `backend`, checking its schema version and running any pending
+    /// migrations first.
+    pub fn open(backend: B) -> Result<Self, StorageError> {
+        Self::open_with_progress(backend, |_| {})
+    }
+
+    /// Like [`KvStorage::open`], reporting each migration to `progress`
+    /// before it runs, e.g. to tell the user why opening takes a while.
+    pub fn open_with_progress(
+        backend: B,
+        mut progress: impl FnMut(&Migration),
+    ) -> Result<Self, StorageError> {
+        upgrade(&backend, &mut progress)?;
+        Ok(Self::with_backend(backend))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryBackend, Storage, TagQuery};
+    use crate::types::{TransactionPayload, TransactionSignature};
+
+    fn rename_legacy_key(
+        backend: &dyn KvBackend,
+        writer: &mut MigrationWriter,
+    ) -> Result<(), StorageError> {
+        let value = backend.get(KvTree::ChainState, b"legacy")?;
+        writer.push([
+            (KvTree::ChainState, b"legacy".to_vec(), None),
+            (KvTree::ChainState, b"renamed".to_vec(), value),
+        ])
+    }
+
+    #[test]
+    fn test_database_is_migrated_in_place() {
+        let backend = MemoryBackend::new();
+        backend.write_batch(version_writes(1)).unwrap();
+        backend.insert(KvTree::ChainState, b"legacy", b"v").unwrap();
+        let migrations = [Migration {
+            from: 1,
+            description: "rename legacy key",
+            migrate: rename_legacy_key,
+        }];
+
+        let mut applied = Vec::new();
+        let mut progress = |migration: &Migration| applied.push(migration.from);
+        assert_eq!(
+            upgrade_with(&backend, 2, &migrations, &mut progress).unwrap(),
+            1
+        );
+        assert_eq!(applied, [1]);
+        assert_eq!(stored_version(&backend).unwrap(), Some(2));
+        assert_eq!(backend.get(KvTree::ChainState, b"legacy").unwrap(), None);
+        assert_eq!(
+            backend.get(KvTree::ChainState, b"renamed").unwrap(),
+            Some(b"v".to_vec())
+        );
+
+        // Already current: nothing runs again.
+        assert_eq!(
+            upgrade_with(&backend, 2, &migrations, &mut |_| panic!("ran again")).unwrap(),
+            2
+        );
+        assert!(matches!(
+            upgrade_with(&backend, 1, &migrations, &mut |_| {}),
+            Err(StorageError::IncompatibleSchema {
+                found: 2,
+                supported: 1
+            })
+        ));
+    }
+
+    fn signed(
+        signing_key: &ed25519_dalek::SigningKey,
+        recipient: Address,
+        payload: TransactionPayload,
+        nonce: u64,
+        metadata: Option<BTreeMap<String, String>>,
+    ) -> Transaction {
+        let mut tx = Transaction {
+            hash: [0; 32],
+            sender: PublicKey::from(signing_key.verifying_key()),
+            recipient,
+            payload,
+            nonce,
+            timestamp: nonce,
+            signature: TransactionSignature::from_bytes(&[0; 64]).unwrap(),
+            gas_limit: 0,
+            priority: 0,
+            metadata,
+        };
+        tx.sign(signing_key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_baseline_database_is_migrated() {
+        let alice_key = ed25519_dalek::SigningKey::from_bytes(&[1; 32]);
+        let alice = PublicKey::from(alice_key.verifying_key());
+        let bob = PublicKey::from(ed25519_dalek::SigningKey::from_bytes(&[2; 32]).verifying_key());
+        let data = signed(
+            &alice_key,
+            Address::Wallet(alice),
+            TransactionPayload::Data { data: vec![0; 5] },
+            1,
+            Some(BTreeMap::from([("app".to_string(), "demo".to_string())])),
+        );
+        let transfer = signed(
+            &alice_key,
+            Address::Wallet(bob),
+            TransactionPayload::Transfer { amount: 10 },
+            2,
+            None,
+        );
+        let pending = signed(
+            &alice_key,
+            Address::Wallet(bob),
+            TransactionPayload::Transfer { amount: 1 },
+            3,
+            None,
+        );
+        let baseline_block = |index: u64, hash: u8, transactions: Vec<Transaction>| BaselineBlock {
+            index,
+            timestamp: index,
+            prev_hash: if index == 0 { [0; 32] } else { [0xa0; 32] },
+            hash: [hash; 32],
+            nonce: 0,
+            transactions,
+            metadata: None,
+        };
+        let contract_id = ContractId::from_bytes(&[4; 32]);
+
+        // What the baseline `SledStorage` wrote, including a block that
+        // never became canonical.
+        let backend = MemoryBackend::new();
+        for (key, block) in [
+            (
+                b"height:00000000000000000000".to_vec(),
+                baseline_block(0, 0xa0, Vec::new()),
+            ),
+            ([0xa0; 32].to_vec(), baseline_block(0, 0xa0, Vec::new())),
+            (
+                b"height:00000000000000000001".to_vec(),
+                baseline_block(1, 0xa1, vec![data.clone(), transfer.clone()]),
+            ),
+            (
+                [0xa1; 32].to_vec(),
+                baseline_block(1, 0xa1, vec![data.clone(), transfer.clone()]),
+            ),
+            ([0xb1; 32].to_vec(), baseline_block(1, 0xb1, Vec::new())),
+        ] {
+            let encoded = bincode::serialize(&block).unwrap();
+            backend.insert(KvTree::Blocks, &key, &encoded).unwrap();
+        }
+        for (index, tx) in [&data, &transfer].into_iter().enumerate() {
+            let key = format!(
+                "block_tx:{}:{}:{:0>10}",
+                "a1".repeat(32),
+                hex::encode(tx.hash),
+                index
+            );
+            backend
+                .insert(KvTree::TxByBlock, key.as_bytes(), &tx.hash)
+                .unwrap();
+            let encoded = bincode::serialize(tx).unwrap();
+            backend
+                .insert(KvTree::Transactions, &tx.hash, &encoded)
+                .unwrap();
+        }
+        let pending_key_bytes = [b"pending:".as_slice(), &pending.hash].concat();
+        backend
+            .insert(
+                KvTree::Mempool,
+                &pending_key_bytes,
+                &bincode::serialize(&pending).unwrap(),
+            )
+            .unwrap();
+        for (address, balance, nonce) in [(alice, 90, 2), (bob, 10, 0)] {
+            let account = Account::Wallet { balance, nonce };
+            backend
+                .insert(
+                    KvTree::Accounts,
+                    &address.to_bytes(),
+                    &bincode::serialize(&account).unwrap(),
+                )
+                .unwrap();
+        }
+        backend
+            .insert(KvTree::ContractCode, &contract_id.id, &[0; 8])
+            .unwrap();
+        let slot_key = format!("state:{}:{}", "04".repeat(32), hex::encode(b"k"));
+        backend
+            .insert(KvTree::ContractStorage, slot_key.as_bytes(), b"v")
+            .unwrap();
+        let chain_state = BaselineChainState {
+            latest_block_hash: [0xa1; 32],
+            latest_block_index: 1,
+            accounts_root_hash: [0; 32],
+            total_supply: 0,
+        };
+        backend
+            .insert(
+                KvTree::ChainState,
+                BASELINE_CHAIN_STATE_KEY,
+                &bincode::serialize(&chain_state).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(stored_version(&backend).unwrap(), None);
+
+        let check = |storage: &KvStorage<MemoryBackend>| {
+            let genesis = storage.get_block_by_height(0).unwrap().unwrap();
+            let tip = storage.get_latest_block().unwrap().unwrap();
+            assert_eq!(tip.index, 1);
+            assert_eq!(tip.transactions, [data.clone(), transfer.clone()]);
+            assert_eq!(tip.hash, tip.calculate_hash().unwrap());
+            assert_eq!(tip.prev_hash, genesis.hash);
+            assert_eq!(storage.get_block(&tip.hash).unwrap(), Some(tip.clone()));
+
+            let chain_state = storage.get_chain_state().unwrap().unwrap();
+            assert_eq!(chain_state.latest_block_hash, tip.hash);
+            assert_eq!(chain_state.accounts_root_hash, tip.state_root);
+            assert_eq!(
+                storage.get_prune_horizon().unwrap(),
+                PruneHorizon {
+                    state: 1,
+                    bodies: 0
+                }
+            );
+            assert_eq!(
+                storage.get_transactions_by_block(&tip.hash).unwrap(),
+                [data.clone(), transfer.clone()]
+            );
+            assert!(storage
+                .get_block_receipts(&tip.hash)
+                .unwrap()
+                .iter()
+                .all(|receipt| receipt.success));
+            assert_eq!(
+                storage
+                    .get_address_transactions(&Address::Wallet(bob), None, Order::Ascending, 10)
+                    .unwrap(),
+                [(
+                    TxPosition {
+                        height: 1,
+                        tx_index_in_block: 1
+                    },
+                    transfer.hash
+                )]
+            );
+            let tagged = storage
+                .get_tagged_transactions(
+                    &TagQuery::equals("app", "demo"),
+                    None,
+                    Order::Ascending,
+                    10,
+                )
+                .unwrap();
+            assert_eq!(tagged.len(), 1);
+            assert_eq!(tagged[0].tx_hash, data.hash);
+            assert_eq!(
+                storage.get_storage_usage(&Address::Wallet(alice)).unwrap(),
+                quota::data_bytes(&data)
+            );
+            assert_eq!(
+                storage
+                    .get_storage_usage(&Address::Contract(contract_id.clone()))
+                    .unwrap(),
+                10
+            );
+            assert_eq!(
+                storage.contract_storage_read(&contract_id, b"k").unwrap(),
+                Some(b"v".to_vec())
+            );
+            assert_eq!(
+                storage.get_pending_transactions().unwrap(),
+                std::slice::from_ref(&pending)
+            );
+            assert_eq!(crate::integrity::verify(storage).unwrap().issues, []);
+        };
+
+        let storage = KvStorage::open(backend.clone()).unwrap();
+        assert_eq!(stored_version(&backend).unwrap(), Some(SCHEMA_VERSION));
+        check(&storage);
+        for (tree, prefix) in [
+            (KvTree::Blocks, b"height:".as_slice()),
+            (KvTree::TxByBlock, b"block_tx:"),
+            (KvTree::Mempool, b"pending:"),
+            (KvTree::ContractStorage, b"state:"),
+            (KvTree::ChainState, b"global:"),
+        ] {
+            assert_eq!(backend.scan_prefix(tree, prefix).unwrap(), []);
+        }
+        for hash in [[0xa0; 32], [0xa1; 32], [0xb1; 32]] {
+            assert_eq!(backend.get(KvTree::Blocks, &hash).unwrap(), None);
+        }
+
+        // A run interrupted before the version bump starts over on a
+        // rewritten database.
+        backend
+            .write_batch(vec![(KvTree::Meta, SCHEMA_VERSION_KEY.to_vec(), None)])
+            .unwrap();
+        let storage = KvStorage::open(backend.clone()).unwrap();
+        check(&storage);
+    }
+
+    #[test]
+    fn test_baseline_genesis_state_is_recorded() {
+        let alice =
+            PublicKey::from(ed25519_dalek::SigningKey::from_bytes(&[1; 32]).verifying_key());
+        let account = Account::Wallet {
+            balance: 100,
+            nonce: 0,
+        };
+        let genesis = BaselineBlock {
+            index: 0,
+            timestamp: 0,
+            prev_hash: [0; 32],
+            hash: [0xa0; 32],
+            nonce: 0,
+            transactions: Vec::new(),
+            metadata: None,
+        };
+        let chain_state = BaselineChainState {
+            latest_block_hash: [0xa0; 32],
+            latest_block_index: 0,
+            accounts_root_hash: [0; 32],
+            total_supply: 0,
+        };
+        let backend = MemoryBackend::new();
+        backend
+            .insert(
+                KvTree::Blocks,
+                &baseline_height_key(0),
+                &bincode::serialize(&genesis).unwrap(),
+            )
+            .unwrap();
+        backend
+            .insert(
+                KvTree::Accounts,
+                &alice.to_bytes(),
+                &bincode::serialize(&account).unwrap(),
+            )
+            .unwrap();
+        backend
+            .insert(
+                KvTree::ChainState,
+                BASELINE_CHAIN_STATE_KEY,
+                &bincode::serialize(&chain_state).unwrap(),
+            )
+            .unwrap();
+
+        let storage = KvStorage::open(backend).unwrap();
+        assert_eq!(
+            storage.get_genesis_state().unwrap(),
+            Some(vec![StateEntry::Account(alice, account)])
+        );
+        assert_eq!(
+            storage.get_prune_horizon().unwrap(),
+            PruneHorizon::default()
+        );
+        assert_eq!(crate::integrity::verify(&storage).unwrap().issues, []);
+    }
+
+    #[test]
+    fn test_new_database_is_stamped_with_current_version() {
+        let backend = MemoryBackend::new();
+        KvStorage::open(backend.clone()).unwrap();
+        assert_eq!(stored_version(&backend).unwrap(), Some(SCHEMA_VERSION));
+    }
+}
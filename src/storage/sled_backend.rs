@@ -0,0 +1,141 @@
+//! sled backend: one sled tree per [`KvTree`], batches committed in a
+//! multi-tree transaction.
+
+use std::path::Path;
+
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::{Db, Tree};
+
+use super::{KvBackend, KvPair, KvStorage, KvTree, KvWrite, Order, StorageError};
+
+/// [`crate::storage::Storage`] backed by sled.
+pub type SledStorage = KvStorage<SledBackend>;
+
+#[derive(Clone)]
+pub struct SledBackend {
+    db: Db,
+    /// Indexed by `KvTree as usize`, in [`KvTree::ALL`] order.
+    trees: Vec<Tree>,
+}
+
+impl SledBackend {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Open a throwaway database that is deleted when dropped.
+    pub fn temporary() -> Result<Self, StorageError> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: Db) -> Result<Self, StorageError> {
+        let trees = KvTree::ALL
+            .iter()
+            .map(|tree| db.open_tree(tree.name()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { db, trees })
+    }
+
+    fn tree(&self, tree: KvTree) -> &Tree {
+        &self.trees[tree as usize]
+    }
+
+    /// Flush dirty buffers to disk.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl SledStorage {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::open(SledBackend::open(path)?)
+    }
+
+    /// Open a throwaway database that is deleted when dropped.
+    pub fn temporary() -> Result<Self, StorageError> {
+        Self::open(SledBackend::temporary()?)
+    }
+}
+
+impl KvBackend for SledBackend {
+    fn get(&self, tree: KvTree, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.tree(tree).get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, tree: KvTree, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.tree(tree).insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: KvTree, key: &[u8]) -> Result<(), StorageError> {
+        self.tree(tree).remove(key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, tree: KvTree, prefix: &[u8]) -> Result<Vec<KvPair>, StorageError> {
+        self.tree(tree)
+            .scan_prefix(prefix)
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn last_with_prefix(
+        &self,
+        tree: KvTree,
+        prefix: &[u8],
+    ) -> Result<Option<KvPair>, StorageError> {
+        match self.tree(tree).scan_prefix(prefix).next_back() {
+            Some(item) => {
+                let (key, value) = item?;
+                Ok(Some((key.to_vec(), value.to_vec())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn scan_range(
+        &self,
+        tree: KvTree,
+        start: &[u8],
+        end: &[u8],
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, StorageError> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let range = self.tree(tree).range(start..end);
+        let entry = |item: sled::Result<(sled::IVec, sled::IVec)>| {
+            let (key, value) = item?;
+            Ok((key.to_vec(), value.to_vec()))
+        };
+        match order {
+            Order::Ascending => range.take(limit).map(entry).collect(),
+            Order::Descending => range.rev().take(limit).map(entry).collect(),
+        }
+    }
+
+    fn write_batch(&self, writes: Vec<KvWrite>) -> Result<(), StorageError> {
+        self.trees
+            .as_slice()
+            .transaction(|trees| {
+                for (tree, key, value) in &writes {
+                    let tree = &trees[*tree as usize];
+                    match value {
+                        Some(value) => tree.insert(key.as_slice(), value.as_slice())?,
+                        None => tree.remove(key.as_slice())?,
+                    };
+                }
+                Ok::<(), ConflictableTransactionError<StorageError>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+//! SQLite backend: one table per [`KvTree`], batches committed in a single
+//! SQLite transaction. Enabled by the `sqlite` cargo feature.
+//!
+//! Every table is keyed by the encoded key and keeps the record in `value`.
+//! The tables host apps query directly (blocks, transactions, accounts,
+//! contract code and storage, and the tx index) also have columns decoded
+//! from each row, and indices for looking rows up by them:
+//!
+//! | table              | columns                              | indices                   |
+//! |--------------------|--------------------------------------|---------------------------|
+//! | `blocks`           | `hash`, `height`                     | `hash`, `height`          |
+//! | `transactions`     | `hash`                               |                           |
+//! | `accounts`         | `address`                            |                           |
+//! | `contract_code`    | `contract_id`                        |                           |
+//! | `contract_storage` | `contract_id`, `slot`                | `(contract_id, slot)`     |
+//! | `tx_by_block`      | `block_hash`, `position`, `tx_hash`  | `tx_hash`                 |
+//!
+//! A column is NULL on rows it does not apply to, e.g. `height` on a block
+//! stored by hash. Columns are read from the key, which stays in the clear
+//! under [`super::EncryptedBackend`]; `tx_hash`, read from the value, is
+//! NULL there.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+
+use super::keys::{self, KeyReader};
+use super::{
+    prefix_upper_bound, KvBackend, KvPair, KvStorage, KvTree, KvWrite, Order, StorageError,
+};
+
+/// A column decoded from every row of a table, besides `key` and `value`.
+struct Column {
+    name: &'static str,
+    sql_type: &'static str,
+    /// Reads the column from a row's key and value; `None` for NULL.
+    decode: fn(&[u8], &[u8]) -> Option<Value>,
+}
+
+impl Column {
+    const fn new(
+        name: &'static str,
+        sql_type: &'static str,
+        decode: fn(&[u8], &[u8]) -> Option<Value>,
+    ) -> Self {
+        Self {
+            name,
+            sql_type,
+            decode,
+        }
+    }
+}
+
+const BLOCK_COLUMNS: &[Column] = &[
+    Column::new("hash", "BLOB", |key, _| tagged_hash(key, keys::BLOCK)),
+    Column::new("height", "INTEGER", |key, _| {
+        integer(KeyReader::new(key, keys::BLOCK_HEIGHT).ok()?.u64().ok()?)
+    }),
+];
+const TRANSACTION_COLUMNS: &[Column] = &[Column::new("hash", "BLOB", |key, _| bare_hash(key))];
+const ACCOUNT_COLUMNS: &[Column] = &[Column::new("address", "BLOB", |key, _| bare_hash(key))];
+const CONTRACT_CODE_COLUMNS: &[Column] =
+    &[Column::new("contract_id", "BLOB", |key, _| bare_hash(key))];
+const CONTRACT_STORAGE_COLUMNS: &[Column] = &[
+    Column::new("contract_id", "BLOB", |key, _| {
+        tagged_hash(key, keys::CONTRACT_SLOT)
+    }),
+    Column::new("slot", "BLOB", |key, _| {
+        let mut reader = KeyReader::new(key, keys::CONTRACT_SLOT).ok()?;
+        reader.hash().ok()?;
+        Some(Value::Blob(reader.rest().to_vec()))
+    }),
+];
+const TX_INDEX_COLUMNS: &[Column] = &[
+    Column::new("block_hash", "BLOB", |key, _| {
+        tagged_hash(key, keys::BLOCK_TX)
+    }),
+    Column::new("position", "INTEGER", |key, _| {
+        let mut reader = KeyReader::new(key, keys::BLOCK_TX).ok()?;
+        reader.hash().ok()?;
+        integer(reader.u32().ok()?.into())
+    }),
+    Column::new("tx_hash", "BLOB", |_, value| bare_hash(value)),
+];
+
+/// Indices on the decoded columns: name, table, indexed columns.
+const INDICES: &[(&str, KvTree, &str)] = &[
+    ("blocks_by_hash", KvTree::Blocks, "hash"),
+    ("blocks_by_height", KvTree::Blocks, "height"),
+    (
+        "contract_storage_by_slot",
+        KvTree::ContractStorage,
+        "contract_id, slot",
+    ),
+    ("tx_by_block_by_tx", KvTree::TxByBlock, "tx_hash"),
+];
+
+/// The decoded columns of `tree`'s table.
+fn columns(tree: KvTree) -> &'static [Column] {
+    match tree {
+        KvTree::Blocks => BLOCK_COLUMNS,
+        KvTree::Transactions => TRANSACTION_COLUMNS,
+        KvTree::Accounts => ACCOUNT_COLUMNS,
+        KvTree::ContractCode => CONTRACT_CODE_COLUMNS,
+        KvTree::ContractStorage => CONTRACT_STORAGE_COLUMNS,
+        KvTree::TxByBlock => TX_INDEX_COLUMNS,
+        _ => &[],
+    }
+}
+
+fn bare_hash(bytes: &[u8]) -> Option<Value> {
+    (bytes.len() == 32).then(|| Value::Blob(bytes.to_vec()))
+}
+
+fn tagged_hash(key: &[u8], tag: u8) -> Option<Value> {
+    let hash = KeyReader::new(key, tag).ok()?.hash().ok()?;
+    Some(Value::Blob(hash.to_vec()))
+}
+
+fn integer(value: u64) -> Option<Value> {
+    Some(Value::Integer(i64::try_from(value).ok()?))
+}
+
+/// [`crate::storage::Storage`] backed by SQLite.
+pub type SqliteStorage = KvStorage<SqliteBackend>;
+
+/// A SQLite connection shared between clones.
+///
+/// BLOB keys compare with `memcmp`, which gives the bytewise key order
+/// [`KvBackend`] requires.
+#[derive(Clone)]
+pub struct SqliteBackend {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    /// Open (or create) the database file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Open a private in-memory database.
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut connection: Connection) -> Result<Self, StorageError> {
+        let transaction = connection.transaction()?;
+        for tree in KvTree::ALL {
+            let definitions: String = columns(tree)
+                .iter()
+                .map(|column| format!(", {} {}", column.name, column.sql_type))
+                .collect();
+            transaction.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (key BLOB PRIMARY KEY, value BLOB NOT NULL{}) WITHOUT ROWID",
+                    tree.name(),
+                    definitions
+                ),
+                [],
+            )?;
+            add_missing_columns(&transaction, tree)?;
+        }
+        for (name, tree, indexed) in INDICES {
+            transaction.execute(
+                &format!(
+                    "CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+                    name,
+                    tree.name(),
+                    indexed
+                ),
+                [],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        // SQLite rolls back an unfinished transaction itself, so a poisoned
+        // lock still guards a consistent database.
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SqliteStorage {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::open(SqliteBackend::open(path)?)
+    }
+
+    pub fn in_memory() -> Result<Self, StorageError> {
+        Self::open(SqliteBackend::open_in_memory()?)
+    }
+}
+
+/// Give a table created before its decoded columns existed the missing
+/// ones, filled in for every row.
+fn add_missing_columns(connection: &Connection, tree: KvTree) -> Result<(), StorageError> {
+    let existing: Vec<String> = connection
+        .prepare(&format!("PRAGMA table_info({})", tree.name()))?
+        .query_map([], |row| row.get(1))?
+        .collect::<Result<_, _>>()?;
+    let mut added = false;
+    for column in columns(tree) {
+        if !existing.iter().any(|name| name == column.name) {
+            connection.execute(
+                &format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    tree.name(),
+                    column.name,
+                    column.sql_type
+                ),
+                [],
+            )?;
+            added = true;
+        }
+    }
+    if added {
+        let rows: Vec<KvPair> = connection
+            .prepare(&format!("SELECT key, value FROM {}", tree.name()))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (key, value) in rows {
+            write(connection, &(tree, key, Some(value)))?;
+        }
+    }
+    Ok(())
+}
+
+fn write(connection: &Connection, write: &KvWrite) -> Result<(), StorageError> {
+    let (tree, key, value) = write;
+    match value {
+        Some(value) => {
+            let columns = columns(*tree);
+            let names: String = columns
+                .iter()
+                .map(|column| format!(", {}", column.name))
+                .collect();
+            let placeholders: String = (3..columns.len() + 3)
+                .map(|index| format!(", ?{}", index))
+                .collect();
+            let decoded = columns
+                .iter()
+                .map(|column| (column.decode)(key, value).unwrap_or(Value::Null));
+            let values = [Value::Blob(key.clone()), Value::Blob(value.clone())]
+                .into_iter()
+                .chain(decoded);
+            connection
+                .prepare_cached(&format!(
+                    "INSERT OR REPLACE INTO {} (key, value{}) VALUES (?1, ?2{})",
+                    tree.name(),
+                    names,
+                    placeholders
+                ))?
+                .execute(params_from_iter(values))?
+        }
+        None => connection
+            .prepare_cached(&format!("DELETE FROM {} WHERE key = ?1", tree.name()))?
+            .execute(params![key])?,
+    };
+    Ok(())
+}
+
+impl KvBackend for SqliteBackend {
+    fn get(&self, tree: KvTree, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self
+            .connection()
+            .prepare_cached(&format!("SELECT value FROM {} WHERE key = ?1", tree.name()))?
+            .query_row(params![key], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn insert(&self, tree: KvTree, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        write(
+            &self.connection(),
+            &(tree, key.to_vec(), Some(value.to_vec())),
+        )
+    }
+
+    fn remove(&self, tree: KvTree, key: &[u8]) -> Result<(), StorageError> {
+        write(&self.connection(), &(tree, key.to_vec(), None))
+    }
+
+    fn scan_prefix(&self, tree: KvTree, prefix: &[u8]) -> Result<Vec<KvPair>, StorageError> {
+        let connection = self.connection();
+        let row = |row: &rusqlite::Row<'_>| Ok((row.get(0)?, row.get(1)?));
+        let entries = match prefix_upper_bound(prefix) {
+            Some(upper) => connection
+                .prepare_cached(&format!(
+                    "SELECT key, value FROM {} WHERE key >= ?1 AND key < ?2 ORDER BY key",
+                    tree.name()
+                ))?
+                .query_map(params![prefix, upper], row)?
+                .collect::<Result<_, _>>()?,
+            None => connection
+                .prepare_cached(&format!(
+                    "SELECT key, value FROM {} WHERE key >= ?1 ORDER BY key",
+                    tree.name()
+                ))?
+                .query_map(params![prefix], row)?
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(entries)
+    }
+
+    fn last_with_prefix(
+        &self,
+        tree: KvTree,
+        prefix: &[u8],
+    ) -> Result<Option<KvPair>, StorageError> {
+        let connection = self.connection();
+        let row = |row: &rusqlite::Row<'_>| Ok((row.get(0)?, row.get(1)?));
+        let entry = match prefix_upper_bound(prefix) {
+            Some(upper) => connection
+                .prepare_cached(&format!(
+                    "SELECT key, value FROM {} WHERE key >= ?1 AND key < ?2 ORDER BY key DESC LIMIT 1",
+                    tree.name()
+                ))?
+                .query_row(params![prefix, upper], row)
+                .optional()?,
+            None => connection
+                .prepare_cached(&format!(
+                    "SELECT key, value FROM {} WHERE key >= ?1 ORDER BY key DESC LIMIT 1",
+                    tree.name()
+                ))?
+                .query_row(params![prefix], row)
+                .optional()?,
+        };
+        Ok(entry)
+    }
+
+    fn scan_range(
+        &self,
+        tree: KvTree,
+        start: &[u8],
+        end: &[u8],
+        order: Order,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, StorageError> {
+        let direction = match order {
+            Order::Ascending => "ASC",
+            Order::Descending => "DESC",
+        };
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let entries = self
+            .connection()
+            .prepare_cached(&format!(
+                "SELECT key, value FROM {} WHERE key >= ?1 AND key < ?2 ORDER BY key {} LIMIT ?3",
+                tree.name(),
+                direction
+            ))?
+            .query_map(params![start, end, limit], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(entries)
+    }
+
+    fn write_batch(&self, writes: Vec<KvWrite>) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for entry in &writes {
+            write(&transaction, entry)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{height_key, Storage, StorageBatch, StorageOperation};
+    use crate::types::ContractId;
+
+    #[test]
+    fn test_tables_have_decoded_columns() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        let contract_id = ContractId::from_bytes(&[4; 32]);
+        let mut batch = StorageBatch::default();
+        batch.ops.push(StorageOperation::IndexTransaction {
+            tx_hash: [9; 32],
+            block_hash: [8; 32],
+            tx_index_in_block: 3,
+        });
+        batch.ops.push(StorageOperation::ContractStorageWrite(
+            contract_id.clone(),
+            b"k".to_vec(),
+            b"v".to_vec(),
+        ));
+        storage.apply_batch(batch).unwrap();
+
+        let connection = storage.backend().connection();
+        let (block_hash, position): (Vec<u8>, i64) = connection
+            .query_row(
+                "SELECT block_hash, position FROM tx_by_block WHERE tx_hash = ?1",
+                params![[9u8; 32]],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((block_hash, position), (vec![8; 32], 3));
+        let slot: Vec<u8> = connection
+            .query_row(
+                "SELECT slot FROM contract_storage WHERE contract_id = ?1",
+                params![contract_id.id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(slot, b"k");
+    }
+
+    #[test]
+    fn test_columns_are_added_to_existing_tables() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute(
+                "CREATE TABLE blocks (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID",
+                [],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO blocks (key, value) VALUES (?1, ?2)",
+                params![height_key(7), b"block".to_vec()],
+            )
+            .unwrap();
+
+        let backend = SqliteBackend::from_connection(connection).unwrap();
+        let (height, hash): (i64, Option<Vec<u8>>) = backend
+            .connection()
+            .query_row("SELECT height, hash FROM blocks", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((height, hash), (7, None));
+        assert_eq!(
+            backend.get(KvTree::Blocks, &height_key(7)).unwrap(),
+            Some(b"block".to_vec())
+        );
+    }
+}
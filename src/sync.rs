@@ -13,10 +13,11 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::{timeout, Duration};
 
-use crate::types::{Block, ChainState, PublicKey};
+use crate::consensus::raft::RaftMessage;
+use crate::types::{Block, BlockHeader, ChainState, EquivocationEvidence, PublicKey, Vote};
 
 #[derive(Debug, Error)]
 pub enum SyncError {
@@ -67,11 +68,25 @@ pub enum NetworkMessage {
     BlocksResponse {
         blocks: Vec<Block>,
     },
+    /// Request canonical headers for header-only sync. Answered with at
+    /// most `crate::runtime::MAX_HEADERS_PER_RESPONSE` of them.
+    GetHeaders {
+        from_height: u64,
+        max_count: u64,
+    },
+    HeadersResponse {
+        headers: Vec<BlockHeader>,
+    },
     NewBlockAnnouncement {
         block_hash: [u8; 32],
         height: u64,
     },
 
+    // Consensus protocol messages
+    Raft(RaftMessage),
+    FinalityVote(Box<Vote>),
+    Evidence(Box<EquivocationEvidence>),
+
     // Keep-alive
     Ping,
     Pong,
@@ -134,6 +149,139 @@ pub trait SyncLayer: Send + Sync {
 
     /// Broadcasts a new block to known peers.
     async fn broadcast_block(&self, block: &Block, peers: &[Peer]) -> Result<(), SyncError>;
+
+    /// Gossips proof of authority equivocation to known peers.
+    ///
+    /// Layers that do not talk to other nodes can rely on the default no-op.
+    async fn broadcast_evidence(
+        &self,
+        _evidence: &EquivocationEvidence,
+        _peers: &[Peer],
+    ) -> Result<(), SyncError> {
+        Ok(())
+    }
+
+    /// Gossips a finality vote to known peers.
+    ///
+    /// Layers that do not talk to other nodes can rely on the default no-op.
+    async fn broadcast_vote(&self, _vote: &Vote, _peers: &[Peer]) -> Result<(), SyncError> {
+        Ok(())
+    }
+
+    /// Sends `message` to one peer, e.g. in reply to its request.
+    ///
+    /// Layers that do not talk to other nodes can rely on the default no-op.
+    fn send_to(&self, _peer: &PublicKey, _message: NetworkMessage) -> Result<(), SyncError> {
+        Ok(())
+    }
+}
+
+/// Point-to-point delivery of protocol messages to a known peer.
+///
+/// Consensus engines that need to talk to other nodes (e.g. Raft) send through
+/// a `Transport` and receive through a [`MessageHandler`]. Delivery is
+/// best-effort: a message to an unreachable peer is dropped, and the protocol
+/// is expected to retry.
+pub trait Transport: Send + Sync {
+    fn send(&self, to: &PublicKey, message: NetworkMessage) -> Result<(), SyncError>;
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn send(&self, to: &PublicKey, message: NetworkMessage) -> Result<(), SyncError> {
+        (**self).send(to, message)
+    }
+}
+
+/// Receiver side of a [`Transport`].
+pub trait MessageHandler: Send + Sync {
+    fn handle_message(&self, from: &PublicKey, message: NetworkMessage);
+}
+
+/// In-process message bus connecting several nodes inside one process.
+///
+/// Messages are delivered synchronously on the sender's thread, which keeps
+/// multi-node tests deterministic. Nodes can be disconnected to simulate a
+/// crash or network partition.
+#[derive(Clone, Default)]
+pub struct InProcessNetwork {
+    handlers: Arc<std::sync::Mutex<HashMap<PublicKey, Arc<dyn MessageHandler>>>>,
+    disconnected: Arc<std::sync::Mutex<std::collections::HashSet<PublicKey>>>,
+}
+
+impl InProcessNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the handler that receives messages addressed to `id`.
+    pub fn register(&self, id: PublicKey, handler: Arc<dyn MessageHandler>) {
+        self.handlers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, handler);
+    }
+
+    /// Create the transport a node uses to send messages as `id`.
+    pub fn transport(&self, id: PublicKey) -> InProcessTransport {
+        InProcessTransport {
+            id,
+            network: self.clone(),
+        }
+    }
+
+    /// Drop all traffic to and from `id` until [`InProcessNetwork::reconnect`].
+    pub fn disconnect(&self, id: &PublicKey) {
+        self.disconnected
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(*id);
+    }
+
+    pub fn reconnect(&self, id: &PublicKey) {
+        self.disconnected
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(id);
+    }
+
+    fn deliver(
+        &self,
+        from: &PublicKey,
+        to: &PublicKey,
+        message: NetworkMessage,
+    ) -> Result<(), SyncError> {
+        {
+            let disconnected = self.disconnected.lock().unwrap_or_else(|e| e.into_inner());
+            if disconnected.contains(from) || disconnected.contains(to) {
+                return Ok(());
+            }
+        }
+        // Release the registry lock before dispatching so handlers can send.
+        let handler = self
+            .handlers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(to)
+            .cloned()
+            .ok_or_else(|| {
+                SyncError::NetworkError(format!("Unknown peer {}", hex::encode(to.to_bytes())))
+            })?;
+        handler.handle_message(from, message);
+        Ok(())
+    }
+}
+
+/// A node's handle onto an [`InProcessNetwork`].
+#[derive(Clone)]
+pub struct InProcessTransport {
+    id: PublicKey,
+    network: InProcessNetwork,
+}
+
+impl Transport for InProcessTransport {
+    fn send(&self, to: &PublicKey, message: NetworkMessage) -> Result<(), SyncError> {
+        self.network.deliver(&self.id, to, message)
+    }
 }
 
 /// Minimal custom P2P sync implementation
@@ -142,6 +290,9 @@ pub struct CustomSync {
     known_peers: Arc<Mutex<HashMap<PublicKey, SocketAddr>>>,
     listen_addr: SocketAddr,
     is_running: Arc<Mutex<bool>>,
+    message_handler: Arc<std::sync::Mutex<Option<Arc<dyn MessageHandler>>>>,
+    /// Queues feeding one long-lived outbound connection per peer.
+    outbound: Arc<std::sync::Mutex<HashMap<PublicKey, mpsc::UnboundedSender<NetworkMessage>>>>,
 }
 
 impl CustomSync {
@@ -151,26 +302,73 @@ impl CustomSync {
             known_peers: Arc::new(Mutex::new(HashMap::new())),
             listen_addr,
             is_running: Arc::new(Mutex::new(false)),
+            message_handler: Arc::new(std::sync::Mutex::new(None)),
+            outbound: Arc::new(std::sync::Mutex::new(HashMap::new())),
         }
     }
 
+    /// Route protocol messages received after the handshake to `handler`.
+    pub fn set_message_handler(&self, handler: Arc<dyn MessageHandler>) {
+        *self
+            .message_handler
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(handler);
+    }
+
     pub async fn add_peer(&self, peer: Peer) {
         let mut peers = self.known_peers.lock().await;
         peers.insert(peer.id, peer.address);
     }
 
     pub async fn start_server(&self) -> Result<(), SyncError> {
+        let Some(listener) = self.bind().await? else {
+            return Ok(());
+        };
+        self.accept_loop(listener).await
+    }
+
+    /// Bind the listen address and accept connections on a background task.
+    ///
+    /// Returns the bound address, which tells peers where to connect when
+    /// the configured port was 0.
+    pub async fn spawn_server(&self) -> Result<SocketAddr, SyncError> {
+        let listener = self
+            .bind()
+            .await?
+            .ok_or_else(|| SyncError::NetworkError("P2P server already running".to_string()))?;
+        let address = listener
+            .local_addr()
+            .map_err(|e| SyncError::NetworkError(e.to_string()))?;
+        let server = Self {
+            peer_id: self.peer_id,
+            known_peers: Arc::clone(&self.known_peers),
+            listen_addr: address,
+            is_running: Arc::clone(&self.is_running),
+            message_handler: Arc::clone(&self.message_handler),
+            outbound: Arc::clone(&self.outbound),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = server.accept_loop(listener).await {
+                eprintln!("P2P server stopped: {}", e);
+            }
+        });
+        Ok(address)
+    }
+
+    /// Bind the listener, or return `None` if the server is already running.
+    async fn bind(&self) -> Result<Option<TcpListener>, SyncError> {
         let mut running = self.is_running.lock().await;
         if *running {
-            return Ok(());
+            return Ok(None);
         }
-        *running = true;
-        drop(running);
-
         let listener = TcpListener::bind(self.listen_addr)
             .await
             .map_err(|e| SyncError::NetworkError(e.to_string()))?;
+        *running = true;
+        Ok(Some(listener))
+    }
 
+    async fn accept_loop(&self, listener: TcpListener) -> Result<(), SyncError> {
         println!("P2P server listening on {}", self.listen_addr);
 
         loop {
@@ -181,9 +379,15 @@ impl CustomSync {
 
             let peer_id = self.peer_id;
             let peers = Arc::clone(&self.known_peers);
+            let handler = self
+                .message_handler
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone();
 
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(socket, addr, peer_id, peers).await {
+                if let Err(e) = Self::handle_connection(socket, addr, peer_id, peers, handler).await
+                {
                     eprintln!("Connection error: {}", e);
                 }
             });
@@ -195,6 +399,7 @@ impl CustomSync {
         addr: SocketAddr,
         peer_id: PublicKey,
         peers: Arc<Mutex<HashMap<PublicKey, SocketAddr>>>,
+        handler: Option<Arc<dyn MessageHandler>>,
     ) -> Result<(), SyncError> {
         // Simple handshake
         let handshake = MessageFrame::new(NetworkMessage::Handshake {
@@ -207,36 +412,40 @@ impl CustomSync {
             .await
             .map_err(|e| SyncError::NetworkError(e.to_string()))?;
 
-        // Read response
-        let mut buffer = [0u8; 1024];
-        let n = tokio::io::AsyncReadExt::read(&mut socket, &mut buffer)
-            .await
-            .map_err(|e| SyncError::NetworkError(e.to_string()))?;
-
-        if n == 0 {
-            return Err(SyncError::NetworkError("Connection closed".to_string()));
-        }
-
-        let frame = MessageFrame::from_bytes(&buffer[..n])?;
-        match frame.message {
+        // Read response. Outbound transport connections open with their own
+        // Handshake rather than an ack, so accept either.
+        let remote_peer_id = match Self::receive_message(&mut socket).await? {
             NetworkMessage::HandshakeAck {
                 peer_id: remote_peer_id,
                 version,
+            }
+            | NetworkMessage::Handshake {
+                peer_id: remote_peer_id,
+                version,
             } => {
                 if version != 1 {
                     return Err(SyncError::NetworkError("Version mismatch".to_string()));
                 }
 
-                // Add to known peers
+                // Add to known peers. A configured address wins over the
+                // connection's ephemeral source port.
                 let mut peers_guard = peers.lock().await;
-                peers_guard.insert(remote_peer_id, addr);
+                peers_guard.entry(remote_peer_id).or_insert(addr);
                 println!(
                     "New peer connected: {} at {}",
                     hex::encode(remote_peer_id.to_bytes()),
                     addr
                 );
+                remote_peer_id
             }
             _ => return Err(SyncError::InvalidMessage),
+        };
+
+        // Hand every following frame to the registered protocol handler.
+        if let Some(handler) = handler {
+            while let Ok(message) = Self::receive_message(&mut socket).await {
+                handler.handle_message(&remote_peer_id, message);
+            }
         }
 
         Ok(())
@@ -254,6 +463,23 @@ impl CustomSync {
         Ok(())
     }
 
+    /// Best-effort send of `message` to every peer; failures are logged.
+    async fn broadcast_message(message: NetworkMessage, peers: &[Peer]) {
+        for peer in peers {
+            if let Ok(Ok(mut stream)) =
+                timeout(Duration::from_secs(2), TcpStream::connect(peer.address)).await
+            {
+                if let Err(e) = Self::send_message(&mut stream, message.clone()).await {
+                    eprintln!(
+                        "Failed to broadcast to {}: {}",
+                        hex::encode(peer.id.to_bytes()),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
     async fn receive_message(stream: &mut TcpStream) -> Result<NetworkMessage, SyncError> {
         let mut length_buffer = [0u8; 4];
         tokio::io::AsyncReadExt::read_exact(stream, &mut length_buffer)
@@ -266,8 +492,8 @@ impl CustomSync {
             .await
             .map_err(|e| SyncError::NetworkError(e.to_string()))?;
 
-        let frame = MessageFrame::from_bytes(&message_buffer)?;
-        Ok(frame.message)
+        bincode::deserialize(&message_buffer)
+            .map_err(|e| SyncError::SerializationError(e.to_string()))
     }
 }
 
@@ -343,25 +569,120 @@ impl SyncLayer for CustomSync {
             block_hash: block.hash,
             height: block.index,
         };
+        Self::broadcast_message(announcement, peers).await;
+        Ok(())
+    }
 
-        for peer in peers {
-            if let Ok(Ok(mut stream)) =
-                timeout(Duration::from_secs(2), TcpStream::connect(peer.address)).await
-            {
-                if let Err(e) = Self::send_message(&mut stream, announcement.clone()).await {
-                    eprintln!(
-                        "Failed to broadcast to {}: {}",
-                        hex::encode(peer.id.to_bytes()),
-                        e
-                    );
-                }
+    async fn broadcast_evidence(
+        &self,
+        evidence: &EquivocationEvidence,
+        peers: &[Peer],
+    ) -> Result<(), SyncError> {
+        Self::broadcast_message(NetworkMessage::Evidence(Box::new(evidence.clone())), peers).await;
+        Ok(())
+    }
+
+    async fn broadcast_vote(&self, vote: &Vote, peers: &[Peer]) -> Result<(), SyncError> {
+        Self::broadcast_message(NetworkMessage::FinalityVote(Box::new(vote.clone())), peers).await;
+        Ok(())
+    }
+
+    fn send_to(&self, peer: &PublicKey, message: NetworkMessage) -> Result<(), SyncError> {
+        Transport::send(self, peer, message)
+    }
+}
+
+impl CustomSync {
+    /// Deliver queued messages to `to` over one connection, reconnecting
+    /// after a failure. Runs until the queue's sender is dropped.
+    async fn run_outbound(
+        peer_id: PublicKey,
+        to: PublicKey,
+        peers: Arc<Mutex<HashMap<PublicKey, SocketAddr>>>,
+        mut queue: mpsc::UnboundedReceiver<NetworkMessage>,
+    ) {
+        let mut connection: Option<TcpStream> = None;
+        while let Some(message) = queue.recv().await {
+            if connection.is_none() {
+                connection = Self::connect(peer_id, &to, &peers).await;
+            }
+            let Some(stream) = connection.as_mut() else {
+                // Unreachable peer: drop the message, the protocol retries.
+                continue;
+            };
+            if let Err(e) = Self::send_message(stream, message).await {
+                eprintln!("Failed to send to {}: {}", hex::encode(to.to_bytes()), e);
+                connection = None;
             }
         }
+    }
 
+    async fn connect(
+        peer_id: PublicKey,
+        to: &PublicKey,
+        peers: &Mutex<HashMap<PublicKey, SocketAddr>>,
+    ) -> Option<TcpStream> {
+        let Some(address) = peers.lock().await.get(to).copied() else {
+            eprintln!("Unknown peer {}", hex::encode(to.to_bytes()));
+            return None;
+        };
+        let mut stream = timeout(Duration::from_secs(2), TcpStream::connect(address))
+            .await
+            .ok()?
+            .ok()?;
+        let handshake = NetworkMessage::Handshake {
+            peer_id,
+            version: 1,
+        };
+        Self::send_message(&mut stream, handshake).await.ok()?;
+        Some(stream)
+    }
+
+    fn spawn_outbound(
+        &self,
+        outbound: &mut HashMap<PublicKey, mpsc::UnboundedSender<NetworkMessage>>,
+        to: &PublicKey,
+        message: NetworkMessage,
+    ) -> Result<(), SyncError> {
+        let handle = tokio::runtime::Handle::try_current()
+            .map_err(|_| SyncError::NetworkError("No async runtime available".to_string()))?;
+        let (sender, queue) = mpsc::unbounded_channel();
+        sender
+            .send(message)
+            .map_err(|_| SyncError::NetworkError("Outbound queue closed".to_string()))?;
+        handle.spawn(Self::run_outbound(
+            self.peer_id,
+            *to,
+            Arc::clone(&self.known_peers),
+            queue,
+        ));
+        outbound.insert(*to, sender);
         Ok(())
     }
 }
 
+impl Transport for CustomSync {
+    /// Send `message` to a known peer over TCP.
+    ///
+    /// Messages to a peer are queued in order onto one long-lived connection
+    /// served by a task on the current tokio runtime, and the call returns
+    /// immediately; failures are logged and left to the protocol to retry.
+    fn send(&self, to: &PublicKey, message: NetworkMessage) -> Result<(), SyncError> {
+        let mut outbound = self.outbound.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(queue) = outbound.get(to) {
+            match queue.send(message) {
+                Ok(()) => return Ok(()),
+                // The connection task is gone (its runtime shut down); start over.
+                Err(mpsc::error::SendError(returned)) => {
+                    outbound.remove(to);
+                    return self.spawn_outbound(&mut outbound, to, returned);
+                }
+            }
+        }
+        self.spawn_outbound(&mut outbound, to, message)
+    }
+}
+
 /// No-operation implementation for testing
 #[derive(Debug, Clone)]
 pub struct NoopSync;
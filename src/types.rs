@@ -76,8 +76,12 @@ impl<'de> Deserialize<'de> for PublicKey {
     where
         D: serde::Deserializer<'de>,
     {
-        let bytes = <[u8; 32]>::deserialize(deserializer)?;
-        PublicKey::from_bytes(&bytes).map_err(serde::de::Error::custom)
+        // Must mirror `serialize_bytes` above, which is length-prefixed.
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let bytes_array: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("Invalid public key length"))?;
+        PublicKey::from_bytes(&bytes_array).map_err(serde::de::Error::custom)
     }
 }
 
@@ -175,16 +179,40 @@ pub struct Block {
     pub timestamp: u64,
     /// Hash of the previous block
     pub prev_hash: [u8; 32],
-    /// Hash of this block (calculated from all fields)
+    /// Hash of this block's header (see [`BlockHeader::hash`])
     pub hash: [u8; 32],
     /// Proof-of-work nonce (currently unused in PoA)
     pub nonce: u64,
+    /// Root of the state Merkle tree after applying this block
+    pub state_root: [u8; 32],
+    /// Merkle root of the transaction hashes (see `crate::merkle::merkle_root`)
+    pub transactions_root: [u8; 32],
+    /// Merkle root of the transaction receipts, in block order
+    pub receipts_root: [u8; 32],
     /// List of transactions included in this block
     pub transactions: Vec<Transaction>,
     /// Optional metadata for extensibility (using BTreeMap for deterministic serialization)
     pub metadata: Option<std::collections::BTreeMap<String, String>>,
+    /// Authority signature over `hash` (not part of the hash itself)
+    pub seal: Option<BlockSeal>,
+}
+
+/// An authority's signature over a block's height and hash.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct BlockSeal {
+    pub signer: PublicKey,
+    pub signature: TransactionSignature,
 }
 
+/// Most tags a data transaction may carry.
+pub const MAX_TAGS: usize = 16;
+
+/// Longest tag name, in bytes.
+pub const MAX_TAG_NAME_LEN: usize = 64;
+
+/// Longest tag value, in bytes.
+pub const MAX_TAG_VALUE_LEN: usize = 256;
+
 /// A transaction in the blockchain.
 ///
 /// Transactions represent state changes, including transfers, contract
@@ -213,13 +241,13 @@ pub struct Transaction {
     pub metadata: Option<std::collections::BTreeMap<String, String>>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize, Deserialize)]
 pub enum Address {
     Wallet(PublicKey),
     Contract(ContractId),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct ContractId {
     pub id: [u8; 32],
 }
@@ -248,18 +276,33 @@ impl From<ContractId> for Address {
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum TransactionPayload {
-    Transfer { amount: u64 },
-    ContractDeploy { wasm_bytes: Vec<u8> },
-    ContractCall { method: String, args: Vec<u8> },
-    Data { data: Vec<u8> },
+    Transfer {
+        amount: u64,
+    },
+    ContractDeploy {
+        wasm_bytes: Vec<u8>,
+    },
+    ContractCall {
+        method: String,
+        args: Vec<u8>,
+    },
+    Data {
+        data: Vec<u8>,
+    },
+    /// Proof that an authority equivocated; applying it jails the offender.
+    SubmitEvidence {
+        evidence: Box<EquivocationEvidence>,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct ChainState {
     pub latest_block_hash: [u8; 32],
     pub latest_block_index: u64,
-    pub accounts_root_hash: [u8; 32], // Merkle root of the accounts/contract state tree
+    pub accounts_root_hash: [u8; 32], // Root of the state Merkle tree (see `crate::merkle`)
     pub total_supply: u64,            // (Optional) If BaaLS has a native token
+    pub finalized_height: u64,        // Highest block covered by a commit certificate
+    pub jailed_authorities: Vec<PublicKey>, // Authorities removed for proven equivocation
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -291,26 +334,226 @@ impl Account {
     }
 }
 
-impl Block {
-    /// Calculate the SHA-256 hash of the block.
+/// Everything needed to roll state back across one applied block.
+///
+/// Written by the ledger alongside each block it applies, and consumed when
+/// a chain reorganization reverts that block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockUndo {
+    /// Chain state before the block was applied.
+    pub prev_chain_state: ChainState,
+    /// Previous value of every account the block touched (`None` if it did not exist).
+    pub accounts: Vec<(PublicKey, Option<Account>)>,
+    /// Previous code of every contract the block deployed.
+    pub contract_code: Vec<(ContractId, Option<Vec<u8>>)>,
+    /// Previous value of every contract storage key the block wrote.
+    pub contract_storage: Vec<(ContractId, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+/// Phase of a finality vote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// A signed finality vote cast by an authority for a block at a given height.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub block_hash: [u8; 32],
+    pub voter: PublicKey,
+    pub signature: TransactionSignature,
+}
+
+/// Proof that more than two thirds of the authorities precommitted a block.
+///
+/// Stored alongside the block it finalizes; once a certificate exists for a
+/// height, the ledger never reverts below it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitCertificate {
+    pub height: u64,
+    pub block_hash: [u8; 32],
+    pub precommits: Vec<Vote>,
+}
+
+impl Vote {
+    /// Message an authority signs for a vote.
     ///
-    /// The hash is computed from all block fields (except the hash itself)
-    /// and serves as the block's unique identifier.
+    /// Domain-separated so a vote signature can never be replayed as a
+    /// transaction signature or vice versa.
+    pub fn signing_hash(kind: VoteKind, height: u64, block_hash: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"baals-vote");
+        hasher.update([match kind {
+            VoteKind::Prevote => 0u8,
+            VoteKind::Precommit => 1u8,
+        }]);
+        hasher.update(height.to_le_bytes());
+        hasher.update(block_hash);
+        hasher.finalize().into()
+    }
+
+    /// Create and sign a vote.
+    pub fn new_signed(
+        kind: VoteKind,
+        height: u64,
+        block_hash: [u8; 32],
+        signing_key: &SigningKey,
+    ) -> Self {
+        let message = Self::signing_hash(kind, height, &block_hash);
+        Vote {
+            kind,
+            height,
+            block_hash,
+            voter: PublicKey::from(signing_key.verifying_key()),
+            signature: TransactionSignature::from(signing_key.sign(&message)),
+        }
+    }
+
+    /// Verify the vote's signature against its voter.
+    pub fn verify_signature(&self) -> bool {
+        let message = Self::signing_hash(self.kind, self.height, &self.block_hash);
+        self.voter.verify(&message, &self.signature.0).is_ok()
+    }
+}
+
+/// Whether `votes` out of `authorities` is strictly more than two thirds.
+pub fn is_supermajority(votes: usize, authorities: usize) -> bool {
+    authorities > 0 && votes * 3 > authorities * 2
+}
+
+impl CommitCertificate {
+    /// Verify the certificate against an authority set.
     ///
-    /// # Errors
+    /// Every precommit must be a valid signature by a distinct authority over
+    /// this certificate's height and block hash, and together they must
+    /// exceed two thirds of `authorities`.
+    pub fn verify(&self, authorities: &[PublicKey]) -> bool {
+        let mut seen = std::collections::BTreeSet::new();
+        for vote in &self.precommits {
+            if vote.kind != VoteKind::Precommit
+                || vote.height != self.height
+                || vote.block_hash != self.block_hash
+                || !authorities.contains(&vote.voter)
+                || !seen.insert(vote.voter)
+                || !vote.verify_signature()
+            {
+                return false;
+            }
+        }
+        is_supermajority(seen.len(), authorities.len())
+    }
+}
+
+/// A block hash signed by an authority, as seen on the wire or in a block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedHeader {
+    pub height: u64,
+    pub block_hash: [u8; 32],
+    pub signer: PublicKey,
+    pub signature: TransactionSignature,
+}
+
+impl SignedHeader {
+    /// Message an authority signs when sealing a block.
     ///
-    /// Returns `CryptoError::HashConversionError` if serialization fails.
-    pub fn calculate_hash(&self) -> Result<[u8; 32], CryptoError> {
+    /// Covers the height explicitly so two seals can only be paired as
+    /// evidence when they really were made for the same height.
+    pub fn signing_hash(height: u64, block_hash: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"baals-seal");
+        hasher.update(height.to_le_bytes());
+        hasher.update(block_hash);
+        hasher.finalize().into()
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        let message = Self::signing_hash(self.height, &self.block_hash);
+        self.signer.verify(&message, &self.signature.0).is_ok()
+    }
+}
+
+/// Verifiable proof that an authority signed two conflicting statements.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EquivocationEvidence {
+    /// Two different blocks sealed at the same height.
+    DoubleSign {
+        first: SignedHeader,
+        second: SignedHeader,
+    },
+    /// Two different finality votes of the same kind at the same height.
+    DoubleVote { first: Vote, second: Vote },
+}
+
+impl EquivocationEvidence {
+    /// The authority that equivocated.
+    pub fn offender(&self) -> PublicKey {
+        match self {
+            EquivocationEvidence::DoubleSign { first, .. } => first.signer,
+            EquivocationEvidence::DoubleVote { first, .. } => first.voter,
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        match self {
+            EquivocationEvidence::DoubleSign { first, .. } => first.height,
+            EquivocationEvidence::DoubleVote { first, .. } => first.height,
+        }
+    }
+
+    /// Check that both statements come from the same signer, at the same
+    /// height, for different blocks, and carry valid signatures.
+    pub fn verify(&self) -> bool {
+        match self {
+            EquivocationEvidence::DoubleSign { first, second } => {
+                first.signer == second.signer
+                    && first.height == second.height
+                    && first.block_hash != second.block_hash
+                    && first.verify_signature()
+                    && second.verify_signature()
+            }
+            EquivocationEvidence::DoubleVote { first, second } => {
+                first.voter == second.voter
+                    && first.kind == second.kind
+                    && first.height == second.height
+                    && first.block_hash != second.block_hash
+                    && first.verify_signature()
+                    && second.verify_signature()
+            }
+        }
+    }
+}
+
+/// The part of a block that is hashed and signed.
+///
+/// Headers are enough to follow the chain and to check Merkle proofs of
+/// state, transactions and receipts without downloading block bodies.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: u64,
+    pub prev_hash: [u8; 32],
+    pub nonce: u64,
+    pub state_root: [u8; 32],
+    pub transactions_root: [u8; 32],
+    pub receipts_root: [u8; 32],
+    pub metadata: Option<std::collections::BTreeMap<String, String>>,
+    /// Authority signature over the hash (not part of the hash itself)
+    pub seal: Option<BlockSeal>,
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> Result<[u8; 32], CryptoError> {
         let mut hasher = Sha256::new();
         hasher.update(self.index.to_le_bytes());
         hasher.update(self.timestamp.to_le_bytes());
         hasher.update(self.prev_hash);
         hasher.update(self.nonce.to_le_bytes());
-
-        // Serialize transactions deterministically
-        let serialized_txns =
-            bincode::serialize(&self.transactions).map_err(|_| CryptoError::HashConversionError)?;
-        hasher.update(serialized_txns);
+        hasher.update(self.state_root);
+        hasher.update(self.transactions_root);
+        hasher.update(self.receipts_root);
 
         // Serialize metadata deterministically
         if let Some(metadata) = &self.metadata {
@@ -321,6 +564,182 @@ impl Block {
 
         Ok(hasher.finalize().into())
     }
+
+    /// Whether `self` directly extends `parent`.
+    pub fn extends(&self, parent: &BlockHeader) -> bool {
+        self.index == parent.index + 1
+            && parent
+                .hash()
+                .is_ok_and(|parent_hash| parent_hash == self.prev_hash)
+    }
+
+    /// Check a state proof for `key` against this header's state root.
+    pub fn verify_state(&self, proof: &StateProof, key: &[u8; 32]) -> bool {
+        proof.verify(&self.state_root, key)
+    }
+
+    /// Check that a transaction is included in this block.
+    pub fn verify_transaction(&self, proof: &TransactionProof) -> bool {
+        proof.verify(&self.transactions_root)
+    }
+
+    /// Check that `receipt` is the receipt at `proof.index` in this block.
+    ///
+    /// Receipt proofs share the [`TransactionProof`] shape, with the receipt
+    /// hash in place of the transaction hash.
+    pub fn verify_receipt(&self, receipt: &TransactionReceipt, proof: &TransactionProof) -> bool {
+        receipt.hash().is_ok_and(|hash| hash == proof.tx_hash) && proof.verify(&self.receipts_root)
+    }
+}
+
+/// Outcome of executing one transaction, committed via `receipts_root`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionReceipt {
+    pub tx_hash: [u8; 32],
+    pub success: bool,
+    /// Return data of a contract call.
+    pub output: Vec<u8>,
+    /// Why a contract call failed.
+    pub error: Option<String>,
+}
+
+impl TransactionReceipt {
+    pub fn hash(&self) -> Result<[u8; 32], CryptoError> {
+        let encoded = bincode::serialize(self).map_err(|_| CryptoError::HashConversionError)?;
+        Ok(Sha256::digest(encoded).into())
+    }
+}
+
+/// Merkle proof of the value, or absence, of a key in the state tree.
+///
+/// Produced by `Runtime::prove_account` and friends; checked by light clients
+/// against a trusted block's `state_root` with [`StateProof::verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateProof {
+    /// State tree key (see `crate::merkle::account_key` and friends).
+    pub key: [u8; 32],
+    /// Value under `key`, or `None` if the proof shows it is absent.
+    pub value: Option<Vec<u8>>,
+    /// Sibling hashes from the root down to the key's position.
+    pub siblings: Vec<[u8; 32]>,
+    /// For absence proofs: key and value hash of a different leaf found at
+    /// the key's position, if the position is not empty.
+    pub other_leaf: Option<([u8; 32], [u8; 32])>,
+}
+
+impl StateProof {
+    /// Check that the proof is about `key` and leads to `state_root`.
+    pub fn verify(&self, state_root: &[u8; 32], key: &[u8; 32]) -> bool {
+        use crate::merkle::{internal_hash, key_bit, leaf_hash, leaf_hash_from_value_hash};
+
+        if self.key != *key || self.siblings.len() > 256 {
+            return false;
+        }
+        let mut node = match (&self.value, &self.other_leaf) {
+            (Some(value), None) => leaf_hash(key, value),
+            (None, Some((other_key, _))) if other_key == key => return false,
+            (None, Some((other_key, value_hash))) => {
+                leaf_hash_from_value_hash(other_key, value_hash)
+            }
+            (None, None) => crate::merkle::EMPTY_ROOT,
+            (Some(_), Some(_)) => return false,
+        };
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            node = if key_bit(key, depth) {
+                internal_hash(sibling, &node)
+            } else {
+                internal_hash(&node, sibling)
+            };
+        }
+        node == *state_root
+    }
+
+    /// Decode the proven value as an account.
+    pub fn account(&self) -> Option<Account> {
+        bincode::deserialize(self.value.as_deref()?).ok()
+    }
+}
+
+/// Merkle proof that a transaction is included in a block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionProof {
+    pub tx_hash: [u8; 32],
+    /// Position of the transaction in the block.
+    pub index: u32,
+    /// Number of transactions in the block.
+    pub tx_count: u32,
+    /// Sibling hashes from the transaction up to the root.
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl TransactionProof {
+    /// Check the proof against a trusted block's `transactions_root`.
+    pub fn verify(&self, transactions_root: &[u8; 32]) -> bool {
+        crate::merkle::merkle_root_from_path(
+            &self.tx_hash,
+            self.index as usize,
+            self.tx_count as usize,
+            &self.siblings,
+        )
+        .is_some_and(|root| root == *transactions_root)
+    }
+}
+
+impl Block {
+    /// Calculate the SHA-256 hash of the block, its unique identifier.
+    ///
+    /// Only the header is hashed (see [`BlockHeader::hash`]). Transactions
+    /// are committed to through `transactions_root`, so the hash does not
+    /// depend on their encoding and stays the same once bodies are pruned.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CryptoError::HashConversionError` if serialization fails.
+    pub fn calculate_hash(&self) -> Result<[u8; 32], CryptoError> {
+        self.header().hash()
+    }
+
+    /// The block's header: everything but the transaction bodies.
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            prev_hash: self.prev_hash,
+            nonce: self.nonce,
+            state_root: self.state_root,
+            transactions_root: self.transactions_root,
+            receipts_root: self.receipts_root,
+            metadata: self.metadata.clone(),
+            seal: self.seal.clone(),
+        }
+    }
+
+    /// Merkle root over the hashes of `transactions`, in block order.
+    pub fn compute_transactions_root(&self) -> [u8; 32] {
+        let hashes: Vec<[u8; 32]> = self.transactions.iter().map(|tx| tx.hash).collect();
+        crate::merkle::merkle_root(&hashes)
+    }
+
+    /// Seal the block: sign its height and hash with an authority key.
+    ///
+    /// The hash must already be set; the seal is not covered by it.
+    pub fn seal(&mut self, signing_key: &SigningKey) {
+        let message = SignedHeader::signing_hash(self.index, &self.hash);
+        self.seal = Some(BlockSeal {
+            signer: PublicKey::from(signing_key.verifying_key()),
+            signature: TransactionSignature::from(signing_key.sign(&message)),
+        });
+    }
+
+    /// The signed header carried by a sealed block.
+    pub fn signed_header(&self) -> Option<SignedHeader> {
+        self.seal.as_ref().map(|seal| SignedHeader {
+            height: self.index,
+            block_hash: self.hash,
+            signer: seal.signer,
+            signature: seal.signature,
+        })
+    }
 }
 
 impl Transaction {
@@ -358,6 +777,54 @@ impl Transaction {
         Ok(hasher.finalize().into())
     }
 
+    /// The addresses whose history includes this transaction: the sender,
+    /// then the recipient unless the transaction is sent to oneself.
+    pub fn addresses(&self) -> Vec<Address> {
+        let sender = Address::Wallet(self.sender);
+        if self.recipient == sender {
+            vec![sender]
+        } else {
+            vec![sender, self.recipient.clone()]
+        }
+    }
+
+    /// The indexed tags of a data transaction: all of its metadata entries.
+    /// Other transactions carry none.
+    pub fn tags(&self) -> impl Iterator<Item = (&str, &str)> {
+        let metadata = match self.payload {
+            TransactionPayload::Data { .. } => self.metadata.as_ref(),
+            _ => None,
+        };
+        metadata
+            .into_iter()
+            .flatten()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Check the tags against [`MAX_TAGS`], [`MAX_TAG_NAME_LEN`] and
+    /// [`MAX_TAG_VALUE_LEN`], which bound what one transaction adds to
+    /// the tag index.
+    pub fn check_tags(&self) -> Result<(), String> {
+        if self.tags().count() > MAX_TAGS {
+            return Err(format!("More than {} tags", MAX_TAGS));
+        }
+        for (name, value) in self.tags() {
+            if name.len() > MAX_TAG_NAME_LEN {
+                return Err(format!(
+                    "Tag name longer than {} bytes: {}",
+                    MAX_TAG_NAME_LEN, name
+                ));
+            }
+            if value.len() > MAX_TAG_VALUE_LEN {
+                return Err(format!(
+                    "Value of tag {} longer than {} bytes",
+                    name, MAX_TAG_VALUE_LEN
+                ));
+            }
+        }
+        Ok(())
+    }
+
     /// Sign the transaction with a private key.
     ///
     /// This calculates the transaction hash and creates an ed25519 signature.
@@ -451,8 +918,12 @@ mod tests {
             prev_hash: [0; 32],
             hash: [0; 32],
             nonce: 0,
+            state_root: [0; 32],
+            transactions_root: [0; 32],
+            receipts_root: [0; 32],
             transactions: vec![tx1.clone(), tx2.clone()],
             metadata: None,
+            seal: None,
         };
 
         let hash1 = block.calculate_hash().unwrap();